@@ -1,20 +1,23 @@
-use crate::parse_device;
+use crate::chat::usage;
+use crate::{device_name, parse_device};
 use actix_telepathy::RemoteAddr;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use candle_core::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::generation::LogitsProcessor;
 use candle_transformers::models::gemma::{Config, Model};
 use either::Either;
-use once_cell::sync::OnceCell;
 use onceuponai_abstractions::EntityValue;
 use onceuponai_actors::abstractions::openai::ChatCompletionRequest;
 use onceuponai_actors::abstractions::{
-    ActorActions, ActorError, ActorInvokeData, ActorInvokeError, ActorInvokeFinish,
-    ActorInvokeRequest, ActorInvokeResponse, ActorInvokeResult,
+    usage_metadata, ActorActions, ActorError, ActorInvokeData, ActorInvokeError, ActorInvokeFinish,
+    ActorInvokeRequest, ActorInvokeResponse, ActorInvokeResult, ModelInfo,
 };
-use onceuponai_core::common::{hf_hub_get, hf_hub_get_multiple, ResultExt};
+use onceuponai_actors::actors::slot::ModelSlot;
+use onceuponai_actors::cluster::security::ClusterSend;
+use onceuponai_actors::telemetry::Phases;
+use onceuponai_core::common::{hf_hub_get, hf_hub_get_multiple, MutexExt, ResultExt};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::ops::Deref;
@@ -24,7 +27,7 @@ use uuid::Uuid;
 
 pub const GEMMA_2B_REPO_ID: &str = "google/gemma-2b-it";
 
-static GEMMA_INSTANCE: OnceCell<Arc<Mutex<GemmaModel>>> = OnceCell::new();
+static GEMMA_INSTANCE: ModelSlot<Mutex<GemmaModel>> = ModelSlot::new();
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct GemmaSpec {
@@ -52,6 +55,10 @@ impl ActorActions for GemmaSpec {
         "gemma".to_string()
     }
 
+    fn model_info(&self) -> ModelInfo {
+        GEMMA_INSTANCE.info()
+    }
+
     async fn init(&self) -> Result<()> {
         GemmaModel::init(self.clone())
     }
@@ -61,19 +68,25 @@ impl ActorActions for GemmaSpec {
         Ok(())
     }
 
+    async fn unload(&self) -> Result<()> {
+        GEMMA_INSTANCE.unload();
+        Ok(())
+    }
+
     async fn invoke(
         &self,
         uuid: Uuid,
         request: &ActorInvokeRequest,
         source: RemoteAddr,
     ) -> Result<()> {
-        let mut model = GemmaModel::lazy(self.clone())?.lock().map_anyhow_err()?;
+        let model = GemmaModel::lazy(self.clone())?;
+        let mut model = model.lock_or_recover();
         let input: String = match request.data.clone() {
             ActorInvokeData::ChatCompletion(chat_completion_request) => {
                 model.map_request(chat_completion_request)?
             }
             _ => {
-                source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+                source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
             uuid,
             task_id: request.task_id,
             error: ActorError::BadRequest(
@@ -90,11 +103,11 @@ impl ActorActions for GemmaSpec {
             uuid,
             task_id: request.task_id,
             stream: request.stream,
-            metadata: HashMap::new(),
+            metadata: usage(&model.tokenizer, &input, &text)?,
             data: HashMap::from([(String::from("content"), vec![EntityValue::STRING(text)])]),
         };
 
-        source.do_send(ActorInvokeResponse::Success(result));
+        source.send_response(ActorInvokeResponse::Success(result));
         Ok(())
     }
 
@@ -104,13 +117,14 @@ impl ActorActions for GemmaSpec {
         request: &ActorInvokeRequest,
         source: RemoteAddr,
     ) -> Result<()> {
-        let mut model = GemmaModel::lazy(self.clone())?.lock().map_anyhow_err()?;
+        let model = GemmaModel::lazy(self.clone())?;
+        let mut model = model.lock_or_recover();
         let input: String = match request.data.clone() {
             ActorInvokeData::ChatCompletion(chat_completion_request) => {
                 model.map_request(chat_completion_request)?
             }
             _ => {
-                source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+                source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
             uuid,
             task_id: request.task_id,
             error: ActorError::BadRequest(
@@ -125,6 +139,8 @@ impl ActorActions for GemmaSpec {
         let sample_len: usize = model.sample_len;
         model.model.clear_kv_cache();
 
+        let mut phases = Phases::new();
+        phases.enter("prompt");
         let mut tokens = model
             .tokenizer
             .encode(input, true)
@@ -133,8 +149,12 @@ impl ActorActions for GemmaSpec {
             .to_vec();
 
         let tokens_len = tokens.len();
+        phases.record("prompt_tokens", tokens_len as i64);
 
         for index in 0..sample_len {
+            if index == 1 {
+                phases.enter("generate");
+            }
             if let Some(_text) = model.loop_process(tokens.len(), index, &mut tokens)? {
                 let text = model
                     .tokenizer
@@ -145,7 +165,7 @@ impl ActorActions for GemmaSpec {
                     uuid,
                     task_id: request.task_id,
                     stream: request.stream,
-                    metadata: HashMap::new(),
+                    metadata: usage_metadata(tokens_len, 1),
                     data: HashMap::from([(
                         String::from("content"),
                         vec![EntityValue::STRING(text)],
@@ -153,7 +173,7 @@ impl ActorActions for GemmaSpec {
                 };
 
                 let response = ActorInvokeResponse::Success(result);
-                source.do_send(response);
+                source.send_response(response);
             } else {
                 break;
             }
@@ -166,7 +186,7 @@ impl ActorActions for GemmaSpec {
         };
 
         let response = ActorInvokeResponse::Finish(result);
-        source.do_send(response);
+        source.send_response(response);
 
         Ok(())
     }
@@ -186,8 +206,8 @@ pub struct GemmaModel {
 impl GemmaModel {
     pub fn map_request(&self, input: ChatCompletionRequest) -> Result<String> {
         let input = match input.messages {
-            Either::Left(mut left) => left
-                .iter_mut()
+            Either::Left(messages) => messages
+                .iter()
                 .map(|x| match x.content.deref() {
                     Either::Left(content) => {
                         let turn_type = match x.role.as_str() {
@@ -195,13 +215,16 @@ impl GemmaModel {
                             "model" => "model",
                             _ => "unknown",
                         };
-                        format!("<start_of_turn>{}\n{}\n<end_of_turn>", turn_type, content)
+                        Ok(format!(
+                            "<start_of_turn>{}\n{}\n<end_of_turn>",
+                            turn_type, content
+                        ))
                     }
-                    Either::Right(_) => unimplemented!(),
+                    Either::Right(_) => Err(anyhow!("ONLY TEXT MESSAGE CONTENT IS SUPPORTED")),
                 })
-                .collect::<Vec<_>>()
+                .collect::<Result<Vec<_>>>()?
                 .join("\n"),
-            Either::Right(_) => unimplemented!(),
+            Either::Right(prompt) => prompt,
         };
         Ok(input)
     }
@@ -209,6 +232,8 @@ impl GemmaModel {
     pub fn invoke(&mut self, prompt: &str) -> Result<String> {
         self.model.clear_kv_cache();
 
+        let mut phases = Phases::new();
+        phases.enter("prompt");
         let mut tokens = self
             .tokenizer
             .encode(prompt, true)
@@ -216,8 +241,12 @@ impl GemmaModel {
             .get_ids()
             .to_vec();
         let tokens_len = tokens.len();
+        phases.record("prompt_tokens", tokens_len as i64);
 
         for index in 0..self.sample_len {
+            if index == 1 {
+                phases.enter("generate");
+            }
             if let Some(_text) = self.loop_process(tokens.len(), index, &mut tokens)? {
             } else {
                 break;
@@ -266,14 +295,8 @@ impl GemmaModel {
     }
 
     #[allow(clippy::too_many_arguments)]
-    pub fn lazy<'a>(spec: GemmaSpec) -> Result<&'a Arc<Mutex<GemmaModel>>> {
-        if GEMMA_INSTANCE.get().is_none() {
-            let model = GemmaModel::load(spec)?;
-
-            let _ = GEMMA_INSTANCE.set(Arc::new(Mutex::new(model))).is_ok();
-        };
-
-        Ok(GEMMA_INSTANCE.get().expect("GEMMA_INSTANCE"))
+    pub fn lazy(spec: GemmaSpec) -> Result<Arc<Mutex<GemmaModel>>> {
+        GEMMA_INSTANCE.get_or_try_load(|| Ok(Mutex::new(GemmaModel::load(spec)?)))
     }
 
     pub fn init(spec: GemmaSpec) -> Result<()> {
@@ -309,7 +332,7 @@ impl GemmaModel {
             &base_repo_id,
             "model.safetensors.index.json",
             hf_token.clone(),
-            spec.model_revision,
+            spec.model_revision.clone(),
         )?;
 
         let device = parse_device(spec.device)?;
@@ -327,6 +350,15 @@ impl GemmaModel {
         let candle_config = hf_hub_get(&base_repo_id, "config.json", hf_token, None)?;
         let candle_config: Config = serde_json::from_slice(&candle_config)?;
         let model = Model::new(use_flash_attn, &candle_config, vb)?;
+        GEMMA_INSTANCE.set_info(ModelInfo {
+            device: Some(device_name(&device)),
+            dtype: Some(format!("{dtype:?}")),
+            context_length: Some(candle_config.max_position_embeddings),
+            vocab_size: Some(candle_config.vocab_size),
+            model_repo: Some(base_repo_id.clone()),
+            model_revision: spec.model_revision,
+            ..Default::default()
+        });
 
         let logits_processor = LogitsProcessor::new(seed, spec.temp, spec.top_p);
 
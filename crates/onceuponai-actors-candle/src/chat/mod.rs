@@ -4,7 +4,29 @@ pub mod openai_chat;
 pub mod quantized;
 
 use anyhow::Result;
+use onceuponai_abstractions::EntityValue;
+use onceuponai_actors::abstractions::usage_metadata;
+use std::collections::HashMap;
+use tokenizers::Tokenizer;
 
 pub trait ChatModelActions {
     fn invoke(&mut self, prompt: &str) -> Result<String>;
 }
+
+/// Usage metadata of a prompt and its completion, counted with the model tokenizer.
+pub fn usage(
+    tokenizer: &Tokenizer,
+    prompt: &str,
+    completion: &str,
+) -> Result<HashMap<String, EntityValue>> {
+    let count = |text: &str, special_tokens: bool| {
+        tokenizer
+            .encode(text, special_tokens)
+            .map(|encoding| encoding.len())
+            .map_err(anyhow::Error::msg)
+    };
+    Ok(usage_metadata(
+        count(prompt, true)?,
+        count(completion, false)?,
+    ))
+}
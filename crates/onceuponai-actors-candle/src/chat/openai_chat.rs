@@ -10,6 +10,8 @@ use onceuponai_actors::abstractions::{
     ActorActions, ActorError, ActorInvokeData, ActorInvokeError, ActorInvokeFinish,
     ActorInvokeRequest, ActorInvokeResponse, ActorInvokeResult,
 };
+use onceuponai_actors::cluster::security::ClusterSend;
+use onceuponai_actors::telemetry;
 use onceuponai_core::common::some_or_env;
 use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
@@ -64,7 +66,7 @@ impl ActorActions for OpenAIChatSpec {
                 model.map_request(chat_completion_request)?
             }
             _ => {
-                source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+                source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
             uuid,
             task_id: request.task_id,
             error: ActorError::BadRequest(
@@ -89,7 +91,7 @@ impl ActorActions for OpenAIChatSpec {
             data: HashMap::from([(String::from("content"), results)]),
         };
 
-        source.do_send(ActorInvokeResponse::Success(result));
+        source.send_response(ActorInvokeResponse::Success(result));
         Ok(())
     }
 
@@ -105,7 +107,7 @@ impl ActorActions for OpenAIChatSpec {
                 model.map_request(chat_completion_request)?
             }
             _ => {
-                source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+                source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
             uuid,
             task_id: request.task_id,
             error: ActorError::BadRequest(
@@ -147,7 +149,7 @@ impl ActorActions for OpenAIChatSpec {
                                         };
 
                                         let response = ActorInvokeResponse::Success(result);
-                                        source.do_send(response);
+                                        source.send_response(response);
                                         actix_rt::task::yield_now().await;
                                     }
                                 }
@@ -166,7 +168,7 @@ impl ActorActions for OpenAIChatSpec {
         };
 
         let response = ActorInvokeResponse::Finish(result);
-        source.do_send(response);
+        source.send_response(response);
 
         Ok(())
     }
@@ -204,13 +206,12 @@ impl OpenAIChatModel {
             .api_key
             .unwrap_or("https://api.openai.com".to_string());
 
-        let res = self
+        let request = self
             .client
             .post(format!("{}/v1/chat/completions", base_url))
             .bearer_auth(api_key)
-            .json(&request_body)
-            .send()
-            .await?;
+            .json(&request_body);
+        let res = telemetry::send(request).await?;
 
         let text = res.text().await?;
         let response_body: ChatCompletionResponse = serde_json::from_str(&text)?;
@@ -233,13 +234,12 @@ impl OpenAIChatModel {
             .api_key
             .unwrap_or("https://api.openai.com".to_string());
 
-        let res = self
+        let request = self
             .client
             .post(format!("{}/v1/chat/completions", base_url))
             .bearer_auth(api_key)
-            .json(&request_body)
-            .send()
-            .await?;
+            .json(&request_body);
+        let res = telemetry::send(request).await?;
 
         Ok(res)
     }
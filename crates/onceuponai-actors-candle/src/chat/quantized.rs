@@ -1,21 +1,24 @@
-use crate::parse_device;
+use crate::chat::usage;
+use crate::{device_name, parse_device};
 use actix_telepathy::RemoteAddr;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use candle_core::quantized::{ggml_file, gguf_file};
+use candle_core::quantized::{ggml_file, gguf_file, GgmlDType};
 use candle_core::{Device, Tensor};
 use candle_transformers::generation::{LogitsProcessor, Sampling};
 use candle_transformers::models::quantized_llama as model;
 use either::Either;
 use model::ModelWeights;
-use once_cell::sync::OnceCell;
 use onceuponai_abstractions::EntityValue;
 use onceuponai_actors::abstractions::openai::ChatCompletionRequest;
 use onceuponai_actors::abstractions::{
-    ActorActions, ActorError, ActorInvokeData, ActorInvokeError, ActorInvokeFinish,
-    ActorInvokeRequest, ActorInvokeResponse, ActorInvokeResult,
+    usage_metadata, ActorActions, ActorError, ActorInvokeData, ActorInvokeError, ActorInvokeFinish,
+    ActorInvokeRequest, ActorInvokeResponse, ActorInvokeResult, ModelInfo,
 };
-use onceuponai_core::common::{hf_hub_get, hf_hub_get_path, OptionToResult, ResultExt};
+use onceuponai_actors::actors::slot::ModelSlot;
+use onceuponai_actors::cluster::security::ClusterSend;
+use onceuponai_actors::telemetry::Phases;
+use onceuponai_core::common::{hf_hub_get, hf_hub_get_path, MutexExt, OptionToResult, ResultExt};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::ops::Deref;
@@ -23,7 +26,7 @@ use std::sync::{Arc, Mutex};
 use tokenizers::Tokenizer;
 use uuid::Uuid;
 
-static QUANTIZED_INSTANCE: OnceCell<Arc<Mutex<QuantizedModel>>> = OnceCell::new();
+static QUANTIZED_INSTANCE: ModelSlot<Mutex<QuantizedModel>> = ModelSlot::new();
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct QuantizedSpec {
@@ -63,6 +66,10 @@ impl ActorActions for QuantizedSpec {
         "quantized".to_string()
     }
 
+    fn model_info(&self) -> ModelInfo {
+        QUANTIZED_INSTANCE.info()
+    }
+
     async fn init(&self) -> Result<()> {
         QuantizedModel::init(self.clone())
     }
@@ -75,21 +82,25 @@ impl ActorActions for QuantizedSpec {
         Ok(())
     }
 
+    async fn unload(&self) -> Result<()> {
+        QUANTIZED_INSTANCE.unload();
+        Ok(())
+    }
+
     async fn invoke(
         &self,
         uuid: Uuid,
         request: &ActorInvokeRequest,
         source: RemoteAddr,
     ) -> Result<()> {
-        let mut model = QuantizedModel::lazy(self.clone())?
-            .lock()
-            .map_anyhow_err()?;
+        let model = QuantizedModel::lazy(self.clone())?;
+        let mut model = model.lock_or_recover();
         let input: String = match request.data.clone() {
             ActorInvokeData::ChatCompletion(chat_completion_request) => {
                 model.map_request(chat_completion_request)?
             }
             _ => {
-                source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+                source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
             uuid,
             task_id: request.task_id,
             error: ActorError::BadRequest(
@@ -106,11 +117,11 @@ impl ActorActions for QuantizedSpec {
             uuid,
             task_id: request.task_id,
             stream: request.stream,
-            metadata: HashMap::new(),
+            metadata: usage(&model.tokenizer, &input, &text)?,
             data: HashMap::from([(String::from("content"), vec![EntityValue::STRING(text)])]),
         };
 
-        source.do_send(ActorInvokeResponse::Success(result));
+        source.send_response(ActorInvokeResponse::Success(result));
         Ok(())
     }
 
@@ -120,15 +131,14 @@ impl ActorActions for QuantizedSpec {
         request: &ActorInvokeRequest,
         source: RemoteAddr,
     ) -> Result<()> {
-        let mut model = QuantizedModel::lazy(self.clone())?
-            .lock()
-            .map_anyhow_err()?;
+        let model = QuantizedModel::lazy(self.clone())?;
+        let mut model = model.lock_or_recover();
         let input: String = match request.data.clone() {
             ActorInvokeData::ChatCompletion(chat_completion_request) => {
                 model.map_request(chat_completion_request)?
             }
             _ => {
-                source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+                source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
             uuid,
             task_id: request.task_id,
             error: ActorError::BadRequest(
@@ -143,9 +153,13 @@ impl ActorActions for QuantizedSpec {
         let repeat_last_n = model.repeat_last_n;
         let repeat_penalty = model.repeat_penalty;
 
+        let mut phases = Phases::new();
+        phases.enter("prompt");
         let prep = model.prepare(&input)?;
 
         let prompt_tokens_len = prep.0;
+        phases.record("prompt_tokens", prompt_tokens_len as i64);
+        phases.enter("generate");
         let mut all_tokens = prep.1;
         let mut logits_processor = prep.2;
 
@@ -153,6 +167,7 @@ impl ActorActions for QuantizedSpec {
         let eos_token = model.eos_token;
 
         let mut previous_text = String::new();
+        let mut emitted_tokens = 0;
         for index in 0..sample_len {
             if let Some(current_text) = model.loop_process(
                 prompt_tokens_len,
@@ -165,12 +180,14 @@ impl ActorActions for QuantizedSpec {
             )? {
                 let text = current_text.split_at(previous_text.len()).1.to_string();
                 previous_text = current_text;
+                let completion_tokens = all_tokens.len() - emitted_tokens;
+                emitted_tokens = all_tokens.len();
 
                 let result = ActorInvokeResult {
                     uuid,
                     task_id: request.task_id,
                     stream: request.stream,
-                    metadata: HashMap::new(),
+                    metadata: usage_metadata(prompt_tokens_len, completion_tokens),
                     data: HashMap::from([(
                         String::from("content"),
                         vec![EntityValue::STRING(text)],
@@ -178,7 +195,7 @@ impl ActorActions for QuantizedSpec {
                 };
 
                 let response = ActorInvokeResponse::Success(result);
-                source.do_send(response);
+                source.send_response(response);
             } else {
                 break;
             }
@@ -191,7 +208,7 @@ impl ActorActions for QuantizedSpec {
         };
 
         let response = ActorInvokeResponse::Finish(result);
-        source.do_send(response);
+        source.send_response(response);
 
         Ok(())
     }
@@ -215,10 +232,10 @@ pub struct QuantizedModel {
 impl QuantizedModel {
     pub fn map_request(&self, input: ChatCompletionRequest) -> Result<String> {
         let input = match input.messages {
-            Either::Left(mut left) => left
-                .iter_mut()
+            Either::Left(messages) => messages
+                .iter()
                 .map(|x| match x.content.deref() {
-                    Either::Left(content) => match &self.spec.prompt_format {
+                    Either::Left(content) => Ok(match &self.spec.prompt_format {
                         Some(PromptFormat::Mistral) => match x.role.as_str() {
                             "user" => format!("<s>[INST] {} [/INST]", content),
                             "model" => format!("\"{}\"</s>", content),
@@ -237,12 +254,12 @@ impl QuantizedModel {
                             _ => content.clone(),
                         },
                         None => content.clone(),
-                    },
-                    Either::Right(_) => unimplemented!(),
+                    }),
+                    Either::Right(_) => Err(anyhow!("ONLY TEXT MESSAGE CONTENT IS SUPPORTED")),
                 })
-                .collect::<Vec<_>>()
+                .collect::<Result<Vec<_>>>()?
                 .join(" "),
-            Either::Right(_) => unimplemented!(),
+            Either::Right(prompt) => prompt,
         };
         Ok(input)
     }
@@ -252,8 +269,12 @@ impl QuantizedModel {
         let repeat_penalty: f32 = self.repeat_penalty;
         let repeat_last_n: usize = self.repeat_last_n;
 
+        let mut phases = Phases::new();
+        phases.enter("prompt");
         let prep = self.prepare(prompt)?;
         let prompt_tokens_len = prep.0;
+        phases.record("prompt_tokens", prompt_tokens_len as i64);
+        phases.enter("generate");
         let mut all_tokens = prep.1;
         let mut logits_processor = prep.2;
 
@@ -355,14 +376,8 @@ impl QuantizedModel {
         Ok(Some(current_text))
     }
 
-    pub fn lazy<'a>(spec: QuantizedSpec) -> Result<&'a Arc<Mutex<QuantizedModel>>> {
-        if QUANTIZED_INSTANCE.get().is_none() {
-            let model = QuantizedModel::load(spec.clone())?;
-
-            let _ = QUANTIZED_INSTANCE.set(Arc::new(Mutex::new(model))).is_ok();
-        };
-
-        Ok(QUANTIZED_INSTANCE.get().expect("QUANTIZED_INSTANCE"))
+    pub fn lazy(spec: QuantizedSpec) -> Result<Arc<Mutex<QuantizedModel>>> {
+        QUANTIZED_INSTANCE.get_or_try_load(|| Ok(Mutex::new(QuantizedModel::load(spec)?)))
     }
 
     pub fn init(spec: QuantizedSpec) -> Result<()> {
@@ -413,7 +428,7 @@ impl QuantizedModel {
                 &model_repo,
                 &model_file,
                 spec.hf_token.clone(),
-                spec.model_revision,
+                spec.model_revision.clone(),
             )?
         };
 
@@ -429,16 +444,36 @@ impl QuantizedModel {
         let tokenizer = Tokenizer::from_bytes(&tokenizer).map_anyhow_err()?;
 
         let mut file = std::fs::File::open(&model_path)?;
-        let model = match model_path.extension().and_then(|ex| ex.to_str()) {
-            Some("gguf") => {
-                let model_content = gguf_file::Content::read(&mut file)?;
-                ModelWeights::from_gguf(model_content, &mut file, &device)?
-            }
-            Some("ggml" | "bin") | Some(_) | None => {
-                let model_content = ggml_file::Content::read(&mut file, &device)?;
-                ModelWeights::from_ggml(model_content, spec.gqa.expect("GQA"))?
-            }
-        };
+        let (model, context_length, quantization) =
+            match model_path.extension().and_then(|ex| ex.to_str()) {
+                Some("gguf") => {
+                    let model_content = gguf_file::Content::read(&mut file)?;
+                    let context_length = gguf_context_length(&model_content);
+                    let quantization = most_common_dtype(
+                        model_content.tensor_infos.values().map(|t| t.ggml_dtype),
+                    );
+                    let model = ModelWeights::from_gguf(model_content, &mut file, &device)?;
+                    (model, context_length, quantization)
+                }
+                Some("ggml" | "bin") | Some(_) | None => {
+                    let model_content = ggml_file::Content::read(&mut file, &device)?;
+                    let quantization =
+                        most_common_dtype(model_content.tensors.values().map(|t| t.dtype()));
+                    let model = ModelWeights::from_ggml(model_content, spec.gqa.expect("GQA"))?;
+                    (model, None, quantization)
+                }
+            };
+
+        QUANTIZED_INSTANCE.set_info(ModelInfo {
+            device: Some(device_name(&device)),
+            quantization,
+            context_length,
+            vocab_size: Some(tokenizer.get_vocab_size(true)),
+            model_repo: Some(model_repo.clone()),
+            model_file: Some(model_file.clone()),
+            model_revision: spec.model_revision,
+            ..Default::default()
+        });
 
         let eos_token = match spec.eos_token {
             Some(eos) => eos,
@@ -471,6 +506,36 @@ impl QuantizedModel {
     }
 }
 
+/// Context length from the `<architecture>.context_length` metadata of a gguf file.
+fn gguf_context_length(content: &gguf_file::Content) -> Option<usize> {
+    let architecture = content
+        .metadata
+        .get("general.architecture")?
+        .to_string()
+        .ok()?;
+    let context_length = content
+        .metadata
+        .get(&format!("{architecture}.context_length"))?
+        .to_u32()
+        .ok()?;
+    Some(context_length as usize)
+}
+
+/// Quantization of a model file, taken as the type most of its tensors are stored in.
+fn most_common_dtype(dtypes: impl Iterator<Item = GgmlDType>) -> Option<String> {
+    let mut counts: Vec<(GgmlDType, usize)> = Vec::new();
+    for dtype in dtypes {
+        match counts.iter_mut().find(|(d, _)| *d == dtype) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((dtype, 1)),
+        }
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|(dtype, _)| format!("{dtype:?}"))
+}
+
 /*
 #[tokio::test]
 async fn test_bielik() -> Result<()> {
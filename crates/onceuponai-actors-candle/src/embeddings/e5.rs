@@ -1,4 +1,4 @@
-use crate::parse_device;
+use crate::{device_name, parse_device};
 use actix_telepathy::RemoteAddr;
 use anyhow::Result;
 use async_trait::async_trait;
@@ -6,13 +6,14 @@ use candle_core::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::models::bert::{BertModel, Config as BertConfig};
 use log::info;
-use once_cell::sync::OnceCell;
 use onceuponai_abstractions::EntityValue;
 use onceuponai_actors::abstractions::{
     ActorActions, ActorError, ActorInvokeData, ActorInvokeError, ActorInvokeRequest,
-    ActorInvokeResponse, ActorInvokeResult,
+    ActorInvokeResponse, ActorInvokeResult, ModelInfo,
 };
-use onceuponai_core::common::{hf_hub_get, OptionToResult, ResultExt};
+use onceuponai_actors::actors::slot::ModelSlot;
+use onceuponai_actors::cluster::security::ClusterSend;
+use onceuponai_core::common::{hf_hub_get, MutexExt, OptionToResult, ResultExt};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
@@ -21,7 +22,7 @@ use uuid::Uuid;
 
 pub const E5_MODEL_REPO: &str = "intfloat/e5-small-v2";
 
-static E5_INSTANCE: OnceCell<Arc<Mutex<E5Model>>> = OnceCell::new();
+static E5_INSTANCE: ModelSlot<Mutex<E5Model>> = ModelSlot::new();
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct E5Spec {
@@ -40,6 +41,10 @@ impl ActorActions for E5Spec {
         "e5".to_string()
     }
 
+    fn model_info(&self) -> ModelInfo {
+        E5_INSTANCE.info()
+    }
+
     async fn init(&self) -> Result<()> {
         E5Model::init(self.clone())
     }
@@ -50,38 +55,44 @@ impl ActorActions for E5Spec {
         Ok(())
     }
 
+    async fn unload(&self) -> Result<()> {
+        E5_INSTANCE.unload();
+        Ok(())
+    }
+
     async fn invoke(
         &self,
         uuid: Uuid,
         request: &ActorInvokeRequest,
         source: RemoteAddr,
     ) -> Result<()> {
-        let input = match request.data.clone() {
-            ActorInvokeData::Entity(entity) => entity.get("input").unwrap().clone(),
-            _ => {
-                source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
-            uuid,
-            task_id: request.task_id,
-            error: ActorError::BadRequest(
-                "REQUEST MUST CONTAINER MESSAGE COLUMN WITH Vec<MESSAGE { role: String, content: String }>".to_string(),
-            ),
-        }));
-
-                return Ok(());
-            }
+        let input = match &request.data {
+            ActorInvokeData::Entity(entity) => entity.get("input").and_then(|input| {
+                input
+                    .iter()
+                    .map(|x| match x {
+                        EntityValue::STRING(i) => Some(i.clone()),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<String>>>()
+            }),
+            _ => None,
         };
 
-        let input: Vec<String> = input
-            .iter()
-            .map(|x| match x {
-                EntityValue::STRING(i) => i.clone(),
-                _ => todo!(),
-            })
-            .collect();
+        let Some(input) = input else {
+            source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
+                uuid,
+                task_id: request.task_id,
+                error: ActorError::BadRequest(
+                    "REQUEST MUST CONTAIN INPUT COLUMN WITH Vec<String>".to_string(),
+                ),
+            }));
+
+            return Ok(());
+        };
 
         let embeddings_data: Vec<EntityValue> = E5Model::lazy(self.clone())?
-            .lock()
-            .map_anyhow_err()?
+            .lock_or_recover()
             .embed(input)?
             .iter()
             .map(|e| EntityValue::FLOAT32ARRAY(e.clone()))
@@ -95,7 +106,7 @@ impl ActorActions for E5Spec {
             data: HashMap::from([(String::from("embeddings"), embeddings_data)]),
         };
 
-        source.do_send(ActorInvokeResponse::Success(result));
+        source.send_response(ActorInvokeResponse::Success(result));
         Ok(())
     }
 }
@@ -109,21 +120,13 @@ pub struct E5Model {
 }
 
 impl E5Model {
-    pub fn lazy<'a>(spec: E5Spec) -> Result<&'a Arc<Mutex<E5Model>>> {
-        if E5_INSTANCE.get().is_none() {
-            let e5_model = E5Model::load(spec)?;
-            let _ = E5_INSTANCE.set(Arc::new(Mutex::new(e5_model))).is_ok();
-        };
-
-        Ok(E5_INSTANCE.get().expect("E5_INSTANCE"))
+    pub fn lazy(spec: E5Spec) -> Result<Arc<Mutex<E5Model>>> {
+        E5_INSTANCE.get_or_try_load(|| Ok(Mutex::new(E5Model::load(spec)?)))
     }
 
     pub fn embeddings(input: Vec<String>) -> Result<Vec<Vec<f32>>> {
-        let model = E5_INSTANCE
-            .get()
-            .ok_or_err("E5_MODEL")?
-            .lock()
-            .map_anyhow_err()?;
+        let model = E5_INSTANCE.get().ok_or_err("E5_MODEL")?;
+        let model = model.lock_or_recover();
         let embeddings_data = model.embed(input)?;
         Ok(embeddings_data)
     }
@@ -169,6 +172,15 @@ impl E5Model {
 
         let vb = VarBuilder::from_buffered_safetensors(weights, DType::F32, &device)?;
         let model = BertModel::load(vb, &candle_config)?;
+        E5_INSTANCE.set_info(ModelInfo {
+            device: Some(device_name(&device)),
+            dtype: Some(format!("{:?}", DType::F32)),
+            context_length: Some(candle_config.max_position_embeddings),
+            vocab_size: Some(candle_config.vocab_size),
+            model_repo: Some(model_repo),
+            model_file: Some("model.safetensors".to_string()),
+            ..Default::default()
+        });
         Ok(E5Model {
             spec: spec_clone,
             model,
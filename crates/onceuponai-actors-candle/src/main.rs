@@ -1,5 +1,5 @@
 use anyhow::Result;
-use candle_core::Device;
+use candle_core::{Device, DeviceLocation};
 use chat::gemma::GemmaSpec;
 use chat::mistral::MistralSpec;
 use chat::openai_chat::OpenAIChatSpec;
@@ -57,6 +57,15 @@ fn parse_device(device_type: Option<String>) -> Result<Device> {
     Ok(device)
 }
 
+/// Device reported in the model info, e.g. `cuda:0`.
+fn device_name(device: &Device) -> String {
+    match device.location() {
+        DeviceLocation::Cpu => "cpu".to_string(),
+        DeviceLocation::Cuda { gpu_id } => format!("cuda:{gpu_id}"),
+        DeviceLocation::Metal { gpu_id } => format!("metal:{gpu_id}"),
+    }
+}
+
 fn cli() -> Command {
     Command::new("onceuponai")
         .about("onceuponai")
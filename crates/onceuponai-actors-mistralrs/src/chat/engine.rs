@@ -4,6 +4,7 @@ use super::parser::parse_chat_completion_request;
 use actix_telepathy::RemoteAddr;
 use anyhow::Result;
 use async_trait::async_trait;
+use candle_core::DeviceLocation;
 use log::{info, warn};
 use mistralrs::{
     get_model_dtype, get_tgt_non_granular_index, initialize_logging, paged_attn_supported,
@@ -11,12 +12,14 @@ use mistralrs::{
     LoaderBuilder, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType, ModelSelected,
     PagedAttentionConfig, Response, SchedulerConfig, TokenSource,
 };
-use once_cell::sync::OnceCell;
 use onceuponai_abstractions::EntityValue;
 use onceuponai_actors::abstractions::{
     ActorActions, ActorError, ActorInvokeData, ActorInvokeError, ActorInvokeFinish,
-    ActorInvokeRequest, ActorInvokeResponse, ActorInvokeResult,
+    ActorInvokeRequest, ActorInvokeResponse, ActorInvokeResult, ModelInfo,
 };
+use onceuponai_actors::actors::slot::ModelSlot;
+use onceuponai_actors::cluster::security::ClusterSend;
+use onceuponai_core::common::OptionToResult;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -24,7 +27,7 @@ use std::{num::NonZeroUsize, path::PathBuf};
 use tokio::sync::mpsc::channel;
 use uuid::Uuid;
 
-static MISTRALRS_INSTANCE: OnceCell<Arc<MistralrsModel>> = OnceCell::new();
+static MISTRALRS_INSTANCE: ModelSlot<MistralrsModel> = ModelSlot::new();
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct MistralrsSpec {
@@ -82,11 +85,11 @@ impl MistralrsSpec {
         invoke_request: &ActorInvokeRequest,
         source: RemoteAddr,
     ) -> Result<()> {
-        let state = MISTRALRS_INSTANCE.get().unwrap().clone();
+        let state = MISTRALRS_INSTANCE.get().ok_or_err("MISTRALRS_MODEL")?;
         let input = match invoke_request.data.clone() {
             ActorInvokeData::ChatCompletion(chat_completion_request) => chat_completion_request,
             _ => {
-                source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+                source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
             uuid,
             task_id: invoke_request.task_id,
             error: ActorError::BadRequest(
@@ -104,7 +107,7 @@ impl MistralrsSpec {
                 Ok(x) => x,
                 Err(e) => {
                     println!("ERROR {:?}", e);
-                    source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+                    source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
                         uuid,
                         task_id: invoke_request.task_id,
                         error: ActorError::FatalError(format!("{}", e)),
@@ -115,7 +118,7 @@ impl MistralrsSpec {
         let sender = state.mistralrs.get_sender().unwrap();
         if let Err(e) = sender.send(request).await {
             println!("ERROR {:?}", e);
-            source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+            source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
                 uuid,
                 task_id: invoke_request.task_id,
                 error: ActorError::FatalError(format!("{}", e)),
@@ -127,7 +130,7 @@ impl MistralrsSpec {
             if let Ok(resp) = rx.try_recv() {
                 match resp {
                     Response::ModelError(msg, _) => {
-                        source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+                        source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
                             uuid,
                             task_id: invoke_request.task_id,
                             error: ActorError::FatalError(msg),
@@ -135,7 +138,7 @@ impl MistralrsSpec {
                         break;
                     }
                     Response::ValidationError(e) => {
-                        source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+                        source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
                             uuid,
                             task_id: invoke_request.task_id,
                             error: ActorError::FatalError(format!("{}", e)),
@@ -143,7 +146,7 @@ impl MistralrsSpec {
                         break;
                     }
                     Response::InternalError(e) => {
-                        source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+                        source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
                             uuid,
                             task_id: invoke_request.task_id,
                             error: ActorError::FatalError(format!("{}", e)),
@@ -158,7 +161,7 @@ impl MistralrsSpec {
                                 stream: invoke_request.stream,
                             };
                             let response = ActorInvokeResponse::Finish(result);
-                            source.do_send(response);
+                            source.send_response(response);
                             break;
                         }
 
@@ -174,7 +177,7 @@ impl MistralrsSpec {
                             )]),
                         });
 
-                        source.do_send(response);
+                        source.send_response(response);
                         actix_rt::task::yield_now().await;
                     }
                     Response::Done(response) => {
@@ -190,7 +193,7 @@ impl MistralrsSpec {
                             )]),
                         });
 
-                        source.do_send(response);
+                        source.send_response(response);
                         actix_rt::task::yield_now().await;
                     }
                     Response::CompletionDone(_) => unreachable!(),
@@ -222,19 +225,28 @@ impl ActorActions for MistralrsSpec {
         "mistralrs".to_string()
     }
 
+    fn model_info(&self) -> ModelInfo {
+        MISTRALRS_INSTANCE.info()
+    }
+
     async fn init(&self) -> Result<()> {
         MistralrsModel::init(self.clone())
     }
 
     async fn start(&self) -> Result<()> {
         let spec = self.clone();
-        tokio::task::spawn_local(async move { MistralrsModel::lazy(spec).await.unwrap() }).await?;
+        tokio::task::spawn_local(async move { MistralrsModel::lazy(spec).await }).await??;
 
         println!("SPEC: {:?}", self);
 
         Ok(())
     }
 
+    async fn unload(&self) -> Result<()> {
+        MISTRALRS_INSTANCE.unload();
+        Ok(())
+    }
+
     async fn invoke(
         &self,
         uuid: Uuid,
@@ -263,20 +275,20 @@ pub struct MistralrsModel {
 }
 
 impl MistralrsModel {
-    pub async fn lazy<'a>(spec: MistralrsSpec) -> Result<&'a Arc<MistralrsModel>> {
-        if MISTRALRS_INSTANCE.get().is_none() {
-            let model = match MistralrsModel::load(spec.clone()).await {
-                Ok(m) => m,
-                Err(e) => {
-                    info!("{:?}", e);
-                    anyhow::bail!("{}", e)
-                }
-            };
+    pub async fn lazy(spec: MistralrsSpec) -> Result<Arc<MistralrsModel>> {
+        if let Some(model) = MISTRALRS_INSTANCE.get() {
+            return Ok(model);
+        }
 
-            let _ = MISTRALRS_INSTANCE.set(Arc::new(model)).is_ok();
+        let model = match MistralrsModel::load(spec.clone()).await {
+            Ok(m) => m,
+            Err(e) => {
+                info!("{:?}", e);
+                anyhow::bail!("{}", e)
+            }
         };
 
-        Ok(MISTRALRS_INSTANCE.get().expect("QUANTIZED_INSTANCE"))
+        Ok(MISTRALRS_INSTANCE.get_or_insert(model))
     }
 
     pub fn init(_spec: MistralrsSpec) -> Result<()> {
@@ -619,6 +631,24 @@ impl MistralrsModel {
         )?;
         info!("Model loaded.");
 
+        let max_seq_len = pipeline.lock().await.get_metadata().max_seq_len;
+        MISTRALRS_INSTANCE.set_info(ModelInfo {
+            device: Some(device_name(&device)),
+            dtype: Some(format!("{dtype:?}")),
+            quantization: spec_clone
+                .in_situ_quant
+                .clone()
+                .or_else(|| spec_clone.quantized_filename.clone()),
+            context_length: Some(max_seq_len),
+            model_repo: spec_clone
+                .model_id
+                .clone()
+                .or_else(|| spec_clone.quantized_model_id.clone()),
+            model_file: spec_clone.quantized_filename.clone(),
+            model_revision: spec_clone.model_revision.clone(),
+            ..Default::default()
+        });
+
         let scheduler_config = if cache_config.is_some() {
             let metadata = pipeline.lock().await.get_metadata();
             // Handle case where we may have device mapping
@@ -658,3 +688,12 @@ impl MistralrsModel {
         })
     }
 }
+
+/// Device reported in the model info, e.g. `cuda:0`.
+fn device_name(device: &Device) -> String {
+    match device.location() {
+        DeviceLocation::Cpu => "cpu".to_string(),
+        DeviceLocation::Cuda { gpu_id } => format!("cuda:{gpu_id}"),
+        DeviceLocation::Metal { gpu_id } => format!("metal:{gpu_id}"),
+    }
+}
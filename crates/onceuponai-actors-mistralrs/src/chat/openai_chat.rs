@@ -1,5 +1,5 @@
 use actix_telepathy::RemoteAddr;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use futures_util::StreamExt;
 use once_cell::sync::Lazy;
@@ -8,7 +8,9 @@ use onceuponai_actors::abstractions::{
     ActorActions, ActorError, ActorInvokeError, ActorInvokeFinish, ActorInvokeRequest,
     ActorInvokeResponse, ActorInvokeResult,
 };
-use onceuponai_core::common::some_or_env;
+use onceuponai_actors::cluster::security::ClusterSend;
+use onceuponai_actors::telemetry;
+use onceuponai_core::common::{some_or_env, OptionToResult};
 use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -59,7 +61,7 @@ impl ActorActions for OpenAIChatSpec {
         let input = request.data.get("message");
 
         if input.is_none() {
-            source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+            source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
             uuid,
             task_id: request.task_id,
             error: ActorError::BadRequest(
@@ -84,7 +86,7 @@ impl ActorActions for OpenAIChatSpec {
             data: HashMap::from([(String::from("content"), results)]),
         };
 
-        source.do_send(ActorInvokeResponse::Success(result));
+        source.send_response(ActorInvokeResponse::Success(result));
         Ok(())
     }
 
@@ -96,7 +98,7 @@ impl ActorActions for OpenAIChatSpec {
     ) -> Result<()> {
         let input = request.data.get("message");
         if input.is_none() {
-            source.do_send(ActorInvokeResponse::Failure(ActorInvokeError {
+            source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
             uuid,
             task_id: request.task_id,
             error: ActorError::BadRequest(
@@ -140,7 +142,7 @@ impl ActorActions for OpenAIChatSpec {
                                         };
 
                                         let response = ActorInvokeResponse::Success(result);
-                                        source.do_send(response);
+                                        source.send_response(response);
                                     }
                                 }
                             }
@@ -158,7 +160,7 @@ impl ActorActions for OpenAIChatSpec {
         };
 
         let response = ActorInvokeResponse::Finish(result);
-        source.do_send(response);
+        source.send_response(response);
 
         Ok(())
     }
@@ -211,17 +213,17 @@ struct OpenAIChatModel {
 
 impl OpenAIChatModel {
     pub fn map_request(&self, input: Option<&Vec<EntityValue>>) -> Result<Vec<ChatMessage>> {
-        let messages: Vec<ChatMessage> = input
-            .expect("MESSAGE")
+        let messages = input
+            .ok_or_err("MESSAGE")?
             .iter()
             .map(|x| match x {
-                EntityValue::MESSAGE { role, content } => ChatMessage {
+                EntityValue::MESSAGE { role, content } => Ok(ChatMessage {
                     role: role.clone(),
                     content: content.clone(),
-                },
-                _ => todo!(),
+                }),
+                _ => Err(anyhow!("MESSAGE COLUMN MUST CONTAIN ONLY MESSAGES")),
             })
-            .collect();
+            .collect::<Result<Vec<ChatMessage>>>()?;
         Ok(messages)
     }
 
@@ -241,13 +243,12 @@ impl OpenAIChatModel {
             .api_key
             .unwrap_or("https://api.openai.com".to_string());
 
-        let res = self
+        let request = self
             .client
             .post(format!("{}/v1/chat/completions", base_url))
             .bearer_auth(api_key)
-            .json(&request_body)
-            .send()
-            .await?;
+            .json(&request_body);
+        let res = telemetry::send(request).await?;
 
         let response_body: ChatCompletionResponse = res.json().await?;
         let output = &response_body.choices[0].message.content;
@@ -270,13 +271,12 @@ impl OpenAIChatModel {
             .api_key
             .unwrap_or("https://api.openai.com".to_string());
 
-        let res = self
+        let request = self
             .client
             .post(format!("{}/v1/chat/completions", base_url))
             .bearer_auth(api_key)
-            .json(&request_body)
-            .send()
-            .await?;
+            .json(&request_body);
+        let res = telemetry::send(request).await?;
 
         Ok(res)
     }
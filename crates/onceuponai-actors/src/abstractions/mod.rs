@@ -1,3 +1,4 @@
+use crate::telemetry::{TraceContext, TracingConfig};
 use actix::prelude::*;
 use actix_telepathy::prelude::*;
 use anyhow::Result;
@@ -18,6 +19,9 @@ pub struct ActorInvokeRequest {
     pub stream: bool,
     pub config: HashMap<String, EntityValue>,
     pub data: ActorInvokeData,
+    /// Context of the dispatching span, the worker's spans continue its trace.
+    #[serde(default)]
+    pub trace_context: TraceContext,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -35,11 +39,32 @@ pub enum ActorInvokeResponse {
     Failure(ActorInvokeError),
 }
 
+impl ActorInvokeResponse {
+    pub fn task_id(&self) -> Uuid {
+        match self {
+            ActorInvokeResponse::Success(result) => result.task_id,
+            ActorInvokeResponse::Finish(finish) => finish.task_id,
+            ActorInvokeResponse::Failure(error) => error.task_id,
+        }
+    }
+
+    /// True for the last response of a task, every one but a streamed chunk.
+    pub fn is_last(&self) -> bool {
+        !matches!(self, ActorInvokeResponse::Success(result) if result.stream)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ActorError {
     FatalError(String),
     NetworkError(String),
     BadRequest(String),
+    /// The worker queue is full, the request can be retried later.
+    Overloaded(String),
+    /// The worker is shutting down.
+    Draining(String),
+    /// The task was cancelled, e.g. from the admin API.
+    Cancelled(String),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -50,6 +75,19 @@ pub struct ActorMetadata {
     pub actor_host: String,
     pub actor_seed: Option<String>,
     pub sidecar_id: Option<Uuid>,
+    /// Requests running at once, unlimited when not set.
+    pub max_concurrency: Option<usize>,
+    /// Requests waiting for a running slot before new ones are rejected.
+    pub queue_limit: Option<usize>,
+    /// Seconds in-flight tasks get to finish when the worker shuts down.
+    pub shutdown_grace_period: Option<u64>,
+    /// Shared secret required to join the cluster, read from `ONCEUPONAI_CLUSTER_SECRET`
+    /// when not set.
+    pub cluster_secret: Option<String>,
+    /// Encrypts invoke payloads with a key derived from the cluster secret.
+    pub cluster_encryption: Option<bool>,
+    /// Export of the spans of this process, read from `OTEL_TRACES_EXPORTER` when not set.
+    pub tracing: Option<TracingConfig>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -61,6 +99,39 @@ pub struct ActorInvokeResult {
     pub data: HashMap<String, Vec<EntityValue>>,
 }
 
+/// Metadata key of the prompt tokens an actor reports with a result.
+pub const PROMPT_TOKENS: &str = "prompt_tokens";
+/// Metadata key of the tokens generated for a result, one for a streamed token.
+pub const COMPLETION_TOKENS: &str = "completion_tokens";
+
+/// Result metadata with the token usage of a request.
+pub fn usage_metadata(
+    prompt_tokens: usize,
+    completion_tokens: usize,
+) -> HashMap<String, EntityValue> {
+    HashMap::from([
+        (
+            PROMPT_TOKENS.to_string(),
+            EntityValue::INT64(prompt_tokens as i64),
+        ),
+        (
+            COMPLETION_TOKENS.to_string(),
+            EntityValue::INT64(completion_tokens as i64),
+        ),
+    ])
+}
+
+impl ActorInvokeResult {
+    /// Prompt and completion tokens reported in the metadata.
+    pub fn usage(&self) -> (Option<u64>, Option<u64>) {
+        let tokens = |key: &str| match self.metadata.get(key) {
+            Some(EntityValue::INT64(tokens)) => u64::try_from(*tokens).ok(),
+            _ => None,
+        };
+        (tokens(PROMPT_TOKENS), tokens(COMPLETION_TOKENS))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ActorInvokeFinish {
     pub uuid: Uuid,
@@ -75,6 +146,33 @@ pub struct ActorInvokeError {
     pub error: ActorError,
 }
 
+/// Model and resources of a worker, reported in `ActorInfo`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ModelInfo {
+    /// `cpu`, `cuda:0`, `metal:0`.
+    pub device: Option<String>,
+    pub dtype: Option<String>,
+    pub quantization: Option<String>,
+    /// Maximum tokens of prompt and completion.
+    pub context_length: Option<usize>,
+    pub vocab_size: Option<usize>,
+    pub model_repo: Option<String>,
+    pub model_file: Option<String>,
+    pub model_revision: Option<String>,
+    pub load_seconds: Option<f64>,
+    /// Resident memory of the worker process.
+    pub memory_bytes: Option<u64>,
+    pub gpu_memory_used_bytes: Option<u64>,
+    pub gpu_memory_total_bytes: Option<u64>,
+}
+
+impl ModelInfo {
+    /// Ordinal of the CUDA device the model runs on.
+    pub fn cuda_ordinal(&self) -> Option<u32> {
+        self.device.as_deref()?.strip_prefix("cuda:")?.parse().ok()
+    }
+}
+
 pub trait ActorKindActions: Clone + Send + Sync {
     fn actor(&self) -> Box<dyn ActorActions>;
     fn metadata(&self) -> ActorMetadata;
@@ -92,8 +190,20 @@ pub trait ActorActions: Send + Sync {
 
     fn kind(&self) -> String;
 
+    /// Model of the actor, empty until it is loaded.
+    fn model_info(&self) -> ModelInfo {
+        ModelInfo::default()
+    }
+
     async fn init(&self) -> Result<()>;
+    /// Loads the model, also after `unload`.
     async fn start(&self) -> Result<()>;
+
+    /// Drops the loaded model, the worker does not invoke the actor until it is started again.
+    async fn unload(&self) -> Result<()> {
+        Ok(())
+    }
+
     async fn invoke(
         &self,
         uuid: Uuid,
@@ -133,6 +133,8 @@ pub struct ChatCompletionRequest {
     pub stream: Option<bool>,
     pub tools: Option<Vec<Tool>>,
     pub tool_choice: Option<ToolChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
 
     // mistral.rs additional
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -0,0 +1,183 @@
+use super::main_actor::INVOKE_TASK_BUFFER;
+use super::{ActorStartInvokeRequest, WorkerActor};
+use crate::abstractions::{ActorInvokeData, ActorInvokeResponse, ActorInvokeResult};
+use crate::telemetry;
+use actix::prelude::*;
+use anyhow::{anyhow, Result};
+use once_cell::sync::OnceCell;
+use onceuponai_abstractions::EntityValue;
+use opentelemetry::Context;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+static ACTOR_CLIENT: OnceCell<ActorClient> = OnceCell::new();
+const DEFAULT_CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Invokes other actors of the cluster through the main actor, so a custom actor can
+/// compose models without going through HTTP. Requests are routed like the ones of the
+/// server, including route rules and pipelines.
+#[derive(Clone)]
+pub struct ActorClient {
+    worker: Addr<WorkerActor>,
+    timeout: Duration,
+}
+
+/// Request of the client, the worker forwards it to the main actor and hands back the
+/// channel of its responses.
+#[derive(Message)]
+#[rtype(result = "Result<mpsc::Receiver<ActorInvokeResponse>>")]
+pub(crate) struct ClientInvoke(pub ActorStartInvokeRequest);
+
+impl ActorClient {
+    pub(crate) fn init(worker: Addr<WorkerActor>) {
+        let _ = ACTOR_CLIENT.set(ActorClient {
+            worker,
+            timeout: DEFAULT_CLIENT_TIMEOUT,
+        });
+    }
+
+    /// Client of this worker process, `None` outside of a started worker.
+    pub fn get() -> Option<ActorClient> {
+        ACTOR_CLIENT.get().cloned()
+    }
+
+    /// Time `invoke` waits for a result.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Invokes `kind/name` and waits for its result.
+    pub async fn invoke(
+        &self,
+        kind: &str,
+        name: &str,
+        config: HashMap<String, EntityValue>,
+        data: ActorInvokeData,
+    ) -> Result<ActorInvokeResult> {
+        let mut rx = self.start(kind, name, config, data, false).await?;
+        let response = tokio::time::timeout(self.timeout, rx.recv())
+            .await
+            .map_err(|_| anyhow!("ACTOR {kind}/{name} TIMED OUT"))?;
+
+        match response {
+            Some(ActorInvokeResponse::Success(result)) => Ok(result),
+            Some(ActorInvokeResponse::Failure(e)) => {
+                Err(anyhow!("ACTOR {kind}/{name} FAILED: {:?}", e.error))
+            }
+            Some(ActorInvokeResponse::Finish(_)) | None => {
+                Err(anyhow!("ACTOR {kind}/{name} RETURNED NO RESULT"))
+            }
+        }
+    }
+
+    /// Streams the responses of `kind/name`, the channel closes after the `Finish` or
+    /// `Failure` response.
+    pub async fn invoke_stream(
+        &self,
+        kind: &str,
+        name: &str,
+        config: HashMap<String, EntityValue>,
+        data: ActorInvokeData,
+    ) -> Result<mpsc::Receiver<ActorInvokeResponse>> {
+        self.start(kind, name, config, data, true).await
+    }
+
+    async fn start(
+        &self,
+        kind: &str,
+        name: &str,
+        config: HashMap<String, EntityValue>,
+        data: ActorInvokeData,
+        stream: bool,
+    ) -> Result<mpsc::Receiver<ActorInvokeResponse>> {
+        self.worker
+            .send(ClientInvoke(ActorStartInvokeRequest {
+                task_id: Uuid::new_v4(),
+                kind: kind.to_string(),
+                name: name.to_string(),
+                stream,
+                config,
+                data,
+                session_key: None,
+                reply_to: None,
+                trace_context: telemetry::inject(&Context::current()),
+                subject: None,
+            }))
+            .await?
+    }
+}
+
+/// Tasks of the client awaiting responses from the main actor.
+#[derive(Default)]
+pub(crate) struct ClientTasks {
+    senders: HashMap<Uuid, mpsc::Sender<ActorInvokeResponse>>,
+}
+
+impl ClientTasks {
+    pub fn register(&mut self, task_id: Uuid) -> mpsc::Receiver<ActorInvokeResponse> {
+        let (sender, receiver) = mpsc::channel(INVOKE_TASK_BUFFER);
+        self.senders.insert(task_id, sender);
+        receiver
+    }
+
+    /// Sender of the task of `response`, the task is forgotten after its last response.
+    pub fn route(
+        &mut self,
+        response: &ActorInvokeResponse,
+    ) -> Option<mpsc::Sender<ActorInvokeResponse>> {
+        let task_id = response.task_id();
+        if response.is_last() {
+            self.senders.remove(&task_id)
+        } else {
+            self.senders.get(&task_id).cloned()
+        }
+    }
+
+    /// Forgets tasks whose receiver was dropped.
+    pub fn sweep(&mut self) {
+        self.senders.retain(|_, sender| !sender.is_closed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abstractions::ActorInvokeFinish;
+
+    fn chunk(task_id: Uuid) -> ActorInvokeResponse {
+        ActorInvokeResponse::Success(ActorInvokeResult {
+            uuid: Uuid::new_v4(),
+            task_id,
+            stream: true,
+            metadata: HashMap::new(),
+            data: HashMap::new(),
+        })
+    }
+
+    #[test]
+    fn test_client_tasks_route_until_last_response() {
+        let mut tasks = ClientTasks::default();
+        let task_id = Uuid::new_v4();
+        let _rx = tasks.register(task_id);
+
+        assert!(tasks.route(&chunk(Uuid::new_v4())).is_none());
+        assert!(tasks.route(&chunk(task_id)).is_some());
+        assert!(tasks.route(&chunk(task_id)).is_some());
+
+        let finish = ActorInvokeResponse::Finish(ActorInvokeFinish {
+            uuid: Uuid::new_v4(),
+            task_id,
+            stream: true,
+        });
+        assert!(tasks.route(&finish).is_some());
+        assert!(tasks.route(&chunk(task_id)).is_none());
+
+        let dropped = Uuid::new_v4();
+        drop(tasks.register(dropped));
+        tasks.sweep();
+        assert!(tasks.route(&chunk(dropped)).is_none());
+    }
+}
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Load of a worker as reported to the main actor.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ActorLoad {
+    pub running: usize,
+    pub queued: usize,
+    pub max_concurrency: Option<usize>,
+    pub queue_limit: Option<usize>,
+}
+
+impl ActorLoad {
+    /// True when a new request would be rejected.
+    pub fn is_full(&self) -> bool {
+        match (self.max_concurrency, self.queue_limit) {
+            (Some(max_concurrency), Some(queue_limit)) => {
+                self.running >= max_concurrency && self.queued >= queue_limit
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Admission control of a worker. At most `max_concurrency` requests run at once, the
+/// rest wait in FIFO order (the semaphore is fair) up to `queue_limit` waiting requests.
+pub struct WorkerLoad {
+    semaphore: Arc<Semaphore>,
+    max_concurrency: Option<usize>,
+    queue_limit: Option<usize>,
+    admitted: AtomicUsize,
+    running: AtomicUsize,
+}
+
+/// Admitted request, keeps its place in the load until dropped.
+pub struct LoadTicket {
+    load: Arc<WorkerLoad>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl WorkerLoad {
+    pub fn new(max_concurrency: Option<usize>, queue_limit: Option<usize>) -> Self {
+        let permits = max_concurrency
+            .unwrap_or(Semaphore::MAX_PERMITS)
+            .clamp(1, Semaphore::MAX_PERMITS);
+        WorkerLoad {
+            semaphore: Arc::new(Semaphore::new(permits)),
+            max_concurrency,
+            queue_limit,
+            admitted: AtomicUsize::new(0),
+            running: AtomicUsize::new(0),
+        }
+    }
+
+    /// Admits a request, `None` when both the running slots and the queue are full.
+    pub fn admit(self: &Arc<Self>) -> Option<LoadTicket> {
+        let limit = match (self.max_concurrency, self.queue_limit) {
+            (Some(max_concurrency), Some(queue_limit)) => max_concurrency.max(1) + queue_limit,
+            _ => usize::MAX,
+        };
+        self.admitted
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |admitted| {
+                (admitted < limit).then_some(admitted + 1)
+            })
+            .ok()?;
+
+        Some(LoadTicket {
+            load: Arc::clone(self),
+            permit: None,
+        })
+    }
+
+    pub fn load(&self) -> ActorLoad {
+        let admitted = self.admitted.load(Ordering::SeqCst);
+        let running = self.running.load(Ordering::SeqCst);
+        ActorLoad {
+            running,
+            queued: admitted.saturating_sub(running),
+            max_concurrency: self.max_concurrency,
+            queue_limit: self.queue_limit,
+        }
+    }
+}
+
+impl LoadTicket {
+    /// Waits for a running slot.
+    pub async fn acquire(&mut self) {
+        if self.permit.is_some() {
+            return;
+        }
+
+        if let Ok(permit) = Arc::clone(&self.load.semaphore).acquire_owned().await {
+            self.load.running.fetch_add(1, Ordering::SeqCst);
+            self.permit = Some(permit);
+        }
+    }
+}
+
+impl Drop for LoadTicket {
+    fn drop(&mut self) {
+        if self.permit.take().is_some() {
+            self.load.running.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.load.admitted.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rejects_when_queue_is_full() {
+        let load = Arc::new(WorkerLoad::new(Some(1), Some(1)));
+        let mut first = load.admit().unwrap();
+        first.acquire().await;
+        let second = load.admit().unwrap();
+        assert!(load.admit().is_none());
+        assert_eq!(
+            load.load(),
+            ActorLoad {
+                running: 1,
+                queued: 1,
+                max_concurrency: Some(1),
+                queue_limit: Some(1),
+            }
+        );
+        assert!(load.load().is_full());
+
+        drop(first);
+        drop(second);
+        assert!(load.admit().is_some());
+        assert_eq!(load.load().running, 0);
+    }
+
+    #[test]
+    fn test_concurrent_admits_respect_the_limit() {
+        let load = Arc::new(WorkerLoad::new(Some(2), Some(3)));
+        let tickets: Vec<LoadTicket> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..32).map(|_| scope.spawn(|| load.admit())).collect();
+            handles
+                .into_iter()
+                .filter_map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        assert_eq!(tickets.len(), 5);
+        assert_eq!(load.load().queued, 5);
+    }
+
+    #[tokio::test]
+    async fn test_waiting_requests_run_in_order() {
+        let load = Arc::new(WorkerLoad::new(Some(1), None));
+        let mut running = load.admit().unwrap();
+        running.acquire().await;
+
+        let order = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut handles = vec![];
+        for ix in 0..5 {
+            let mut ticket = load.admit().unwrap();
+            let order = Arc::clone(&order);
+            handles.push(tokio::spawn(async move {
+                ticket.acquire().await;
+                order.lock().unwrap().push(ix);
+            }));
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(load.load().queued, 5);
+        drop(running);
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+}
@@ -1,42 +1,207 @@
-use super::{ActorInfo, ActorInfoRequest, ActorStartInvokeRequest};
-use crate::abstractions::{ActorActions, ActorInvokeRequest, ActorInvokeResponse, ActorObject};
+use super::pipeline::{run_pipeline, PipelineSpec, PIPELINE_KIND};
+use super::routing::{pick_replica, RouteRule, RouteTarget, RouteTargetStats};
+use super::{
+    cancel_proof_parts, drain_proof_parts, event_proof_parts, grace_period_bytes,
+    invoke_proof_parts, main_proof_parts, metrics_proof_parts, model_proof_parts,
+    response_proof_parts, start_proof_parts, worker_proof_parts, ActorCancelRequest,
+    ActorDrainRequest, ActorEvent, ActorInfo, ActorInfoRequest, ActorMetrics, ActorModelRequest,
+    ActorStartInvokeRequest, ModelCommand,
+};
+use crate::abstractions::{
+    ActorActions, ActorError, ActorInvokeError, ActorInvokeFinish, ActorInvokeRequest,
+    ActorInvokeResponse, ActorInvokeResult, ActorObject,
+};
 use crate::actors::WorkerActor;
+use crate::cluster::security::{
+    ClusterSecurity, ClusterSend, Proof, SeenNonces, SignedInvokeResponse, SignedStartInvokeRequest,
+};
+use crate::metrics::{self, WorkerMetrics, LATENCY_BUCKETS, TOKENS_PER_SECOND_BUCKETS};
+use crate::telemetry;
 use actix::prelude::*;
 use actix_broker::BrokerSubscribe;
 use actix_telepathy::prelude::*;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
 use once_cell::sync::OnceCell;
+use onceuponai_core::events::{self, Event, EventKind, TaskState};
+use opentelemetry::trace::SpanKind;
+use opentelemetry::KeyValue;
 use rand::seq::SliceRandom;
-use serde::Deserialize;
-use std::sync::mpsc;
+use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, net::SocketAddr};
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
 pub static CONNECTED_ACTORS: OnceCell<Arc<Mutex<HashMap<Uuid, ActorInfo>>>> = OnceCell::new();
 pub static INVOKE_TASKS: OnceCell<Arc<Mutex<HashMap<Uuid, InvokeTask>>>> = OnceCell::new();
+pub static ROUTE_STATS: OnceCell<Arc<Mutex<HashMap<String, RouteTargetStats>>>> = OnceCell::new();
+/// Counters reported by the connected workers.
+pub static WORKER_METRICS: OnceCell<Arc<Mutex<HashMap<Uuid, WorkerMetrics>>>> = OnceCell::new();
+
+/// Number of responses buffered in the channel of a task, the responses of a client falling
+/// further behind wait in its forwarder.
+pub const INVOKE_TASK_BUFFER: usize = 256;
+const INVOKE_TASKS_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_INVOKE_TIMEOUT: u64 = 60;
+/// Subject of the personal token printed at startup.
+pub const ROOT_SUBJECT: &str = "root";
+/// Subject of the session opened with the login token.
+pub const TOKEN_LOGIN_SUBJECT: &str = "user@";
 
 #[derive(Debug)]
 pub struct InvokeTask {
+    /// Time of the last activity (creation or last streamed chunk).
     pub time: Instant,
     pub sender: mpsc::Sender<ActorInvokeResponse>,
 }
 
+impl InvokeTask {
+    pub fn new() -> (Self, mpsc::Receiver<ActorInvokeResponse>) {
+        let (sender, receiver) = mpsc::channel(INVOKE_TASK_BUFFER);
+        let task = InvokeTask {
+            time: Instant::now(),
+            sender,
+        };
+        (task, receiver)
+    }
+
+    fn is_expired(&self, timeout: Duration) -> bool {
+        self.sender.is_closed() || self.time.elapsed() > timeout
+    }
+}
+
 #[derive(RemoteActor)]
-#[remote_messages(ActorInfo, ActorInvokeResponse)]
+#[remote_messages(
+    ActorInfo,
+    ActorInvokeResponse,
+    SignedInvokeResponse,
+    ActorStartInvokeRequest,
+    SignedStartInvokeRequest,
+    ActorMetrics,
+    ActorEvent
+)]
 pub struct MainActor {
     pub uuid: Uuid,
     pub actor: ActorObject<MainActorSpec>,
     pub own_addr: SocketAddr,
     pub remote_addr: RemoteAddr,
     pub connected_actors: HashMap<Uuid, ActorInfo>,
+    pub dispatched_tasks: HashMap<Uuid, DispatchedTask>,
+    /// Tasks of actor clients, their responses are sent back to the calling worker.
+    pub remote_tasks: HashMap<Uuid, RemoteInvokeTask>,
+    /// Signed by workers to prove they know the cluster secret.
+    pub challenge: Vec<u8>,
+    /// Nonces of the worker proofs, a proof is accepted once.
+    pub seen_nonces: SeenNonces,
+    pub(crate) forwarders: ResponseForwarders,
+}
+
+/// Drains a connected worker, answers whether the actor is connected.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct DrainActor {
+    pub uuid: Uuid,
+    pub grace_period: Option<u64>,
+}
+
+/// Swaps the model of a connected worker, answers whether the actor is connected.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct SwapActorModel {
+    pub uuid: Uuid,
+    pub command: ModelCommand,
+}
+
+/// Lists the in-flight tasks, of one subject when set.
+#[derive(Message)]
+#[rtype(result = "Vec<TaskInfo>")]
+pub struct ListTasks {
+    pub subject: Option<String>,
+}
+
+/// Starts a task for the server or a pipeline of this process. Unlike
+/// `ActorStartInvokeRequest` it is never received from the cluster.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StartInvoke(pub ActorStartInvokeRequest);
+
+/// Response of a task failed by this process. Unlike `ActorInvokeResponse` it is never
+/// received from the cluster.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TaskResponse(pub ActorInvokeResponse);
+
+/// Cancels an in-flight task, answers the task when it was found. Only tasks of `subject`
+/// match when it is set.
+#[derive(Message)]
+#[rtype(result = "Option<TaskInfo>")]
+pub struct CancelTask {
+    pub task_id: Uuid,
+    pub subject: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskInfo {
+    pub task_id: Uuid,
+    /// Model id (`kind/name`).
+    pub model: String,
+    pub subject: Option<String>,
+    pub started: DateTime<Utc>,
+    pub stream: bool,
+    /// Worker the task was dispatched to.
+    pub actor: Uuid,
+    /// Streamed chunks so far, one per generated token.
+    pub tokens: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DispatchedTask {
+    pub actor: Uuid,
+    /// Target id (`kind/name`) the task was dispatched to.
+    pub target: String,
+    pub subject: Option<String>,
+    pub stream: bool,
+    pub started: Instant,
+    pub started_at: DateTime<Utc>,
+    pub shadow: bool,
+    pub first_chunk: Option<Instant>,
+    /// Streamed chunks, one per generated token.
+    pub chunks: u64,
+    /// Context of the dispatch span, ended with the task.
+    pub trace: opentelemetry::Context,
+}
+
+#[derive(Debug, Clone)]
+pub struct RemoteInvokeTask {
+    pub reply_to: RemoteAddr,
+    /// Time of the last activity (creation or last streamed chunk).
+    pub time: Instant,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct MainActorAuthConfig {
     pub oidc: Option<MainActorOidcConfig>,
     pub _auth_token: Option<String>,
+    /// Roles of the OIDC users, every user who logs in is an admin when not set.
+    pub roles: Option<MainActorRolesConfig>,
+}
+
+/// Maps the groups of an OIDC user, read from an ID token claim, to roles.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MainActorRolesConfig {
+    /// Claim with the groups, a dotted path such as `realm_access.roles`, `groups` when not
+    /// set.
+    pub claim: Option<String>,
+    /// Groups with the admin role.
+    pub admin_groups: Option<Vec<String>>,
+    /// Groups with the user role, every user who logs in when not set.
+    pub user_groups: Option<Vec<String>>,
+    /// Groups allowed to invoke a model by model id (`kind/name` or `kind/*`). Models not
+    /// listed are open to every user.
+    pub models: Option<HashMap<String, Vec<String>>>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -49,6 +214,97 @@ pub struct MainActorSpec {
     pub session_key: Option<String>,
     pub personal_access_token_secret: Option<String>,
     pub auth: Option<MainActorAuthConfig>,
+    /// Subjects (e-mails, personal token subjects) with the admin role, the root token and
+    /// the token login user when not set.
+    pub admins: Option<Vec<String>>,
+    /// In-flight tasks per actor above which a session is moved to another replica.
+    pub overload_threshold: Option<usize>,
+    pub routes: Option<Vec<RouteRule>>,
+    pub cache: Option<MainActorCacheConfig>,
+    pub pipelines: Option<Vec<PipelineSpec>>,
+    pub spawn: Option<MainActorSpawnConfig>,
+    pub audit: Option<MainActorAuditConfig>,
+    pub personal_tokens: Option<MainActorPersonalTokensConfig>,
+}
+
+/// Worker processes the server may start from the actors gallery, disabled when not set.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MainActorSpawnConfig {
+    /// Directory with the worker binaries (`{sidecar}-{device}`), `PATH` when not set.
+    pub bin_dir: Option<String>,
+    /// Host the spawned workers listen on.
+    pub actor_host: Option<String>,
+    /// Seed the spawned workers join, usually the main actor host.
+    pub actor_seed: Option<String>,
+    /// First port tried for a spawned worker.
+    pub base_port: Option<u16>,
+    pub max_workers: Option<usize>,
+    /// Restarts of a crashed worker allowed within ten minutes, 5 when not set.
+    pub max_restarts: Option<u32>,
+}
+
+/// Response cache settings, the cache is disabled when not set.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MainActorCacheConfig {
+    /// Directory where entries are persisted, in-memory only when not set.
+    pub path: Option<String>,
+    /// Default time to live in seconds.
+    pub ttl: Option<u64>,
+    /// Default byte limit of a single model cache.
+    pub max_bytes: Option<usize>,
+    /// Overrides by model id (`kind/name`).
+    pub models: Option<HashMap<String, MainActorCacheModelConfig>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MainActorCacheModelConfig {
+    pub enabled: Option<bool>,
+    pub ttl: Option<u64>,
+    pub max_bytes: Option<usize>,
+    pub semantic: Option<MainActorSemanticCacheConfig>,
+}
+
+/// Audit log of the invoke requests, disabled when not set.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MainActorAuditConfig {
+    /// Directory of the log files, `audit` when not set.
+    pub path: Option<String>,
+    /// Size at which the log file is rotated, 100 MiB when not set.
+    pub max_bytes: Option<u64>,
+    /// Rotated files kept, 10 when not set.
+    pub max_files: Option<usize>,
+    /// Also records the prompts and responses, after redaction.
+    pub capture_payloads: Option<bool>,
+    /// Redaction rules of the captured payloads, replace the default rules (e-mail
+    /// addresses, API keys, card numbers) when set.
+    pub redact: Option<Vec<MainActorRedactionRule>>,
+}
+
+/// Store of the issued personal access tokens, which can be listed and revoked.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MainActorPersonalTokensConfig {
+    /// SQLite database of the tokens, `personal_tokens.db` when not set.
+    pub path: Option<String>,
+    /// Seconds a token lookup is cached by the auth guard, 30 when not set.
+    pub cache_ttl: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MainActorRedactionRule {
+    /// Regular expression of the redacted text.
+    pub pattern: String,
+    /// `[REDACTED]` when not set.
+    pub replacement: Option<String>,
+}
+
+/// Answers chat requests whose last user message is similar to a cached one.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MainActorSemanticCacheConfig {
+    /// Actor with the `embed` feature (`kind/name`) used to embed prompts.
+    pub embed_model: String,
+    /// Minimal cosine similarity of a hit.
+    pub threshold: Option<f32>,
+    pub max_entries: Option<usize>,
 }
 
 #[async_trait]
@@ -96,9 +352,58 @@ impl MainActorSpec {
         self.auth = Some(MainActorAuthConfig {
             _auth_token: Some(_auth_token),
             oidc: None,
+            roles: None,
         });
     }
 
+    pub fn is_admin(&self, subject: &str) -> bool {
+        match &self.admins {
+            Some(admins) => admins.iter().any(|admin| admin == subject),
+            None => subject == ROOT_SUBJECT || subject == TOKEN_LOGIN_SUBJECT,
+        }
+    }
+
+    pub fn route(&self, model: &str) -> Option<&RouteRule> {
+        self.routes.as_ref()?.iter().find(|r| r.model == model)
+    }
+
+    pub fn pipeline(&self, name: &str) -> Option<&PipelineSpec> {
+        self.pipelines.as_ref()?.iter().find(|p| p.name == name)
+    }
+
+    /// Models an invocation of `model` may run: the model, the targets and the shadow of its
+    /// route and the steps of a pipeline, resolved recursively.
+    pub fn invoke_targets(&self, model: &str) -> Vec<String> {
+        let mut targets: Vec<String> = vec![];
+        let mut pending = vec![model.to_string()];
+        while let Some(model) = pending.pop() {
+            if targets.contains(&model) {
+                continue;
+            }
+            if let Some(rule) = self.route(&model) {
+                pending.extend(rule.targets.iter().chain(&rule.shadow).map(RouteTarget::id));
+            }
+            let pipeline = model
+                .strip_prefix(PIPELINE_KIND)
+                .and_then(|name| name.strip_prefix('/'))
+                .and_then(|name| self.pipeline(name));
+            if let Some(pipeline) = pipeline {
+                pending.extend(
+                    pipeline
+                        .steps
+                        .iter()
+                        .map(|step| format!("{}/{}", step.kind, step.name)),
+                );
+            }
+            targets.push(model);
+        }
+        targets
+    }
+
+    pub fn roles(&self) -> Option<&MainActorRolesConfig> {
+        self.auth.as_ref()?.roles.as_ref()
+    }
+
     pub fn is_oidc(&self) -> bool {
         if let Some(auth) = self.auth.clone() {
             return auth.oidc.is_some();
@@ -114,6 +419,13 @@ pub struct MainActorOidcConfig {
     pub client_id: String,
     pub client_secret: String,
     pub redirect_url: String,
+    /// Where the provider sends the user back after logging out.
+    pub post_logout_redirect_url: Option<String>,
+    /// Seconds the provider metadata and signing keys are reused, an hour when not set.
+    pub metadata_ttl: Option<u64>,
+    /// Keeps the refresh token in the session, which then expires with the ID token unless
+    /// it is refreshed.
+    pub refresh_tokens: Option<bool>,
 }
 
 impl Actor for MainActor {
@@ -128,6 +440,327 @@ impl Actor for MainActor {
         INVOKE_TASKS
             .set(Arc::new(Mutex::new(HashMap::new())))
             .unwrap();
+        ROUTE_STATS
+            .set(Arc::new(Mutex::new(HashMap::new())))
+            .unwrap();
+        WORKER_METRICS
+            .set(Arc::new(Mutex::new(HashMap::new())))
+            .unwrap();
+
+        let invoke_timeout = Duration::from_secs(
+            self.actor
+                .spec()
+                .invoke_timeout
+                .unwrap_or(DEFAULT_INVOKE_TIMEOUT),
+        );
+        ctx.run_interval(INVOKE_TASKS_SWEEP_INTERVAL, move |act, _ctx| {
+            sweep_invoke_tasks(invoke_timeout);
+            act.remote_tasks
+                .retain(|_, task| task.time.elapsed() <= invoke_timeout);
+            act.sweep_dispatched_tasks(invoke_timeout);
+            act.forwarders.sweep();
+        });
+    }
+}
+
+impl DispatchedTask {
+    fn info(&self, task_id: Uuid) -> TaskInfo {
+        TaskInfo {
+            task_id,
+            model: self.target.clone(),
+            subject: self.subject.clone(),
+            started: self.started_at,
+            stream: self.stream,
+            actor: self.actor,
+            tokens: self.chunks,
+        }
+    }
+
+    fn event(&self, task_id: Uuid, state: TaskState) -> EventKind {
+        EventKind::Task {
+            task_id,
+            model: self.target.clone(),
+            subject: self.subject.clone(),
+            stream: self.stream,
+            state,
+        }
+    }
+}
+
+impl MainActor {
+    fn in_flight(&self, actor: &Uuid) -> usize {
+        self.dispatched_tasks
+            .values()
+            .filter(|t| t.actor == *actor)
+            .count()
+    }
+
+    fn is_overloaded(&self, actor: &Uuid) -> bool {
+        let queue_full = self
+            .connected_actors
+            .get(actor)
+            .and_then(|a| a.load.as_ref())
+            .is_some_and(|load| load.is_full());
+        if queue_full {
+            return true;
+        }
+
+        match self.actor.spec().overload_threshold {
+            Some(threshold) => self.in_flight(actor) >= threshold,
+            None => false,
+        }
+    }
+
+    fn is_connected(&self, kind: &str, name: &str) -> bool {
+        self.connected_actors
+            .values()
+            .any(|a| a.kind == kind && a.metadata.name == name && !a.draining)
+    }
+
+    fn choose_actor(&self, kind: &str, name: &str, session_key: Option<&str>) -> Option<ActorInfo> {
+        let actors: Vec<&ActorInfo> = self
+            .connected_actors
+            .values()
+            .filter(|a| a.kind == kind && a.metadata.name == name && !a.draining)
+            .collect();
+
+        let uuid = match session_key {
+            Some(key) => {
+                let replicas: Vec<Uuid> = actors.iter().map(|a| a.uuid).collect();
+                pick_replica(key, &replicas, |a| self.is_overloaded(a))?
+            }
+            None => {
+                let available: Vec<&ActorInfo> = actors
+                    .iter()
+                    .filter(|a| !self.is_overloaded(&a.uuid))
+                    .copied()
+                    .collect();
+                available
+                    .choose(&mut rand::thread_rng())
+                    .or_else(|| actors.choose(&mut rand::thread_rng()))?
+                    .uuid
+            }
+        };
+
+        self.connected_actors.get(&uuid).cloned()
+    }
+
+    fn dispatch(
+        &mut self,
+        actor: &ActorInfo,
+        msg: &ActorStartInvokeRequest,
+        task_id: Uuid,
+        shadow: bool,
+    ) {
+        let target = format!("{}/{}", actor.kind, actor.metadata.name);
+        let trace = telemetry::start(
+            &telemetry::extract(&msg.trace_context),
+            "main.dispatch",
+            SpanKind::Producer,
+            vec![
+                KeyValue::new("model", target.clone()),
+                KeyValue::new("actor.uuid", actor.uuid.to_string()),
+                KeyValue::new("task.id", task_id.to_string()),
+                KeyValue::new("shadow", shadow),
+            ],
+        );
+        let parts = invoke_proof_parts(&self.challenge, &actor.uuid);
+        actor.source.send_request(
+            ActorInvokeRequest {
+                task_id,
+                stream: msg.stream && !shadow,
+                config: msg.config.clone(),
+                data: msg.data.clone(),
+                source: self.remote_addr.clone(),
+                trace_context: telemetry::inject(&trace),
+            },
+            &parts,
+        );
+        let task = DispatchedTask {
+            actor: actor.uuid,
+            target,
+            subject: msg.subject.clone(),
+            stream: msg.stream && !shadow,
+            started: Instant::now(),
+            started_at: Utc::now(),
+            shadow,
+            first_chunk: None,
+            chunks: 0,
+            trace,
+        };
+        if !shadow {
+            events::publish(task.event(task_id, TaskState::Started));
+        }
+        self.dispatched_tasks.insert(task_id, task);
+    }
+
+    /// True when the worker proved the knowledge of the cluster secret.
+    fn is_authenticated(&mut self, actor_info: &ActorInfo) -> bool {
+        let worker_addr = actor_info.source.node.socket_addr.to_string();
+        let parts = worker_proof_parts(&self.challenge, &actor_info.uuid, &worker_addr);
+        is_proven(&parts, actor_info.proof.as_ref(), &mut self.seen_nonces)
+    }
+
+    fn complete_task(&mut self, task_id: &Uuid, state: TaskState) {
+        if let Some(task) = self.dispatched_tasks.remove(task_id) {
+            let failed = state != TaskState::Finished;
+            if !task.shadow {
+                events::publish(task.event(*task_id, state));
+            }
+            record_route_stats(&task, failed);
+            record_task_metrics(&task, failed);
+            telemetry::set_attribute(&task.trace, "chunks", task.chunks as i64);
+            telemetry::end(&task.trace, failed.then_some("TASK FAILED"));
+        }
+    }
+
+    /// Forgets tasks which are no longer awaited. Shadow tasks have no client, so they are
+    /// kept until the invoke timeout and then counted as errors.
+    fn sweep_dispatched_tasks(&mut self, timeout: Duration) {
+        let tasks = INVOKE_TASKS.get().expect("INVOKE_TASKS").lock().unwrap();
+        let expired: Vec<Uuid> = self
+            .dispatched_tasks
+            .iter()
+            .filter(|(task_id, task)| {
+                if task.shadow {
+                    task.started.elapsed() > timeout
+                } else {
+                    !tasks.contains_key(task_id) && !self.remote_tasks.contains_key(task_id)
+                }
+            })
+            .map(|(task_id, _)| *task_id)
+            .collect();
+        drop(tasks);
+
+        for task_id in expired {
+            self.complete_task(&task_id, TaskState::Failed);
+        }
+    }
+}
+
+fn record_route_stats(task: &DispatchedTask, failed: bool) {
+    ROUTE_STATS
+        .get()
+        .expect("ROUTE_STATS")
+        .lock()
+        .unwrap()
+        .entry(task.target.clone())
+        .or_default()
+        .record(task.started.elapsed(), failed, task.shadow);
+}
+
+fn record_task_metrics(task: &DispatchedTask, failed: bool) {
+    let shadow = task.shadow.to_string();
+    let labels = [("model", task.target.as_str()), ("shadow", shadow.as_str())];
+    metrics::record(|m| {
+        m.counter(
+            "onceuponai_model_requests_total",
+            "Requests dispatched to a model.",
+            &labels,
+            1.0,
+        );
+        if failed {
+            m.counter(
+                "onceuponai_model_errors_total",
+                "Failed or expired model requests.",
+                &labels,
+                1.0,
+            );
+        }
+        m.observe(
+            "onceuponai_model_request_duration_seconds",
+            "Time from the dispatch to the last response of a model request.",
+            LATENCY_BUCKETS,
+            &labels,
+            task.started.elapsed().as_secs_f64(),
+        );
+
+        if let Some(first_chunk) = task.first_chunk {
+            let model = [("model", task.target.as_str())];
+            m.counter(
+                "onceuponai_model_generated_tokens_total",
+                "Streamed chunks of a model, one per generated token.",
+                &model,
+                task.chunks as f64,
+            );
+            let generation = first_chunk.elapsed().as_secs_f64();
+            if task.chunks > 1 && generation > 0.0 {
+                m.observe(
+                    "onceuponai_model_tokens_per_second",
+                    "Generation throughput of streamed requests after the first token.",
+                    TOKENS_PER_SECOND_BUCKETS,
+                    &model,
+                    (task.chunks - 1) as f64 / generation,
+                );
+            }
+        }
+    });
+}
+
+/// Drops tasks whose client went away or which did not receive anything within `timeout`.
+/// Dropping the sender closes the channel, so a waiting handler finishes immediately.
+fn sweep_invoke_tasks(timeout: Duration) {
+    let mut tasks = INVOKE_TASKS.get().expect("INVOKE_TASKS").lock().unwrap();
+    let before = tasks.len();
+    tasks.retain(|_, task| !task.is_expired(timeout));
+    let expired = before - tasks.len();
+    if expired > 0 {
+        info!("EXPIRED INVOKE TASKS: {expired}");
+    }
+}
+
+/// Checks the proof of a worker message, messages are not proven when the cluster has no
+/// secret.
+fn is_proven(parts: &[&[u8]], proof: Option<&Proof>, seen: &mut SeenNonces) -> bool {
+    match ClusterSecurity::get() {
+        Some(security) => security.verify_once(parts, proof, seen),
+        None => true,
+    }
+}
+
+/// Forwards the responses of tasks to their channels. Each task has a future sending its
+/// responses in arrival order, waiting for a slow client instead of dropping responses, so
+/// the actor never blocks on a client.
+#[derive(Default)]
+pub(crate) struct ResponseForwarders {
+    queues: HashMap<Uuid, mpsc::UnboundedSender<ActorInvokeResponse>>,
+}
+
+impl ResponseForwarders {
+    /// Queues `msg` for `sender`, `on_closed` is called once the client went away.
+    pub fn forward<F>(
+        &mut self,
+        sender: &mpsc::Sender<ActorInvokeResponse>,
+        msg: ActorInvokeResponse,
+        on_closed: F,
+    ) where
+        F: FnOnce() + 'static,
+    {
+        let task_id = msg.task_id();
+        let is_last = msg.is_last();
+        let queue = self.queues.entry(task_id).or_insert_with(|| {
+            let (queue, mut responses) = mpsc::unbounded_channel();
+            let sender = sender.clone();
+            actix::spawn(async move {
+                while let Some(msg) = responses.recv().await {
+                    if sender.send(msg).await.is_err() {
+                        on_closed();
+                        return;
+                    }
+                }
+            });
+            queue
+        });
+        let _ = queue.send(msg);
+        // The future ends once the queued responses are sent.
+        if is_last {
+            self.queues.remove(&task_id);
+        }
+    }
+
+    /// Forgets the tasks whose client went away.
+    pub fn sweep(&mut self) {
+        self.queues.retain(|_, queue| !queue.is_closed());
     }
 }
 
@@ -135,7 +768,31 @@ impl Handler<ActorInfo> for MainActor {
     type Result = ();
 
     fn handle(&mut self, actor_info: ActorInfo, _ctx: &mut Self::Context) -> Self::Result {
-        info!("Received model state: {:?}", actor_info);
+        if !self.is_authenticated(&actor_info) {
+            warn!(
+                "REFUSED ACTOR {} FROM UNAUTHENTICATED NODE {}",
+                actor_info.uuid, actor_info.source.node.socket_addr
+            );
+            return;
+        }
+
+        match self.connected_actors.get(&actor_info.uuid) {
+            Some(known)
+                if known.draining == actor_info.draining && known.model == actor_info.model =>
+            {
+                debug!("Received model load: {:?}", actor_info.load)
+            }
+            Some(_) => info!("Received model state: {:?}", actor_info),
+            None => {
+                info!("Received model state: {:?}", actor_info);
+                events::forward(
+                    Event::new(EventKind::ActorConnected {
+                        model: format!("{}/{}", actor_info.kind, actor_info.metadata.name),
+                    })
+                    .with_actor(actor_info.uuid),
+                );
+            }
+        }
         self.connected_actors
             .insert(actor_info.uuid, actor_info.clone());
         CONNECTED_ACTORS
@@ -150,84 +807,455 @@ impl Handler<ActorInfo> for MainActor {
 impl Handler<ActorStartInvokeRequest> for MainActor {
     type Result = ();
 
-    fn handle(&mut self, msg: ActorStartInvokeRequest, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: ActorStartInvokeRequest, ctx: &mut Self::Context) -> Self::Result {
+        // Remote requests prove the knowledge of the cluster secret.
+        if ClusterSecurity::get().is_some() {
+            warn!("REFUSED UNSIGNED START INVOKE REQUEST");
+            return;
+        }
+
+        self.start_invoke(msg, ctx);
+    }
+}
+
+impl Handler<StartInvoke> for MainActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: StartInvoke, ctx: &mut Self::Context) -> Self::Result {
+        self.start_invoke(msg.0, ctx);
+    }
+}
+
+impl Handler<SignedStartInvokeRequest> for MainActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SignedStartInvokeRequest, ctx: &mut Self::Context) -> Self::Result {
+        let Some(security) = ClusterSecurity::get() else {
+            warn!("REFUSED SIGNED START INVOKE REQUEST, CLUSTER SECRET NOT SET");
+            return;
+        };
+
+        let parts = start_proof_parts(&self.challenge);
+        match security.open_payload::<ActorStartInvokeRequest>(
+            &parts,
+            &msg.signed,
+            &mut self.seen_nonces,
+        ) {
+            Ok(request) if request.reply_to.is_some() => self.start_invoke(request, ctx),
+            Ok(_) => warn!("REFUSED START INVOKE REQUEST WITHOUT REPLY ADDRESS"),
+            Err(e) => warn!("REFUSED START INVOKE REQUEST: {e}"),
+        }
+    }
+}
+
+impl MainActor {
+    fn start_invoke(&mut self, msg: ActorStartInvokeRequest, ctx: &mut Context<Self>) {
         info!("START INVOKE REQUEST: {:?}", msg);
-        let kind = msg.kind;
-        let name = msg.name;
+        if let Some(reply_to) = &msg.reply_to {
+            self.remote_tasks.insert(
+                msg.task_id,
+                RemoteInvokeTask {
+                    reply_to: reply_to.clone(),
+                    time: Instant::now(),
+                },
+            );
+        }
+
+        let spec = self.actor.spec();
+        if msg.kind == PIPELINE_KIND {
+            let Some(pipeline) = spec.pipeline(&msg.name) else {
+                ctx.notify(TaskResponse(ActorInvokeResponse::Failure(
+                    ActorInvokeError {
+                        uuid: self.uuid,
+                        task_id: msg.task_id,
+                        error: ActorError::BadRequest(format!("PIPELINE {:?} NOT FOUND", msg.name)),
+                    },
+                )));
+                return;
+            };
+
+            let timeout =
+                Duration::from_secs(spec.invoke_timeout.unwrap_or(DEFAULT_INVOKE_TIMEOUT));
+            actix_rt::spawn(run_pipeline(
+                ctx.address(),
+                self.uuid,
+                pipeline.clone(),
+                msg,
+                timeout,
+            ));
+            return;
+        }
+
+        let model = format!("{}/{}", msg.kind, msg.name);
+        let rule = spec.route(&model);
+
+        let (kind, name) = match rule {
+            Some(rule) => match rule.choose_target(msg.session_key.as_deref(), |t| {
+                self.is_connected(&t.kind, &t.name)
+            }) {
+                Some(target) => (target.kind.clone(), target.name.clone()),
+                None => (msg.kind.clone(), msg.name.clone()),
+            },
+            None => (msg.kind.clone(), msg.name.clone()),
+        };
         info!("KIND/NAME: {kind:?}/{name:?}");
 
-        let actors: Vec<ActorInfo> = self
-            .connected_actors
-            .iter()
-            .filter(|a| a.1.kind == kind && a.1.metadata.name == name)
-            .map(|a| a.1.clone())
-            .collect();
+        let Some(worker_actor) = self.choose_actor(&kind, &name, msg.session_key.as_deref()) else {
+            ctx.notify(TaskResponse(ActorInvokeResponse::Failure(
+                ActorInvokeError {
+                    uuid: self.uuid,
+                    task_id: msg.task_id,
+                    error: ActorError::BadRequest(format!(
+                        "ACTOR WITH KIND: {kind:?} NAME: {name:?} NOT CONNECTED"
+                    )),
+                },
+            )));
+            return;
+        };
 
-        let worker_actor = actors
-            .choose(&mut rand::thread_rng())
-            .expect("WORKER_ACTOR");
-        worker_actor.source.do_send(ActorInvokeRequest {
-            task_id: msg.task_id,
-            stream: msg.stream,
-            config: msg.config,
-            data: msg.data,
-            source: self.remote_addr.clone(),
-        });
+        self.dispatch(&worker_actor, &msg, msg.task_id, false);
+
+        if let Some(shadow) = rule.and_then(|r| r.shadow.as_ref()) {
+            if let Some(shadow_actor) = self.choose_actor(&shadow.kind, &shadow.name, None) {
+                self.dispatch(&shadow_actor, &msg, Uuid::new_v4(), true);
+            }
+        }
     }
 }
 
 impl Handler<ActorInvokeResponse> for MainActor {
     type Result = ();
 
-    fn handle(&mut self, msg: ActorInvokeResponse, _ctx: &mut Self::Context) -> Self::Result {
-        //info!("Received invoke response: {:?}", msg);
+    fn handle(&mut self, msg: ActorInvokeResponse, ctx: &mut Self::Context) -> Self::Result {
+        if ClusterSecurity::get().is_some() {
+            warn!("REFUSED UNSIGNED INVOKE RESPONSE");
+            return;
+        }
 
-        tokio::spawn(async move {
-            match &msg {
-                ActorInvokeResponse::Failure(result) => {
-                    let mut tasks = INVOKE_TASKS.get().expect("INVOKE_TASKS").lock().unwrap();
+        self.task_response(msg, ctx);
+    }
+}
 
-                    if let Some(task) = tasks.remove(&result.task_id) {
-                        let _ = task.sender.send(msg);
-                    }
-                }
-                ActorInvokeResponse::Success(result) => {
-                    let mut tasks = INVOKE_TASKS.get().expect("INVOKE_TASKS").lock().unwrap();
-                    if !result.stream {
-                        if let Some(task) = tasks.remove(&result.task_id) {
-                            let _ = task.sender.send(msg);
-                        }
-                    } else if let Some(task) = tasks.get(&result.task_id) {
-                        let _ = task.sender.send(msg);
-                    }
+impl Handler<SignedInvokeResponse> for MainActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SignedInvokeResponse, ctx: &mut Self::Context) -> Self::Result {
+        let Some(security) = ClusterSecurity::get() else {
+            warn!("REFUSED SIGNED INVOKE RESPONSE, CLUSTER SECRET NOT SET");
+            return;
+        };
+
+        let parts = response_proof_parts(&self.challenge, &self.remote_addr.id);
+        match security.open_payload::<ActorInvokeResponse>(
+            &parts,
+            &msg.signed,
+            &mut self.seen_nonces,
+        ) {
+            Ok(response) => self.task_response(response, ctx),
+            Err(e) => warn!("REFUSED INVOKE RESPONSE: {e}"),
+        }
+    }
+}
+
+impl Handler<TaskResponse> for MainActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: TaskResponse, ctx: &mut Self::Context) -> Self::Result {
+        self.task_response(msg.0, ctx);
+    }
+}
+
+impl MainActor {
+    fn task_response(&mut self, msg: ActorInvokeResponse, ctx: &mut Context<Self>) {
+        //info!("Received invoke response: {:?}", msg);
+        if let ActorInvokeResponse::Success(result) = &msg {
+            if let Some(task) = self.dispatched_tasks.get_mut(&result.task_id) {
+                task.chunks += u64::from(result.stream);
+                if result.stream && task.first_chunk.is_none() {
+                    task.first_chunk = Some(Instant::now());
+                    telemetry::add_event(&task.trace, "first_chunk");
+                    let ttft = task.started.elapsed().as_secs_f64();
+                    metrics::record(|m| {
+                        m.observe(
+                            "onceuponai_model_time_to_first_token_seconds",
+                            "Time from the dispatch to the first streamed chunk.",
+                            LATENCY_BUCKETS,
+                            &[("model", task.target.as_str())],
+                            ttft,
+                        )
+                    });
                 }
-                ActorInvokeResponse::Finish(result) => {
-                    let mut tasks = INVOKE_TASKS.get().expect("INVOKE_TASKS").lock().unwrap();
-                    if let Some(task) = tasks.remove(&result.task_id) {
-                        let _ = task.sender.send(msg);
-                    }
+            }
+        }
+
+        match &msg {
+            ActorInvokeResponse::Success(result) if result.stream => {}
+            ActorInvokeResponse::Success(ActorInvokeResult { task_id, .. })
+            | ActorInvokeResponse::Finish(ActorInvokeFinish { task_id, .. }) => {
+                self.complete_task(task_id, TaskState::Finished)
+            }
+            ActorInvokeResponse::Failure(ActorInvokeError { task_id, .. }) => {
+                self.complete_task(task_id, TaskState::Failed)
+            }
+        }
+
+        if let Some(task) = self.remote_tasks.get_mut(&msg.task_id()) {
+            let reply_to = task.reply_to.clone();
+            if msg.is_last() {
+                self.remote_tasks.remove(&msg.task_id());
+            } else {
+                task.time = Instant::now();
+            }
+            reply_to.send_response(msg);
+            return;
+        }
+
+        let mut tasks = INVOKE_TASKS.get().expect("INVOKE_TASKS").lock().unwrap();
+        let sender = match &msg {
+            ActorInvokeResponse::Failure(result) => {
+                tasks.remove(&result.task_id).map(|task| task.sender)
+            }
+            ActorInvokeResponse::Success(result) => {
+                if !result.stream {
+                    tasks.remove(&result.task_id).map(|task| task.sender)
+                } else if let Some(task) = tasks.get_mut(&result.task_id) {
+                    task.time = Instant::now();
+                    Some(task.sender.clone())
+                } else {
+                    None
                 }
-            };
-        });
+            }
+            ActorInvokeResponse::Finish(result) => {
+                tasks.remove(&result.task_id).map(|task| task.sender)
+            }
+        };
+
+        drop(tasks);
+        if let Some(sender) = sender {
+            let task_id = msg.task_id();
+            let main = ctx.address();
+            self.forwarders.forward(&sender, msg, move || {
+                info!("TASK {task_id} CANCELLED, CLIENT GONE");
+                // Stops the worker and fails the task like a cancellation by the client.
+                main.do_send(CancelTask {
+                    task_id,
+                    subject: None,
+                });
+            });
+        }
+    }
+}
+
+impl Handler<ActorMetrics> for MainActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ActorMetrics, _ctx: &mut Self::Context) -> Self::Result {
+        let parts = metrics_proof_parts(&self.challenge, &msg.uuid);
+        if !is_proven(&parts, msg.proof.as_ref(), &mut self.seen_nonces) {
+            warn!("REFUSED METRICS OF UNAUTHENTICATED ACTOR {}", msg.uuid);
+            return;
+        }
+
+        let known = self
+            .connected_actors
+            .get(&msg.uuid)
+            .is_some_and(|a| a.source.node.socket_addr == msg.source.node.socket_addr);
+        if !known {
+            debug!("IGNORED METRICS OF UNKNOWN ACTOR {}", msg.uuid);
+            return;
+        }
+
+        WORKER_METRICS
+            .get()
+            .expect("WORKER_METRICS")
+            .lock()
+            .unwrap()
+            .insert(msg.uuid, msg.metrics);
+    }
+}
+
+impl Handler<ActorEvent> for MainActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ActorEvent, _ctx: &mut Self::Context) -> Self::Result {
+        let parts = event_proof_parts(&self.challenge, &msg.uuid);
+        if !is_proven(&parts, msg.proof.as_ref(), &mut self.seen_nonces) {
+            warn!("REFUSED EVENT OF UNAUTHENTICATED ACTOR {}", msg.uuid);
+            return;
+        }
+
+        let known = self
+            .connected_actors
+            .get(&msg.uuid)
+            .is_some_and(|a| a.source.node.socket_addr == msg.source.node.socket_addr);
+        if !known {
+            debug!("IGNORED EVENT OF UNKNOWN ACTOR {}", msg.uuid);
+            return;
+        }
+
+        events::forward(msg.event);
+    }
+}
+
+impl Handler<DrainActor> for MainActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: DrainActor, _ctx: &mut Self::Context) -> Self::Result {
+        match self.connected_actors.get(&msg.uuid) {
+            Some(actor) => {
+                info!("DRAIN ACTOR {}", msg.uuid);
+                let grace_period = grace_period_bytes(msg.grace_period);
+                let proof = ClusterSecurity::get().map(|security| {
+                    security.prove(&drain_proof_parts(
+                        &self.challenge,
+                        &msg.uuid,
+                        &grace_period,
+                    ))
+                });
+                actor.source.do_send(ActorDrainRequest {
+                    grace_period: msg.grace_period,
+                    proof,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Handler<ListTasks> for MainActor {
+    type Result = Vec<TaskInfo>;
+
+    fn handle(&mut self, msg: ListTasks, _ctx: &mut Self::Context) -> Self::Result {
+        let mut tasks: Vec<TaskInfo> = self
+            .dispatched_tasks
+            .iter()
+            .filter(|(_, task)| !task.shadow)
+            .filter(|(_, task)| msg.subject.is_none() || task.subject == msg.subject)
+            .map(|(task_id, task)| task.info(*task_id))
+            .collect();
+        tasks.sort_by_key(|task| task.started);
+        tasks
+    }
+}
+
+impl Handler<CancelTask> for MainActor {
+    type Result = Option<TaskInfo>;
+
+    fn handle(&mut self, msg: CancelTask, ctx: &mut Self::Context) -> Self::Result {
+        let task = self
+            .dispatched_tasks
+            .get(&msg.task_id)
+            .filter(|task| !task.shadow)
+            .filter(|task| msg.subject.is_none() || task.subject == msg.subject)?;
+        let info = task.info(msg.task_id);
+
+        info!("CANCEL TASK {} OF ACTOR {}", msg.task_id, task.actor);
+        if let Some(actor) = self.connected_actors.get(&task.actor) {
+            let proof = ClusterSecurity::get().map(|security| {
+                security.prove(&cancel_proof_parts(
+                    &self.challenge,
+                    &task.actor,
+                    &msg.task_id,
+                ))
+            });
+            actor.source.do_send(ActorCancelRequest {
+                task_id: msg.task_id,
+                proof,
+            });
+        }
+
+        // Fails the task right away, whatever the worker still sends is dropped.
+        let uuid = task.actor;
+        self.complete_task(&msg.task_id, TaskState::Cancelled);
+        ctx.notify(TaskResponse(ActorInvokeResponse::Failure(
+            ActorInvokeError {
+                uuid,
+                task_id: msg.task_id,
+                error: ActorError::Cancelled("TASK CANCELLED".to_string()),
+            },
+        )));
+        Some(info)
+    }
+}
+
+impl Handler<SwapActorModel> for MainActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: SwapActorModel, _ctx: &mut Self::Context) -> Self::Result {
+        match self.connected_actors.get(&msg.uuid) {
+            Some(actor) => {
+                info!("SWAP MODEL OF ACTOR {}: {:?}", msg.uuid, msg.command);
+                let command = serde_json::to_vec(&msg.command).unwrap_or_default();
+                let proof = ClusterSecurity::get().map(|security| {
+                    security.prove(&model_proof_parts(&self.challenge, &msg.uuid, &command))
+                });
+                actor.source.do_send(ActorModelRequest {
+                    command: msg.command,
+                    proof,
+                });
+                true
+            }
+            None => false,
+        }
     }
 }
 
 impl Handler<ClusterLog> for MainActor {
     type Result = ();
 
-    fn handle(&mut self, msg: ClusterLog, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: ClusterLog, ctx: &mut Self::Context) -> Self::Result {
         match msg {
             ClusterLog::NewMember(node) => {
                 info!("New model joined the cluster. Node: {node:?}");
                 if self.own_addr != node.socket_addr {
                     let model_addr = node.get_remote_addr(String::from("WorkerActor"));
+                    let proof = ClusterSecurity::get().map(|security| {
+                        security.prove(&main_proof_parts(
+                            &self.challenge,
+                            &self.remote_addr.node.socket_addr.to_string(),
+                        ))
+                    });
                     model_addr.do_send(ActorInfoRequest {
                         source: self.remote_addr.clone(),
+                        challenge: self.challenge.clone(),
+                        proof,
                     });
                 }
             }
             ClusterLog::MemberLeft(addr) => {
                 info!("MEMBER LEFT {:?}", addr);
+                for actor in self
+                    .connected_actors
+                    .values()
+                    .filter(|a| a.source.node.socket_addr == addr)
+                {
+                    events::forward(
+                        Event::new(EventKind::ActorLeft {
+                            model: format!("{}/{}", actor.kind, actor.metadata.name),
+                        })
+                        .with_actor(actor.uuid),
+                    );
+                }
+                self.connected_actors
+                    .retain(|_, a| a.source.node.socket_addr != addr);
+                // Fails the tasks of the workers which left, so their clients do not wait
+                // for the invoke timeout.
+                for (task_id, task) in &self.dispatched_tasks {
+                    if !self.connected_actors.contains_key(&task.actor) {
+                        ctx.notify(TaskResponse(ActorInvokeResponse::Failure(
+                            ActorInvokeError {
+                                uuid: task.actor,
+                                task_id: *task_id,
+                                error: ActorError::NetworkError(format!(
+                                    "ACTOR {} LEFT THE CLUSTER",
+                                    task.actor
+                                )),
+                            },
+                        )));
+                    }
+                }
+
                 let actors: Vec<Uuid> = CONNECTED_ACTORS
                     .get()
                     .expect("CONNECTED_MODELS")
@@ -245,6 +1273,12 @@ impl Handler<ClusterLog> for MainActor {
                         .lock()
                         .unwrap()
                         .remove(&actor);
+                    WORKER_METRICS
+                        .get()
+                        .expect("WORKER_METRICS")
+                        .lock()
+                        .unwrap()
+                        .remove(&actor);
                 }
             }
         }
@@ -1,18 +1,43 @@
+pub mod client;
+pub mod load;
 pub mod main_actor;
+pub mod pipeline;
+pub mod routing;
+pub mod slot;
 use crate::abstractions::{
-    ActorActions, ActorInvokeData, ActorInvokeRequest, ActorKindActions, ActorMetadata, ActorObject,
+    ActorActions, ActorError, ActorInvokeData, ActorInvokeError, ActorInvokeRequest,
+    ActorInvokeResponse, ActorKindActions, ActorMetadata, ActorObject, ModelInfo,
 };
+use crate::cluster::security::{
+    ClusterSecurity, ClusterSend, Proof, SeenNonces, SignedInvokeRequest, SignedInvokeResponse,
+};
+use crate::metrics::{self, WorkerMetrics};
+use crate::resources;
+use crate::telemetry::{self, TraceContext};
 use actix::prelude::*;
 use actix_telepathy::prelude::*;
-use anyhow::Result;
-use log::info;
-use main_actor::{MainActor, MainActorSpec};
+use actix_telepathy::{AddressRequest, AddressResolver};
+use anyhow::{anyhow, Result};
+use client::{ActorClient, ClientInvoke, ClientTasks};
+use futures::FutureExt;
+use load::{ActorLoad, WorkerLoad};
+use log::{debug, error, info, warn};
+use main_actor::{MainActor, MainActorSpec, ResponseForwarders};
 use onceuponai_abstractions::EntityValue;
+use onceuponai_core::events::{self, Event, EventKind, ModelLoadState};
 use onceuponai_core::notifications::{Notification, NotificationLevel};
+use opentelemetry::trace::{FutureExt as TraceFutureExt, SpanKind};
+use opentelemetry::KeyValue;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::any::Any;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
 use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::AbortHandle;
 // use tokio::runtime::Builder;
 use tokio::runtime::Runtime;
 use uuid::Uuid;
@@ -24,8 +49,35 @@ pub struct ActorInfo {
     pub metadata: ActorMetadata,
     pub source: RemoteAddr,
     pub kind: String,
+    pub load: Option<ActorLoad>,
+    /// The actor finishes its tasks and does not accept new ones.
+    #[serde(default)]
+    pub draining: bool,
+    /// Proof over the main actor challenge, required when the cluster has a secret.
+    #[serde(default)]
+    pub proof: Option<Proof>,
+    #[serde(default)]
+    pub model: ModelState,
+    #[serde(default)]
+    pub model_info: ModelInfo,
+}
+
+/// State of the model of a worker, only a loaded model accepts requests.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum ModelState {
+    #[default]
+    Loaded,
+    /// Waiting for in-flight tasks, then unloading or loading the model.
+    Swapping,
+    Unloaded,
 }
 
+const LOAD_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+const METRICS_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: u64 = 30;
+/// Events kept until the main actor connects, later ones are dropped.
+const PENDING_EVENTS_LIMIT: usize = 256;
+
 #[derive(RemoteMessage, Serialize, Deserialize, Clone)]
 pub struct ModelRequest {
     pub uuid: Uuid,
@@ -38,10 +90,116 @@ pub struct ModelResponse {
     pub response: String,
 }
 
+/// Counters of a worker, sent to the main actor when they changed.
+#[derive(RemoteMessage, Serialize, Deserialize, Debug, Clone)]
+#[with_source(source)]
+pub struct ActorMetrics {
+    pub uuid: Uuid,
+    pub source: RemoteAddr,
+    pub metrics: WorkerMetrics,
+    /// Proof over the main actor challenge, required when the cluster has a secret.
+    #[serde(default)]
+    pub proof: Option<Proof>,
+}
+
+/// Event of a worker process, published on the event bus of the main actor.
+#[derive(RemoteMessage, Serialize, Deserialize, Debug, Clone)]
+#[with_source(source)]
+pub struct ActorEvent {
+    pub uuid: Uuid,
+    pub source: RemoteAddr,
+    pub event: Event,
+    /// Proof over the main actor challenge, required when the cluster has a secret.
+    #[serde(default)]
+    pub proof: Option<Proof>,
+}
+
 #[derive(RemoteMessage, Serialize, Deserialize, Debug, Clone)]
 #[with_source(source)]
 pub struct ActorInfoRequest {
     pub source: RemoteAddr,
+    /// Random bytes the worker signs in its `ActorInfo`.
+    pub challenge: Vec<u8>,
+    /// Proof that the request comes from a main actor of the cluster.
+    pub proof: Option<Proof>,
+}
+
+/// Asks a worker to drain, e.g. from the admin API.
+#[derive(RemoteMessage, Serialize, Deserialize, Debug, Clone)]
+pub struct ActorDrainRequest {
+    /// Seconds given to in-flight tasks, the worker default when not set.
+    pub grace_period: Option<u64>,
+    pub proof: Option<Proof>,
+}
+
+/// Cancels an in-flight task of a worker, e.g. from the admin API.
+#[derive(RemoteMessage, Serialize, Deserialize, Debug, Clone)]
+pub struct ActorCancelRequest {
+    pub task_id: Uuid,
+    pub proof: Option<Proof>,
+}
+
+/// Unloads, loads or reloads the model of a worker, e.g. from the admin API.
+#[derive(RemoteMessage, Serialize, Deserialize, Debug, Clone)]
+pub struct ActorModelRequest {
+    pub command: ModelCommand,
+    pub proof: Option<Proof>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ModelCommand {
+    Unload,
+    /// Loads a new spec, `config` is the worker configuration accepted by `spawn -j` in JSON.
+    /// The metadata of the worker does not change.
+    Load {
+        config: String,
+    },
+    Reload,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct ModelSwapped {
+    actor: Arc<Box<dyn ActorActions>>,
+    loaded: bool,
+    load_time: Option<Duration>,
+    error: Option<String>,
+}
+
+/// Other workers hosted by the process, the primary worker forwards them the info requests
+/// of the main actor.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct HostActors(pub Vec<Addr<WorkerActor>>);
+
+/// Event of the process bus, forwarded by the primary worker to the main actor.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ForwardEvent(pub Event);
+
+/// Builds the actor of a new spec from the worker configuration in JSON.
+type ActorFactory = Arc<dyn Fn(&str) -> Result<Box<dyn ActorActions>> + Send + Sync>;
+
+/// Local drain request, resolves once every in-flight task finished or was cancelled.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Drain {
+    pub grace_period: Option<Duration>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct TaskDone {
+    task_id: Uuid,
+    elapsed: Duration,
+    failed: bool,
+    /// Streamed chunks of the task.
+    tokens: u64,
+}
+
+struct InFlightTask {
+    abort: AbortHandle,
+    source: RemoteAddr,
 }
 
 #[derive(RemoteMessage, Serialize, Deserialize, Debug, Clone)]
@@ -52,6 +210,17 @@ pub struct ActorStartInvokeRequest {
     pub stream: bool,
     pub config: HashMap<String, EntityValue>,
     pub data: ActorInvokeData,
+    /// Conversation key used to pin multi-turn requests to the same replica.
+    pub session_key: Option<String>,
+    /// Worker awaiting the responses, set for requests of an `ActorClient`.
+    #[serde(default)]
+    pub reply_to: Option<RemoteAddr>,
+    /// Context of the span of the caller.
+    #[serde(default)]
+    pub trace_context: TraceContext,
+    /// Authenticated caller, listed with the in-flight tasks.
+    #[serde(default)]
+    pub subject: Option<String>,
 }
 
 pub struct ActorBuilder {}
@@ -59,11 +228,20 @@ pub struct ActorBuilder {}
 impl ActorBuilder {
     pub fn build_main(actor: ActorObject<MainActorSpec>) -> Result<MainActor> {
         let actor = actor.setup(MainActor::ACTOR_ID, None);
+        ClusterSecurity::init(&actor.metadata())?;
+        telemetry::init("onceuponai-main", &actor.metadata())?;
         let remote_addr = actor.metadata().remote_addr()?;
+        let challenge = ClusterSecurity::challenge();
+        ClusterSecurity::set_main_challenge(&challenge);
         Ok(MainActor {
             uuid: Uuid::new_v4(),
             remote_addr,
             connected_actors: HashMap::new(),
+            dispatched_tasks: HashMap::new(),
+            remote_tasks: HashMap::new(),
+            challenge,
+            seen_nonces: SeenNonces::default(),
+            forwarders: ResponseForwarders::default(),
             own_addr: actor.own_addr()?,
             actor,
         })
@@ -71,10 +249,24 @@ impl ActorBuilder {
 
     pub fn build_worker<T>(metadata: ActorMetadata, actor_kind: T) -> Result<WorkerActor>
     where
-        T: ActorKindActions + Clone + Send + Sync + 'static,
+        T: ActorKindActions + DeserializeOwned + Clone + Send + Sync + 'static,
+    {
+        ActorBuilder::build_hosted_worker(metadata, actor_kind, WorkerActor::ACTOR_ID)
+    }
+
+    /// Worker registered under `actor_id`, so several workers can share a process.
+    pub fn build_hosted_worker<T>(
+        metadata: ActorMetadata,
+        actor_kind: T,
+        actor_id: &str,
+    ) -> Result<WorkerActor>
+    where
+        T: ActorKindActions + DeserializeOwned + Clone + Send + Sync + 'static,
     {
         let actor = actor_kind.clone().actor();
-        let metadata = metadata.setup(WorkerActor::ACTOR_ID, actor.features());
+        let metadata = metadata.setup(actor_id, actor.features());
+        ClusterSecurity::init(&metadata)?;
+        telemetry::init(&format!("onceuponai-{}", actor.kind()), &metadata)?;
         let remote_addr = metadata.remote_addr()?;
 
         Ok(WorkerActor {
@@ -83,26 +275,262 @@ impl ActorBuilder {
             seed_addr: metadata.seed_addr()?,
             remote_addr,
             actor: Arc::new(actor_kind.actor()),
+            actor_factory: Arc::new(|config: &str| Ok(serde_json::from_str::<T>(config)?.actor())),
+            model: ModelState::Loaded,
+            load: Arc::new(WorkerLoad::new(
+                metadata.max_concurrency,
+                metadata.queue_limit,
+            )),
+            main_addr: None,
+            main_challenge: None,
+            seen_nonces: SeenNonces::default(),
+            reported_load: None,
+            draining: false,
+            in_flight: HashMap::new(),
+            drain_waiters: vec![],
+            idle_waiters: vec![],
+            hosted: vec![],
+            client_tasks: ClientTasks::default(),
+            forwarders: ResponseForwarders::default(),
+            metrics: WorkerMetrics::default(),
+            reported_metrics: None,
+            pending_events: vec![],
+            drained: Arc::new(Notify::new()),
             metadata,
         })
     }
 }
 
 #[derive(RemoteActor)]
-#[remote_messages(ActorInfoRequest, ActorInvokeRequest)]
+#[remote_messages(
+    ActorInfoRequest,
+    ActorInvokeRequest,
+    SignedInvokeRequest,
+    ActorInvokeResponse,
+    SignedInvokeResponse,
+    ActorDrainRequest,
+    ActorCancelRequest,
+    ActorModelRequest
+)]
 pub struct WorkerActor {
     pub uuid: Uuid,
     pub metadata: ActorMetadata,
     pub actor: Arc<Box<dyn ActorActions>>,
+    actor_factory: ActorFactory,
+    pub model: ModelState,
     pub own_addr: SocketAddr,
     pub seed_addr: SocketAddr,
     pub remote_addr: RemoteAddr,
+    pub load: Arc<WorkerLoad>,
+    /// Main actor which requested the info, load updates are sent to it.
+    pub main_addr: Option<RemoteAddr>,
+    /// Challenge of the main actor, signed in every `ActorInfo`.
+    main_challenge: Option<Vec<u8>>,
+    /// Nonces of the main actor proofs, a proof is accepted once.
+    seen_nonces: SeenNonces,
+    pub reported_load: Option<ActorLoad>,
+    pub draining: bool,
+    in_flight: HashMap<Uuid, InFlightTask>,
+    drain_waiters: Vec<oneshot::Sender<()>>,
+    /// Resolved once no task is in flight, before the model is swapped.
+    idle_waiters: Vec<oneshot::Sender<()>>,
+    /// Notified once a drain completed, the worker process can leave the cluster.
+    pub drained: Arc<Notify>,
+    hosted: Vec<Addr<WorkerActor>>,
+    /// Tasks started by the `ActorClient` of the process.
+    client_tasks: ClientTasks,
+    forwarders: ResponseForwarders,
+    pub(crate) metrics: WorkerMetrics,
+    reported_metrics: Option<WorkerMetrics>,
+    /// Events published before the main actor connected.
+    pending_events: Vec<Event>,
 }
 
 impl WorkerActor {
+    /// Actor id of the `index`-th worker of a process, the first one is found by the main actor.
+    pub fn hosted_actor_id(index: usize) -> String {
+        match index {
+            0 => WorkerActor::ACTOR_ID.to_string(),
+            _ => format!("{}-{index}", WorkerActor::ACTOR_ID),
+        }
+    }
+
     pub fn metadata(&self) -> ActorMetadata {
         self.metadata.clone()
     }
+
+    fn actor_info(&self) -> ActorInfo {
+        let mut metadata = self.metadata();
+        metadata.cluster_secret = None;
+        let proof = ClusterSecurity::get()
+            .zip(self.main_challenge.as_ref())
+            .map(|(security, challenge)| {
+                security.prove(&worker_proof_parts(
+                    challenge,
+                    &self.uuid,
+                    &self.remote_addr.node.socket_addr.to_string(),
+                ))
+            });
+
+        ActorInfo {
+            uuid: self.uuid,
+            metadata,
+            source: self.remote_addr.clone(),
+            kind: self.actor.kind(),
+            load: Some(self.load.load()),
+            draining: self.draining || self.model != ModelState::Loaded,
+            proof,
+            model: self.model,
+            model_info: self.model_info(),
+        }
+    }
+
+    /// Model info of the actor with the load time and the memory of the process.
+    fn model_info(&self) -> ModelInfo {
+        let mut info = self.actor.model_info();
+        info.load_seconds = self.metrics.model_load_seconds;
+        info.memory_bytes = resources::resident_memory();
+        if let Some((used, total)) = info.cuda_ordinal().and_then(resources::gpu_memory) {
+            info.gpu_memory_used_bytes = Some(used);
+            info.gpu_memory_total_bytes = Some(total);
+        }
+        info
+    }
+
+    fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs(
+            self.metadata
+                .shutdown_grace_period
+                .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD),
+        )
+    }
+
+    /// Stops accepting requests and tells the main actor. In-flight tasks get
+    /// `grace_period` to finish, the remaining ones are cancelled.
+    fn start_drain(
+        &mut self,
+        grace_period: Option<Duration>,
+        ctx: &mut Context<Self>,
+    ) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        self.drain_waiters.push(tx);
+
+        if !self.draining {
+            self.draining = true;
+            let grace_period = grace_period.unwrap_or_else(|| self.shutdown_grace_period());
+            info!(
+                "DRAINING {} IN-FLIGHT TASKS, GRACE PERIOD {grace_period:?}",
+                self.in_flight.len()
+            );
+            self.announce();
+            ctx.run_later(grace_period, |act, _ctx| act.cancel_in_flight());
+        }
+
+        self.check_drained();
+        rx
+    }
+
+    fn cancel_in_flight(&mut self) {
+        for (task_id, task) in self.in_flight.drain() {
+            task.abort.abort();
+            metrics::end_task(&task_id);
+            task.source
+                .send_response(ActorInvokeResponse::Failure(ActorInvokeError {
+                    uuid: self.uuid,
+                    task_id,
+                    error: ActorError::Draining(
+                        "TASK CANCELLED, ACTOR IS SHUTTING DOWN".to_string(),
+                    ),
+                }));
+        }
+        self.check_drained();
+    }
+
+    fn check_drained(&mut self) {
+        if self.in_flight.is_empty() {
+            for waiter in self.idle_waiters.drain(..) {
+                let _ = waiter.send(());
+            }
+        }
+
+        if !self.draining || !self.in_flight.is_empty() {
+            return;
+        }
+
+        for waiter in self.drain_waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+        self.drained.notify_one();
+    }
+
+    fn wait_idle(&mut self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        self.idle_waiters.push(tx);
+        self.check_drained();
+        rx
+    }
+
+    fn forward_event(&self, mut event: Event) {
+        if let Some(main_addr) = &self.main_addr {
+            event.actor.get_or_insert(self.uuid);
+            let proof = ClusterSecurity::get()
+                .zip(self.main_challenge.as_ref())
+                .map(|(security, challenge)| {
+                    security.prove(&event_proof_parts(challenge, &self.uuid))
+                });
+            main_addr.do_send(ActorEvent {
+                uuid: self.uuid,
+                source: self.remote_addr.clone(),
+                event,
+                proof,
+            });
+        }
+    }
+
+    /// Sends the current state to the main actor.
+    fn announce(&mut self) {
+        if let Some(main_addr) = &self.main_addr {
+            let actor_info = self.actor_info();
+            self.reported_load = actor_info.load.clone();
+            main_addr.do_send(actor_info);
+        }
+    }
+
+    /// Sends the current load to the main actor when it changed since the last report.
+    fn report_load(&mut self) {
+        let Some(main_addr) = &self.main_addr else {
+            return;
+        };
+
+        // Model info reads the process memory, so it is only built when the load changed.
+        if Some(self.load.load()) != self.reported_load {
+            let actor_info = self.actor_info();
+            self.reported_load = actor_info.load.clone();
+            main_addr.do_send(actor_info);
+        }
+    }
+
+    /// Sends the counters to the main actor when they changed since the last report.
+    fn report_metrics(&mut self) {
+        let Some(main_addr) = &self.main_addr else {
+            return;
+        };
+
+        if self.reported_metrics.as_ref() != Some(&self.metrics) {
+            self.reported_metrics = Some(self.metrics.clone());
+            let proof = ClusterSecurity::get()
+                .zip(self.main_challenge.as_ref())
+                .map(|(security, challenge)| {
+                    security.prove(&metrics_proof_parts(challenge, &self.uuid))
+                });
+            main_addr.do_send(ActorMetrics {
+                uuid: self.uuid,
+                source: self.remote_addr.clone(),
+                metrics: self.metrics.clone(),
+                proof,
+            });
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -115,7 +543,22 @@ impl Actor for WorkerActor {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.register(ctx.address().recipient());
+        let actor_id = self.metadata.actor_id();
+        if actor_id == WorkerActor::ACTOR_ID {
+            self.register(ctx.address().recipient());
+            ActorClient::init(ctx.address());
+        } else {
+            AddressResolver::from_registry().do_send(AddressRequest::Register(
+                ctx.address().recipient(),
+                actor_id,
+            ));
+        }
+        ctx.run_interval(LOAD_REPORT_INTERVAL, |act, _ctx| {
+            act.report_load();
+            act.client_tasks.sweep();
+            act.forwarders.sweep();
+        });
+        ctx.run_interval(METRICS_REPORT_INTERVAL, |act, _ctx| act.report_metrics());
     }
 }
 
@@ -123,14 +566,29 @@ impl Handler<ActorInfoRequest> for WorkerActor {
     type Result = ();
 
     fn handle(&mut self, msg: ActorInfoRequest, _ctx: &mut Self::Context) -> Self::Result {
+        info!("MODEL INFO REQUEST: {:?}", msg.source);
+        for hosted in &self.hosted {
+            hosted.do_send(msg.clone());
+        }
+
+        if let Some(security) = ClusterSecurity::get() {
+            let source = msg.source.node.socket_addr.to_string();
+            if !security.verify_once(
+                &main_proof_parts(&msg.challenge, &source),
+                msg.proof.as_ref(),
+                &mut self.seen_nonces,
+            ) {
+                warn!("REFUSED MODEL INFO REQUEST FROM UNAUTHENTICATED NODE {source}");
+                return;
+            }
+        }
+
         let metadata = self.metadata();
-        let model_info = ActorInfo {
-            uuid: self.uuid,
-            metadata: metadata.clone(),
-            source: self.remote_addr.clone(),
-            kind: self.actor.kind(),
-        };
-        info!("MODEL INFO REQUEST: {:?}", msg);
+        self.main_addr = Some(msg.source.clone());
+        self.main_challenge = Some(msg.challenge.clone());
+        ClusterSecurity::set_main_challenge(&msg.challenge);
+        let model_info = self.actor_info();
+        self.reported_load = model_info.load.clone();
         Notification::publish(
             &format!(
                 "ACTOR {}/{} ({}) CONNECTED",
@@ -140,14 +598,60 @@ impl Handler<ActorInfoRequest> for WorkerActor {
             ),
             NotificationLevel::Success,
         );
-        msg.source.do_send(model_info)
+        msg.source.do_send(model_info);
+        for event in std::mem::take(&mut self.pending_events) {
+            self.forward_event(event);
+        }
     }
 }
 
 impl Handler<ActorInvokeRequest> for WorkerActor {
     type Result = ();
 
-    fn handle(&mut self, msg: ActorInvokeRequest, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: ActorInvokeRequest, ctx: &mut Self::Context) -> Self::Result {
+        if ClusterSecurity::get().is_some() {
+            warn!(
+                "REFUSED UNSIGNED INVOKE REQUEST FROM {}",
+                msg.source.node.socket_addr
+            );
+            return;
+        }
+
+        self.invoke(msg, ctx);
+    }
+}
+
+impl Handler<SignedInvokeRequest> for WorkerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SignedInvokeRequest, ctx: &mut Self::Context) -> Self::Result {
+        let (Some(security), Some(challenge)) = (ClusterSecurity::get(), &self.main_challenge)
+        else {
+            warn!(
+                "REFUSED SIGNED INVOKE REQUEST FROM {}, NO AUTHENTICATED MAIN ACTOR",
+                msg.source.node.socket_addr
+            );
+            return;
+        };
+
+        let parts = invoke_proof_parts(challenge, &self.uuid);
+        match security.open_payload::<ActorInvokeRequest>(
+            &parts,
+            &msg.signed,
+            &mut self.seen_nonces,
+        ) {
+            // The responses go to the signed source.
+            Ok(request) => self.invoke(request, ctx),
+            Err(e) => warn!(
+                "REFUSED INVOKE REQUEST FROM {}: {e}",
+                msg.source.node.socket_addr
+            ),
+        }
+    }
+}
+
+impl WorkerActor {
+    fn invoke(&mut self, msg: ActorInvokeRequest, ctx: &mut Context<Self>) {
         info!("MODEL INVOKE REQUEST: {:?}", msg);
         let is_stream = msg.stream;
         let source = msg.source.clone();
@@ -155,12 +659,476 @@ impl Handler<ActorInvokeRequest> for WorkerActor {
         let task_id = msg.task_id;
         let req = msg.clone();
 
+        if self.draining {
+            source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
+                uuid: self.uuid,
+                task_id,
+                error: ActorError::Draining(format!(
+                    "ACTOR {}/{} IS SHUTTING DOWN",
+                    self.actor.kind(),
+                    self.metadata.name
+                )),
+            }));
+            return;
+        }
+
+        if self.model != ModelState::Loaded {
+            source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
+                uuid: self.uuid,
+                task_id,
+                error: ActorError::Draining(format!(
+                    "MODEL OF ACTOR {}/{} IS {:?}",
+                    self.actor.kind(),
+                    self.metadata.name,
+                    self.model
+                )),
+            }));
+            return;
+        }
+
+        let Some(mut ticket) = self.load.admit() else {
+            let load = self.load.load();
+            source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
+                uuid: self.uuid,
+                task_id,
+                error: ActorError::Overloaded(format!(
+                    "ACTOR {}/{} OVERLOADED: {} RUNNING, {} QUEUED",
+                    self.actor.kind(),
+                    self.metadata.name,
+                    load.running,
+                    load.queued
+                )),
+            }));
+            return;
+        };
+
+        let addr = ctx.address();
+        let uuid = self.uuid;
+        let task_source = source.clone();
+        metrics::begin_task(task_id);
+        let task_cx = telemetry::start(
+            &telemetry::extract(&msg.trace_context),
+            "worker.task",
+            SpanKind::Consumer,
+            vec![
+                KeyValue::new("actor.kind", self.actor.kind()),
+                KeyValue::new("actor.name", self.metadata.name.clone()),
+                KeyValue::new("task.id", task_id.to_string()),
+                KeyValue::new("stream", is_stream),
+            ],
+        );
+        let queue_cx = telemetry::start(&task_cx, "worker.queue", SpanKind::Internal, vec![]);
+        let handle = actix_rt::spawn(async move {
+            ticket.acquire().await;
+            telemetry::end(&queue_cx, None);
+            let started = Instant::now();
+            let invocation = async {
+                if !is_stream {
+                    actor.invoke(task_id, &req, task_source.clone()).await
+                } else {
+                    actor
+                        .invoke_stream(task_id, &msg, task_source.clone())
+                        .await
+                }
+            };
+            let invocation = invocation.with_context(task_cx.clone());
+            let error = match AssertUnwindSafe(invocation).catch_unwind().await {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(format!("{e:?}")),
+                Err(panic) => Some(format!("ACTOR PANICKED: {}", panic_message(&*panic))),
+            };
+            let failed = error.is_some();
+            let tokens = metrics::end_task(&task_id);
+            telemetry::set_attribute(&task_cx, "generated_tokens", tokens as i64);
+            telemetry::end(&task_cx, error.as_deref());
+            if let Some(error) = error {
+                error!("TASK {task_id} FAILED: {error}");
+                task_source.send_response(ActorInvokeResponse::Failure(ActorInvokeError {
+                    uuid,
+                    task_id,
+                    error: ActorError::FatalError(error),
+                }));
+            }
+            drop(ticket);
+            addr.do_send(TaskDone {
+                task_id,
+                elapsed: started.elapsed(),
+                failed,
+                tokens,
+            });
+        });
+
+        self.in_flight.insert(
+            task_id,
+            InFlightTask {
+                abort: handle.abort_handle(),
+                source,
+            },
+        );
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "UNKNOWN PANIC".to_string()
+    }
+}
+
+impl Handler<ClientInvoke> for WorkerActor {
+    type Result = Result<tokio::sync::mpsc::Receiver<ActorInvokeResponse>>;
+
+    fn handle(&mut self, msg: ClientInvoke, _ctx: &mut Self::Context) -> Self::Result {
+        let main_addr = self
+            .main_addr
+            .as_ref()
+            .ok_or(anyhow!("MAIN ACTOR NOT CONNECTED"))?;
+        let mut request = msg.0;
+        request.reply_to = Some(self.remote_addr.clone());
+        let receiver = self.client_tasks.register(request.task_id);
+        let challenge = self.main_challenge.as_deref().unwrap_or_default();
+        main_addr.send_start_request(request, &start_proof_parts(challenge));
+        Ok(receiver)
+    }
+}
+
+impl Handler<ActorInvokeResponse> for WorkerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ActorInvokeResponse, _ctx: &mut Self::Context) -> Self::Result {
+        if ClusterSecurity::get().is_some() {
+            warn!("REFUSED UNSIGNED INVOKE RESPONSE");
+            return;
+        }
+
+        self.client_response(msg);
+    }
+}
+
+impl Handler<SignedInvokeResponse> for WorkerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SignedInvokeResponse, _ctx: &mut Self::Context) -> Self::Result {
+        let Some(security) = ClusterSecurity::get() else {
+            warn!("REFUSED SIGNED INVOKE RESPONSE, CLUSTER SECRET NOT SET");
+            return;
+        };
+
+        let challenge = self.main_challenge.as_deref().unwrap_or_default();
+        let parts = response_proof_parts(challenge, &self.remote_addr.id);
+        match security.open_payload::<ActorInvokeResponse>(
+            &parts,
+            &msg.signed,
+            &mut self.seen_nonces,
+        ) {
+            Ok(response) => self.client_response(response),
+            Err(e) => warn!("REFUSED INVOKE RESPONSE: {e}"),
+        }
+    }
+}
+
+impl WorkerActor {
+    fn client_response(&mut self, msg: ActorInvokeResponse) {
+        let task_id = msg.task_id();
+        match self.client_tasks.route(&msg) {
+            Some(sender) => self.forwarders.forward(&sender, msg, move || {
+                debug!("CLIENT TASK {task_id} DROPPED, CLIENT GONE")
+            }),
+            None => debug!("RESPONSE OF UNKNOWN CLIENT TASK {task_id}"),
+        }
+    }
+}
+
+impl Handler<ForwardEvent> for WorkerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ForwardEvent, _ctx: &mut Self::Context) -> Self::Result {
+        if self.main_addr.is_some() {
+            self.forward_event(msg.0);
+        } else if self.pending_events.len() < PENDING_EVENTS_LIMIT {
+            self.pending_events.push(msg.0);
+        }
+    }
+}
+
+impl Handler<HostActors> for WorkerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: HostActors, _ctx: &mut Self::Context) -> Self::Result {
+        self.hosted = msg.0;
+    }
+}
+
+impl Handler<TaskDone> for WorkerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: TaskDone, _ctx: &mut Self::Context) -> Self::Result {
+        self.in_flight.remove(&msg.task_id);
+        self.metrics
+            .record_task(msg.elapsed, msg.failed, msg.tokens);
+        self.check_drained();
+    }
+}
+
+impl Handler<Drain> for WorkerActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: Drain, ctx: &mut Self::Context) -> Self::Result {
+        let drained = self.start_drain(msg.grace_period, ctx);
+        Box::pin(async move {
+            let _ = drained.await;
+        })
+    }
+}
+
+impl Handler<ActorDrainRequest> for WorkerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ActorDrainRequest, ctx: &mut Self::Context) -> Self::Result {
+        info!("DRAIN REQUEST: {:?}", msg.grace_period);
+        if let Some(security) = ClusterSecurity::get() {
+            let grace_period = grace_period_bytes(msg.grace_period);
+            let authenticated = self.main_challenge.as_ref().is_some_and(|challenge| {
+                security.verify_once(
+                    &drain_proof_parts(challenge, &self.uuid, &grace_period),
+                    msg.proof.as_ref(),
+                    &mut self.seen_nonces,
+                )
+            });
+            if !authenticated {
+                warn!("REFUSED DRAIN REQUEST FROM UNAUTHENTICATED NODE");
+                return;
+            }
+        }
+
+        let _ = self.start_drain(msg.grace_period.map(Duration::from_secs), ctx);
+    }
+}
+
+impl Handler<ActorCancelRequest> for WorkerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ActorCancelRequest, _ctx: &mut Self::Context) -> Self::Result {
+        info!("CANCEL REQUEST: {}", msg.task_id);
+        if let Some(security) = ClusterSecurity::get() {
+            let authenticated = self.main_challenge.as_ref().is_some_and(|challenge| {
+                security.verify_once(
+                    &cancel_proof_parts(challenge, &self.uuid, &msg.task_id),
+                    msg.proof.as_ref(),
+                    &mut self.seen_nonces,
+                )
+            });
+            if !authenticated {
+                warn!("REFUSED CANCEL REQUEST FROM UNAUTHENTICATED NODE");
+                return;
+            }
+        }
+
+        // The main actor already failed the task, so no response is sent back.
+        if let Some(task) = self.in_flight.remove(&msg.task_id) {
+            task.abort.abort();
+            metrics::end_task(&msg.task_id);
+            self.check_drained();
+        }
+    }
+}
+
+impl Handler<ActorModelRequest> for WorkerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ActorModelRequest, ctx: &mut Self::Context) -> Self::Result {
+        info!("MODEL REQUEST: {:?}", msg.command);
+        if let Some(security) = ClusterSecurity::get() {
+            let command = serde_json::to_vec(&msg.command).unwrap_or_default();
+            let authenticated = self.main_challenge.as_ref().is_some_and(|challenge| {
+                security.verify_once(
+                    &model_proof_parts(challenge, &self.uuid, &command),
+                    msg.proof.as_ref(),
+                    &mut self.seen_nonces,
+                )
+            });
+            if !authenticated {
+                warn!("REFUSED MODEL REQUEST FROM UNAUTHENTICATED NODE");
+                return;
+            }
+        }
+
+        if self.draining || self.model == ModelState::Swapping {
+            warn!("REFUSED MODEL REQUEST, ACTOR IS DRAINING OR SWAPPING ITS MODEL");
+            return;
+        }
+
+        let old = Arc::clone(&self.actor);
+        let (new, load) = match msg.command {
+            ModelCommand::Unload => (Arc::clone(&old), false),
+            ModelCommand::Reload => (Arc::clone(&old), true),
+            ModelCommand::Load { config } => match (self.actor_factory)(&config) {
+                Ok(actor) => (Arc::new(actor), true),
+                Err(e) => {
+                    Notification::publish(
+                        &format!("INVALID MODEL CONFIG: {e}"),
+                        NotificationLevel::Error,
+                    );
+                    return;
+                }
+            },
+        };
+
+        // The main actor stops routing to the worker while it drains and swaps.
+        self.model = ModelState::Swapping;
+        self.announce();
+        if load {
+            events::publish(EventKind::ModelLoading {
+                model: format!("{}/{}", new.kind(), self.metadata.name),
+                state: ModelLoadState::Started,
+                seconds: None,
+                error: None,
+            });
+        }
+        let idle = self.wait_idle();
+        let addr = ctx.address();
         actix_rt::spawn(async move {
-            if !is_stream {
-                actor.invoke(task_id, &req, source).await.unwrap();
-            } else {
-                actor.invoke_stream(task_id, &msg, source).await.unwrap();
+            let _ = idle.await;
+            let result = async {
+                old.unload().await?;
+                if !load {
+                    return anyhow::Ok(None);
+                }
+                let started = Instant::now();
+                new.start().await?;
+                anyhow::Ok(Some(started.elapsed()))
             }
+            .await;
+            addr.do_send(ModelSwapped {
+                actor: new,
+                loaded: load && result.is_ok(),
+                load_time: result.as_ref().ok().copied().flatten(),
+                error: result.err().map(|e| format!("{e:?}")),
+            });
         });
     }
 }
+
+impl Handler<ModelSwapped> for WorkerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ModelSwapped, _ctx: &mut Self::Context) -> Self::Result {
+        self.actor = msg.actor;
+        self.metadata.features = self.actor.features();
+        if let Some(load_time) = msg.load_time {
+            self.metrics.model_load_seconds = Some(load_time.as_secs_f64());
+        }
+        self.model = if msg.loaded {
+            ModelState::Loaded
+        } else {
+            ModelState::Unloaded
+        };
+
+        events::publish(EventKind::ModelLoading {
+            model: format!("{}/{}", self.actor.kind(), self.metadata.name),
+            state: match (&msg.error, msg.loaded) {
+                (Some(_), _) => ModelLoadState::Failed,
+                (None, true) => ModelLoadState::Loaded,
+                (None, false) => ModelLoadState::Unloaded,
+            },
+            seconds: msg.load_time.map(|load_time| load_time.as_secs_f64()),
+            error: msg.error.clone(),
+        });
+
+        let actor = format!("{}/{}", self.metadata.name, self.actor.kind());
+        match msg.error {
+            Some(e) => Notification::publish(
+                &format!("MODEL SWAP OF ACTOR {actor} FAILED: {e}"),
+                NotificationLevel::Error,
+            ),
+            None => Notification::publish(
+                &format!("MODEL OF ACTOR {actor} {:?}", self.model),
+                NotificationLevel::Success,
+            ),
+        }
+        self.announce();
+    }
+}
+
+/// Signed by the main actor in `ActorInfoRequest`.
+pub fn main_proof_parts<'a>(challenge: &'a [u8], main_addr: &'a str) -> [&'a [u8]; 3] {
+    [b"main", challenge, main_addr.as_bytes()]
+}
+
+/// Signed by a worker in `ActorInfo`, binds the proof to the worker uuid and address.
+pub fn worker_proof_parts<'a>(
+    challenge: &'a [u8],
+    uuid: &'a Uuid,
+    worker_addr: &'a str,
+) -> [&'a [u8]; 4] {
+    [
+        b"worker",
+        challenge,
+        uuid.as_bytes(),
+        worker_addr.as_bytes(),
+    ]
+}
+
+/// Signed by a worker in `ActorMetrics`, binds the proof to the worker uuid.
+pub fn metrics_proof_parts<'a>(challenge: &'a [u8], uuid: &'a Uuid) -> [&'a [u8]; 3] {
+    [b"metrics", challenge, uuid.as_bytes()]
+}
+
+/// Signed by a worker in `ActorEvent`, binds the proof to the worker uuid.
+pub fn event_proof_parts<'a>(challenge: &'a [u8], uuid: &'a Uuid) -> [&'a [u8]; 3] {
+    [b"event", challenge, uuid.as_bytes()]
+}
+
+/// Signed by the main actor in `ActorDrainRequest`, binds the proof to the grace period.
+pub fn drain_proof_parts<'a>(
+    challenge: &'a [u8],
+    uuid: &'a Uuid,
+    grace_period: &'a [u8],
+) -> [&'a [u8]; 4] {
+    [b"drain", challenge, uuid.as_bytes(), grace_period]
+}
+
+/// Grace period of an `ActorDrainRequest` as signed, empty when not set.
+pub fn grace_period_bytes(grace_period: Option<u64>) -> Vec<u8> {
+    grace_period
+        .map(|grace_period| grace_period.to_be_bytes().to_vec())
+        .unwrap_or_default()
+}
+
+/// Signed by the main actor in `ActorCancelRequest`, binds the proof to the task.
+pub fn cancel_proof_parts<'a>(
+    challenge: &'a [u8],
+    uuid: &'a Uuid,
+    task_id: &'a Uuid,
+) -> [&'a [u8]; 4] {
+    [b"cancel", challenge, uuid.as_bytes(), task_id.as_bytes()]
+}
+
+/// Signed by the main actor in `ActorModelRequest`, binds the proof to the JSON of the
+/// command.
+pub fn model_proof_parts<'a>(
+    challenge: &'a [u8],
+    uuid: &'a Uuid,
+    command: &'a [u8],
+) -> [&'a [u8]; 4] {
+    [b"model", challenge, uuid.as_bytes(), command]
+}
+
+/// Signed by the main actor in `SignedInvokeRequest`, binds the request to the worker.
+pub fn invoke_proof_parts<'a>(challenge: &'a [u8], uuid: &'a Uuid) -> [&'a [u8]; 3] {
+    [b"invoke", challenge, uuid.as_bytes()]
+}
+
+/// Signed by the sender of a `SignedInvokeResponse`, binds the response to the actor id it is
+/// sent to.
+pub fn response_proof_parts<'a>(challenge: &'a [u8], actor_id: &'a str) -> [&'a [u8]; 3] {
+    [b"response", challenge, actor_id.as_bytes()]
+}
+
+/// Signed by an actor client in `SignedStartInvokeRequest`.
+pub fn start_proof_parts(challenge: &[u8]) -> [&[u8]; 2] {
+    [b"start", challenge]
+}
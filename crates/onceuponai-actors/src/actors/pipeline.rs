@@ -0,0 +1,524 @@
+use super::main_actor::{InvokeTask, MainActor, StartInvoke, TaskResponse, INVOKE_TASKS};
+use super::ActorStartInvokeRequest;
+use crate::abstractions::{
+    ActorError, ActorInvokeData, ActorInvokeError, ActorInvokeResponse, ActorInvokeResult,
+};
+use actix::Addr;
+use anyhow::{anyhow, bail, Result};
+use onceuponai_abstractions::EntityValue;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Kind under which pipelines are invoked, the model id is `pipeline/{name}`.
+pub const PIPELINE_KIND: &str = "pipeline";
+
+/// DAG of steps, each invoking an actor with inputs templated from the request and
+/// from the outputs of earlier steps.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PipelineSpec {
+    pub name: String,
+    pub steps: Vec<PipelineStep>,
+    /// Step whose response is returned, the last step when not set.
+    pub output: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PipelineStep {
+    pub id: String,
+    pub kind: String,
+    pub name: String,
+    /// `ActorInvokeData` template. Strings may contain `{{input.<path>}}` and
+    /// `{{steps.<id>.<path>}}` placeholders.
+    pub data: Value,
+    #[serde(default)]
+    pub config: HashMap<String, EntityValue>,
+    /// Extra dependencies which are not referenced in `data`.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+impl PipelineStep {
+    fn dependencies(&self) -> HashSet<String> {
+        let mut dependencies: HashSet<String> = self.depends_on.iter().cloned().collect();
+        collect_step_references(&self.data, &mut dependencies);
+        dependencies
+    }
+}
+
+impl PipelineSpec {
+    /// Groups the steps into stages which can run concurrently, in execution order,
+    /// and returns them with the output step, which always runs last.
+    pub fn plan(&self) -> Result<(Vec<Vec<&PipelineStep>>, &PipelineStep)> {
+        let output = match &self.output {
+            Some(id) => self
+                .steps
+                .iter()
+                .find(|s| &s.id == id)
+                .ok_or_else(|| anyhow!("PIPELINE {}: UNKNOWN OUTPUT STEP {id:?}", self.name))?,
+            None => self
+                .steps
+                .last()
+                .ok_or_else(|| anyhow!("PIPELINE {}: NO STEPS", self.name))?,
+        };
+
+        let mut ids = HashSet::new();
+        for step in &self.steps {
+            if !ids.insert(step.id.as_str()) {
+                bail!("PIPELINE {}: DUPLICATED STEP {:?}", self.name, step.id);
+            }
+            if step.kind == PIPELINE_KIND && step.name == self.name {
+                bail!("PIPELINE {}: STEP {:?} INVOKES ITSELF", self.name, step.id);
+            }
+        }
+
+        let mut dependencies: HashMap<&str, HashSet<String>> = HashMap::new();
+        for step in &self.steps {
+            let step_dependencies = step.dependencies();
+            if let Some(unknown) = step_dependencies.iter().find(|d| !ids.contains(d.as_str())) {
+                bail!(
+                    "PIPELINE {}: STEP {:?} DEPENDS ON UNKNOWN STEP {unknown:?}",
+                    self.name,
+                    step.id
+                );
+            }
+            if step.id != output.id && step_dependencies.contains(&output.id) {
+                bail!(
+                    "PIPELINE {}: STEP {:?} DEPENDS ON OUTPUT STEP {:?}",
+                    self.name,
+                    step.id,
+                    output.id
+                );
+            }
+            dependencies.insert(step.id.as_str(), step_dependencies);
+        }
+
+        let mut done: HashSet<String> = HashSet::new();
+        let mut remaining: Vec<&PipelineStep> =
+            self.steps.iter().filter(|s| s.id != output.id).collect();
+        let mut stages = vec![];
+        while !remaining.is_empty() {
+            let (ready, blocked): (Vec<&PipelineStep>, Vec<&PipelineStep>) = remaining
+                .into_iter()
+                .partition(|s| dependencies[s.id.as_str()].is_subset(&done));
+            if ready.is_empty() {
+                bail!("PIPELINE {}: STEPS CONTAIN A CYCLE", self.name);
+            }
+            done.extend(ready.iter().map(|s| s.id.clone()));
+            stages.push(ready);
+            remaining = blocked;
+        }
+
+        if dependencies[output.id.as_str()].contains(&output.id) {
+            bail!("PIPELINE {}: STEPS CONTAIN A CYCLE", self.name);
+        }
+
+        Ok((stages, output))
+    }
+}
+
+/// Plans every pipeline and rejects pipelines which invoke each other in a cycle
+/// (`a -> b -> a`), such calls would nest until the invoke timeout.
+pub fn validate_pipelines(pipelines: &[PipelineSpec]) -> Result<()> {
+    let calls: HashMap<&str, Vec<&str>> = pipelines
+        .iter()
+        .map(|pipeline| {
+            let callees = pipeline
+                .steps
+                .iter()
+                .filter(|step| step.kind == PIPELINE_KIND)
+                .map(|step| step.name.as_str())
+                .collect();
+            (pipeline.name.as_str(), callees)
+        })
+        .collect();
+
+    for pipeline in pipelines {
+        pipeline.plan()?;
+        find_call_cycle(&calls, &mut vec![pipeline.name.as_str()])?;
+    }
+    Ok(())
+}
+
+fn find_call_cycle<'a>(
+    calls: &HashMap<&'a str, Vec<&'a str>>,
+    path: &mut Vec<&'a str>,
+) -> Result<()> {
+    let current = *path.last().expect("PIPELINE");
+    for callee in calls.get(current).into_iter().flatten() {
+        if path.contains(callee) {
+            bail!(
+                "PIPELINES INVOKE EACH OTHER: {} -> {callee}",
+                path.join(" -> ")
+            );
+        }
+        path.push(callee);
+        find_call_cycle(calls, path)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Runs a pipeline for a start request. Intermediate steps are awaited here, the output
+/// step reuses the request task id, so its responses (including streams) go to the caller.
+pub async fn run_pipeline(
+    addr: Addr<MainActor>,
+    uuid: Uuid,
+    pipeline: PipelineSpec,
+    msg: ActorStartInvokeRequest,
+    timeout: Duration,
+) {
+    if let Err(e) = execute(&addr, &pipeline, &msg, timeout).await {
+        addr.do_send(TaskResponse(ActorInvokeResponse::Failure(
+            ActorInvokeError {
+                uuid,
+                task_id: msg.task_id,
+                error: ActorError::BadRequest(e.to_string()),
+            },
+        )));
+    }
+}
+
+async fn execute(
+    addr: &Addr<MainActor>,
+    pipeline: &PipelineSpec,
+    msg: &ActorStartInvokeRequest,
+    timeout: Duration,
+) -> Result<()> {
+    let (stages, output) = pipeline.plan()?;
+
+    let input = serde_json::to_value(&msg.data)?;
+    let input = match input {
+        // ActorInvokeData is externally tagged, templates address the inner request.
+        Value::Object(variant) if variant.len() == 1 => variant
+            .into_iter()
+            .next()
+            .map(|(_, v)| v)
+            .unwrap_or_default(),
+        value => value,
+    };
+    let mut context = json!({ "input": input, "steps": {} });
+
+    for stage in stages {
+        let mut handles = vec![];
+        for step in stage {
+            let request = step_request(step, msg, &context, Uuid::new_v4(), false)?;
+            handles.push((
+                step.id.clone(),
+                actix_rt::spawn(invoke_step(addr.clone(), request, timeout)),
+            ));
+        }
+
+        for (id, handle) in handles {
+            let result = handle
+                .await
+                .map_err(|e| anyhow!("PIPELINE {}: STEP {id:?} {e}", pipeline.name))?
+                .map_err(|e| anyhow!("PIPELINE {}: STEP {id:?} {e}", pipeline.name))?;
+            context["steps"][&id] = serde_json::to_value(&result.data)?;
+        }
+    }
+
+    let request = step_request(output, msg, &context, msg.task_id, msg.stream)?;
+    addr.do_send(StartInvoke(request));
+    Ok(())
+}
+
+fn step_request(
+    step: &PipelineStep,
+    msg: &ActorStartInvokeRequest,
+    context: &Value,
+    task_id: Uuid,
+    stream: bool,
+) -> Result<ActorStartInvokeRequest> {
+    let data: ActorInvokeData = serde_json::from_value(render(&step.data, context)?)
+        .map_err(|e| anyhow!("STEP {:?} DATA: {e}", step.id))?;
+    let mut config = msg.config.clone();
+    config.extend(step.config.clone());
+
+    Ok(ActorStartInvokeRequest {
+        task_id,
+        kind: step.kind.clone(),
+        name: step.name.clone(),
+        stream,
+        config,
+        data,
+        session_key: msg.session_key.clone(),
+        reply_to: None,
+        trace_context: msg.trace_context.clone(),
+        subject: msg.subject.clone(),
+    })
+}
+
+async fn invoke_step(
+    addr: Addr<MainActor>,
+    request: ActorStartInvokeRequest,
+    timeout: Duration,
+) -> Result<ActorInvokeResult> {
+    let task_id = request.task_id;
+    let (task, mut rx) = InvokeTask::new();
+    INVOKE_TASKS
+        .get()
+        .expect("INVOKE_TASKS")
+        .lock()
+        .map_err(|e| anyhow!("{e}"))?
+        .insert(task_id, task);
+
+    addr.do_send(StartInvoke(request));
+    let response = tokio::time::timeout(timeout, rx.recv()).await;
+    if let Ok(mut tasks) = INVOKE_TASKS.get().expect("INVOKE_TASKS").lock() {
+        tasks.remove(&task_id);
+    }
+
+    match response {
+        Ok(Some(ActorInvokeResponse::Success(result))) => Ok(result),
+        Ok(Some(ActorInvokeResponse::Failure(result))) => {
+            Err(anyhow!("FAILED: {:?}", result.error))
+        }
+        Ok(Some(ActorInvokeResponse::Finish(_))) => Err(anyhow!("FINISHED WITHOUT RESULT")),
+        Ok(None) | Err(_) => Err(anyhow!("TIMEOUT ( > {timeout:?})")),
+    }
+}
+
+/// Replaces placeholders in every string of a template. A string which is a single
+/// placeholder takes the referenced value as is, otherwise values are interpolated.
+pub fn render(template: &Value, context: &Value) -> Result<Value> {
+    Ok(match template {
+        Value::String(text) => render_string(text, context)?,
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| render(item, context))
+                .collect::<Result<Vec<Value>>>()?,
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), render(v, context)?)))
+                .collect::<Result<Map<String, Value>>>()?,
+        ),
+        value => value.clone(),
+    })
+}
+
+fn render_string(text: &str, context: &Value) -> Result<Value> {
+    let trimmed = text.trim();
+    if let Some(path) = trimmed
+        .strip_prefix("{{")
+        .and_then(|t| t.strip_suffix("}}"))
+        .filter(|p| !p.contains("{{"))
+    {
+        return Ok(lookup(context, path.trim())?.clone());
+    }
+
+    let mut rendered = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .map(|e| start + e)
+            .ok_or_else(|| anyhow!("UNCLOSED PLACEHOLDER IN {text:?}"))?;
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(&to_text(lookup(context, rest[start + 2..end].trim())?));
+        rest = &rest[end + 2..];
+    }
+    rendered.push_str(rest);
+
+    Ok(Value::String(rendered))
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(to_text)
+            .collect::<Vec<String>>()
+            .join("\n"),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+/// Resolves a dotted path, array items are addressed by index (negative from the end).
+fn lookup<'a>(context: &'a Value, path: &str) -> Result<&'a Value> {
+    let mut value = context;
+    for segment in path.split('.') {
+        value = match value {
+            Value::Object(fields) => fields.get(segment),
+            Value::Array(items) => segment.parse::<i64>().ok().and_then(|ix| {
+                let ix = if ix < 0 { items.len() as i64 + ix } else { ix };
+                usize::try_from(ix).ok().and_then(|ix| items.get(ix))
+            }),
+            _ => None,
+        }
+        .ok_or_else(|| anyhow!("PLACEHOLDER {path:?} NOT FOUND"))?;
+    }
+    Ok(value)
+}
+
+fn collect_step_references(template: &Value, references: &mut HashSet<String>) {
+    match template {
+        Value::String(text) => {
+            let mut rest = text.as_str();
+            while let Some(start) = rest.find("{{") {
+                rest = &rest[start + 2..];
+                let path = rest.split("}}").next().unwrap_or_default().trim();
+                if let Some(step) = path.strip_prefix("steps.") {
+                    let id = step.split('.').next().unwrap_or_default();
+                    references.insert(id.to_string());
+                }
+            }
+        }
+        Value::Array(items) => items
+            .iter()
+            .for_each(|item| collect_step_references(item, references)),
+        Value::Object(fields) => fields
+            .values()
+            .for_each(|v| collect_step_references(v, references)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actors::main_actor::MainActorSpec;
+
+    fn step(id: &str, data: Value) -> PipelineStep {
+        PipelineStep {
+            id: id.to_string(),
+            kind: "e5".to_string(),
+            name: id.to_string(),
+            data,
+            config: HashMap::new(),
+            depends_on: vec![],
+        }
+    }
+
+    #[test]
+    fn test_pipelines_invoking_each_other() {
+        let pipeline = |name: &str, callee: &str| PipelineSpec {
+            name: name.to_string(),
+            steps: vec![PipelineStep {
+                kind: PIPELINE_KIND.to_string(),
+                ..step(callee, json!({}))
+            }],
+            output: None,
+        };
+
+        assert!(validate_pipelines(&[pipeline("a", "b"), pipeline("b", "c")]).is_ok());
+        let cycle = [pipeline("a", "b"), pipeline("b", "c"), pipeline("c", "a")];
+        let error = validate_pipelines(&cycle).unwrap_err().to_string();
+        assert!(error.contains("a -> b -> c -> a"), "{error}");
+    }
+
+    #[test]
+    fn test_invoke_targets_of_pipelines_and_routes() {
+        let spec: MainActorSpec = serde_json::from_value(json!({
+            "server_host": "0.0.0.0",
+            "server_port": 8080,
+            "routes": [{
+                "model": "quantized/chat",
+                "targets": [{"kind": "quantized", "name": "bielik"}],
+                "shadow": {"kind": "quantized", "name": "next"}
+            }],
+            "pipelines": [
+                {"name": "rag", "steps": [
+                    {"id": "context", "kind": "e5", "name": "small", "data": {}},
+                    {"id": "answer", "kind": "quantized", "name": "chat", "data": {}}
+                ]}
+            ]
+        }))
+        .unwrap();
+
+        let mut targets = spec.invoke_targets("pipeline/rag");
+        targets.sort();
+        assert_eq!(
+            targets,
+            vec![
+                "e5/small",
+                "pipeline/rag",
+                "quantized/bielik",
+                "quantized/chat",
+                "quantized/next"
+            ]
+        );
+        assert_eq!(spec.invoke_targets("e5/small"), vec!["e5/small"]);
+    }
+
+    #[test]
+    fn test_render_placeholders() {
+        let context = json!({
+            "input": {"messages": [{"content": "first"}, {"content": "hello"}]},
+            "steps": {"context": {"content": ["a", "b"]}}
+        });
+
+        let template = json!({
+            "ChatCompletion": {
+                "messages": [{
+                    "role": "user",
+                    "content": "{{steps.context.content}}\nQ: {{ input.messages.-1.content }}"
+                }],
+                "raw": "{{steps.context.content}}"
+            }
+        });
+        let rendered = render(&template, &context).unwrap();
+        assert_eq!(
+            rendered["ChatCompletion"]["messages"][0]["content"],
+            json!("a\nb\nQ: hello")
+        );
+        assert_eq!(rendered["ChatCompletion"]["raw"], json!(["a", "b"]));
+        assert!(render(&json!("{{steps.missing.content}}"), &context).is_err());
+    }
+
+    #[test]
+    fn test_plan_orders_stages() {
+        let pipeline = PipelineSpec {
+            name: "rag".to_string(),
+            steps: vec![
+                step("embed", json!({"Entity": {"input": ["{{input.input.0}}"]}})),
+                step(
+                    "search",
+                    json!({"Entity": {"v": "{{steps.embed.embeddings}}"}}),
+                ),
+                step("other", json!({"Entity": {}})),
+                step(
+                    "answer",
+                    json!({"Entity": {"q": "{{steps.search.content}} {{steps.other.x}}"}}),
+                ),
+            ],
+            output: None,
+        };
+
+        let (stages, output) = pipeline.plan().unwrap();
+        let ids: Vec<Vec<&str>> = stages
+            .iter()
+            .map(|s| s.iter().map(|s| s.id.as_str()).collect())
+            .collect();
+        assert_eq!(ids, vec![vec!["embed", "other"], vec!["search"]]);
+        assert_eq!(output.id, "answer");
+    }
+
+    #[test]
+    fn test_plan_rejects_cycles_and_unknown_steps() {
+        let cycle = PipelineSpec {
+            name: "cycle".to_string(),
+            steps: vec![
+                step("a", json!("{{steps.b.x}}")),
+                step("b", json!("{{steps.a.x}}")),
+                step("out", json!("{{steps.a.x}}")),
+            ],
+            output: None,
+        };
+        assert!(cycle.plan().is_err());
+
+        let unknown = PipelineSpec {
+            name: "unknown".to_string(),
+            steps: vec![step("a", json!("{{steps.nope.x}}"))],
+            output: None,
+        };
+        assert!(unknown.plan().is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Maps a public model id (`kind/name`) to one or more actor targets.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RouteRule {
+    pub model: String,
+    pub targets: Vec<RouteTarget>,
+    /// Receives a copy of every request, its responses are discarded.
+    pub shadow: Option<RouteTarget>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RouteTarget {
+    pub kind: String,
+    pub name: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl RouteTarget {
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.kind, self.name)
+    }
+}
+
+/// Reads the route rules of a YAML file, a list of rules, and validates them.
+pub fn load_routes(path: &str) -> Result<Vec<RouteRule>> {
+    let yaml =
+        std::fs::read_to_string(path).map_err(|e| anyhow!("ROUTES {path} CAN NOT BE READ: {e}"))?;
+    let rules: Vec<RouteRule> = serde_yaml::from_str(&yaml)?;
+    validate_routes(&rules)?;
+    Ok(rules)
+}
+
+pub fn validate_routes(rules: &[RouteRule]) -> Result<()> {
+    let mut models = HashSet::new();
+    for rule in rules {
+        if rule.model.split_once('/').is_none() {
+            return Err(anyhow!("ROUTE {}: MODEL IS NOT kind/name", rule.model));
+        }
+        if !models.insert(&rule.model) {
+            return Err(anyhow!("ROUTE {}: DEFINED TWICE", rule.model));
+        }
+        if !rule.targets.iter().any(|t| t.weight > 0) {
+            return Err(anyhow!("ROUTE {}: NO TARGET WITH A WEIGHT", rule.model));
+        }
+        for target in rule.targets.iter().chain(&rule.shadow) {
+            if target.kind.is_empty() || target.name.is_empty() {
+                return Err(anyhow!("ROUTE {}: TARGET WITHOUT KIND OR NAME", rule.model));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl RouteRule {
+    /// Chooses a target proportionally to its weight among the available ones.
+    /// With a session key the choice is stable, so a conversation stays on one version.
+    pub fn choose_target<F>(
+        &self,
+        session_key: Option<&str>,
+        is_available: F,
+    ) -> Option<&RouteTarget>
+    where
+        F: Fn(&RouteTarget) -> bool,
+    {
+        let targets: Vec<&RouteTarget> = self
+            .targets
+            .iter()
+            .filter(|t| t.weight > 0 && is_available(t))
+            .collect();
+        let total: u64 = targets.iter().map(|t| t.weight as u64).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut point = match session_key {
+            Some(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                self.model.hash(&mut hasher);
+                hasher.finish() % total
+            }
+            None => rand::thread_rng().gen_range(0..total),
+        };
+
+        for target in targets {
+            if point < target.weight as u64 {
+                return Some(target);
+            }
+            point -= target.weight as u64;
+        }
+
+        None
+    }
+}
+
+/// Per target counters used to compare actor versions. Shadow calls are counted apart, so
+/// the latency of a version as primary can be compared with its latency as shadow.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RouteTargetStats {
+    pub requests: u64,
+    pub errors: u64,
+    pub total_latency_ms: u64,
+    pub max_latency_ms: u64,
+    pub avg_latency_ms: f64,
+    pub shadow_requests: u64,
+    pub shadow_errors: u64,
+    pub shadow_total_latency_ms: u64,
+    pub shadow_max_latency_ms: u64,
+    pub shadow_avg_latency_ms: f64,
+}
+
+impl RouteTargetStats {
+    pub fn record(&mut self, latency: Duration, failed: bool, shadow: bool) {
+        let latency_ms = latency.as_millis() as u64;
+        let (requests, errors, total, max, avg) = if shadow {
+            (
+                &mut self.shadow_requests,
+                &mut self.shadow_errors,
+                &mut self.shadow_total_latency_ms,
+                &mut self.shadow_max_latency_ms,
+                &mut self.shadow_avg_latency_ms,
+            )
+        } else {
+            (
+                &mut self.requests,
+                &mut self.errors,
+                &mut self.total_latency_ms,
+                &mut self.max_latency_ms,
+                &mut self.avg_latency_ms,
+            )
+        };
+
+        *requests += 1;
+        if failed {
+            *errors += 1;
+        }
+        *total += latency_ms;
+        *max = (*max).max(latency_ms);
+        *avg = *total as f64 / *requests as f64;
+    }
+}
+
+/// Orders replicas for a session key using rendezvous (highest random weight) hashing.
+/// The first replica is the preferred one; when a replica leaves, only the sessions
+/// pinned to it move, and they move to the next replica in their own ranking.
+pub fn rank_replicas(session_key: &str, replicas: &[Uuid]) -> Vec<Uuid> {
+    let mut ranked: Vec<(u64, Uuid)> = replicas
+        .iter()
+        .map(|replica| (replica_score(session_key, replica), *replica))
+        .collect();
+    ranked.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    ranked.into_iter().map(|(_, replica)| replica).collect()
+}
+
+/// Picks the highest ranked replica which is not overloaded. When every replica is
+/// overloaded the preferred one is returned so the session keeps its locality.
+pub fn pick_replica<F>(session_key: &str, replicas: &[Uuid], is_overloaded: F) -> Option<Uuid>
+where
+    F: Fn(&Uuid) -> bool,
+{
+    let ranked = rank_replicas(session_key, replicas);
+    ranked
+        .iter()
+        .find(|replica| !is_overloaded(replica))
+        .or_else(|| ranked.first())
+        .copied()
+}
+
+fn replica_score(session_key: &str, replica: &Uuid) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    session_key.hash(&mut hasher);
+    replica.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_key_same_replica() {
+        let replicas: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        let first = pick_replica("conversation-1", &replicas, |_| false);
+        for _ in 0..10 {
+            assert_eq!(pick_replica("conversation-1", &replicas, |_| false), first);
+        }
+    }
+
+    #[test]
+    fn test_replica_left_moves_only_its_sessions() {
+        let replicas: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        let keys: Vec<String> = (0..200).map(|i| format!("session-{i}")).collect();
+        let before: Vec<Uuid> = keys
+            .iter()
+            .map(|k| pick_replica(k, &replicas, |_| false).unwrap())
+            .collect();
+
+        let left = replicas[0];
+        let remaining: Vec<Uuid> = replicas[1..].to_vec();
+        for (key, previous) in keys.iter().zip(before) {
+            let current = pick_replica(key, &remaining, |_| false).unwrap();
+            if previous != left {
+                assert_eq!(current, previous);
+            }
+        }
+    }
+
+    fn rule(weights: &[u32]) -> RouteRule {
+        RouteRule {
+            model: "chat/assistant".to_string(),
+            targets: weights
+                .iter()
+                .enumerate()
+                .map(|(i, w)| RouteTarget {
+                    kind: "quantized".to_string(),
+                    name: format!("v{i}"),
+                    weight: *w,
+                })
+                .collect(),
+            shadow: None,
+        }
+    }
+
+    #[test]
+    fn test_weighted_targets() {
+        let rule = rule(&[90, 10, 0]);
+        let mut counts = [0usize; 3];
+        for _ in 0..2000 {
+            let target = rule.choose_target(None, |_| true).unwrap();
+            let ix = rule
+                .targets
+                .iter()
+                .position(|t| t.name == target.name)
+                .unwrap();
+            counts[ix] += 1;
+        }
+        assert_eq!(counts[2], 0);
+        assert!(counts[0] > counts[1] * 4);
+
+        let target = rule.choose_target(None, |t| t.name != "v0").unwrap();
+        assert_eq!(target.name, "v1");
+        assert!(rule.choose_target(None, |_| false).is_none());
+    }
+
+    #[test]
+    fn test_validate_routes() {
+        assert!(validate_routes(&[rule(&[90, 10])]).is_ok());
+        assert!(validate_routes(&[rule(&[0])]).is_err());
+        assert!(validate_routes(&[rule(&[1]), rule(&[1])]).is_err());
+
+        let yaml = "- model: chat/assistant\n  targets:\n    - kind: quantized\n      name: v1\n  shadow:\n    kind: quantized\n    name: v2\n";
+        let rules: Vec<RouteRule> = serde_yaml::from_str(yaml).unwrap();
+        assert!(validate_routes(&rules).is_ok());
+        assert_eq!(rules[0].targets[0].weight, 1);
+    }
+
+    #[test]
+    fn test_session_target_is_stable() {
+        let rule = rule(&[50, 50]);
+        let first = rule
+            .choose_target(Some("conversation-3"), |_| true)
+            .unwrap();
+        for _ in 0..10 {
+            let target = rule
+                .choose_target(Some("conversation-3"), |_| true)
+                .unwrap();
+            assert_eq!(target.name, first.name);
+        }
+    }
+
+    #[test]
+    fn test_shadow_latency_is_kept_apart() {
+        let mut stats = RouteTargetStats::default();
+        stats.record(Duration::from_millis(100), false, false);
+        stats.record(Duration::from_millis(300), true, false);
+        stats.record(Duration::from_millis(1000), false, true);
+
+        assert_eq!((stats.requests, stats.errors), (2, 1));
+        assert_eq!(stats.max_latency_ms, 300);
+        assert_eq!(stats.avg_latency_ms, 200.0);
+        assert_eq!((stats.shadow_requests, stats.shadow_errors), (1, 0));
+        assert_eq!(stats.shadow_max_latency_ms, 1000);
+        assert_eq!(stats.shadow_avg_latency_ms, 1000.0);
+    }
+
+    #[test]
+    fn test_overloaded_replica_spills_over() {
+        let replicas: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let ranked = rank_replicas("conversation-2", &replicas);
+        let picked = pick_replica("conversation-2", &replicas, |r| *r == ranked[0]);
+        assert_eq!(picked, Some(ranked[1]));
+
+        let picked = pick_replica("conversation-2", &replicas, |_| true);
+        assert_eq!(picked, Some(ranked[0]));
+    }
+}
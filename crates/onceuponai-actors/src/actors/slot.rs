@@ -0,0 +1,119 @@
+use crate::abstractions::ModelInfo;
+use anyhow::Result;
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// Model of a worker which can be unloaded and loaded again with another spec.
+/// Requests keep their `Arc` of the model, the worker drains them before a swap.
+pub struct ModelSlot<M> {
+    model: RwLock<Option<Arc<M>>>,
+    /// Kept apart from the model, so reading it does not wait for a running request.
+    info: RwLock<Option<ModelInfo>>,
+}
+
+impl<M> ModelSlot<M> {
+    pub const fn new() -> Self {
+        ModelSlot {
+            model: RwLock::new(None),
+            info: RwLock::new(None),
+        }
+    }
+
+    /// Info of the loaded model, empty when the slot is empty.
+    pub fn info(&self) -> ModelInfo {
+        self.info
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+            .unwrap_or_default()
+    }
+
+    pub fn set_info(&self, info: ModelInfo) {
+        *self.info.write().unwrap_or_else(PoisonError::into_inner) = Some(info);
+    }
+
+    pub fn get(&self) -> Option<Arc<M>> {
+        self.model
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.get().is_some()
+    }
+
+    /// Loaded model, `load` runs when the slot is empty.
+    pub fn get_or_try_load<F>(&self, load: F) -> Result<Arc<M>>
+    where
+        F: FnOnce() -> Result<M>,
+    {
+        if let Some(model) = self.get() {
+            return Ok(model);
+        }
+
+        let mut slot = self.model.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(model) = slot.as_ref() {
+            return Ok(Arc::clone(model));
+        }
+
+        let model = Arc::new(load()?);
+        *slot = Some(Arc::clone(&model));
+        Ok(model)
+    }
+
+    /// Stores a model loaded outside of the slot, e.g. asynchronously. A model loaded
+    /// meanwhile is kept.
+    pub fn get_or_insert(&self, model: M) -> Arc<M> {
+        let mut slot = self.model.write().unwrap_or_else(PoisonError::into_inner);
+        Arc::clone(slot.get_or_insert_with(|| Arc::new(model)))
+    }
+
+    /// Empties the slot, the model is dropped with its last `Arc`.
+    pub fn unload(&self) -> Option<Arc<M>> {
+        self.info
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        self.model
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+}
+
+impl<M> Default for ModelSlot<M> {
+    fn default() -> Self {
+        ModelSlot::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_model_slot_load_and_unload() {
+        let slot: ModelSlot<String> = ModelSlot::new();
+        assert!(!slot.is_loaded());
+        assert!(slot.get_or_try_load(|| Err(anyhow!("LOAD"))).is_err());
+        assert!(!slot.is_loaded());
+
+        let model = slot.get_or_try_load(|| Ok("first".to_string())).unwrap();
+        assert_eq!(*model, "first");
+        let model = slot.get_or_try_load(|| Ok("second".to_string())).unwrap();
+        assert_eq!(*model, "first");
+        slot.set_info(ModelInfo {
+            context_length: Some(4096),
+            ..Default::default()
+        });
+        assert_eq!(slot.info().context_length, Some(4096));
+
+        let unloaded = slot.unload().unwrap();
+        assert_eq!(*unloaded, "first");
+        assert!(!slot.is_loaded());
+        assert_eq!(slot.info(), ModelInfo::default());
+        assert_eq!(*slot.get_or_insert("second".to_string()), "second");
+        assert_eq!(*slot.get_or_insert("third".to_string()), "second");
+    }
+}
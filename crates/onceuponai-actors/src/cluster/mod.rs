@@ -1,18 +1,58 @@
+pub mod security;
 use crate::{
     abstractions::{ActorKindActions, ActorMetadata, ActorObject},
     actors::{
         main_actor::{MainActor, MainActorSpec},
-        ActorBuilder, WorkerActor,
+        ActorBuilder, Drain, ForwardEvent, HostActors, WorkerActor,
     },
+    telemetry,
 };
 use actix::prelude::*;
 use actix_telepathy::{Cluster, RemoteActor};
 use anyhow::{anyhow, Result};
+use futures::future::{join_all, try_join_all};
 use onceuponai_core::{
     common::{decode_and_deserialize, ResultExt, SerializationType},
     config::read_config_str,
+    events::{self, Event, EventKind, ModelLoadState},
 };
 use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{broadcast, Notify};
+
+/// Worker configuration, one actor object or several sharing the worker process.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum WorkerConfig<T> {
+    Hosted { actors: Vec<T> },
+    Single(T),
+}
+
+impl<T> WorkerConfig<T> {
+    pub fn actors(self) -> Vec<T> {
+        match self {
+            WorkerConfig::Hosted { actors } => actors,
+            WorkerConfig::Single(actor) => vec![actor],
+        }
+    }
+
+    /// Actors of the worker, one per `kind`. The models are loaded into a slot per kind, so
+    /// hosted actors of the same kind would share one model.
+    pub fn hosted_actors<F: Fn(&T) -> String>(self, kind: F) -> Result<Vec<T>> {
+        let actors = self.actors();
+        let mut kinds = HashSet::new();
+        for actor in &actors {
+            let kind = kind(actor);
+            if !kinds.insert(kind.clone()) {
+                return Err(anyhow!("WORKER HOSTS MORE THAN ONE ACTOR OF KIND {kind}"));
+            }
+        }
+        Ok(actors)
+    }
+}
 
 pub fn start_main_actor(main_actor: MainActor) -> Result<Option<(MainActorSpec, Addr<MainActor>)>> {
     println!("{}", LOGO);
@@ -24,16 +64,72 @@ pub fn start_main_actor(main_actor: MainActor) -> Result<Option<(MainActorSpec,
 
 pub async fn start_worker_actor(
     worker_actor: WorkerActor,
+) -> Result<Option<(MainActorSpec, Addr<MainActor>)>> {
+    start_worker_actors(vec![worker_actor]).await
+}
+
+/// Starts the workers of one process, the process leaves the cluster once all of them drained.
+pub async fn start_worker_actors(
+    worker_actors: Vec<WorkerActor>,
+) -> Result<Option<(MainActorSpec, Addr<MainActor>)>> {
+    run_worker_actors(worker_actors, events::subscribe()).await
+}
+
+/// Runs the workers, the primary one forwards the `events` of the process to the main actor.
+async fn run_worker_actors(
+    worker_actors: Vec<WorkerActor>,
+    mut events: broadcast::Receiver<Event>,
 ) -> Result<Option<(MainActorSpec, Addr<MainActor>)>> {
     // println!("{}", LOGO);
     // env_logger::init();
-    let _ = Cluster::new(worker_actor.own_addr, vec![worker_actor.seed_addr]);
-    worker_actor.start();
-    tokio::signal::ctrl_c().await?;
-    println!("Ctrl-C received, shutting down");
+    let primary = worker_actors
+        .first()
+        .ok_or(anyhow!("WORKER CONFIGURATION WITHOUT ACTORS"))?;
+    let _ = Cluster::new(primary.own_addr, vec![primary.seed_addr]);
+    let drained: Vec<Arc<Notify>> = worker_actors
+        .iter()
+        .map(|worker_actor| Arc::clone(&worker_actor.drained))
+        .collect();
+    let addrs: Vec<Addr<WorkerActor>> = worker_actors.into_iter().map(Actor::start).collect();
+    if let Some((primary, hosted)) = addrs.split_first() {
+        primary.do_send(HostActors(hosted.to_vec()));
+        let primary = primary.clone();
+        actix_rt::spawn(async move {
+            while let Some(event) = events::recv(&mut events).await {
+                primary.do_send(ForwardEvent(event));
+            }
+        });
+    }
+
+    tokio::select! {
+        signal = shutdown_signal() => {
+            signal?;
+            println!("Shutdown signal received, draining");
+            try_join_all(addrs.iter().map(|addr| addr.send(Drain { grace_period: None }))).await?;
+        }
+        _ = join_all(drained.iter().map(|d| d.notified())) => {}
+    }
+    println!("Worker drained, shutting down");
+    telemetry::shutdown();
     Ok(None)
 }
 
+/// Resolves on Ctrl-C, and on SIGTERM where available.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            ctrl_c = tokio::signal::ctrl_c() => ctrl_c?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
 pub async fn start_main_cluster(
     metadata: ActorMetadata,
     spec: MainActorSpec,
@@ -53,7 +149,7 @@ pub async fn start_worker_cluster<T: ActorKindActions + DeserializeOwned + 'stat
     json: Option<&String>,
     metadata_yaml: Option<&String>,
 ) -> Result<()> {
-    let actor_kind: T = if let Some(f) = file {
+    let config: WorkerConfig<T> = if let Some(f) = file {
         let configuration_str = read_config_str(f, Some(true)).await.map_anyhow_err()?;
         serde_yaml::from_str(&configuration_str)?
     } else if let Some(t) = toml {
@@ -67,23 +163,72 @@ pub async fn start_worker_cluster<T: ActorKindActions + DeserializeOwned + 'stat
         return Err(anyhow!("Wrong worker actor configuration"));
     };
 
-    let metadata = if let Some(m) = metadata_yaml {
+    // Subscribed before the models load, so their events reach the main actor once connected.
+    let events = events::subscribe();
+    let actor_kinds = config.hosted_actors(|actor_kind| actor_kind.actor().kind())?;
+    let first = actor_kinds
+        .first()
+        .ok_or(anyhow!("WORKER CONFIGURATION WITHOUT ACTORS"))?;
+    let metadata: ActorMetadata = if let Some(m) = metadata_yaml {
         decode_and_deserialize(m, SerializationType::YAML)?
     } else {
-        actor_kind.metadata()
+        first.metadata()
     };
 
-    actor_kind.actor().start().await?;
-    let worker_actor = ActorBuilder::build_worker(metadata, actor_kind)?;
-    start_worker_actor(worker_actor).await?;
+    // Hosted actors share the host, seed and limits of the process, and keep their names.
+    let hosted = actor_kinds.len() > 1;
+    let mut worker_actors = Vec::with_capacity(actor_kinds.len());
+    for (index, actor_kind) in actor_kinds.into_iter().enumerate() {
+        let mut actor_metadata = metadata.clone();
+        if hosted {
+            actor_metadata.name = actor_kind.metadata().name;
+        }
+
+        let model = format!("{}/{}", actor_kind.actor().kind(), actor_metadata.name);
+        events::publish(EventKind::ModelLoading {
+            model: model.clone(),
+            state: ModelLoadState::Started,
+            seconds: None,
+            error: None,
+        });
+        let started = Instant::now();
+        if let Err(e) = actor_kind.actor().start().await {
+            events::publish(EventKind::ModelLoading {
+                model,
+                state: ModelLoadState::Failed,
+                seconds: None,
+                error: Some(format!("{e:?}")),
+            });
+            return Err(e);
+        }
+        events::publish(EventKind::ModelLoading {
+            model,
+            state: ModelLoadState::Loaded,
+            seconds: Some(started.elapsed().as_secs_f64()),
+            error: None,
+        });
+        let mut worker_actor = ActorBuilder::build_hosted_worker(
+            actor_metadata,
+            actor_kind,
+            &WorkerActor::hosted_actor_id(index),
+        )?;
+        worker_actor.metrics.model_load_seconds = Some(started.elapsed().as_secs_f64());
+        worker_actors.push(worker_actor);
+    }
+
+    run_worker_actors(worker_actors, events).await?;
     Ok(())
 }
 
 pub async fn init_actor<T: ActorKindActions + DeserializeOwned>(
     json: Option<&String>,
 ) -> Result<()> {
-    let actor_kind: T = decode_and_deserialize(json.expect("json"), SerializationType::JSON)?;
-    actor_kind.actor().init().await
+    let config: WorkerConfig<T> =
+        decode_and_deserialize(json.expect("json"), SerializationType::JSON)?;
+    for actor_kind in config.actors() {
+        actor_kind.actor().init().await?;
+    }
+    Ok(())
 }
 
 const LOGO: &str = r#"
@@ -94,3 +239,60 @@ const LOGO: &str = r#"
 ╚██████╔╝██║ ╚████║╚██████╗███████╗    ╚██████╔╝██║     ╚██████╔╝██║ ╚████║    ██╗██╗██╗    ██║  ██║██║
  ╚═════╝ ╚═╝  ╚═══╝ ╚═════╝╚══════╝     ╚═════╝ ╚═╝      ╚═════╝ ╚═╝  ╚═══╝    ╚═╝╚═╝╚═╝    ╚═╝  ╚═╝╚═╝
 "#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    #[serde(tag = "kind", rename_all = "camelCase")]
+    enum TestKind {
+        E5 { name: String },
+        Quantized { name: String },
+    }
+
+    #[test]
+    fn test_worker_config_single_or_hosted() {
+        let single: WorkerConfig<TestKind> = serde_yaml::from_str("kind: e5\nname: embed").unwrap();
+        assert_eq!(
+            single.actors(),
+            vec![TestKind::E5 {
+                name: "embed".to_string()
+            }]
+        );
+
+        let hosted: WorkerConfig<TestKind> = serde_yaml::from_str(
+            "actors:\n- kind: e5\n  name: embed\n- kind: quantized\n  name: chat",
+        )
+        .unwrap();
+        assert_eq!(
+            hosted.actors(),
+            vec![
+                TestKind::E5 {
+                    name: "embed".to_string()
+                },
+                TestKind::Quantized {
+                    name: "chat".to_string()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_worker_config_rejects_duplicate_kinds() {
+        let kind = |actor: &TestKind| match actor {
+            TestKind::E5 { .. } => "e5".to_string(),
+            TestKind::Quantized { .. } => "quantized".to_string(),
+        };
+        let hosted: WorkerConfig<TestKind> =
+            serde_yaml::from_str("actors:\n- kind: e5\n  name: embed\n- kind: e5\n  name: search")
+                .unwrap();
+        assert!(hosted.hosted_actors(kind).is_err());
+
+        let hosted: WorkerConfig<TestKind> = serde_yaml::from_str(
+            "actors:\n- kind: e5\n  name: embed\n- kind: quantized\n  name: chat",
+        )
+        .unwrap();
+        assert_eq!(hosted.hosted_actors(kind).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,425 @@
+use crate::abstractions::{ActorInvokeRequest, ActorInvokeResponse, ActorMetadata};
+use crate::actors::{response_proof_parts, ActorStartInvokeRequest};
+use crate::metrics;
+use actix_telepathy::prelude::*;
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use log::error;
+use once_cell::sync::{Lazy, OnceCell};
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{PoisonError, RwLock};
+
+/// Environment variable read when the metadata does not set `cluster_secret`.
+pub const CLUSTER_SECRET_ENV: &str = "ONCEUPONAI_CLUSTER_SECRET";
+
+/// Proofs older than this, or ahead of the clock of the receiver by more, are refused. The
+/// clocks of the nodes must agree within this window.
+pub const PROOF_MAX_AGE_MS: i64 = 60_000;
+
+type HmacSha256 = Hmac<Sha256>;
+
+static CLUSTER_SECURITY: OnceCell<Option<ClusterSecurity>> = OnceCell::new();
+/// Challenge of the main actor of the cluster, the invoke responses are signed for it.
+static MAIN_CHALLENGE: Lazy<RwLock<Vec<u8>>> = Lazy::new(|| RwLock::new(vec![]));
+
+/// Shared-secret membership of the actor cluster. Nodes prove the knowledge of the secret
+/// with an HMAC over a challenge of the main actor, the message and a nonce which is accepted
+/// once. Invoke payloads are optionally sealed with ChaCha20-Poly1305.
+#[derive(Clone)]
+pub struct ClusterSecurity {
+    secret: Vec<u8>,
+    cipher: Option<ChaCha20Poly1305>,
+}
+
+/// Encrypted payload, the nonce is unique per message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sealed {
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+/// HMAC of a message together with the time it was created at and a random nonce.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Proof {
+    /// Unix time in milliseconds.
+    pub timestamp: i64,
+    pub nonce: Vec<u8>,
+    pub mac: Vec<u8>,
+}
+
+/// Nonces of the proofs an actor accepted. A proof is accepted once and only within
+/// `PROOF_MAX_AGE_MS` of its creation, so older nonces are forgotten.
+#[derive(Debug, Default)]
+pub struct SeenNonces {
+    nonces: HashMap<Vec<u8>, i64>,
+    pruned_at: i64,
+}
+
+impl SeenNonces {
+    fn accept(&mut self, proof: &Proof, now: i64) -> bool {
+        if (now - proof.timestamp).abs() > PROOF_MAX_AGE_MS {
+            return false;
+        }
+
+        if now - self.pruned_at > PROOF_MAX_AGE_MS {
+            self.nonces
+                .retain(|_, timestamp| now - *timestamp <= PROOF_MAX_AGE_MS);
+            self.pruned_at = now;
+        }
+        self.nonces
+            .insert(proof.nonce.clone(), proof.timestamp)
+            .is_none()
+    }
+}
+
+/// Message payload with the proof of its sender. The payload is the JSON of the message, or
+/// of its `Sealed` form when the cluster encryption is enabled.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Signed {
+    pub payload: Vec<u8>,
+    pub proof: Proof,
+}
+
+/// `ActorInvokeRequest` of the main actor, signed for the worker it is sent to.
+#[derive(RemoteMessage, Serialize, Deserialize, Debug, Clone)]
+#[with_source(source)]
+pub struct SignedInvokeRequest {
+    pub source: RemoteAddr,
+    pub signed: Signed,
+}
+
+/// `ActorInvokeResponse` signed for the actor it is sent to.
+#[derive(RemoteMessage, Serialize, Deserialize, Debug, Clone)]
+pub struct SignedInvokeResponse {
+    pub signed: Signed,
+}
+
+/// `ActorStartInvokeRequest` of an actor client, signed for the main actor.
+#[derive(RemoteMessage, Serialize, Deserialize, Debug, Clone)]
+pub struct SignedStartInvokeRequest {
+    pub signed: Signed,
+}
+
+impl ClusterSecurity {
+    pub fn new(secret: &str, encrypt: bool) -> Self {
+        let cipher = encrypt.then(|| {
+            let key = Sha256::new()
+                .chain_update(b"onceuponai-cluster-encryption")
+                .chain_update(secret.as_bytes())
+                .finalize();
+            ChaCha20Poly1305::new(Key::from_slice(&key))
+        });
+
+        ClusterSecurity {
+            secret: secret.as_bytes().to_vec(),
+            cipher,
+        }
+    }
+
+    pub fn from_metadata(metadata: &ActorMetadata) -> Result<Option<Self>> {
+        let secret = metadata
+            .cluster_secret
+            .clone()
+            .or_else(|| std::env::var(CLUSTER_SECRET_ENV).ok())
+            .filter(|s| !s.is_empty());
+        let encrypt = metadata.cluster_encryption.unwrap_or(false);
+
+        match secret {
+            Some(secret) => Ok(Some(ClusterSecurity::new(&secret, encrypt))),
+            None if encrypt => Err(anyhow!(
+                "CLUSTER ENCRYPTION REQUIRES cluster_secret OR {CLUSTER_SECRET_ENV}"
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the security of this process, the first call wins.
+    pub fn init(metadata: &ActorMetadata) -> Result<()> {
+        let security = ClusterSecurity::from_metadata(metadata)?;
+        let _ = CLUSTER_SECURITY.set(security);
+        Ok(())
+    }
+
+    /// Security of this process, `None` when the cluster is open.
+    pub fn get() -> Option<&'static ClusterSecurity> {
+        CLUSTER_SECURITY.get()?.as_ref()
+    }
+
+    /// Sets the challenge of the main actor, known to the workers once it requested their info.
+    pub fn set_main_challenge(challenge: &[u8]) {
+        *MAIN_CHALLENGE
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = challenge.to_vec();
+    }
+
+    fn main_challenge() -> Vec<u8> {
+        MAIN_CHALLENGE
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn challenge() -> Vec<u8> {
+        let mut challenge = vec![0u8; 32];
+        rand::thread_rng().fill_bytes(&mut challenge);
+        challenge
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> HmacSha256 {
+        let mut mac =
+            <HmacSha256 as Mac>::new_from_slice(&self.secret).expect("HMAC ACCEPTS ANY KEY LENGTH");
+        for part in parts {
+            mac.update(&(part.len() as u64).to_be_bytes());
+            mac.update(part);
+        }
+        mac
+    }
+
+    fn sign(&self, parts: &[&[u8]]) -> Vec<u8> {
+        self.mac(parts).finalize().into_bytes().to_vec()
+    }
+
+    /// Constant time check of a MAC created by `sign`.
+    fn verify(&self, parts: &[&[u8]], mac: Option<&[u8]>) -> bool {
+        match mac {
+            Some(mac) => self.mac(parts).verify_slice(mac).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Signs `parts` with a new nonce.
+    pub fn prove(&self, parts: &[&[u8]]) -> Proof {
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let mut nonce = vec![0u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mac = self.sign(&[parts, &[&timestamp.to_be_bytes(), &nonce]].concat());
+        Proof {
+            timestamp,
+            nonce,
+            mac,
+        }
+    }
+
+    /// Checks a proof created by `prove` and records its nonce, so a replayed or expired
+    /// proof is refused.
+    pub fn verify_once(
+        &self,
+        parts: &[&[u8]],
+        proof: Option<&Proof>,
+        seen: &mut SeenNonces,
+    ) -> bool {
+        let Some(proof) = proof else {
+            return false;
+        };
+        let timestamp = proof.timestamp.to_be_bytes();
+        self.verify(
+            &[parts, &[&timestamp, &proof.nonce]].concat(),
+            Some(&proof.mac),
+        ) && seen.accept(proof, chrono::Utc::now().timestamp_millis())
+    }
+
+    /// Signs a message for `parts`, encrypted when the cluster encryption is enabled.
+    pub fn sign_payload<T: Serialize>(&self, parts: &[&[u8]], value: &T) -> Result<Signed> {
+        let payload = match self.cipher {
+            Some(_) => serde_json::to_vec(&self.seal(value)?)?,
+            None => serde_json::to_vec(value)?,
+        };
+        let proof = self.prove(&[parts, &[&payload]].concat());
+        Ok(Signed { payload, proof })
+    }
+
+    /// Opens a message of `sign_payload`, refusing forged, expired and replayed ones.
+    pub fn open_payload<T: DeserializeOwned>(
+        &self,
+        parts: &[&[u8]],
+        signed: &Signed,
+        seen: &mut SeenNonces,
+    ) -> Result<T> {
+        if !self.verify_once(
+            &[parts, &[&signed.payload]].concat(),
+            Some(&signed.proof),
+            seen,
+        ) {
+            return Err(anyhow!("INVALID OR REPLAYED PROOF"));
+        }
+
+        match self.cipher {
+            Some(_) => self.open(&serde_json::from_slice(&signed.payload)?),
+            None => Ok(serde_json::from_slice(&signed.payload)?),
+        }
+    }
+
+    pub fn seal<T: Serialize>(&self, value: &T) -> Result<Sealed> {
+        let cipher = self.cipher.as_ref().ok_or(anyhow!("ENCRYPTION DISABLED"))?;
+        let mut nonce = vec![0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let plaintext = serde_json::to_vec(value)?;
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|e| anyhow!("SEAL: {e}"))?;
+        Ok(Sealed { nonce, ciphertext })
+    }
+
+    pub fn open<T: DeserializeOwned>(&self, sealed: &Sealed) -> Result<T> {
+        let cipher = self.cipher.as_ref().ok_or(anyhow!("ENCRYPTION DISABLED"))?;
+        if sealed.nonce.len() != 12 {
+            return Err(anyhow!("OPEN: INVALID NONCE"));
+        }
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(&sealed.nonce),
+                sealed.ciphertext.as_slice(),
+            )
+            .map_err(|e| anyhow!("OPEN: {e}"))?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+}
+
+/// Sends invoke messages signed for `parts` when the cluster has a secret, and sealed when
+/// the cluster encryption is enabled.
+pub trait ClusterSend {
+    fn send_request(&self, request: ActorInvokeRequest, parts: &[&[u8]]);
+    /// Signs for the challenge of the main actor and the id of the receiving actor.
+    fn send_response(&self, response: ActorInvokeResponse);
+    fn send_start_request(&self, request: ActorStartInvokeRequest, parts: &[&[u8]]);
+}
+
+impl ClusterSend for RemoteAddr {
+    fn send_request(&self, request: ActorInvokeRequest, parts: &[&[u8]]) {
+        match ClusterSecurity::get() {
+            Some(security) => match security.sign_payload(parts, &request) {
+                Ok(signed) => self.do_send(SignedInvokeRequest {
+                    source: request.source,
+                    signed,
+                }),
+                Err(e) => error!("TASK {}: {e}", request.task_id),
+            },
+            None => self.do_send(request),
+        }
+    }
+
+    fn send_response(&self, response: ActorInvokeResponse) {
+        metrics::record_sent(&response);
+        match ClusterSecurity::get() {
+            Some(security) => {
+                let challenge = ClusterSecurity::main_challenge();
+                let parts = response_proof_parts(&challenge, &self.id);
+                match security.sign_payload(&parts, &response) {
+                    Ok(signed) => self.do_send(SignedInvokeResponse { signed }),
+                    Err(e) => error!("TASK {}: {e}", response.task_id()),
+                }
+            }
+            None => self.do_send(response),
+        }
+    }
+
+    fn send_start_request(&self, request: ActorStartInvokeRequest, parts: &[&[u8]]) {
+        match ClusterSecurity::get() {
+            Some(security) => match security.sign_payload(parts, &request) {
+                Ok(signed) => self.do_send(SignedStartInvokeRequest { signed }),
+                Err(e) => error!("TASK {}: {e}", request.task_id),
+            },
+            None => self.do_send(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let security = ClusterSecurity::new("secret", false);
+        let challenge = ClusterSecurity::challenge();
+        let proof = security.sign(&[b"worker", &challenge]);
+
+        assert!(security.verify(&[b"worker", &challenge], Some(&proof)));
+        assert!(!security.verify(&[b"main", &challenge], Some(&proof)));
+        assert!(!security.verify(&[b"worker", &challenge], None));
+        assert!(
+            !ClusterSecurity::new("other", false).verify(&[b"worker", &challenge], Some(&proof))
+        );
+    }
+
+    #[test]
+    fn test_proofs_are_accepted_once() {
+        let security = ClusterSecurity::new("secret", false);
+        let challenge = ClusterSecurity::challenge();
+        let mut seen = SeenNonces::default();
+        let proof = security.prove(&[b"drain", &challenge]);
+
+        assert!(!security.verify_once(&[b"model", &challenge], Some(&proof), &mut seen));
+        assert!(security.verify_once(&[b"drain", &challenge], Some(&proof), &mut seen));
+        assert!(!security.verify_once(&[b"drain", &challenge], Some(&proof), &mut seen));
+        assert!(security.verify_once(
+            &[b"drain", &challenge],
+            Some(&security.prove(&[b"drain", &challenge])),
+            &mut seen
+        ));
+
+        let mut expired = security.prove(&[b"drain", &challenge]);
+        expired.timestamp -= PROOF_MAX_AGE_MS + 1;
+        expired.mac = security.sign(&[
+            b"drain",
+            &challenge,
+            &expired.timestamp.to_be_bytes(),
+            &expired.nonce,
+        ]);
+        assert!(!security.verify_once(&[b"drain", &challenge], Some(&expired), &mut seen));
+    }
+
+    #[test]
+    fn test_signed_payloads() {
+        for encrypt in [false, true] {
+            let security = ClusterSecurity::new("secret", encrypt);
+            let mut seen = SeenNonces::default();
+            let signed = security
+                .sign_payload(&[b"invoke"], &vec!["prompt".to_string()])
+                .unwrap();
+            assert_eq!(encrypt, !signed.payload.windows(6).any(|w| w == b"prompt"));
+
+            let opened: Vec<String> = security
+                .open_payload(&[b"invoke"], &signed, &mut seen)
+                .unwrap();
+            assert_eq!(opened, vec!["prompt".to_string()]);
+            assert!(security
+                .open_payload::<Vec<String>>(&[b"invoke"], &signed, &mut seen)
+                .is_err());
+
+            let mut tampered = security.sign_payload(&[b"invoke"], &1).unwrap();
+            tampered.payload = security.sign_payload(&[b"invoke"], &2).unwrap().payload;
+            assert!(security
+                .open_payload::<i32>(&[b"invoke"], &tampered, &mut seen)
+                .is_err());
+            let other = security.sign_payload(&[b"start"], &1).unwrap();
+            assert!(security
+                .open_payload::<i32>(&[b"invoke"], &other, &mut seen)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let security = ClusterSecurity::new("secret", true);
+        let sealed = security.seal(&vec!["prompt".to_string()]).unwrap();
+        assert!(!sealed.ciphertext.windows(6).any(|w| w == b"prompt"));
+
+        let opened: Vec<String> = security.open(&sealed).unwrap();
+        assert_eq!(opened, vec!["prompt".to_string()]);
+
+        let other = ClusterSecurity::new("other", true);
+        assert!(other.open::<Vec<String>>(&sealed).is_err());
+        assert!(ClusterSecurity::new("secret", false).seal(&1).is_err());
+    }
+}
@@ -2,3 +2,7 @@ pub mod abstractions;
 pub mod actors;
 pub mod cluster;
 pub mod initialize;
+pub mod metrics;
+pub mod resources;
+pub mod supervisor;
+pub mod telemetry;
@@ -0,0 +1,310 @@
+use crate::abstractions::ActorInvokeResponse;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Metrics of this process, rendered by the server in the Prometheus text format.
+pub static METRICS: Lazy<Mutex<Metrics>> = Lazy::new(|| Mutex::new(Metrics::default()));
+
+/// Chunks streamed by the tasks of the workers of this process, one per generated token.
+static STREAMED_CHUNKS: Lazy<Mutex<HashMap<Uuid, u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+pub const TOKENS_PER_SECOND_BUCKETS: &[f64] =
+    &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0];
+
+/// Records into the process metrics.
+pub fn record<F: FnOnce(&mut Metrics)>(f: F) {
+    f(&mut METRICS.lock().unwrap_or_else(PoisonError::into_inner));
+}
+
+/// Copy of the process metrics, e.g. to add gauges computed at scrape time.
+pub fn snapshot() -> Metrics {
+    METRICS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Starts counting the chunks a worker task streams.
+pub fn begin_task(task_id: Uuid) {
+    STREAMED_CHUNKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(task_id, 0);
+}
+
+/// Counts a streamed chunk of a task started with `begin_task`.
+pub fn record_sent(response: &ActorInvokeResponse) {
+    if let ActorInvokeResponse::Success(result) = response {
+        if result.stream {
+            let mut chunks = STREAMED_CHUNKS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if let Some(count) = chunks.get_mut(&result.task_id) {
+                *count += 1;
+            }
+        }
+    }
+}
+
+/// Chunks streamed by a finished task.
+pub fn end_task(task_id: &Uuid) -> u64 {
+    STREAMED_CHUNKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .remove(task_id)
+        .unwrap_or(0)
+}
+
+/// Counters a worker reports to the main actor.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WorkerMetrics {
+    /// Seconds the last model load took.
+    pub model_load_seconds: Option<f64>,
+    pub tasks: u64,
+    pub failed_tasks: u64,
+    /// Seconds spent running tasks, queueing excluded.
+    pub busy_seconds: f64,
+    /// Streamed chunks, one per generated token.
+    pub generated_tokens: u64,
+    /// Seconds spent running streamed tasks.
+    pub generation_seconds: f64,
+}
+
+impl WorkerMetrics {
+    pub fn record_task(&mut self, elapsed: Duration, failed: bool, tokens: u64) {
+        self.tasks += 1;
+        if failed {
+            self.failed_tasks += 1;
+        }
+        self.busy_seconds += elapsed.as_secs_f64();
+        if tokens > 0 {
+            self.generated_tokens += tokens;
+            self.generation_seconds += elapsed.as_secs_f64();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(buckets: &'static [f64]) -> Self {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(index) = self.buckets.iter().position(|b| value <= *b) {
+            self.counts[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Series {
+    Value(f64),
+    Histogram(Histogram),
+}
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Debug, Clone)]
+struct Family {
+    help: &'static str,
+    kind: MetricKind,
+    series: BTreeMap<Labels, Series>,
+}
+
+/// Counters, gauges and histograms by name and labels.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    families: BTreeMap<String, Family>,
+}
+
+impl Metrics {
+    fn series(
+        &mut self,
+        name: &str,
+        help: &'static str,
+        kind: MetricKind,
+        labels: &[(&str, &str)],
+        init: impl FnOnce() -> Series,
+    ) -> &mut Series {
+        let labels: Labels = labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        self.families
+            .entry(name.to_string())
+            .or_insert_with(|| Family {
+                help,
+                kind,
+                series: BTreeMap::new(),
+            })
+            .series
+            .entry(labels)
+            .or_insert_with(init)
+    }
+
+    pub fn counter(&mut self, name: &str, help: &'static str, labels: &[(&str, &str)], by: f64) {
+        if let Series::Value(value) = self.series(name, help, MetricKind::Counter, labels, || {
+            Series::Value(0.0)
+        }) {
+            *value += by;
+        }
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &'static str, labels: &[(&str, &str)], value: f64) {
+        if let Series::Value(gauge) =
+            self.series(name, help, MetricKind::Gauge, labels, || Series::Value(0.0))
+        {
+            *gauge = value;
+        }
+    }
+
+    pub fn observe(
+        &mut self,
+        name: &str,
+        help: &'static str,
+        buckets: &'static [f64],
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        if let Series::Histogram(histogram) =
+            self.series(name, help, MetricKind::Histogram, labels, || {
+                Series::Histogram(Histogram::new(buckets))
+            })
+        {
+            histogram.observe(value);
+        }
+    }
+
+    /// Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, family) in &self.families {
+            let _ = writeln!(out, "# HELP {name} {}", family.help);
+            let _ = writeln!(out, "# TYPE {name} {}", family.kind.as_str());
+            for (labels, series) in &family.series {
+                match series {
+                    Series::Value(value) => {
+                        let _ = writeln!(out, "{name}{} {value}", format_labels(labels, None));
+                    }
+                    Series::Histogram(histogram) => {
+                        let mut cumulative = 0;
+                        for (bucket, count) in histogram.buckets.iter().zip(&histogram.counts) {
+                            cumulative += count;
+                            let le = bucket.to_string();
+                            let _ = writeln!(
+                                out,
+                                "{name}_bucket{} {cumulative}",
+                                format_labels(labels, Some(&le))
+                            );
+                        }
+                        let _ = writeln!(
+                            out,
+                            "{name}_bucket{} {}",
+                            format_labels(labels, Some("+Inf")),
+                            histogram.count
+                        );
+                        let labels = format_labels(labels, None);
+                        let _ = writeln!(out, "{name}_sum{labels} {}", histogram.sum);
+                        let _ = writeln!(out, "{name}_count{labels} {}", histogram.count);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{k}=\"{}\"", escape_label(v)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{le}\""));
+    }
+
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let mut metrics = Metrics::default();
+        metrics.counter("requests_total", "Requests.", &[("model", "e5/\"a\"")], 1.0);
+        metrics.counter("requests_total", "Requests.", &[("model", "e5/\"a\"")], 2.0);
+        metrics.gauge("invoke_tasks", "Tasks.", &[], 4.0);
+        metrics.observe("latency_seconds", "Latency.", &[0.1, 1.0], &[], 0.5);
+        metrics.observe("latency_seconds", "Latency.", &[0.1, 1.0], &[], 5.0);
+
+        assert_eq!(
+            metrics.render(),
+            "# HELP invoke_tasks Tasks.\n\
+             # TYPE invoke_tasks gauge\n\
+             invoke_tasks 4\n\
+             # HELP latency_seconds Latency.\n\
+             # TYPE latency_seconds histogram\n\
+             latency_seconds_bucket{le=\"0.1\"} 0\n\
+             latency_seconds_bucket{le=\"1\"} 1\n\
+             latency_seconds_bucket{le=\"+Inf\"} 2\n\
+             latency_seconds_sum 5.5\n\
+             latency_seconds_count 2\n\
+             # HELP requests_total Requests.\n\
+             # TYPE requests_total counter\n\
+             requests_total{model=\"e5/\\\"a\\\"\"} 3\n"
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// GPU memory is read with `nvidia-smi`, so it is reused for a while.
+const GPU_MEMORY_TTL: Duration = Duration::from_secs(10);
+
+type GpuMemory = Option<(u64, u64)>;
+
+/// Last read memory of a CUDA device.
+#[derive(Default)]
+struct GpuMemoryRead {
+    read: Option<Instant>,
+    memory: GpuMemory,
+    refreshing: bool,
+}
+
+static GPU_MEMORY: Lazy<Mutex<HashMap<u32, GpuMemoryRead>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Resident memory of the process in bytes, only known on Linux.
+pub fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    parse_vm_rss(&status)
+}
+
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Used and total memory of a CUDA device in bytes, `None` without `nvidia-smi`. Returns the
+/// last read, a stale one is refreshed on a background thread so callers never wait for
+/// `nvidia-smi`, and `None` until the first read finished.
+pub fn gpu_memory(ordinal: u32) -> GpuMemory {
+    let mut cache = GPU_MEMORY.lock().unwrap_or_else(PoisonError::into_inner);
+    let entry = cache.entry(ordinal).or_default();
+    let stale = match entry.read {
+        Some(read) => read.elapsed() >= GPU_MEMORY_TTL,
+        None => true,
+    };
+    if stale && !entry.refreshing {
+        entry.refreshing = true;
+        std::thread::spawn(move || {
+            let memory = query_gpu_memory(ordinal);
+            let mut cache = GPU_MEMORY.lock().unwrap_or_else(PoisonError::into_inner);
+            cache.insert(
+                ordinal,
+                GpuMemoryRead {
+                    read: Some(Instant::now()),
+                    memory,
+                    refreshing: false,
+                },
+            );
+        });
+    }
+    entry.memory
+}
+
+fn query_gpu_memory(ordinal: u32) -> GpuMemory {
+    let output = Command::new("nvidia-smi")
+        .args([
+            "--query-gpu=memory.used,memory.total",
+            "--format=csv,noheader,nounits",
+            &format!("--id={ordinal}"),
+        ])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_gpu_memory(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parses `used, total` in MiB.
+fn parse_gpu_memory(output: &str) -> GpuMemory {
+    let (used, total) = output.lines().next()?.split_once(',')?;
+    let used: u64 = used.trim().parse().ok()?;
+    let total: u64 = total.trim().parse().ok()?;
+    Some((used * 1024 * 1024, total * 1024 * 1024))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory() {
+        let status = "Name:\tworker\nVmPeak:\t  20000 kB\nVmRSS:\t   1536 kB\n";
+        assert_eq!(parse_vm_rss(status), Some(1536 * 1024));
+        assert_eq!(parse_vm_rss("Name:\tworker\n"), None);
+
+        assert_eq!(
+            parse_gpu_memory("2048, 24576\n"),
+            Some((2048 * 1024 * 1024, 24576 * 1024 * 1024))
+        );
+        assert_eq!(parse_gpu_memory("[N/A], [N/A]"), None);
+    }
+}
@@ -0,0 +1,393 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use log::warn;
+use onceuponai_core::notifications::{Notification, NotificationLevel};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use uuid::Uuid;
+
+/// Worker child process watched by a `Supervisor`.
+#[async_trait]
+pub trait WorkerProcess: Send {
+    fn pid(&self) -> Option<u32>;
+    /// Waits until the process exits and returns its exit code.
+    async fn wait(&mut self) -> Option<i32>;
+    async fn kill(&mut self) -> Result<()>;
+}
+
+#[async_trait]
+impl WorkerProcess for tokio::process::Child {
+    fn pid(&self) -> Option<u32> {
+        self.id()
+    }
+
+    async fn wait(&mut self) -> Option<i32> {
+        tokio::process::Child::wait(self)
+            .await
+            .ok()
+            .and_then(|s| s.code())
+    }
+
+    async fn kill(&mut self) -> Result<()> {
+        Ok(tokio::process::Child::kill(self).await?)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// Restarts allowed within `window`, the worker is given up afterwards.
+    pub max_restarts: u32,
+    pub window: Duration,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: 5,
+            window: Duration::from_secs(600),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Sliding window of restarts, the backoff doubles with every restart in the window.
+#[derive(Debug)]
+pub struct RestartBudget {
+    policy: RestartPolicy,
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartBudget {
+    pub fn new(policy: RestartPolicy) -> Self {
+        RestartBudget {
+            policy,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Records a restart at `now` and returns its backoff, `None` when the budget is exhausted.
+    pub fn next_backoff(&mut self, now: Instant) -> Option<Duration> {
+        while let Some(restart) = self.restarts.front() {
+            if now.duration_since(*restart) < self.policy.window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+
+        let restarts = self.restarts.len() as u32;
+        if restarts >= self.policy.max_restarts {
+            return None;
+        }
+
+        self.restarts.push_back(now);
+        let backoff = self
+            .policy
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(restarts));
+        Some(backoff.min(self.policy.max_backoff))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SupervisorEvent {
+    /// The worker exited, it is restarted after `restart_in`. `None` after a clean exit or
+    /// when the restart budget is exhausted.
+    Exited {
+        code: Option<i32>,
+        restart_in: Option<Duration>,
+    },
+    Restarted {
+        pid: Option<u32>,
+        restarts: u32,
+    },
+    /// The worker was killed on request.
+    Stopped,
+}
+
+impl SupervisorEvent {
+    pub fn notification(&self, name: &str, sidecar_id: &Uuid) -> (String, NotificationLevel) {
+        match self {
+            SupervisorEvent::Exited {
+                code,
+                restart_in: Some(restart_in),
+            } => (
+                format!(
+                    "WORKER {name} ({sidecar_id}) EXITED WITH {code:?}, RESTARTING IN {}s",
+                    restart_in.as_secs_f32()
+                ),
+                NotificationLevel::Warn,
+            ),
+            SupervisorEvent::Exited {
+                code: Some(0),
+                restart_in: None,
+            } => (
+                format!("WORKER {name} ({sidecar_id}) EXITED"),
+                NotificationLevel::Info,
+            ),
+            SupervisorEvent::Exited {
+                code,
+                restart_in: None,
+            } => (
+                format!(
+                    "WORKER {name} ({sidecar_id}) EXITED WITH {code:?}, RESTART BUDGET EXHAUSTED"
+                ),
+                NotificationLevel::Error,
+            ),
+            SupervisorEvent::Restarted { restarts, .. } => (
+                format!("WORKER {name} ({sidecar_id}) RESTARTED ({restarts})"),
+                NotificationLevel::Info,
+            ),
+            SupervisorEvent::Stopped => (
+                format!("WORKER {name} ({sidecar_id}) STOPPED"),
+                NotificationLevel::Info,
+            ),
+        }
+    }
+}
+
+/// Stops the supervised worker when stopped or dropped.
+#[derive(Debug)]
+pub struct SupervisorHandle {
+    stop: watch::Sender<bool>,
+}
+
+impl SupervisorHandle {
+    pub fn stop(&self) {
+        let _ = self.stop.send(true);
+    }
+}
+
+/// Restarts a crashed worker with the same launcher, so its metadata name, sidecar id and
+/// host stay stable. Every event is published as a `Notification`.
+pub struct Supervisor {
+    name: String,
+    sidecar_id: Uuid,
+    policy: RestartPolicy,
+}
+
+impl Supervisor {
+    pub fn new(name: &str, sidecar_id: Uuid, policy: RestartPolicy) -> Self {
+        Supervisor {
+            name: name.to_string(),
+            sidecar_id,
+            policy,
+        }
+    }
+
+    /// Watches the already started `process` on the tokio runtime.
+    pub fn run<P, L, E>(self, process: P, launch: L, on_event: E) -> SupervisorHandle
+    where
+        P: WorkerProcess + 'static,
+        L: FnMut() -> Result<P> + Send + 'static,
+        E: Fn(&SupervisorEvent) + Send + Sync + 'static,
+    {
+        let (stop, stopped) = watch::channel(false);
+        tokio::spawn(self.supervise(process, launch, on_event, stopped));
+        SupervisorHandle { stop }
+    }
+
+    async fn supervise<P, L, E>(
+        self,
+        process: P,
+        mut launch: L,
+        on_event: E,
+        mut stopped: watch::Receiver<bool>,
+    ) where
+        P: WorkerProcess,
+        L: FnMut() -> Result<P>,
+        E: Fn(&SupervisorEvent),
+    {
+        let emit = |event: SupervisorEvent| {
+            let (message, level) = event.notification(&self.name, &self.sidecar_id);
+            Notification::publish(&message, level);
+            on_event(&event);
+        };
+
+        let mut budget = RestartBudget::new(self.policy.clone());
+        let mut restarts = 0;
+        let mut process = Some(process);
+        loop {
+            let code = match process.as_mut() {
+                Some(process) => tokio::select! {
+                    code = process.wait() => code,
+                    _ = stopped.changed() => {
+                        if let Err(e) = process.kill().await {
+                            warn!("KILL WORKER {}: {e}", self.sidecar_id);
+                        }
+                        emit(SupervisorEvent::Stopped);
+                        return;
+                    }
+                },
+                None => None,
+            };
+            if *stopped.borrow() || stopped.has_changed().unwrap_or(true) {
+                emit(SupervisorEvent::Stopped);
+                return;
+            }
+
+            // Only crashes and deaths by signal are restarted.
+            if code == Some(0) {
+                emit(SupervisorEvent::Exited {
+                    code,
+                    restart_in: None,
+                });
+                return;
+            }
+
+            let restart_in = budget.next_backoff(Instant::now());
+            emit(SupervisorEvent::Exited { code, restart_in });
+            let Some(restart_in) = restart_in else {
+                return;
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(restart_in) => {}
+                _ = stopped.changed() => {
+                    emit(SupervisorEvent::Stopped);
+                    return;
+                }
+            }
+
+            restarts += 1;
+            process = match launch() {
+                Ok(process) => {
+                    emit(SupervisorEvent::Restarted {
+                        pid: process.pid(),
+                        restarts,
+                    });
+                    Some(process)
+                }
+                Err(e) => {
+                    warn!("RESTART WORKER {}: {e}", self.sidecar_id);
+                    None
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct ExitingProcess(i32);
+
+    #[async_trait]
+    impl WorkerProcess for ExitingProcess {
+        fn pid(&self) -> Option<u32> {
+            None
+        }
+
+        async fn wait(&mut self) -> Option<i32> {
+            Some(self.0)
+        }
+
+        async fn kill(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_restart_budget() {
+        let mut budget = RestartBudget::new(RestartPolicy {
+            max_restarts: 3,
+            window: Duration::from_secs(60),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(3),
+        });
+        let now = Instant::now();
+
+        assert_eq!(budget.next_backoff(now), Some(Duration::from_secs(1)));
+        assert_eq!(budget.next_backoff(now), Some(Duration::from_secs(2)));
+        assert_eq!(budget.next_backoff(now), Some(Duration::from_secs(3)));
+        assert_eq!(budget.next_backoff(now), None);
+        assert_eq!(
+            budget.next_backoff(now + Duration::from_secs(61)),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_supervisor_does_not_restart_a_clean_exit() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (done, finished) = tokio::sync::oneshot::channel();
+        let done = Mutex::new(Some(done));
+
+        let recorded = events.clone();
+        let _handle = Supervisor::new("worker", Uuid::new_v4(), RestartPolicy::default()).run(
+            ExitingProcess(0),
+            || -> Result<ExitingProcess> { panic!("RESTARTED AFTER A CLEAN EXIT") },
+            move |event| {
+                recorded.lock().unwrap().push(event.clone());
+                let _ = done.lock().unwrap().take().map(|d| d.send(()));
+            },
+        );
+        finished.await.unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![SupervisorEvent::Exited {
+                code: Some(0),
+                restart_in: None
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_supervisor_restarts_until_budget_exhausted() {
+        let policy = RestartPolicy {
+            max_restarts: 2,
+            window: Duration::from_secs(60),
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (done, finished) = tokio::sync::oneshot::channel();
+        let done = Mutex::new(Some(done));
+
+        let recorded = events.clone();
+        let _handle = Supervisor::new("worker", Uuid::new_v4(), policy).run(
+            ExitingProcess(1),
+            || Ok(ExitingProcess(2)),
+            move |event| {
+                recorded.lock().unwrap().push(event.clone());
+                if let SupervisorEvent::Exited {
+                    restart_in: None, ..
+                } = event
+                {
+                    let _ = done.lock().unwrap().take().map(|d| d.send(()));
+                }
+            },
+        );
+        finished.await.unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 5);
+        assert!(matches!(
+            events[0],
+            SupervisorEvent::Exited {
+                code: Some(1),
+                restart_in: Some(_)
+            }
+        ));
+        assert_eq!(
+            events[3],
+            SupervisorEvent::Restarted {
+                pid: None,
+                restarts: 2
+            }
+        );
+        assert_eq!(
+            events[4],
+            SupervisorEvent::Exited {
+                code: Some(2),
+                restart_in: None
+            }
+        );
+    }
+}
@@ -0,0 +1,420 @@
+use crate::abstractions::ActorMetadata;
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use log::error;
+use once_cell::sync::OnceCell;
+use opentelemetry::global::{self, BoxedTracer};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue, Value};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::runtime::TokioCurrentThread;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+use opentelemetry_sdk::Resource;
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::{RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable read when the metadata does not set `tracing`.
+pub const TRACES_EXPORTER_ENV: &str = "OTEL_TRACES_EXPORTER";
+
+/// Headers of the W3C trace context, accepted by the server and sent upstream.
+pub const TRACE_HEADERS: [&str; 2] = ["traceparent", "tracestate"];
+
+const TRACER_NAME: &str = "onceuponai";
+
+/// W3C trace context carried by the messages of the cluster, e.g. `traceparent`.
+pub type TraceContext = HashMap<String, String>;
+
+static TRACING: OnceCell<Option<TracingConfig>> = OnceCell::new();
+static TRACER_PROVIDER: OnceCell<TracerProvider> = OnceCell::new();
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TracingExporter {
+    /// OTLP over HTTP, to a collector or a tracing backend.
+    Otlp,
+    /// Finished spans as JSON lines on stdout.
+    Stdout,
+    /// Finished spans as JSON lines appended to `path`.
+    File,
+}
+
+impl FromStr for TracingExporter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "otlp" => Ok(TracingExporter::Otlp),
+            "stdout" | "console" => Ok(TracingExporter::Stdout),
+            "file" => Ok(TracingExporter::File),
+            _ => Err(format!("UNKNOWN TRACES EXPORTER: {s}")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TracingConfig {
+    pub exporter: TracingExporter,
+    /// OTLP traces endpoint, e.g. `http://localhost:4318/v1/traces`.
+    pub endpoint: Option<String>,
+    /// File of the `file` exporter.
+    pub path: Option<String>,
+    /// Share of the traces started by this cluster which are recorded, 1.0 when not set.
+    pub sample_ratio: Option<f64>,
+}
+
+impl TracingConfig {
+    /// Exporter selected with `OTEL_TRACES_EXPORTER`, tracing stays off when it is not set
+    /// or `none`. The OTLP exporter also reads the standard `OTEL_EXPORTER_OTLP_*` variables.
+    pub fn from_env() -> Option<Self> {
+        let exporter = std::env::var(TRACES_EXPORTER_ENV).ok()?;
+        if exporter.is_empty() || exporter == "none" {
+            return None;
+        }
+
+        match exporter.parse() {
+            Ok(exporter) => Some(TracingConfig {
+                exporter,
+                endpoint: None,
+                path: None,
+                sample_ratio: None,
+            }),
+            Err(e) => {
+                error!("{e}");
+                None
+            }
+        }
+    }
+}
+
+/// Starts exporting the spans of this process, the first call wins. `service` names the
+/// process in the traces.
+pub fn init(service: &str, metadata: &ActorMetadata) -> Result<()> {
+    let config = metadata.tracing.clone().or_else(TracingConfig::from_env);
+    if TRACING.set(config.clone()).is_err() {
+        return Ok(());
+    }
+    let Some(config) = config else {
+        return Ok(());
+    };
+
+    let provider = build_provider(service, &metadata.name, &config)?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+    let _ = TRACER_PROVIDER.set(provider);
+    Ok(())
+}
+
+/// Tracing of this process, handed down to the workers it spawns.
+pub fn config() -> Option<TracingConfig> {
+    TRACING.get().cloned().flatten()
+}
+
+/// Exports the spans still buffered, called before the process exits.
+pub fn shutdown() {
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            error!("TRACER PROVIDER SHUTDOWN: {e}");
+        }
+    }
+}
+
+fn build_provider(service: &str, instance: &str, config: &TracingConfig) -> Result<TracerProvider> {
+    let resource = Resource::new([
+        KeyValue::new("service.name", service.to_string()),
+        KeyValue::new("service.instance.id", instance.to_string()),
+    ]);
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+        config.sample_ratio.unwrap_or(1.0),
+    )));
+    let builder = TracerProvider::builder()
+        .with_resource(resource)
+        .with_sampler(sampler);
+
+    let builder = match config.exporter {
+        TracingExporter::Otlp => {
+            let mut exporter = opentelemetry_otlp::SpanExporter::builder().with_http();
+            if let Some(endpoint) = &config.endpoint {
+                exporter = exporter.with_endpoint(endpoint);
+            }
+            builder.with_batch_exporter(exporter.build()?, TokioCurrentThread)
+        }
+        TracingExporter::Stdout => builder.with_simple_exporter(JsonLinesExporter::stdout()),
+        TracingExporter::File => {
+            let path = config
+                .path
+                .as_deref()
+                .ok_or(anyhow!("FILE TRACES EXPORTER REQUIRES path"))?;
+            builder.with_simple_exporter(JsonLinesExporter::file(path)?)
+        }
+    };
+
+    Ok(builder.build())
+}
+
+pub fn tracer() -> BoxedTracer {
+    global::tracer(TRACER_NAME)
+}
+
+/// Starts a span, child of the span of `parent`, and returns the context carrying it. The
+/// span ends with `end` or once the last clone of the context is dropped.
+pub fn start(
+    parent: &Context,
+    name: impl Into<Cow<'static, str>>,
+    kind: SpanKind,
+    attributes: Vec<KeyValue>,
+) -> Context {
+    let tracer = tracer();
+    let span = tracer
+        .span_builder(name)
+        .with_kind(kind)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, parent);
+    parent.with_span(span)
+}
+
+/// Ends the span of `cx`, marked as failed when `error` is set.
+pub fn end(cx: &Context, error: Option<&str>) {
+    let span = cx.span();
+    if let Some(error) = error {
+        span.set_status(Status::error(error.to_string()));
+    }
+    span.end();
+}
+
+pub fn set_attribute(cx: &Context, key: &'static str, value: impl Into<Value>) {
+    cx.span().set_attribute(KeyValue::new(key, value));
+}
+
+pub fn add_event(cx: &Context, name: &'static str) {
+    cx.span().add_event(name, vec![]);
+}
+
+pub fn inject(cx: &Context) -> TraceContext {
+    let mut carrier = TraceContext::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(cx, &mut carrier));
+    carrier
+}
+
+pub fn extract(carrier: &TraceContext) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(carrier))
+}
+
+/// Consecutive phases of a task, e.g. prompt processing then generation. Each phase is a
+/// span, child of the current context, which ends when the next phase is entered.
+pub struct Phases {
+    parent: Context,
+    current: Option<Context>,
+}
+
+impl Phases {
+    pub fn new() -> Self {
+        Phases {
+            parent: Context::current(),
+            current: None,
+        }
+    }
+
+    pub fn enter(&mut self, phase: &'static str) {
+        self.finish();
+        self.current = Some(start(&self.parent, phase, SpanKind::Internal, vec![]));
+    }
+
+    /// Sets an attribute on the current phase.
+    pub fn record(&self, key: &'static str, value: impl Into<Value>) {
+        if let Some(cx) = &self.current {
+            set_attribute(cx, key, value);
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(cx) = self.current.take() {
+            end(&cx, None);
+        }
+    }
+}
+
+impl Default for Phases {
+    fn default() -> Self {
+        Phases::new()
+    }
+}
+
+impl Drop for Phases {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Sends an upstream HTTP request in a client span, child of the current context. The
+/// trace context goes along in the request headers and the span ends with the response head.
+pub async fn send(request: RequestBuilder) -> reqwest::Result<Response> {
+    let (client, request) = request.build_split();
+    let mut request = request?;
+    let method = request.method().to_string();
+    let mut url = request.url().clone();
+    url.set_query(None);
+    let cx = start(
+        &Context::current(),
+        format!("{method} {}", url.path()),
+        SpanKind::Client,
+        vec![
+            KeyValue::new("http.request.method", method),
+            KeyValue::new(
+                "server.address",
+                url.host_str().unwrap_or_default().to_string(),
+            ),
+            KeyValue::new("url.full", url.to_string()),
+        ],
+    );
+    for (name, value) in inject(&cx) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            request.headers_mut().insert(name, value);
+        }
+    }
+
+    match client.execute(request).await {
+        Ok(response) => {
+            let status = response.status();
+            set_attribute(&cx, "http.response.status_code", status.as_u16() as i64);
+            let error = (status.is_client_error() || status.is_server_error())
+                .then(|| format!("HTTP {status}"));
+            end(&cx, error.as_deref());
+            Ok(response)
+        }
+        Err(e) => {
+            end(&cx, Some(&e.to_string()));
+            Err(e)
+        }
+    }
+}
+
+/// Writes finished spans as JSON lines, for local use without a collector.
+struct JsonLinesExporter {
+    writer: Box<dyn Write + Send + Sync>,
+}
+
+impl JsonLinesExporter {
+    fn stdout() -> Self {
+        JsonLinesExporter {
+            writer: Box::new(std::io::stdout()),
+        }
+    }
+
+    fn file(path: &str) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonLinesExporter {
+            writer: Box::new(file),
+        })
+    }
+
+    fn write(&mut self, batch: &[SpanData]) -> std::io::Result<()> {
+        for span in batch {
+            serde_json::to_writer(&mut self.writer, &span_json(span))?;
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.flush()
+    }
+}
+
+impl fmt::Debug for JsonLinesExporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonLinesExporter").finish()
+    }
+}
+
+impl SpanExporter for JsonLinesExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let result = self.write(&batch).map_err(|e| e.to_string().into());
+        Box::pin(std::future::ready(result))
+    }
+}
+
+fn span_json(span: &SpanData) -> serde_json::Value {
+    let attributes: serde_json::Map<String, serde_json::Value> = span
+        .attributes
+        .iter()
+        .map(|kv| (kv.key.to_string(), kv.value.to_string().into()))
+        .collect();
+    let events: Vec<serde_json::Value> = span
+        .events
+        .iter()
+        .map(|event| serde_json::json!({ "name": event.name, "time_ns": unix_nanos(event.timestamp) }))
+        .collect();
+
+    serde_json::json!({
+        "trace_id": span.span_context.trace_id().to_string(),
+        "span_id": span.span_context.span_id().to_string(),
+        "parent_span_id": span.parent_span_id.to_string(),
+        "name": span.name,
+        "kind": format!("{:?}", span.span_kind),
+        "start_time_ns": unix_nanos(span.start_time),
+        "duration_ms": span
+            .end_time
+            .duration_since(span.start_time)
+            .unwrap_or_default()
+            .as_secs_f64() * 1000.0,
+        "status": format!("{:?}", span.status),
+        "attributes": attributes,
+        "events": events,
+        "scope": span.instrumentation_scope.name(),
+    })
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_context_round_trip_and_json_lines() {
+        let path =
+            std::env::temp_dir().join(format!("onceuponai-traces-{}.jsonl", uuid::Uuid::new_v4()));
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(JsonLinesExporter::file(path.to_str().unwrap()).unwrap())
+            .build();
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        global::set_tracer_provider(provider.clone());
+
+        let server = start(&Context::new(), "server", SpanKind::Server, vec![]);
+        let carrier = inject(&server);
+        assert!(carrier.contains_key("traceparent"));
+
+        let remote = extract(&carrier);
+        assert_eq!(
+            remote.span().span_context().trace_id(),
+            server.span().span_context().trace_id()
+        );
+        let worker = start(&remote, "worker", SpanKind::Consumer, vec![]);
+        end(&worker, Some("FAILED"));
+        end(&server, None);
+        let _ = provider.force_flush();
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["name"], "worker");
+        assert_eq!(lines[0]["trace_id"], lines[1]["trace_id"]);
+        assert_eq!(lines[0]["parent_span_id"], lines[1]["span_id"]);
+        assert!(lines[0]["status"].as_str().unwrap().contains("FAILED"));
+    }
+}
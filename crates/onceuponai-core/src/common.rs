@@ -5,6 +5,7 @@ use rand::distributions::Alphanumeric;
 use rand::{Rng, RngCore};
 use serde::de::DeserializeOwned;
 use std::io::{self, Result as IoResult};
+use std::sync::{Mutex, MutexGuard};
 use std::{env, fs, path::PathBuf};
 
 #[derive(thiserror::Error, Debug)]
@@ -39,6 +40,20 @@ impl<T> OptionToResult<T> for Option<T> {
     }
 }
 
+pub trait MutexExt<T> {
+    /// Locks the mutex, recovering it when a previous holder panicked.
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> MutexExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|poisoned| {
+            self.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+}
+
 pub trait ResultExt<T, E> {
     fn map_anyhow_err(self) -> Result<T>;
 
@@ -323,4 +338,22 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_lock_or_recover() -> Result<()> {
+        let mutex = std::sync::Arc::new(Mutex::new(1));
+        let poisoner = std::sync::Arc::clone(&mutex);
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poison");
+        })
+        .join();
+
+        assert!(mutex.is_poisoned());
+        *mutex.lock_or_recover() += 1;
+        assert!(!mutex.is_poisoned());
+        assert_eq!(*mutex.lock_or_recover(), 2);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,145 @@
+use crate::notifications::NotificationLevel;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+/// Events kept for a slow subscriber before it starts missing the oldest ones.
+const EVENT_BUS_CAPACITY: usize = 1024;
+
+static EVENT_BUS: Lazy<broadcast::Sender<Event>> =
+    Lazy::new(|| broadcast::channel(EVENT_BUS_CAPACITY).0);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelLoadState {
+    Started,
+    Loaded,
+    Unloaded,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Started,
+    Finished,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// `model` is the actor id (`kind/name`).
+    ActorConnected {
+        model: String,
+    },
+    ActorLeft {
+        model: String,
+    },
+    ModelLoading {
+        model: String,
+        state: ModelLoadState,
+        seconds: Option<f64>,
+        error: Option<String>,
+    },
+    Task {
+        task_id: Uuid,
+        model: String,
+        subject: Option<String>,
+        stream: bool,
+        state: TaskState,
+    },
+    Error {
+        message: String,
+    },
+    Notification {
+        level: NotificationLevel,
+        message: String,
+    },
+}
+
+impl EventKind {
+    /// Name of the event, e.g. the `event` field of a server-sent event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::ActorConnected { .. } => "actor_connected",
+            EventKind::ActorLeft { .. } => "actor_left",
+            EventKind::ModelLoading { .. } => "model_loading",
+            EventKind::Task { .. } => "task",
+            EventKind::Error { .. } => "error",
+            EventKind::Notification { .. } => "notification",
+        }
+    }
+}
+
+/// Event of the process, workers forward theirs to the main actor.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    pub timestamp: DateTime<Utc>,
+    /// Actor which emitted the event, set when it was forwarded by a worker.
+    pub actor: Option<Uuid>,
+    pub kind: EventKind,
+}
+
+impl Event {
+    pub fn new(kind: EventKind) -> Self {
+        Event {
+            timestamp: Utc::now(),
+            actor: None,
+            kind,
+        }
+    }
+
+    pub fn with_actor(mut self, actor: Uuid) -> Self {
+        self.actor = Some(actor);
+        self
+    }
+}
+
+/// Sends an event to the current subscribers, dropped when there are none.
+pub fn publish(kind: EventKind) {
+    forward(Event::new(kind));
+}
+
+/// Sends an already built event, e.g. one received from a worker.
+pub fn forward(event: Event) {
+    let _ = EVENT_BUS.send(event);
+}
+
+/// Receives the events published from now on.
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    EVENT_BUS.subscribe()
+}
+
+/// Next event of a subscription, skipping the events a lagging subscriber missed.
+pub async fn recv(receiver: &mut broadcast::Receiver<Event>) -> Option<Event> {
+    loop {
+        match receiver.recv().await {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_subscribers_receive_published_events() {
+        let mut receiver = subscribe();
+        publish(EventKind::ActorConnected {
+            model: "gemma/gemma2b".to_string(),
+        });
+
+        let event = recv(&mut receiver).await.unwrap();
+        assert_eq!(event.kind.name(), "actor_connected");
+        assert!(event.actor.is_none());
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["kind"]["actor_connected"]["model"], "gemma/gemma2b");
+    }
+}
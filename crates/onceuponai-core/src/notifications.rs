@@ -2,8 +2,9 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
 use crate::common::ResultExt;
+use crate::events::{self, EventKind};
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum NotificationLevel {
     Error,
     Warn,
@@ -30,9 +31,20 @@ impl Notification {
         serde_json::to_string(&notification).map_anyhow_err()
     }
 
+    /// Prints the notification for a parent process reading the output, and publishes it
+    /// on the event bus.
     pub fn publish(message: &str, notification_type: NotificationLevel) {
-        let n = Notification::build(message, notification_type).unwrap();
+        let n = Notification::build(message, notification_type.clone()).unwrap();
         println!("{NOTIFICATION_PREFIX}{n}");
+        events::publish(match notification_type {
+            NotificationLevel::Error => EventKind::Error {
+                message: message.to_string(),
+            },
+            level => EventKind::Notification {
+                level,
+                message: message.to_string(),
+            },
+        });
     }
 
     pub fn read(notification: &str) -> Option<String> {
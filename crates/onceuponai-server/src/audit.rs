@@ -0,0 +1,446 @@
+use crate::handlers::auth::verify_pat_token;
+use actix_session::SessionExt;
+use actix_web::HttpRequest;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::error;
+use onceuponai_actors::abstractions::ActorInvokeResult;
+use onceuponai_actors::actors::main_actor::MainActorAuditConfig;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use uuid::Uuid;
+
+const DEFAULT_PATH: &str = "audit";
+const LOG_FILE: &str = "audit.jsonl";
+const DEFAULT_MAX_BYTES: u64 = 100 * 1024 * 1024;
+const DEFAULT_MAX_FILES: usize = 10;
+const DEFAULT_REPLACEMENT: &str = "[REDACTED]";
+/// Characters per token when the actor does not report its usage.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Redaction rules used when the config has none: e-mail addresses, bearer tokens and API
+/// keys, payment card numbers.
+const DEFAULT_REDACTIONS: [&str; 4] = [
+    r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}",
+    r"(?i)bearer\s+[A-Za-z0-9._~+/=-]+",
+    r"\b(sk|pk|rk)-[A-Za-z0-9_-]{16,}\b",
+    r"\b(?:\d[ -]?){13,19}\b",
+];
+
+/// How the caller of a request authenticated.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    Session,
+    Pat,
+}
+
+/// Caller of a request, resolved when the request arrives.
+#[derive(Debug, Clone, Default)]
+pub struct AuditRequest {
+    pub received: DateTime<Utc>,
+    pub subject: Option<String>,
+    pub auth: Option<AuthMethod>,
+    /// Peer address of the connection.
+    pub client_ip: Option<String>,
+    /// Client address reported by a proxy (`Forwarded`, `X-Forwarded-For`), when it differs.
+    pub forwarded_for: Option<String>,
+}
+
+impl AuditRequest {
+    pub fn from_request(req: &HttpRequest, pat_secret: Option<&str>) -> Self {
+        let (auth, subject) = match request_subject(req, pat_secret) {
+            Some((auth, subject)) => (Some(auth), Some(subject)),
+            None => (None, None),
+        };
+        let client_ip = req.peer_addr().map(|addr| addr.ip().to_string());
+        let forwarded_for = req
+            .connection_info()
+            .realip_remote_addr()
+            .map(|addr| addr.to_string())
+            .filter(|addr| client_ip.as_deref() != Some(addr.as_str()));
+
+        AuditRequest {
+            received: Utc::now(),
+            subject,
+            auth,
+            client_ip,
+            forwarded_for,
+        }
+    }
+
+    /// Record of an invoke of `model`, completed by the caller before it is written.
+    pub fn record(&self, model: &str, stream: bool) -> AuditRecord {
+        AuditRecord {
+            timestamp: self.received,
+            subject: self.subject.clone(),
+            auth: self.auth,
+            client_ip: self.client_ip.clone(),
+            forwarded_for: self.forwarded_for.clone(),
+            model: model.to_string(),
+            task_id: None,
+            stream,
+            status: 200,
+            cache: None,
+            duration_ms: 0.0,
+            first_chunk_ms: None,
+            prompt_tokens: None,
+            completion_tokens: None,
+            error: None,
+            prompt: None,
+            response: None,
+        }
+    }
+}
+
+/// Authenticated caller of a request, the session e-mail or the personal token subject.
+pub fn request_subject(
+    req: &HttpRequest,
+    pat_secret: Option<&str>,
+) -> Option<(AuthMethod, String)> {
+    if let Ok(Some(email)) = req.get_session().get::<String>("EMAIL") {
+        return Some((AuthMethod::Session, email));
+    }
+
+    let token = req
+        .headers()
+        .get("authorization")?
+        .to_str()
+        .ok()?
+        .replace("Bearer ", "");
+    let claims = verify_pat_token(&token, pat_secret?).ok()?.claims;
+    Some((AuthMethod::Pat, claims.sub))
+}
+
+/// One line of the audit log.
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditRecord {
+    /// Time the request was received.
+    pub timestamp: DateTime<Utc>,
+    pub subject: Option<String>,
+    pub auth: Option<AuthMethod>,
+    pub client_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forwarded_for: Option<String>,
+    /// Model id (`kind/name`).
+    pub model: String,
+    pub task_id: Option<Uuid>,
+    pub stream: bool,
+    /// HTTP status of the response.
+    pub status: u16,
+    /// Cache status (`HIT`, `MISS`, ...) when the model is cached.
+    pub cache: Option<&'static str>,
+    pub duration_ms: f64,
+    pub first_chunk_ms: Option<f64>,
+    /// Tokens of the prompt, reported by the actor or estimated from the request.
+    pub prompt_tokens: Option<u64>,
+    /// Generated tokens, reported by the actor or estimated from the generated content.
+    pub completion_tokens: Option<u64>,
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<Value>,
+}
+
+impl AuditRecord {
+    /// Milliseconds since the request was received.
+    pub fn elapsed_ms(&self) -> f64 {
+        (Utc::now() - self.timestamp)
+            .num_microseconds()
+            .unwrap_or_default() as f64
+            / 1000.0
+    }
+
+    /// Adds the token usage of a result, a streamed chunk adds to the previous ones. Results
+    /// without reported usage are estimated from their content.
+    pub fn add_usage(&mut self, result: &ActorInvokeResult) {
+        let (prompt_tokens, completion_tokens) = result.usage();
+        if prompt_tokens.is_some() {
+            self.prompt_tokens = prompt_tokens;
+        }
+        let completion_tokens = completion_tokens.or_else(|| {
+            result
+                .data
+                .get("content")
+                .map(|content| estimate_tokens(&serde_json::json!(content)))
+        });
+        if let Some(tokens) = completion_tokens {
+            *self.completion_tokens.get_or_insert(0) += tokens;
+        }
+    }
+}
+
+/// Rough token count of the text in a payload.
+pub fn estimate_tokens(payload: &Value) -> u64 {
+    fn chars(value: &Value) -> usize {
+        match value {
+            Value::String(text) => text.chars().count(),
+            Value::Array(values) => values.iter().map(chars).sum(),
+            Value::Object(map) => map.values().map(chars).sum(),
+            _ => 0,
+        }
+    }
+    chars(payload).div_ceil(CHARS_PER_TOKEN) as u64
+}
+
+/// JSON lines log of the invoke requests, rotated by size. Prompts and responses are only
+/// captured when enabled, after redaction.
+pub struct AuditLog {
+    capture_payloads: bool,
+    redactions: Vec<(Regex, String)>,
+    /// Lines for the writer thread, requests never wait for the file.
+    lines: Option<mpsc::Sender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AuditLog {
+    pub fn new(config: MainActorAuditConfig) -> Result<Self> {
+        let redactions = match &config.redact {
+            Some(rules) => rules
+                .iter()
+                .map(|rule| {
+                    let replacement = rule
+                        .replacement
+                        .clone()
+                        .unwrap_or(DEFAULT_REPLACEMENT.to_string());
+                    Ok((Regex::new(&rule.pattern)?, replacement))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => DEFAULT_REDACTIONS
+                .iter()
+                .map(|pattern| Ok((Regex::new(pattern)?, DEFAULT_REPLACEMENT.to_string())))
+                .collect::<Result<Vec<_>>>()?,
+        };
+
+        let dir = PathBuf::from(config.path.as_deref().unwrap_or(DEFAULT_PATH));
+        let file = RotatingFile::open(
+            dir,
+            config.max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            config.max_files.unwrap_or(DEFAULT_MAX_FILES),
+        )?;
+        let (lines, receiver) = mpsc::channel::<Vec<u8>>();
+        let writer = std::thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || {
+                let mut file = file;
+                for line in receiver {
+                    if let Err(e) = file.write_line(&line) {
+                        error!("AUDIT LOG WRITE: {e}");
+                    }
+                }
+            })?;
+
+        Ok(AuditLog {
+            capture_payloads: config.capture_payloads.unwrap_or(false),
+            redactions,
+            lines: Some(lines),
+            writer: Some(writer),
+        })
+    }
+
+    /// Redacted copy of a prompt or response, `None` unless payloads are captured.
+    pub fn capture<T: Serialize>(&self, payload: &T) -> Option<Value> {
+        if !self.capture_payloads {
+            return None;
+        }
+        let mut value = serde_json::to_value(payload).ok()?;
+        self.redact(&mut value);
+        Some(value)
+    }
+
+    fn redact(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => {
+                for (pattern, replacement) in &self.redactions {
+                    if let std::borrow::Cow::Owned(redacted) =
+                        pattern.replace_all(text, replacement.as_str())
+                    {
+                        *text = redacted;
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|v| self.redact(v)),
+            Value::Object(map) => map.values_mut().for_each(|v| self.redact(v)),
+            _ => {}
+        }
+    }
+
+    pub fn write(&self, mut record: AuditRecord) {
+        record.duration_ms = record.elapsed_ms();
+        let line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("AUDIT RECORD: {e}");
+                return;
+            }
+        };
+
+        let sent = self.lines.as_ref().map(|lines| lines.send(line));
+        if !matches!(sent, Some(Ok(()))) {
+            error!("AUDIT LOG WRITER STOPPED");
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    /// Waits for the queued records to be written.
+    fn drop(&mut self) {
+        self.lines.take();
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                error!("AUDIT LOG WRITER PANICKED");
+            }
+        }
+    }
+}
+
+/// `audit.jsonl` in a directory, moved to `audit.jsonl.1` once it exceeds `max_bytes`. Older
+/// files shift up to `audit.jsonl.{max_files}` and are deleted beyond.
+struct RotatingFile {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(dir: PathBuf, max_bytes: u64, max_files: usize) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            dir,
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn path(&self, index: usize) -> PathBuf {
+        match index {
+            0 => self.dir.join(LOG_FILE),
+            _ => self.dir.join(format!("{LOG_FILE}.{index}")),
+        }
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.file.write_all(b"\n")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        let oldest = self.path(self.max_files);
+        if oldest.exists() {
+            fs::remove_file(oldest)?;
+        }
+        for index in (0..self.max_files).rev() {
+            let path = self.path(index);
+            if path.exists() {
+                fs::rename(path, self.path(index + 1))?;
+            }
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(0))?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use onceuponai_abstractions::EntityValue;
+    use onceuponai_actors::abstractions::usage_metadata;
+    use onceuponai_actors::actors::main_actor::MainActorRedactionRule;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_audit_log_redacts_payloads_and_rotates() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("onceuponai-audit-{}", Uuid::new_v4()));
+        let audit = AuditLog::new(MainActorAuditConfig {
+            path: Some(dir.to_string_lossy().to_string()),
+            max_bytes: Some(600),
+            max_files: Some(2),
+            capture_payloads: Some(true),
+            redact: Some(vec![MainActorRedactionRule {
+                pattern: r"\d{3}-\d{4}".to_string(),
+                replacement: Some("[PHONE]".to_string()),
+            }]),
+        })?;
+
+        let prompt = audit.capture(&json!({"messages": [{"content": "call 555-1234"}]}));
+        assert_eq!(
+            prompt,
+            Some(json!({"messages": [{"content": "call [PHONE]"}]}))
+        );
+
+        let request = AuditRequest {
+            subject: Some("user@example.com".to_string()),
+            auth: Some(AuthMethod::Pat),
+            ..Default::default()
+        };
+        for _ in 0..10 {
+            audit.write(request.record("gemma/gemma2b", false));
+        }
+        drop(audit);
+
+        let current = fs::read_to_string(dir.join(LOG_FILE))?;
+        let record: Value = serde_json::from_str(current.lines().next().unwrap_or_default())?;
+        assert_eq!(record["subject"], "user@example.com");
+        assert_eq!(record["auth"], "pat");
+        assert!(record.get("prompt").is_none());
+        assert!(dir.join(format!("{LOG_FILE}.2")).exists());
+        assert!(!dir.join(format!("{LOG_FILE}.3")).exists());
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_adds_reported_or_estimated_usage() {
+        let result = |metadata, content: &str| ActorInvokeResult {
+            uuid: Uuid::new_v4(),
+            task_id: Uuid::new_v4(),
+            stream: true,
+            metadata,
+            data: HashMap::from([(
+                "content".to_string(),
+                vec![EntityValue::STRING(content.to_string())],
+            )]),
+        };
+
+        let mut record = AuditRequest::default().record("gemma/gemma2b", true);
+        record.prompt_tokens = Some(estimate_tokens(&json!({"content": "hello there"})));
+        assert_eq!(record.prompt_tokens, Some(3));
+
+        record.add_usage(&result(usage_metadata(7, 1), "Hel"));
+        record.add_usage(&result(usage_metadata(7, 1), "lo"));
+        assert_eq!(record.prompt_tokens, Some(7));
+        assert_eq!(record.completion_tokens, Some(2));
+
+        record.add_usage(&result(HashMap::new(), "twelve chars"));
+        assert_eq!(record.prompt_tokens, Some(7));
+        assert_eq!(record.completion_tokens, Some(5));
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+struct LruEntry<V> {
+    value: V,
+    size: usize,
+    expires: Instant,
+    tick: u64,
+}
+
+/// Least recently used cache bounded by the total size of its values.
+pub struct LruCache<V> {
+    entries: HashMap<String, LruEntry<V>>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl<V: Clone> LruCache<V> {
+    pub fn new(max_bytes: usize) -> Self {
+        LruCache {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<V> {
+        let expired = self.entries.get(key)?.expires <= Instant::now();
+        if expired {
+            self.remove(key);
+            return None;
+        }
+
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.tick);
+        entry.tick = tick;
+        self.order.insert(tick, key.to_string());
+        Some(entry.value.clone())
+    }
+
+    /// Inserts a value and returns the keys evicted to stay within the byte limit.
+    pub fn insert(&mut self, key: &str, value: V, size: usize, ttl: Duration) -> Vec<String> {
+        self.remove(key);
+        if size > self.max_bytes {
+            return vec![];
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.to_string());
+        self.entries.insert(
+            key.to_string(),
+            LruEntry {
+                value,
+                size,
+                expires: Instant::now() + ttl,
+                tick: self.tick,
+            },
+        );
+        self.bytes += size;
+
+        let mut evicted = vec![];
+        while self.bytes > self.max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.bytes -= entry.size;
+            }
+            evicted.push(oldest);
+        }
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.order.remove(&entry.tick);
+                self.bytes -= entry.size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = LruCache::new(30);
+        cache.insert("a", 1, 10, TTL);
+        cache.insert("b", 2, 10, TTL);
+        cache.insert("c", 3, 10, TTL);
+        assert_eq!(cache.get("a"), Some(1));
+
+        let evicted = cache.insert("d", 4, 10, TTL);
+        assert_eq!(evicted, vec!["b".to_string()]);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.bytes(), 30);
+    }
+
+    #[test]
+    fn test_expired_entries_are_dropped() {
+        let mut cache = LruCache::new(100);
+        cache.insert("a", 1, 10, Duration::ZERO);
+        assert_eq!(cache.get("a"), None);
+        assert!(cache.is_empty());
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn test_replace_and_oversized_values() {
+        let mut cache = LruCache::new(20);
+        cache.insert("a", 1, 10, TTL);
+        cache.insert("a", 2, 15, TTL);
+        assert_eq!(cache.get("a"), Some(2));
+        assert_eq!(cache.bytes(), 15);
+
+        cache.insert("b", 3, 50, TTL);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(2));
+    }
+}
@@ -0,0 +1,550 @@
+pub mod lru;
+pub mod semantic;
+
+use self::lru::LruCache;
+use self::semantic::SemanticIndex;
+use crate::models::InvokeRequest;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest};
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use onceuponai_abstractions::EntityValue;
+use onceuponai_actors::abstractions::ActorInvokeData;
+use onceuponai_actors::actors::main_actor::{
+    MainActorCacheConfig, MainActorCacheModelConfig, MainActorSemanticCacheConfig,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const CACHE_STATUS_HEADER: &str = "x-cache";
+pub const CACHE_SIMILARITY_HEADER: &str = "x-cache-similarity";
+const DEFAULT_TTL: u64 = 3600;
+const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_SEMANTIC_THRESHOLD: f32 = 0.95;
+const DEFAULT_SEMANTIC_MAX_ENTRIES: usize = 10_000;
+
+/// Request fields which don't change the response.
+const IGNORED_FIELDS: [&str; 2] = ["stream", "user"];
+
+pub type CachedData = HashMap<String, Vec<EntityValue>>;
+
+/// How a request interacts with the cache, taken from its `Cache-Control` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CachePolicy {
+    #[default]
+    Default,
+    /// `no-cache`: skip the lookup but store the fresh response.
+    Refresh,
+    /// `no-store`: don't touch the cache at all.
+    Bypass,
+}
+
+impl CachePolicy {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let directives = req
+            .headers()
+            .get_all(header::CACHE_CONTROL)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|d| d.trim().to_lowercase())
+            .collect::<Vec<String>>();
+
+        if directives.iter().any(|d| d == "no-store") {
+            CachePolicy::Bypass
+        } else if directives.iter().any(|d| d == "no-cache") {
+            CachePolicy::Refresh
+        } else {
+            CachePolicy::Default
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedEntry {
+    /// Not set in entries persisted before the model was recorded, they are dropped.
+    #[serde(default)]
+    model: Option<String>,
+    key: String,
+    expires_at: u64,
+    data: CachedData,
+}
+
+/// Per model LRU caches of invoke responses, optionally persisted to disk. The files are
+/// read and written on the blocking thread pool, never under the lock of the caches.
+pub struct ResponseCache {
+    config: MainActorCacheConfig,
+    models: Mutex<HashMap<String, LruCache<CachedData>>>,
+    semantic: Mutex<HashMap<String, SemanticIndex>>,
+}
+
+impl ResponseCache {
+    pub fn new(config: MainActorCacheConfig) -> Self {
+        let cache = ResponseCache {
+            config,
+            models: Mutex::new(HashMap::new()),
+            semantic: Mutex::new(HashMap::new()),
+        };
+        cache.restore();
+        cache
+    }
+
+    fn model_config(&self, model: &str) -> Option<&MainActorCacheModelConfig> {
+        self.config.models.as_ref()?.get(model)
+    }
+
+    pub fn is_enabled(&self, model: &str) -> bool {
+        self.model_config(model)
+            .and_then(|c| c.enabled)
+            .unwrap_or(true)
+    }
+
+    fn ttl(&self, model: &str) -> Duration {
+        let ttl = self
+            .model_config(model)
+            .and_then(|c| c.ttl)
+            .or(self.config.ttl)
+            .unwrap_or(DEFAULT_TTL);
+        Duration::from_secs(ttl)
+    }
+
+    fn max_bytes(&self, model: &str) -> usize {
+        self.model_config(model)
+            .and_then(|c| c.max_bytes)
+            .or(self.config.max_bytes)
+            .unwrap_or(DEFAULT_MAX_BYTES)
+    }
+
+    pub async fn get(&self, model: &str, key: &str) -> Option<CachedData> {
+        let cached = self
+            .models
+            .lock()
+            .ok()?
+            .get_mut(model)
+            .and_then(|cache| cache.get(key));
+        if cached.is_some() {
+            return cached;
+        }
+
+        // Entries persisted by another server sharing the directory.
+        let path = self.entry_path(model, key)?;
+        let entry_key = key.to_string();
+        let entry = web::block(move || read_entry(&path, &entry_key))
+            .await
+            .ok()??;
+        let ttl = Duration::from_secs(entry.expires_at.saturating_sub(unix_now()));
+        let size = serde_json::to_vec(&entry.data).ok()?.len();
+        let evicted = self.insert(model, key, entry.data.clone(), size, ttl);
+        self.remove_files(model, &evicted).await;
+        Some(entry.data)
+    }
+
+    pub async fn put(&self, model: &str, key: &str, data: CachedData) {
+        let Ok(bytes) = serde_json::to_vec(&data) else {
+            return;
+        };
+        let ttl = self.ttl(model);
+        let evicted = self.insert(model, key, data.clone(), bytes.len(), ttl);
+        self.remove_files(model, &evicted).await;
+
+        // Entries over the limit are not cached, so they are not persisted either.
+        if bytes.len() > self.max_bytes(model) {
+            return;
+        }
+        if let Some(path) = self.entry_path(model, key) {
+            let entry = PersistedEntry {
+                model: Some(model.to_string()),
+                key: key.to_string(),
+                expires_at: unix_now() + ttl.as_secs(),
+                data,
+            };
+            let result =
+                web::block(move || write_entry(&path, &entry).map_err(|e| (path, e))).await;
+            if let Ok(Err((path, e))) = result {
+                warn!("CACHE WRITE {path:?} FAILED: {e:?}");
+            }
+        }
+    }
+
+    pub fn semantic_config(&self, model: &str) -> Option<&MainActorSemanticCacheConfig> {
+        self.model_config(model)?.semantic.as_ref()
+    }
+
+    pub fn semantic_get(&self, model: &str, embedding: &[f32]) -> Option<(f32, CachedData)> {
+        let threshold = self
+            .semantic_config(model)?
+            .threshold
+            .unwrap_or(DEFAULT_SEMANTIC_THRESHOLD);
+        self.semantic
+            .lock()
+            .ok()?
+            .get_mut(model)?
+            .search(embedding, threshold)
+    }
+
+    pub fn semantic_put(&self, model: &str, embedding: Vec<f32>, data: CachedData) {
+        let Some(config) = self.semantic_config(model) else {
+            return;
+        };
+        let max_entries = config.max_entries.unwrap_or(DEFAULT_SEMANTIC_MAX_ENTRIES);
+        if let Ok(mut semantic) = self.semantic.lock() {
+            semantic
+                .entry(model.to_string())
+                .or_insert_with(|| SemanticIndex::new(max_entries))
+                .insert(embedding, data, self.ttl(model));
+        }
+    }
+
+    /// Drops every entry of a model, in memory and on disk.
+    pub async fn invalidate(&self, model: &str) {
+        if let Ok(mut models) = self.models.lock() {
+            models.remove(model);
+        }
+
+        if let Ok(mut semantic) = self.semantic.lock() {
+            semantic.remove(model);
+        }
+
+        if let Some(dir) = self.model_dir(model) {
+            let result = web::block(move || match dir.exists() {
+                true => std::fs::remove_dir_all(&dir).map_err(|e| (dir, e)),
+                false => Ok(()),
+            })
+            .await;
+            if let Ok(Err((dir, e))) = result {
+                warn!("CACHE INVALIDATE {dir:?} FAILED: {e:?}");
+            }
+        }
+    }
+
+    /// Adds an entry to the memory cache of a model, returns the keys it evicted.
+    fn insert(
+        &self,
+        model: &str,
+        key: &str,
+        data: CachedData,
+        size: usize,
+        ttl: Duration,
+    ) -> Vec<String> {
+        match self.models.lock() {
+            Ok(mut models) => models
+                .entry(model.to_string())
+                .or_insert_with(|| LruCache::new(self.max_bytes(model)))
+                .insert(key, data, size, ttl),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Counts the entries persisted before a restart against the byte limits. Entries over
+    /// the limits, expired or without a model are removed. Runs once, before serving.
+    fn restore(&self) {
+        let Some(path) = &self.config.path else {
+            return;
+        };
+        let Ok(dirs) = std::fs::read_dir(path) else {
+            return;
+        };
+
+        let files = dirs
+            .flatten()
+            .filter_map(|dir| std::fs::read_dir(dir.path()).ok())
+            .flatten()
+            .flatten()
+            .map(|file| file.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<PathBuf>>();
+        let mut restored = 0;
+        for path in files {
+            let entry = std::fs::read(&path)
+                .ok()
+                .and_then(|bytes| serde_json::from_slice::<PersistedEntry>(&bytes).ok())
+                .filter(|entry| entry.expires_at > unix_now());
+            let Some((model, entry)) = entry.and_then(|entry| Some((entry.model.clone()?, entry)))
+            else {
+                let _ = std::fs::remove_file(&path);
+                continue;
+            };
+            // Entries written under another file name, e.g. by an older version, are moved.
+            let Some(entry_path) = self.entry_path(&model, &entry.key) else {
+                continue;
+            };
+            if entry_path != path && std::fs::rename(&path, &entry_path).is_err() {
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+
+            let size = serde_json::to_vec(&entry.data).map_or(usize::MAX, |bytes| bytes.len());
+            if size > self.max_bytes(&model) {
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            let ttl = Duration::from_secs(entry.expires_at.saturating_sub(unix_now()));
+            for evicted in self.insert(&model, &entry.key, entry.data, size, ttl) {
+                if let Some(path) = self.entry_path(&model, &evicted) {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+            restored += 1;
+        }
+        if restored > 0 {
+            info!("CACHE RESTORED {restored} ENTRIES FROM {path}");
+        }
+    }
+
+    async fn remove_files(&self, model: &str, keys: &[String]) {
+        let paths: Vec<PathBuf> = keys
+            .iter()
+            .filter_map(|key| self.entry_path(model, key))
+            .collect();
+        if paths.is_empty() {
+            return;
+        }
+
+        let _ = web::block(move || {
+            for path in paths {
+                let _ = std::fs::remove_file(path);
+            }
+        })
+        .await;
+    }
+
+    fn model_dir(&self, model: &str) -> Option<PathBuf> {
+        let path = self.config.path.as_ref()?;
+        Some(PathBuf::from(path).join(model.replace(['/', '\\'], "_")))
+    }
+
+    /// File of an entry, named by the SHA-256 of its key so the name is stable across
+    /// releases. The full key is stored in the file, so collisions are detected on load.
+    fn entry_path(&self, model: &str, key: &str) -> Option<PathBuf> {
+        let digest = Sha256::digest(key.as_bytes());
+        let name: String = digest[..16].iter().map(|b| format!("{b:02x}")).collect();
+        Some(self.model_dir(model)?.join(format!("{name}.json")))
+    }
+}
+
+fn read_entry(path: &Path, key: &str) -> Option<PersistedEntry> {
+    let bytes = std::fs::read(path).ok()?;
+    let entry: PersistedEntry = serde_json::from_slice(&bytes).ok()?;
+    if entry.key != key || entry.expires_at <= unix_now() {
+        let _ = std::fs::remove_file(path);
+        return None;
+    }
+    Some(entry)
+}
+
+fn write_entry(path: &Path, entry: &PersistedEntry) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_vec(entry)?)
+}
+
+/// Parses the overrides of a model, as `kind/name=ttl:60,max_bytes:1048576,enabled:false`.
+pub fn parse_model_config(value: &str) -> Result<(String, MainActorCacheModelConfig)> {
+    let (model, settings) = value
+        .split_once('=')
+        .filter(|(model, _)| model.split_once('/').is_some())
+        .ok_or_else(|| anyhow!("CACHE MODEL {value}: EXPECTED kind/name=setting:value,..."))?;
+
+    let mut config = MainActorCacheModelConfig::default();
+    for setting in settings.split(',').filter(|s| !s.trim().is_empty()) {
+        let (name, setting_value) = setting
+            .split_once(':')
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .ok_or_else(|| anyhow!("CACHE MODEL {model}: EXPECTED setting:value GOT {setting}"))?;
+        let invalid = |e: &dyn std::fmt::Display| {
+            anyhow!("CACHE MODEL {model}: INVALID {name} {setting_value}: {e}")
+        };
+        match name {
+            "ttl" => config.ttl = Some(setting_value.parse().map_err(|e| invalid(&e))?),
+            "max_bytes" => config.max_bytes = Some(setting_value.parse().map_err(|e| invalid(&e))?),
+            "enabled" => config.enabled = Some(setting_value.parse().map_err(|e| invalid(&e))?),
+            _ => return Err(anyhow!("CACHE MODEL {model}: UNKNOWN SETTING {name}")),
+        }
+    }
+    Ok((model.to_string(), config))
+}
+
+/// Parses the semantic cache of a chat model, as `kind/name=embed_kind/embed_name`, into the
+/// model and its embeddings model.
+pub fn parse_semantic_config(value: &str) -> Result<(String, String)> {
+    value
+        .split_once('=')
+        .filter(|(model, embed_model)| {
+            model.split_once('/').is_some() && embed_model.split_once('/').is_some()
+        })
+        .map(|(model, embed_model)| (model.to_string(), embed_model.to_string()))
+        .ok_or_else(|| anyhow!("CACHE SEMANTIC {value}: EXPECTED kind/name=embed_kind/embed_name"))
+}
+
+/// Key of a deterministic request, `None` when its response can't be reused.
+pub fn request_key(invoke_request: &InvokeRequest) -> Option<String> {
+    match &invoke_request.data {
+        ActorInvokeData::ChatCompletion(r) if r.temperature == Some(0.0) => {}
+        ActorInvokeData::Completion(r) if r.temperature == Some(0.0) => {}
+        _ => return None,
+    }
+
+    let mut data = serde_json::to_value(&invoke_request.data).ok()?;
+    if let Some(request) = data.as_object_mut().and_then(|d| d.values_mut().next()) {
+        if let Some(fields) = request.as_object_mut() {
+            for field in IGNORED_FIELDS {
+                fields.remove(field);
+            }
+        }
+    }
+
+    let config = serde_json::to_value(&invoke_request.config).ok()?;
+    Some(format!(
+        "{}|{}",
+        canonical_json(&data),
+        canonical_json(&config)
+    ))
+}
+
+/// Key of a single embeddings input.
+pub fn embedding_key(input: &EntityValue, config: &HashMap<String, EntityValue>) -> Option<String> {
+    let input = serde_json::to_value(input).ok()?;
+    let config = serde_json::to_value(config).ok()?;
+    Some(format!(
+        "embed|{}|{}",
+        canonical_json(&input),
+        canonical_json(&config)
+    ))
+}
+
+/// Serializes a value with sorted object keys, so equal requests give equal keys.
+fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            let fields = keys
+                .into_iter()
+                .map(|k| format!("{}:{}", Value::String(k.clone()), canonical_json(&map[k])))
+                .collect::<Vec<String>>();
+            format!("{{{}}}", fields.join(","))
+        }
+        Value::Array(items) => {
+            let items = items.iter().map(canonical_json).collect::<Vec<String>>();
+            format!("[{}]", items.join(","))
+        }
+        _ => value.to_string(),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_restore_counts_persisted_entries() {
+        let dir = std::env::temp_dir().join(format!("cache-{}", uuid::Uuid::new_v4()));
+        let config = MainActorCacheConfig {
+            path: Some(dir.to_string_lossy().to_string()),
+            max_bytes: Some(64),
+            ..Default::default()
+        };
+        let data = |value: &str| HashMap::from([(value.to_string(), vec![])]);
+        let cache = ResponseCache::new(config.clone());
+        let persist = |model: Option<&str>, key: &str, expires_at: u64| {
+            let path = cache.entry_path("e5/small", key).unwrap();
+            let entry = PersistedEntry {
+                model: model.map(String::from),
+                key: key.to_string(),
+                expires_at,
+                data: data(&format!("{key:0>20}")),
+            };
+            write_entry(&path, &entry).unwrap();
+        };
+        let expires_at = unix_now() + 60;
+        for key in ["a", "b", "c"] {
+            persist(Some("e5/small"), key, expires_at);
+        }
+        persist(Some("e5/small"), "expired", unix_now() - 1);
+        persist(None, "legacy", expires_at);
+
+        let cache = ResponseCache::new(config);
+        let files = std::fs::read_dir(cache.model_dir("e5/small").unwrap())
+            .unwrap()
+            .count();
+        let cached = cache.models.lock().unwrap()["e5/small"].len();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(cached, 2);
+        assert_eq!(files, 2);
+    }
+
+    #[test]
+    fn test_restore_moves_entries_with_stale_names() {
+        let dir = std::env::temp_dir().join(format!("cache-{}", uuid::Uuid::new_v4()));
+        let config = MainActorCacheConfig {
+            path: Some(dir.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let cache = ResponseCache::new(config.clone());
+        let path = cache.entry_path("e5/small", "a").unwrap();
+        assert_eq!(
+            path.file_name().unwrap(),
+            "ca978112ca1bbdcafac231b39a23dc4d.json"
+        );
+        let entry = PersistedEntry {
+            model: Some("e5/small".to_string()),
+            key: "a".to_string(),
+            expires_at: unix_now() + 60,
+            data: HashMap::new(),
+        };
+        write_entry(&path.with_file_name("0123456789abcdef.json"), &entry).unwrap();
+
+        let cache = ResponseCache::new(config);
+        let moved = path.exists();
+        let cached = cache.models.lock().unwrap()["e5/small"].len();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(moved);
+        assert_eq!(cached, 1);
+    }
+
+    #[test]
+    fn test_parse_model_config() {
+        let (model, config) =
+            parse_model_config("quantized/mistral7b=ttl:60, max_bytes:1024,enabled:false").unwrap();
+        assert_eq!(model, "quantized/mistral7b");
+        assert_eq!(config.ttl, Some(60));
+        assert_eq!(config.max_bytes, Some(1024));
+        assert_eq!(config.enabled, Some(false));
+
+        assert!(parse_model_config("mistral7b=ttl:60").is_err());
+        assert!(parse_model_config("quantized/mistral7b=ttl:soon").is_err());
+        assert!(parse_model_config("quantized/mistral7b=size:1").is_err());
+    }
+
+    #[test]
+    fn test_parse_semantic_config() {
+        assert_eq!(
+            parse_semantic_config("quantized/mistral7b=e5/e5").unwrap(),
+            ("quantized/mistral7b".to_string(), "e5/e5".to_string())
+        );
+        assert!(parse_semantic_config("quantized/mistral7b").is_err());
+        assert!(parse_semantic_config("mistral7b=e5/e5").is_err());
+        assert!(parse_semantic_config("quantized/mistral7b=e5").is_err());
+    }
+
+    #[test]
+    fn test_canonical_json_ignores_key_order() {
+        let a = json!({"b": 1, "a": {"y": [1, 2], "x": "s"}});
+        let b = json!({"a": {"x": "s", "y": [1, 2]}, "b": 1});
+        assert_eq!(canonical_json(&a), canonical_json(&b));
+        assert_ne!(canonical_json(&a), canonical_json(&json!({"b": 2})));
+    }
+}
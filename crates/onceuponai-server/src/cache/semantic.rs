@@ -0,0 +1,144 @@
+use super::CachedData;
+use either::Either;
+use onceuponai_actors::abstractions::openai::ChatCompletionRequest;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+struct SemanticEntry {
+    embedding: Vec<f32>,
+    data: CachedData,
+    expires: Instant,
+}
+
+/// Brute force cosine similarity index of previous prompts and their answers.
+pub struct SemanticIndex {
+    entries: VecDeque<SemanticEntry>,
+    max_entries: usize,
+}
+
+impl SemanticIndex {
+    pub fn new(max_entries: usize) -> Self {
+        SemanticIndex {
+            entries: VecDeque::new(),
+            max_entries,
+        }
+    }
+
+    /// Returns the answer of the most similar prompt with its similarity,
+    /// if the similarity reaches the threshold.
+    pub fn search(&mut self, embedding: &[f32], threshold: f32) -> Option<(f32, CachedData)> {
+        let now = Instant::now();
+        self.entries.retain(|e| e.expires > now);
+
+        self.entries
+            .iter()
+            .map(|e| (cosine_similarity(embedding, &e.embedding), e))
+            .filter(|(similarity, _)| *similarity >= threshold)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(similarity, e)| (similarity, e.data.clone()))
+    }
+
+    /// Adds a prompt, the oldest one is dropped when the index is full.
+    pub fn insert(&mut self, embedding: Vec<f32>, data: CachedData, ttl: Duration) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        while self.entries.len() >= self.max_entries {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(SemanticEntry {
+            embedding,
+            data,
+            expires: Instant::now() + ttl,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
+
+/// Text of the last user message, which is what the semantic cache compares.
+pub fn last_user_message(request: &ChatCompletionRequest) -> Option<String> {
+    let messages = match &request.messages {
+        Either::Left(messages) => messages,
+        Either::Right(prompt) => return Some(prompt.clone()),
+    };
+
+    let message = messages.iter().rev().find(|m| m.role == "user")?;
+    let text = match &*message.content {
+        Either::Left(text) => text.clone(),
+        Either::Right(parts) => parts
+            .iter()
+            .filter_map(|part| match &**part.get("text")? {
+                Either::Left(text) => Some(text.clone()),
+                Either::Right(_) => None,
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+    };
+
+    (!text.trim().is_empty()).then_some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn answer(text: &str) -> CachedData {
+        HashMap::from([(
+            "content".to_string(),
+            vec![onceuponai_abstractions::EntityValue::STRING(
+                text.to_string(),
+            )],
+        )])
+    }
+
+    #[test]
+    fn test_search_returns_most_similar_above_threshold() {
+        let mut index = SemanticIndex::new(10);
+        index.insert(vec![1.0, 0.0], answer("a"), TTL);
+        index.insert(vec![0.8, 0.6], answer("b"), TTL);
+
+        let (similarity, data) = index.search(&[0.9, 0.5], 0.9).unwrap();
+        assert!(similarity > 0.99);
+        assert_eq!(format!("{data:?}"), format!("{:?}", answer("b")));
+        assert!(index.search(&[0.0, 1.0], 0.9).is_none());
+    }
+
+    #[test]
+    fn test_index_drops_oldest_and_expired() {
+        let mut index = SemanticIndex::new(2);
+        index.insert(vec![1.0, 0.0], answer("a"), TTL);
+        index.insert(vec![0.0, 1.0], answer("b"), TTL);
+        index.insert(vec![1.0, 1.0], answer("c"), Duration::ZERO);
+        assert_eq!(index.len(), 2);
+        assert!(index.search(&[1.0, 0.0], 0.9).is_none());
+        assert!(index.search(&[0.0, 1.0], 0.9).is_some());
+        assert_eq!(index.len(), 1);
+    }
+}
@@ -1,26 +1,59 @@
 use actix_session::SessionExt;
 use actix_web::guard::Guard;
+use std::sync::Arc;
 
 use crate::handlers::auth::verify_pat_token;
+use crate::roles::{UserRoles, ROLES_SESSION_KEY};
+use crate::scopes::Scopes;
+use crate::session::SessionExt as _;
+use crate::tokens::PersonalTokenStore;
+use onceuponai_actors::actors::main_actor::MainActorRolesConfig;
 
 #[derive(Clone)]
 pub struct AuthGuard {
     pub secret: String,
+    pub tokens: Arc<PersonalTokenStore>,
+    pub roles: Option<MainActorRolesConfig>,
 }
 
 impl Guard for AuthGuard {
     fn check(&self, ctx: &actix_web::guard::GuardContext<'_>) -> bool {
         let session = ctx.get_session();
         if let Ok(Some(_email)) = session.get::<String>("EMAIL") {
-            return true;
+            // Sessions with a refresh token are refreshed before they expire, see
+            // `refresh_expiring_sessions`.
+            if session.is_expired() {
+                return false;
+            }
+            if self.roles.is_none() {
+                return true;
+            }
+            // Sessions from before the roles were configured have none and log in again.
+            let head = ctx.head();
+            return session
+                .get::<UserRoles>(ROLES_SESSION_KEY)
+                .ok()
+                .flatten()
+                .is_some_and(|roles| {
+                    Scopes::for_roles(&roles).allows_route(&head.method, head.uri.path())
+                });
         }
 
         let auth_header = ctx.head().headers().get("authorization");
         if let Some(pat_token) = auth_header {
             if let Ok(pat) = pat_token.to_str() {
                 let pat = pat.replace("Bearer ", "");
-                if verify_pat_token(&pat, &self.secret).is_ok() {
-                    return true;
+                if let Ok(token) = verify_pat_token(&pat, &self.secret) {
+                    let Ok(scopes) = Scopes::parse(token.claims.scopes.as_deref()) else {
+                        return false;
+                    };
+                    let head = ctx.head();
+                    // Only tokens recorded in the store can be revoked, so others are rejected.
+                    return scopes.allows_route(&head.method, head.uri.path())
+                        && token
+                            .claims
+                            .jti
+                            .is_some_and(|jti| self.tokens.is_active(&jti));
                 }
             }
         }
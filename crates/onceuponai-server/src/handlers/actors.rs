@@ -1,24 +1,105 @@
-use crate::models::InvokeRequest;
+use crate::audit::{estimate_tokens, request_subject, AuditLog, AuditRecord, AuditRequest};
+use crate::cache::semantic::last_user_message;
+use crate::cache::{
+    embedding_key, request_key, CachePolicy, CachedData, ResponseCache, CACHE_SIMILARITY_HEADER,
+    CACHE_STATUS_HEADER,
+};
+use crate::models::{
+    ActorModelRequest, DrainRequest, InvokeRequest, ModelAction, SpawnActorRequest,
+};
+use crate::roles::{request_roles, Role, UserRoles};
+use crate::scopes::Scopes;
 use crate::serve::AppState;
+use crate::spawn::gallery::ACTORS_GALLERY;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
 use actix_web::Responder;
 use actix_web::{web, HttpRequest, HttpResponse};
 use anyhow::Result;
 use futures::stream::Stream;
 use futures::task::{Context, Poll};
-use log::info;
-use onceuponai_actors::abstractions::ActorInvokeResponse;
-use onceuponai_actors::actors::main_actor::{InvokeTask, CONNECTED_ACTORS, INVOKE_TASKS};
-use onceuponai_actors::actors::ActorStartInvokeRequest;
+use log::{info, warn};
+use onceuponai_abstractions::EntityValue;
+use onceuponai_actors::abstractions::{
+    ActorError, ActorInvokeData, ActorInvokeResponse, ActorInvokeResult,
+};
+use onceuponai_actors::actors::main_actor::{
+    DrainActor, InvokeTask, StartInvoke, SwapActorModel, CONNECTED_ACTORS, INVOKE_TASKS,
+    ROOT_SUBJECT, ROUTE_STATS,
+};
+use onceuponai_actors::actors::pipeline::PIPELINE_KIND;
+use onceuponai_actors::actors::{ActorStartInvokeRequest, ModelCommand};
+use onceuponai_actors::telemetry::{self, TraceContext, TRACE_HEADERS};
 use onceuponai_core::common::ResultExt;
+use opentelemetry::trace::SpanKind;
+use opentelemetry::KeyValue;
 use serde_json::json;
+use std::collections::HashMap;
 use std::error::Error;
 use std::pin::Pin;
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
-const ACTORS_GALLERY: &str = include_str!("../../actors_gallery.yaml");
+const SESSION_ID_HEADER: &str = "x-session-id";
+const EMBEDDINGS: &str = "embeddings";
+const CACHE_HIT: &str = "HIT";
+const CACHE_MISS: &str = "MISS";
+const CACHE_PARTIAL: &str = "PARTIAL";
+const CACHE_BYPASS: &str = "BYPASS";
+
+/// Request scoped data which is not part of the invoke payload.
+#[derive(Debug, Clone, Default)]
+pub struct InvokeContext {
+    pub session_key: Option<String>,
+    pub cache_policy: CachePolicy,
+    /// Context of the span of the request, continues the trace of the caller.
+    pub trace: opentelemetry::Context,
+    /// Caller of the request, for the audit log.
+    pub audit: AuditRequest,
+    /// Scopes of the caller token, checked against the invoked model.
+    pub scopes: Scopes,
+    /// Roles of the OIDC user, checked against the model access lists. The root and the
+    /// admins get the admin role when their session or token carries no roles.
+    pub roles: Option<UserRoles>,
+}
+
+impl InvokeContext {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let session_key = req
+            .headers()
+            .get(SESSION_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let app_state = req.app_data::<web::Data<AppState>>();
+        let pat_secret =
+            app_state.and_then(|app_state| app_state.spec.personal_access_token_secret.clone());
+        let roles = request_roles(req, pat_secret.as_deref()).or_else(|| {
+            let (_, subject) = request_subject(req, pat_secret.as_deref())?;
+            let is_admin = subject == ROOT_SUBJECT || app_state?.spec.is_admin(&subject);
+            is_admin.then(|| UserRoles {
+                role: Role::Admin,
+                groups: vec![],
+            })
+        });
+        let carrier: TraceContext = TRACE_HEADERS
+            .iter()
+            .filter_map(|header| {
+                let value = req.headers().get(*header)?.to_str().ok()?;
+                Some((header.to_string(), value.to_string()))
+            })
+            .collect();
+        InvokeContext {
+            session_key,
+            cache_policy: CachePolicy::from_request(req),
+            trace: telemetry::extract(&carrier),
+            audit: AuditRequest::from_request(req, pat_secret.as_deref()),
+            scopes: Scopes::of_request(req, pat_secret.as_deref()),
+            roles,
+        }
+    }
+}
 
 pub async fn actors_gallery() -> impl Responder {
     HttpResponse::Ok()
@@ -37,6 +118,89 @@ pub async fn connected_actors(_req: HttpRequest) -> Result<impl Responder, Box<d
     Ok(HttpResponse::Ok().json(keys.clone()))
 }
 
+pub async fn drain_actor(
+    req: HttpRequest,
+    drain_request: web::Query<DrainRequest>,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let uuid = req.match_info().get("uuid").expect("UUID");
+    let uuid = Uuid::parse_str(uuid).map_box_err()?;
+
+    let connected = app_state
+        .addr
+        .send(DrainActor {
+            uuid,
+            grace_period: drain_request.grace_period,
+        })
+        .await
+        .map_box_err()?;
+
+    if connected {
+        Ok(HttpResponse::Accepted().json(json!({ "uuid": uuid, "draining": true })))
+    } else {
+        Ok(HttpResponse::NotFound().body(format!("ACTOR {uuid} NOT CONNECTED")))
+    }
+}
+
+pub async fn spawn_actor(
+    spawn_request: web::Json<SpawnActorRequest>,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let Some(spawner) = &app_state.spawner else {
+        return Ok(HttpResponse::NotFound().body("SPAWNING WORKERS NOT ENABLED"));
+    };
+
+    match spawner.spawn(spawn_request.into_inner()) {
+        Ok(worker) => Ok(HttpResponse::Created().json(worker)),
+        Err(e) => {
+            warn!("SPAWN WORKER: {e}");
+            Ok(HttpResponse::BadRequest().body(e.to_string()))
+        }
+    }
+}
+
+pub async fn spawned_actors(
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let workers = app_state
+        .spawner
+        .as_ref()
+        .map(|spawner| spawner.list())
+        .unwrap_or_default();
+    Ok(HttpResponse::Ok().json(workers))
+}
+
+pub async fn kill_actor(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let sidecar_id = req.match_info().get("sidecar_id").expect("SIDECAR_ID");
+    let sidecar_id = Uuid::parse_str(sidecar_id).map_box_err()?;
+
+    match app_state
+        .spawner
+        .as_ref()
+        .and_then(|spawner| spawner.kill(&sidecar_id))
+    {
+        Some(worker) => Ok(HttpResponse::Ok().json(worker)),
+        None => Ok(HttpResponse::NotFound().body(format!("WORKER {sidecar_id} NOT SPAWNED"))),
+    }
+}
+
+pub async fn routes(app_state: web::Data<AppState>) -> Result<impl Responder, Box<dyn Error>> {
+    let stats = ROUTE_STATS
+        .get()
+        .expect("ROUTE_STATS")
+        .lock()
+        .map_box_err()?
+        .clone();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "routes": app_state.spec.routes.clone().unwrap_or_default(),
+        "stats": stats,
+    })))
+}
+
 pub async fn invoke(
     req: HttpRequest,
     invoke_request: web::Json<InvokeRequest>,
@@ -56,134 +220,766 @@ pub async fn invoke(
         .to_string()
         .to_lowercase();
 
-    base_invoke(kind, name, app_state, invoke_request.clone(), Mappers::Base).await
+    let context = InvokeContext::from_request(&req);
+    base_invoke(
+        kind,
+        name,
+        app_state,
+        context,
+        invoke_request.clone(),
+        Mappers::Base,
+    )
+    .await
+}
+
+pub async fn invalidate_cache(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let kind = req.match_info().get("kind").expect("KIND").to_lowercase();
+    let name = req.match_info().get("name").expect("NAME").to_lowercase();
+
+    match &app_state.cache {
+        Some(cache) => {
+            cache.invalidate(&format!("{kind}/{name}")).await;
+            Ok(HttpResponse::NoContent().finish())
+        }
+        None => Ok(HttpResponse::NotFound().body("CACHE NOT ENABLED")),
+    }
 }
 
 pub async fn base_invoke(
     kind: String,
     name: String,
     app_state: web::Data<AppState>,
+    mut context: InvokeContext,
     invoke_request: InvokeRequest,
-    mut mapper: Mappers,
+    mapper: Mappers,
 ) -> Result<impl Responder, Box<dyn Error>> {
-    let kind_connected = CONNECTED_ACTORS
+    let model = format!("{kind}/{name}");
+    // Ends once the response is built, or with the stream.
+    context.trace = telemetry::start(
+        &context.trace,
+        "server.invoke",
+        SpanKind::Server,
+        vec![
+            KeyValue::new("model", model.clone()),
+            KeyValue::new("stream", invoke_request.stream.unwrap_or_default()),
+        ],
+    );
+
+    let Some(features) = model_features(&app_state, &kind, &name) else {
+        let error = format!("ACTOR WITH KIND: {kind:?} NAME: {name:?} NOT CONNECTED");
+        let mut record = context
+            .audit
+            .record(&model, invoke_request.stream.unwrap_or_default());
+        record.status = StatusCode::NOT_FOUND.as_u16();
+        record.error = Some(error.clone());
+        finish_invoke(&app_state, &context.trace, record);
+        return Ok(HttpResponse::NotFound().body(error));
+    };
+
+    // The OpenAI routes name the operation, the generic route runs any feature of the model.
+    let features = match (&mapper, &invoke_request.data) {
+        (Mappers::OaiChatCompletions, _) | (_, ActorInvokeData::ChatCompletion(_)) => {
+            vec!["chat".to_string()]
+        }
+        (Mappers::OaiEmbeddings, _) => vec!["embed".to_string()],
+        _ => features,
+    };
+    // Routes and pipelines run other models, the caller must be allowed on each of them.
+    let targets = app_state.spec.invoke_targets(&model);
+    let denied = match targets
+        .iter()
+        .find(|target| !context.scopes.allows_invoke(target, &features))
+    {
+        Some(target) => Some(format!("TOKEN SCOPES DO NOT ALLOW INVOKING {target}")),
+        None => match (app_state.spec.roles(), &context.roles) {
+            (Some(_), None) => Some(format!("NO ROLES TO INVOKE {model}")),
+            (Some(config), Some(roles)) => targets
+                .iter()
+                .find(|target| !roles.allows_model(config, target))
+                .map(|target| format!("ROLES DO NOT ALLOW INVOKING {target}")),
+            _ => None,
+        },
+    };
+    if let Some(error) = denied {
+        let mut record = context
+            .audit
+            .record(&model, invoke_request.stream.unwrap_or_default());
+        record.status = StatusCode::FORBIDDEN.as_u16();
+        record.error = Some(error.clone());
+        finish_invoke(&app_state, &context.trace, record);
+        return Ok(HttpResponse::Forbidden().body(error));
+    }
+
+    if invoke_request.stream.unwrap_or_default() {
+        let (task_id, rx) = start_task(
+            &app_state,
+            &kind,
+            &name,
+            &context,
+            true,
+            invoke_request.config.clone(),
+            invoke_request.data.clone(),
+        )?;
+        let audit = app_state.audit.clone().map(|audit| {
+            let mut record = context.audit.record(&model, true);
+            record.task_id = Some(task_id);
+            record.prompt_tokens = Some(estimate_tokens(&json!(invoke_request.data)));
+            record.completion_tokens = Some(0);
+            record.prompt = audit.capture(&invoke_request.data);
+            StreamAudit {
+                audit,
+                record,
+                chunks: vec![],
+                finished: false,
+            }
+        });
+        let stream = MpscStream {
+            reqeust: invoke_request,
+            receiver: rx,
+            task_id,
+            mapper,
+            trace: context.trace,
+            span_ended: false,
+            audit,
+        };
+        return Ok(HttpResponse::Ok().streaming(stream));
+    }
+
+    let cache = app_state.cache.clone().filter(|c| c.is_enabled(&model));
+    let Some(cache) = cache else {
+        let response = invoke_once(&app_state, &kind, &name, &context, &invoke_request).await?;
+        return Ok(invoke_response(
+            &app_state,
+            &context,
+            &model,
+            response,
+            invoke_request,
+            mapper,
+            None,
+        ));
+    };
+
+    if context.cache_policy == CachePolicy::Bypass {
+        let response = invoke_once(&app_state, &kind, &name, &context, &invoke_request).await?;
+        return Ok(invoke_response(
+            &app_state,
+            &context,
+            &model,
+            response,
+            invoke_request,
+            mapper,
+            Some(CACHE_BYPASS),
+        ));
+    }
+
+    if let Some(inputs) = embeddings_input(&kind, &name, &invoke_request) {
+        return invoke_embeddings(
+            &app_state,
+            &kind,
+            &name,
+            &context,
+            &cache,
+            inputs,
+            invoke_request,
+            mapper,
+        )
+        .await;
+    }
+
+    let key = request_key(&invoke_request);
+    let cached = match (&key, context.cache_policy) {
+        (Some(key), CachePolicy::Default) => cache.get(&model, key).await,
+        _ => None,
+    };
+    if let Some(data) = cached {
+        let result = cached_result(data);
+        return Ok(invoke_response(
+            &app_state,
+            &context,
+            &model,
+            Some(ActorInvokeResponse::Success(result)),
+            invoke_request,
+            mapper,
+            Some(CACHE_HIT),
+        ));
+    }
+
+    let embedding = match semantic_prompt(&cache, &model, &invoke_request) {
+        Some((embed_model, prompt)) => {
+            embed_prompt(&app_state, &context, &embed_model, prompt).await
+        }
+        None => None,
+    };
+
+    if context.cache_policy == CachePolicy::Default {
+        if let Some((similarity, data)) = embedding
+            .as_ref()
+            .and_then(|embedding| cache.semantic_get(&model, embedding))
+        {
+            let result = cached_result(data);
+            let mut response = invoke_response(
+                &app_state,
+                &context,
+                &model,
+                Some(ActorInvokeResponse::Success(result)),
+                invoke_request,
+                mapper,
+                Some(CACHE_HIT),
+            );
+            response.headers_mut().insert(
+                HeaderName::from_static(CACHE_SIMILARITY_HEADER),
+                HeaderValue::from_str(&format!("{similarity:.4}"))?,
+            );
+            return Ok(response);
+        }
+    }
+
+    let cache_status = if key.is_some() || embedding.is_some() {
+        CACHE_MISS
+    } else {
+        CACHE_BYPASS
+    };
+
+    let response = invoke_once(&app_state, &kind, &name, &context, &invoke_request).await?;
+    if let Some(ActorInvokeResponse::Success(result)) = &response {
+        if let Some(key) = &key {
+            cache.put(&model, key, result.data.clone()).await;
+        }
+        if let Some(embedding) = embedding {
+            cache.semantic_put(&model, embedding, result.data.clone());
+        }
+    }
+    Ok(invoke_response(
+        &app_state,
+        &context,
+        &model,
+        response,
+        invoke_request,
+        mapper,
+        Some(cache_status),
+    ))
+}
+
+/// Embed model and prompt of a chat request when the model has a semantic cache.
+fn semantic_prompt(
+    cache: &ResponseCache,
+    model: &str,
+    invoke_request: &InvokeRequest,
+) -> Option<(String, String)> {
+    let config = cache.semantic_config(model)?;
+    let ActorInvokeData::ChatCompletion(request) = &invoke_request.data else {
+        return None;
+    };
+    let prompt = last_user_message(request)?;
+    Some((config.embed_model.clone(), prompt))
+}
+
+/// Embeds a prompt with an embed actor, `None` when it isn't available.
+async fn embed_prompt(
+    app_state: &AppState,
+    context: &InvokeContext,
+    embed_model: &str,
+    prompt: String,
+) -> Option<Vec<f32>> {
+    let (kind, name) = embed_model.split_once('/')?;
+    let embed_request = InvokeRequest {
+        config: HashMap::new(),
+        data: ActorInvokeData::Entity(HashMap::from([(
+            "input".to_string(),
+            vec![EntityValue::STRING(prompt)],
+        )])),
+        stream: Some(false),
+    };
+
+    let response = invoke_once(
+        app_state,
+        kind,
+        name,
+        &InvokeContext {
+            trace: context.trace.clone(),
+            ..Default::default()
+        },
+        &embed_request,
+    )
+    .await
+    .ok()?;
+
+    let Some(ActorInvokeResponse::Success(result)) = response else {
+        warn!("SEMANTIC CACHE EMBED MODEL {embed_model:?} NOT AVAILABLE");
+        return None;
+    };
+
+    match result.data.get(EMBEDDINGS)?.first()? {
+        EntityValue::FLOAT32ARRAY(v) => Some(v.clone()),
+        EntityValue::FLOAT64ARRAY(v) => Some(v.iter().map(|x| *x as f32).collect()),
+        _ => None,
+    }
+}
+
+/// Inputs of an embeddings request, these are cached one by one.
+fn embeddings_input(
+    kind: &str,
+    name: &str,
+    invoke_request: &InvokeRequest,
+) -> Option<Vec<EntityValue>> {
+    let ActorInvokeData::Entity(entity) = &invoke_request.data else {
+        return None;
+    };
+    if entity.len() != 1 {
+        return None;
+    }
+    let input = entity.get("input")?;
+
+    let embeds = CONNECTED_ACTORS
         .get()
-        .expect("CONNECTED_MODELS")
+        .expect("CONNECTED_ACTORS")
         .lock()
-        .unwrap()
+        .ok()?
+        .values()
+        .any(|a| {
+            a.kind == kind
+                && a.metadata.name == name
+                && a.metadata
+                    .features
+                    .as_ref()
+                    .is_some_and(|f| f.iter().any(|f| f == "embed"))
+        });
+
+    embeds.then(|| input.clone())
+}
+
+/// Answers cached inputs from the cache and sends only the misses to the actor.
+#[allow(clippy::too_many_arguments)]
+async fn invoke_embeddings(
+    app_state: &AppState,
+    kind: &str,
+    name: &str,
+    context: &InvokeContext,
+    cache: &ResponseCache,
+    inputs: Vec<EntityValue>,
+    invoke_request: InvokeRequest,
+    mapper: Mappers,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let model = format!("{kind}/{name}");
+    let keys: Vec<Option<String>> = inputs
         .iter()
-        .any(|a| a.1.kind == kind && a.1.metadata.name == name);
+        .map(|input| embedding_key(input, &invoke_request.config))
+        .collect();
 
-    if !kind_connected {
-        return Ok(HttpResponse::NotFound().body(format!(
-            "ACTOR WITH KIND: {kind:?} NAME: {name:?} NOT CONNECTED"
-        )));
+    let mut embeddings: Vec<Option<EntityValue>> = Vec::with_capacity(keys.len());
+    for key in &keys {
+        let embedding = match (context.cache_policy, key) {
+            (CachePolicy::Default, Some(key)) => cache
+                .get(&model, key)
+                .await
+                .and_then(|data| data.get(EMBEDDINGS)?.first().cloned()),
+            _ => None,
+        };
+        embeddings.push(embedding);
     }
 
+    let misses: Vec<usize> = (0..inputs.len())
+        .filter(|ix| embeddings[*ix].is_none())
+        .collect();
+
+    let mut result = cached_result(HashMap::new());
+    if !misses.is_empty() {
+        let misses_request = InvokeRequest {
+            config: invoke_request.config.clone(),
+            data: ActorInvokeData::Entity(HashMap::from([(
+                "input".to_string(),
+                misses.iter().map(|ix| inputs[*ix].clone()).collect(),
+            )])),
+            stream: Some(false),
+        };
+
+        let response = invoke_once(app_state, kind, name, context, &misses_request).await?;
+        let Some(ActorInvokeResponse::Success(misses_result)) = response else {
+            return Ok(invoke_response(
+                app_state,
+                context,
+                &model,
+                response,
+                invoke_request,
+                mapper,
+                Some(CACHE_MISS),
+            ));
+        };
+
+        let computed = misses_result
+            .data
+            .get(EMBEDDINGS)
+            .cloned()
+            .unwrap_or_default();
+        if computed.len() != misses.len() {
+            let error = format!(
+                "EXPECTED {} EMBEDDINGS GOT {}",
+                misses.len(),
+                computed.len()
+            );
+            let mut record = context.audit.record(&model, false);
+            record.task_id = Some(misses_result.task_id);
+            record.status = StatusCode::INTERNAL_SERVER_ERROR.as_u16();
+            record.error = Some(error.clone());
+            finish_invoke(app_state, &context.trace, record);
+            return Ok(HttpResponse::InternalServerError().body(error));
+        }
+
+        for (ix, embedding) in misses.iter().zip(computed) {
+            if let Some(key) = &keys[*ix] {
+                let data = HashMap::from([(EMBEDDINGS.to_string(), vec![embedding.clone()])]);
+                cache.put(&model, key, data).await;
+            }
+            embeddings[*ix] = Some(embedding);
+        }
+
+        result.uuid = misses_result.uuid;
+        result.task_id = misses_result.task_id;
+    }
+
+    let status = if misses.is_empty() {
+        CACHE_HIT
+    } else if misses.len() == inputs.len() {
+        CACHE_MISS
+    } else {
+        CACHE_PARTIAL
+    };
+
+    result.data = HashMap::from([(
+        EMBEDDINGS.to_string(),
+        embeddings.into_iter().flatten().collect(),
+    )]);
+    Ok(invoke_response(
+        app_state,
+        context,
+        &model,
+        Some(ActorInvokeResponse::Success(result)),
+        invoke_request,
+        mapper,
+        Some(status),
+    ))
+}
+
+fn cached_result(data: CachedData) -> ActorInvokeResult {
+    ActorInvokeResult {
+        uuid: Uuid::nil(),
+        task_id: Uuid::new_v4(),
+        stream: false,
+        metadata: HashMap::new(),
+        data,
+    }
+}
+
+fn start_task(
+    app_state: &AppState,
+    kind: &str,
+    name: &str,
+    context: &InvokeContext,
+    stream: bool,
+    config: HashMap<String, EntityValue>,
+    data: ActorInvokeData,
+) -> Result<(Uuid, mpsc::Receiver<ActorInvokeResponse>), Box<dyn Error>> {
     let task_id = Uuid::new_v4();
-    let (tx, rx) = mpsc::channel();
+    let (task, rx) = InvokeTask::new();
 
     {
-        let mut response_map = INVOKE_TASKS.get().expect("INVOKE_TASKS").lock()?;
-        response_map.insert(
-            task_id,
-            InvokeTask {
-                time: Instant::now(),
-                sender: tx,
-            },
-        );
+        let mut response_map = INVOKE_TASKS
+            .get()
+            .expect("INVOKE_TASKS")
+            .lock()
+            .map_box_err()?;
+        response_map.insert(task_id, task);
     }
 
-    let stream = invoke_request.stream.unwrap_or_default();
-
-    app_state.addr.do_send(ActorStartInvokeRequest {
+    app_state.addr.do_send(StartInvoke(ActorStartInvokeRequest {
         task_id,
+        kind: kind.to_string(),
+        name: name.to_string(),
+        stream,
+        config,
+        data,
+        session_key: context.session_key.clone(),
+        reply_to: None,
+        trace_context: telemetry::inject(&context.trace),
+        subject: context.audit.subject.clone(),
+    }));
+
+    Ok((task_id, rx))
+}
+
+/// Sends a non streaming request and waits for its response, `None` on timeout.
+async fn invoke_once(
+    app_state: &AppState,
+    kind: &str,
+    name: &str,
+    context: &InvokeContext,
+    invoke_request: &InvokeRequest,
+) -> Result<Option<ActorInvokeResponse>, Box<dyn Error>> {
+    let (task_id, mut rx) = start_task(
+        app_state,
         kind,
         name,
-        stream,
-        config: invoke_request.config.clone(),
-        data: invoke_request.data.clone(),
-    });
-
-    if !stream {
-        let invoke_timeout = app_state.spec.invoke_timeout.unwrap_or(5u64);
-        match rx.recv_timeout(Duration::from_secs(invoke_timeout)) {
-            Ok(response) => {
-                remove_invoke_task(&task_id);
-                match response {
-                    ActorInvokeResponse::Success(result) => {
-                        Ok(HttpResponse::Ok().json(mapper.map(invoke_request, result)))
-                    }
-                    ActorInvokeResponse::Failure(result) => {
-                        Ok(HttpResponse::BadRequest().json(result.error))
-                    }
-                    ActorInvokeResponse::Finish(_) => Ok(HttpResponse::Ok().body("")),
+        context,
+        false,
+        invoke_request.config.clone(),
+        invoke_request.data.clone(),
+    )?;
+
+    let invoke_timeout = app_state.spec.invoke_timeout.unwrap_or(5u64);
+    let response = tokio::time::timeout(Duration::from_secs(invoke_timeout), rx.recv()).await;
+    remove_invoke_task(&task_id);
+    Ok(response.ok().flatten())
+}
+
+pub async fn actor_model(
+    req: HttpRequest,
+    model_request: web::Json<ActorModelRequest>,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let uuid = req.match_info().get("uuid").expect("UUID");
+    let uuid = Uuid::parse_str(uuid).map_box_err()?;
+
+    let model_request = model_request.into_inner();
+    let command = match (model_request.command, model_request.config) {
+        (ModelAction::Unload, _) => ModelCommand::Unload,
+        (ModelAction::Reload, _) => ModelCommand::Reload,
+        (ModelAction::Load, Some(config)) => ModelCommand::Load {
+            config: config.to_string(),
+        },
+        (ModelAction::Load, None) => {
+            return Ok(HttpResponse::BadRequest().body("CONFIG REQUIRED TO LOAD A MODEL"))
+        }
+    };
+
+    let connected = app_state
+        .addr
+        .send(SwapActorModel { uuid, command })
+        .await
+        .map_box_err()?;
+
+    if connected {
+        Ok(
+            HttpResponse::Accepted()
+                .json(json!({ "uuid": uuid, "command": model_request.command })),
+        )
+    } else {
+        Ok(HttpResponse::NotFound().body(format!("ACTOR {uuid} NOT CONNECTED")))
+    }
+}
+
+fn invoke_response(
+    app_state: &AppState,
+    context: &InvokeContext,
+    model: &str,
+    response: Option<ActorInvokeResponse>,
+    invoke_request: InvokeRequest,
+    mut mapper: Mappers,
+    cache_status: Option<&'static str>,
+) -> HttpResponse {
+    let mut record = context.audit.record(model, false);
+    record.task_id = response.as_ref().map(ActorInvokeResponse::task_id);
+    record.cache = cache_status;
+    if let Some(audit) = &app_state.audit {
+        record.prompt_tokens = Some(estimate_tokens(&json!(invoke_request.data)));
+        record.prompt = audit.capture(&invoke_request.data);
+    }
+
+    let mut http_response = match response {
+        Some(ActorInvokeResponse::Success(result)) => {
+            record.add_usage(&result);
+            let body = mapper.map(invoke_request, result);
+            if let Some(audit) = &app_state.audit {
+                record.response = audit.capture(&body);
+            }
+            HttpResponse::Ok().json(body)
+        }
+        Some(ActorInvokeResponse::Failure(result)) => {
+            record.error = Some(format!("{:?}", result.error));
+            match result.error {
+                ActorError::Overloaded(_) | ActorError::Draining(_) => {
+                    HttpResponse::ServiceUnavailable().json(result.error)
                 }
+                _ => HttpResponse::BadRequest().json(result.error),
             }
-            Err(_) => {
-                remove_invoke_task(&task_id);
-                Ok(HttpResponse::InternalServerError()
-                    .body(format!("Request timeout ( > {invoke_timeout:?} s)")))
+        }
+        Some(ActorInvokeResponse::Finish(_)) => HttpResponse::Ok().body(""),
+        None => {
+            let invoke_timeout = app_state.spec.invoke_timeout.unwrap_or(5u64);
+            record.error = Some("TIMEOUT".to_string());
+            HttpResponse::InternalServerError()
+                .body(format!("Request timeout ( > {invoke_timeout:?} s)"))
+        }
+    };
+
+    if let Some(status) = cache_status {
+        http_response.headers_mut().insert(
+            HeaderName::from_static(CACHE_STATUS_HEADER),
+            HeaderValue::from_static(status),
+        );
+    }
+    record.status = http_response.status().as_u16();
+    finish_invoke(app_state, &context.trace, record);
+    http_response
+}
+
+/// Features of a model, `None` when it is neither connected, routed nor a pipeline. A routed
+/// model has the features of its connected targets.
+fn model_features(app_state: &AppState, kind: &str, name: &str) -> Option<Vec<String>> {
+    if kind == PIPELINE_KIND {
+        return app_state
+            .spec
+            .pipeline(name)
+            .map(|_| vec![PIPELINE_KIND.to_string()]);
+    }
+
+    let model = format!("{kind}/{name}");
+    let mut models = vec![model.clone()];
+    let routed = app_state.spec.route(&model);
+    if let Some(route) = routed {
+        models.extend(route.targets.iter().map(|target| target.id()));
+    }
+
+    let connected_actors = CONNECTED_ACTORS
+        .get()
+        .expect("CONNECTED_MODELS")
+        .lock()
+        .unwrap();
+    let mut connected = false;
+    let mut features: Vec<String> = vec![];
+    for actor in connected_actors.values() {
+        if !models.contains(&format!("{}/{}", actor.kind, actor.metadata.name)) {
+            continue;
+        }
+        connected = true;
+        for feature in actor.metadata.features.iter().flatten() {
+            if !features.contains(feature) {
+                features.push(feature.clone());
             }
         }
-    } else {
-        let rx = Arc::new(Mutex::new(rx));
+    }
 
-        let stream = MpscStream {
-            reqeust: invoke_request,
-            receiver: rx,
-            task_id,
-            mapper,
-        };
-        Ok(HttpResponse::Ok().streaming(stream))
+    (connected || routed.is_some()).then_some(features)
+}
+
+/// Ends the span of a non streamed invocation, failed when the record has an error, and
+/// writes the audit record.
+fn finish_invoke(app_state: &AppState, trace: &opentelemetry::Context, record: AuditRecord) {
+    telemetry::end(trace, record.error.as_deref());
+    if let Some(audit) = &app_state.audit {
+        audit.write(record);
+    }
+}
+
+/// Audit record of a stream, written once the stream is dropped.
+struct StreamAudit {
+    audit: Arc<AuditLog>,
+    record: AuditRecord,
+    /// Captured chunks, only kept when payloads are captured.
+    chunks: Vec<serde_json::Value>,
+    finished: bool,
+}
+
+impl StreamAudit {
+    fn chunk(&mut self, chunk: &serde_json::Value) {
+        if self.record.first_chunk_ms.is_none() {
+            self.record.first_chunk_ms = Some(self.record.elapsed_ms());
+        }
+        if let Some(chunk) = self.audit.capture(chunk) {
+            self.chunks.push(chunk);
+        }
+    }
+}
+
+impl Drop for StreamAudit {
+    fn drop(&mut self) {
+        let mut record = self.record.clone();
+        if !self.finished && record.error.is_none() {
+            record.error = Some("STREAM INTERRUPTED".to_string());
+        }
+        if !self.chunks.is_empty() {
+            record.response = Some(serde_json::Value::Array(std::mem::take(&mut self.chunks)));
+        }
+        self.audit.write(record);
     }
 }
 
 struct MpscStream {
     reqeust: InvokeRequest,
-    receiver: Arc<Mutex<mpsc::Receiver<ActorInvokeResponse>>>,
+    receiver: mpsc::Receiver<ActorInvokeResponse>,
     task_id: Uuid,
     mapper: Mappers,
+    trace: opentelemetry::Context,
+    span_ended: bool,
+    audit: Option<StreamAudit>,
+}
+
+impl MpscStream {
+    /// Ends the server span once, failed when `error` is set.
+    fn end_span(&mut self, error: Option<&str>) {
+        if !self.span_ended {
+            self.span_ended = true;
+            telemetry::end(&self.trace, error);
+        }
+    }
 }
 
 impl Stream for MpscStream {
     type Item = Result<bytes::Bytes, actix_web::Error>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let receiver = self.receiver.lock().unwrap();
-        match receiver.try_recv() {
-            Ok(response) => match response {
+        let this = self.get_mut();
+        match this.receiver.poll_recv(cx) {
+            Poll::Ready(Some(response)) => match response {
                 ActorInvokeResponse::Success(result) => {
-                    let mut mapper = self.mapper.clone();
-                    let request = self.reqeust.clone();
-                    let text = mapper.map(request, result).to_string();
-                    let byte = bytes::Bytes::from(text);
+                    if let Some(audit) = &mut this.audit {
+                        audit.record.add_usage(&result);
+                    }
+                    let chunk = this.mapper.map(this.reqeust.clone(), result);
+                    if let Some(audit) = &mut this.audit {
+                        audit.chunk(&chunk);
+                    }
+                    let byte = bytes::Bytes::from(chunk.to_string());
                     Poll::Ready(Some(Ok(byte)))
                 }
                 ActorInvokeResponse::Failure(result) => {
                     let text = json!(result.error).to_string();
                     info!("ERROR {text:?}");
-                    remove_invoke_task(&self.task_id);
+                    this.end_span(Some(&text));
+                    if let Some(audit) = &mut this.audit {
+                        audit.record.error = Some(text);
+                        audit.finished = true;
+                    }
                     Poll::Ready(None)
                 }
                 ActorInvokeResponse::Finish(_) => {
-                    remove_invoke_task(&self.task_id);
+                    this.end_span(None);
+                    if let Some(audit) = &mut this.audit {
+                        audit.finished = true;
+                    }
                     Poll::Ready(None)
                 }
             },
-
-            Err(mpsc::TryRecvError::Empty) => {
-                cx.waker().wake_by_ref();
-                Poll::Pending
-            }
-            Err(mpsc::TryRecvError::Disconnected) => {
-                remove_invoke_task(&self.task_id);
+            // The task expired or was dropped before it finished.
+            Poll::Ready(None) => {
+                this.end_span(Some("STREAM INTERRUPTED"));
                 Poll::Ready(None)
             }
+            Poll::Pending => Poll::Pending,
         }
     }
 }
 
+impl Drop for MpscStream {
+    fn drop(&mut self) {
+        // Also covers clients that disconnect in the middle of a stream.
+        remove_invoke_task(&self.task_id);
+        self.end_span(Some("CLIENT DISCONNECTED"));
+    }
+}
+
 fn remove_invoke_task(task_id: &Uuid) {
     let mut response_map = INVOKE_TASKS.get().expect("INVOKE_TASKS").lock().unwrap();
     response_map.remove(task_id);
@@ -1,19 +1,31 @@
+use crate::audit::{request_subject, AuthMethod};
+use crate::handlers::tasks::TaskScope;
 use crate::models::{AuthCallback, PATClaims, PATRequest, PATResponse, TokenLogin};
+use crate::oidc::OidcProvider;
+use crate::roles::{jwt_claims, request_roles, UserRoles, ROLES_SESSION_KEY};
+use crate::scopes::Scopes;
 use crate::serve::AppState;
 use crate::session::SessionExt;
-use actix_web::HttpResponse;
+use crate::tokens::TokenGrant;
+use actix_session::SessionExt as _;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
 use actix_web::{web, Responder};
+use actix_web::{HttpRequest, HttpResponse};
 use anyhow::anyhow;
 use anyhow::Result;
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Utc};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, TokenData, Validation};
-use onceuponai_core::common::{Errors, OptionToResult};
-use openidconnect::core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata};
-use openidconnect::reqwest::http_client;
+use log::warn;
+use onceuponai_actors::actors::main_actor::TOKEN_LOGIN_SUBJECT;
+use onceuponai_core::common::{Errors, OptionToResult, ResultExt};
+use openidconnect::core::CoreAuthenticationFlow;
+use openidconnect::reqwest::async_http_client;
 use openidconnect::PkceCodeVerifier;
 use openidconnect::{
-    AccessTokenHash, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce,
-    PkceCodeChallenge, RedirectUrl,
+    AccessTokenHash, AuthorizationCode, ClientId, CsrfToken, LogoutRequest, Nonce,
+    PkceCodeChallenge, PostLogoutRedirectUrl, RefreshToken,
 };
 use openidconnect::{OAuth2TokenResponse, TokenResponse};
 use serde_json::json;
@@ -23,19 +35,11 @@ pub async fn auth(
     session: actix_session::Session,
     app_state: web::Data<AppState>,
 ) -> Result<impl Responder, Box<dyn Error>> {
-    let oidc = app_state.spec.oidc();
-    let provider_metadata =
-        CoreProviderMetadata::discover(&IssuerUrl::new(oidc.issuer_url.clone())?, http_client)?;
-
-    let client = CoreClient::from_provider_metadata(
-        provider_metadata,
-        ClientId::new(oidc.client_id.clone()),
-        Some(ClientSecret::new(oidc.client_secret.clone())),
-    )
-    .set_redirect_uri(RedirectUrl::new(oidc.redirect_url.clone())?);
+    let oidc = app_state.oidc.as_ref().ok_or_err("OIDC")?;
+    let (client, _) = oidc.client().await?;
 
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
-    let (auth_url, _csrf_token, nonce) = client
+    let (auth_url, csrf_token, nonce) = client
         .authorize_url(
             CoreAuthenticationFlow::AuthorizationCode,
             CsrfToken::new_random,
@@ -44,13 +48,9 @@ pub async fn auth(
         .set_pkce_challenge(pkce_challenge)
         .url();
 
-    // println!("Browse to: {}", auth_url);
-    // println!("PKCE: {}", pkce_verifier.secret());
-    // println!("NONCE: {}", nonce.secret());
-    // println!("CSRF: {}", csrf_token.secret());
-
     session.set_pkce(pkce_verifier.secret())?;
     session.set_nonce(nonce.secret())?;
+    session.set_state(csrf_token.secret())?;
 
     Ok(HttpResponse::Found()
         .append_header(("Location", auth_url.to_string()))
@@ -62,7 +62,13 @@ pub async fn auth_callback(
     session: actix_session::Session,
     app_state: web::Data<AppState>,
 ) -> Result<impl Responder, Box<dyn Error>> {
-    let oidc = app_state.spec.oidc();
+    let oidc = app_state.oidc.as_ref().ok_or_err("OIDC")?;
+
+    // The state is single use, a callback which was not started by this session is rejected.
+    let state = session.rm_state().ok();
+    if state.as_deref() != Some(request.state.as_str()) {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "Invalid state"})));
+    }
 
     let pkce = session.pkce()?;
     let nonce = session.nonce()?;
@@ -70,25 +76,21 @@ pub async fn auth_callback(
     let pkce_verifier = PkceCodeVerifier::new(pkce);
     let nonce = Nonce::new(nonce);
 
-    let provider_metadata =
-        CoreProviderMetadata::discover(&IssuerUrl::new(oidc.issuer_url.clone())?, http_client)?;
-
-    let client = CoreClient::from_provider_metadata(
-        provider_metadata,
-        ClientId::new(oidc.client_id.clone()),
-        Some(ClientSecret::new(oidc.client_secret.clone())),
-    )
-    .set_redirect_uri(RedirectUrl::new(oidc.redirect_url.clone())?);
+    let (client, _) = oidc.client().await?;
 
     let token_response = client
         .exchange_code(AuthorizationCode::new(request.code.clone()))
         .set_pkce_verifier(pkce_verifier)
-        .request(http_client)?;
+        .request_async(async_http_client)
+        .await?;
 
     let id_token = token_response
         .id_token()
         .ok_or_else(|| anyhow!("Server did not return an ID token"))?;
-    let claims = id_token.claims(&client.id_token_verifier(), &nonce)?;
+    // The provider may have rotated its keys, they are discovered again on the next login.
+    let claims = id_token
+        .claims(&client.id_token_verifier(), &nonce)
+        .inspect_err(|_| oidc.invalidate())?;
 
     if let Some(expected_access_token_hash) = claims.access_token_hash() {
         let actual_access_token_hash =
@@ -98,33 +100,189 @@ pub async fn auth_callback(
         }
     }
 
-    // println!(
-    //     "User {} with e-mail address {} has authenticated successfully",
-    //     claims.subject().as_str(),
-    //     claims
-    //         .email()
-    //         .map(|email| email.as_str())
-    //         .unwrap_or("<not provided>"),
-    // );
-
     let email = claims.email().ok_or_err("EMAIL")?;
     session.rm_pkce()?;
     session.rm_nonce()?;
+
+    if let Some(roles_config) = app_state.spec.roles() {
+        let id_token_claims = jwt_claims(&id_token.to_string())?;
+        let admins = app_state.spec.admins.as_deref();
+        let Some(roles) = UserRoles::resolve(roles_config, admins, email, &id_token_claims) else {
+            return Ok(HttpResponse::Forbidden().json(json!({"error": "Forbidden"})));
+        };
+        session.try_set(ROLES_SESSION_KEY, roles)?;
+    }
     session.set_email(email)?;
 
-    /*
-        let userinfo: CoreUserInfoClaims = client
-            .user_info(token_response.access_token().to_owned(), None)
-            .map_err(|err| anyhow!("No user info endpoint: {:?}", err))?
-            .request(http_client)
-            .map_err(|err| anyhow!("Failed requesting user info: {:?}", err))?;
-    */
+    if oidc.refresh_tokens() {
+        session.set_expires_at(claims.expiration().timestamp())?;
+        if let Some(refresh_token) = token_response.refresh_token() {
+            session.set_refresh_token(refresh_token.secret())?;
+        }
+    }
 
     Ok(HttpResponse::Found()
         .append_header(("Location", "/".to_string()))
         .finish())
 }
 
+/// Sessions expiring in less than this many seconds are refreshed before the request is
+/// handled.
+const SESSION_REFRESH_MARGIN: i64 = 60;
+
+/// Outcome of [`refresh_session`].
+enum SessionRefresh {
+    /// The session was extended until this unix time.
+    Extended(i64),
+    /// The provider did not refresh the tokens, the session is kept.
+    Failed,
+    /// The user is not the one of the session any more, the session is purged.
+    Unauthorized,
+    /// The user lost its role, the session is purged.
+    Forbidden,
+}
+
+/// Extends a session with its refresh token. The provider checks the user again, so users
+/// disabled there or removed from their groups lose their session.
+pub async fn refresh(
+    session: actix_session::Session,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let oidc = app_state.oidc.as_ref().ok_or_err("OIDC")?;
+    let (Ok(email), Ok(refresh_token)) = (session.email(), session.refresh_token()) else {
+        return Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"})));
+    };
+
+    match refresh_session(&session, &app_state, oidc, &email, refresh_token).await? {
+        SessionRefresh::Extended(expires_at) => {
+            Ok(HttpResponse::Ok().json(json!({"expires_at": expires_at})))
+        }
+        SessionRefresh::Failed => {
+            session.purge();
+            Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"})))
+        }
+        SessionRefresh::Unauthorized => {
+            Ok(HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"})))
+        }
+        SessionRefresh::Forbidden => {
+            Ok(HttpResponse::Forbidden().json(json!({"error": "Forbidden"})))
+        }
+    }
+}
+
+/// Middleware refreshing the sessions which are about to expire, so users stay logged in for
+/// as long as the provider keeps them. A failed refresh keeps the session, it may have raced
+/// with a concurrent request of the same session, and the guards reject it once it expired.
+pub async fn refresh_expiring_sessions(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let app_state = req.app_data::<web::Data<AppState>>().cloned();
+    let oidc = app_state
+        .as_ref()
+        .and_then(|app_state| app_state.oidc.clone());
+    if let (Some(app_state), Some(oidc)) = (app_state, oidc) {
+        let session = req.get_session();
+        if oidc.refresh_tokens() && session.expires_within(SESSION_REFRESH_MARGIN) {
+            if let (Ok(email), Ok(refresh_token)) = (session.email(), session.refresh_token()) {
+                if let Err(e) =
+                    refresh_session(&session, &app_state, &oidc, &email, refresh_token).await
+                {
+                    warn!("SESSION REFRESH OF {email} FAILED: {e}");
+                }
+            }
+        }
+    }
+    next.call(req).await
+}
+
+async fn refresh_session(
+    session: &actix_session::Session,
+    app_state: &AppState,
+    oidc: &OidcProvider,
+    email: &str,
+    refresh_token: String,
+) -> Result<SessionRefresh, Box<dyn Error>> {
+    let (client, _) = oidc.client().await?;
+    let token_response = match client
+        .exchange_refresh_token(&RefreshToken::new(refresh_token))
+        .request_async(async_http_client)
+        .await
+    {
+        Ok(token_response) => token_response,
+        Err(e) => {
+            warn!("OIDC REFRESH OF {email} FAILED: {e}");
+            return Ok(SessionRefresh::Failed);
+        }
+    };
+
+    // Providers do not have to return an ID token on refresh, the access token expiry is
+    // used then.
+    let expires_at = match token_response.id_token() {
+        Some(id_token) => {
+            // Refreshed ID tokens carry the nonce of the login, if any, which is not kept.
+            let claims = id_token
+                .claims(&client.id_token_verifier(), |_: Option<&Nonce>| {
+                    Ok::<(), String>(())
+                })
+                .inspect_err(|_| oidc.invalidate())?;
+            if claims.email().map(|e| e.as_str()) != Some(email) {
+                session.purge();
+                return Ok(SessionRefresh::Unauthorized);
+            }
+
+            if let Some(roles_config) = app_state.spec.roles() {
+                let id_token_claims = jwt_claims(&id_token.to_string())?;
+                let admins = app_state.spec.admins.as_deref();
+                let Some(roles) = UserRoles::resolve(roles_config, admins, email, &id_token_claims)
+                else {
+                    session.purge();
+                    return Ok(SessionRefresh::Forbidden);
+                };
+                session.try_set(ROLES_SESSION_KEY, roles)?;
+            }
+            claims.expiration().timestamp()
+        }
+        None => {
+            let expires_in = token_response.expires_in().unwrap_or_default();
+            Utc::now().timestamp() + expires_in.as_secs() as i64
+        }
+    };
+
+    session.set_expires_at(expires_at)?;
+    if let Some(refresh_token) = token_response.refresh_token() {
+        session.set_refresh_token(refresh_token.secret())?;
+    }
+    Ok(SessionRefresh::Extended(expires_at))
+}
+
+/// Clears the session and logs out of the provider too when it supports RP-initiated logout.
+pub async fn logout(
+    session: actix_session::Session,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    session.purge();
+
+    let oidc = app_state.oidc.as_ref().ok_or_err("OIDC")?;
+    let post_logout_redirect_url = oidc.config().post_logout_redirect_url.clone();
+    let (_, end_session_url) = oidc.client().await?;
+    let location = match end_session_url {
+        Some(end_session_url) => {
+            let mut request = LogoutRequest::from(end_session_url)
+                .set_client_id(ClientId::new(oidc.config().client_id.clone()));
+            if let Some(url) = post_logout_redirect_url {
+                request = request.set_post_logout_redirect_uri(PostLogoutRedirectUrl::new(url)?);
+            }
+            request.http_get_url().to_string()
+        }
+        None => post_logout_redirect_url.unwrap_or("/".to_string()),
+    };
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", location))
+        .finish())
+}
+
 pub async fn unauthorized() -> HttpResponse {
     HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}))
 }
@@ -137,7 +295,7 @@ pub async fn token_login(
     let token = token.token.clone();
 
     if app_state.spec._auth_token() == token {
-        let _ = session.set_email("user@");
+        let _ = session.set_email(TOKEN_LOGIN_SUBJECT);
         return Ok(HttpResponse::Found()
             .append_header(("Location", "/".to_string()))
             .finish());
@@ -147,32 +305,111 @@ pub async fn token_login(
 }
 
 pub async fn personal_token(
+    req: HttpRequest,
     pat_request: web::Json<PATRequest>,
-    session: actix_session::Session,
     app_state: web::Data<AppState>,
 ) -> Result<impl Responder, Box<dyn Error>> {
-    let email = session.get("EMAIL")?.unwrap_or("root".to_string());
-    let secret = &app_state
+    let secret = app_state
         .spec
-        .clone()
         .personal_access_token_secret
-        .expect("PERSONAL_ACCESS_TOKEN_SECRET")
-        .to_string();
-    let personal_access_token = generate_pat_token(secret, &email, pat_request.expiration_days);
+        .as_deref()
+        .expect("PERSONAL_ACCESS_TOKEN_SECRET");
+    let Some((auth, subject)) = request_subject(&req, Some(secret)) else {
+        return Ok(HttpResponse::Unauthorized().finish());
+    };
+    if pat_request.expiration_days <= 0 {
+        return Ok(HttpResponse::BadRequest().body("EXPIRATION DAYS MUST BE POSITIVE"));
+    }
+
+    // A scoped token only mints tokens with the same or narrower scopes.
+    let caller_scopes = Scopes::of_request(&req, Some(secret));
+    let scopes = match pat_request.scopes.as_deref() {
+        Some(scopes) => match Scopes::parse(Some(scopes)) {
+            Ok(scopes) => scopes,
+            Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+        },
+        None => caller_scopes.clone(),
+    };
+    if !caller_scopes.covers(&scopes) {
+        return Ok(HttpResponse::Forbidden().body("SCOPES WIDER THAN THE CALLER SCOPES"));
+    }
+
+    let (personal_access_token, token) = app_state
+        .tokens
+        .issue(
+            secret,
+            &subject,
+            pat_request.name.as_deref().unwrap_or("personal token"),
+            pat_request.expiration_days,
+            req.peer_addr().map(|addr| addr.ip().to_string()),
+            TokenGrant {
+                scopes: scopes.to_strings(),
+                roles: request_roles(&req, Some(secret)),
+                // A token only mints tokens that expire no later than itself.
+                expires_before: match auth {
+                    AuthMethod::Pat => request_token_expiry(&req, secret),
+                    AuthMethod::Session => None,
+                },
+            },
+        )
+        .map_box_err()?;
     Ok(HttpResponse::Ok().json(PATResponse {
         personal_access_token,
+        token,
     }))
 }
 
-pub fn generate_pat_token(secret: &str, email: &str, expiration_days: i64) -> String {
-    let my_claims = PATClaims {
-        sub: email.to_string(),
-        exp: (Utc::now() + Duration::days(expiration_days)).timestamp() as usize,
+/// Personal tokens of the caller, admins see the tokens of every subject.
+pub async fn personal_tokens(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let Some(scope) = TaskScope::of(&req, &app_state) else {
+        return Ok(HttpResponse::Unauthorized().finish());
+    };
+
+    let tokens = app_state
+        .tokens
+        .list(scope.subject().as_deref())
+        .map_box_err()?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+pub async fn revoke_personal_token(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let id = req.match_info().get("id").expect("ID");
+    let Some(scope) = TaskScope::of(&req, &app_state) else {
+        return Ok(HttpResponse::Unauthorized().finish());
     };
 
+    match app_state
+        .tokens
+        .revoke(id, scope.subject().as_deref())
+        .map_box_err()?
+    {
+        Some(token) => Ok(HttpResponse::Ok().json(token)),
+        None => Ok(HttpResponse::NotFound().body(format!("PERSONAL TOKEN {id} NOT FOUND"))),
+    }
+}
+
+/// Expiry of the personal token of a request.
+fn request_token_expiry(req: &HttpRequest, secret: &str) -> Option<DateTime<Utc>> {
+    let token = req
+        .headers()
+        .get("authorization")?
+        .to_str()
+        .ok()?
+        .replace("Bearer ", "");
+    let claims = verify_pat_token(&token, secret).ok()?.claims;
+    DateTime::from_timestamp(claims.exp as i64, 0)
+}
+
+pub fn generate_pat_token(secret: &str, claims: &PATClaims) -> String {
     encode(
         &Header::default(),
-        &my_claims,
+        claims,
         &EncodingKey::from_secret(secret.as_ref()),
     )
     .unwrap()
@@ -0,0 +1,66 @@
+use crate::handlers::tasks::TaskScope;
+use crate::serve::AppState;
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use anyhow::Result;
+use bytes::Bytes;
+use onceuponai_core::events::{self, Event, EventKind};
+use std::error::Error;
+use std::time::Duration;
+
+/// Interval of the comments keeping idle connections open through proxies.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Server-sent events of the cluster. Callers other than admins only receive the events of
+/// their own tasks.
+pub async fn events(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let Some(scope) = TaskScope::of(&req, &app_state) else {
+        return Ok(HttpResponse::Unauthorized().finish());
+    };
+    let subject = scope.subject();
+
+    let receiver = events::subscribe();
+    let stream = futures::stream::unfold(receiver, move |mut receiver| {
+        let subject = subject.clone();
+        async move {
+            loop {
+                let event =
+                    match tokio::time::timeout(KEEP_ALIVE_INTERVAL, events::recv(&mut receiver))
+                        .await
+                    {
+                        Ok(Some(event)) => event,
+                        Ok(None) => return None,
+                        Err(_) => {
+                            let keep_alive = Bytes::from_static(b": keep-alive\n\n");
+                            return Some((Ok::<_, actix_web::Error>(keep_alive), receiver));
+                        }
+                    };
+                if is_visible(&event, subject.as_deref()) {
+                    return Some((Ok(sse_message(&event)), receiver));
+                }
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(stream))
+}
+
+fn is_visible(event: &Event, subject: Option<&str>) -> bool {
+    match (&event.kind, subject) {
+        (EventKind::Task { subject: owner, .. }, Some(subject)) => {
+            owner.as_deref() == Some(subject)
+        }
+        _ => true,
+    }
+}
+
+fn sse_message(event: &Event) -> Bytes {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    Bytes::from(format!("event: {}\ndata: {data}\n\n", event.kind.name()))
+}
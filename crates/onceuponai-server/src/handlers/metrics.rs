@@ -0,0 +1,164 @@
+use actix_web::{HttpResponse, Responder};
+use onceuponai_actors::actors::main_actor::{CONNECTED_ACTORS, INVOKE_TASKS, WORKER_METRICS};
+use onceuponai_actors::metrics::{self, LATENCY_BUCKETS};
+use onceuponai_core::common::ResultExt;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::Duration;
+
+/// Label of requests which matched no route, keeps the cardinality bounded.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Records a served request, `route` is the matched pattern (e.g. `/api/invoke/{kind}/{name}`).
+pub fn record_http_request(route: Option<&str>, method: &str, status: u16, elapsed: Duration) {
+    let route = route.unwrap_or(UNMATCHED_ROUTE);
+    let status = status.to_string();
+    metrics::record(|m| {
+        m.counter(
+            "onceuponai_http_requests_total",
+            "HTTP requests by route, method and status.",
+            &[
+                ("route", route),
+                ("method", method),
+                ("status", status.as_str()),
+            ],
+            1.0,
+        );
+        if status.starts_with('5') {
+            m.counter(
+                "onceuponai_http_errors_total",
+                "HTTP requests answered with a server error.",
+                &[("route", route), ("method", method)],
+                1.0,
+            );
+        }
+        m.observe(
+            "onceuponai_http_request_duration_seconds",
+            "Time until the response head of an HTTP request.",
+            LATENCY_BUCKETS,
+            &[("route", route), ("method", method)],
+            elapsed.as_secs_f64(),
+        );
+    });
+}
+
+/// Prometheus scrape of the server and of the counters reported by the workers.
+pub async fn metrics() -> Result<impl Responder, Box<dyn Error>> {
+    let mut metrics = metrics::snapshot();
+
+    let invoke_tasks = INVOKE_TASKS
+        .get()
+        .expect("INVOKE_TASKS")
+        .lock()
+        .map_box_err()?
+        .len();
+    metrics.gauge(
+        "onceuponai_invoke_tasks",
+        "Requests of the server awaiting responses from the actors.",
+        &[],
+        invoke_tasks as f64,
+    );
+
+    let actors = CONNECTED_ACTORS
+        .get()
+        .expect("CONNECTED_ACTORS")
+        .lock()
+        .map_box_err()?
+        .clone();
+    let mut connected: BTreeMap<(&str, &str), usize> = BTreeMap::new();
+    for actor in actors.values() {
+        *connected
+            .entry((actor.kind.as_str(), actor.metadata.name.as_str()))
+            .or_default() += 1;
+
+        let Some(load) = &actor.load else {
+            continue;
+        };
+        let uuid = actor.uuid.to_string();
+        let labels = [
+            ("kind", actor.kind.as_str()),
+            ("name", actor.metadata.name.as_str()),
+            ("uuid", uuid.as_str()),
+        ];
+        metrics.gauge(
+            "onceuponai_actor_queue_depth",
+            "Requests waiting for a running slot of an actor.",
+            &labels,
+            load.queued as f64,
+        );
+        metrics.gauge(
+            "onceuponai_actor_running_tasks",
+            "Requests running on an actor.",
+            &labels,
+            load.running as f64,
+        );
+    }
+    for ((kind, name), count) in connected {
+        metrics.gauge(
+            "onceuponai_connected_actors",
+            "Connected actors by kind and name.",
+            &[("kind", kind), ("name", name)],
+            count as f64,
+        );
+    }
+
+    let workers = WORKER_METRICS
+        .get()
+        .expect("WORKER_METRICS")
+        .lock()
+        .map_box_err()?
+        .clone();
+    for (uuid, worker) in workers {
+        let Some(actor) = actors.get(&uuid) else {
+            continue;
+        };
+        let uuid = uuid.to_string();
+        let labels = [
+            ("kind", actor.kind.as_str()),
+            ("name", actor.metadata.name.as_str()),
+            ("uuid", uuid.as_str()),
+        ];
+        if let Some(model_load_seconds) = worker.model_load_seconds {
+            metrics.gauge(
+                "onceuponai_worker_model_load_seconds",
+                "Duration of the last model load of a worker.",
+                &labels,
+                model_load_seconds,
+            );
+        }
+        metrics.counter(
+            "onceuponai_worker_tasks_total",
+            "Tasks run by a worker.",
+            &labels,
+            worker.tasks as f64,
+        );
+        metrics.counter(
+            "onceuponai_worker_failed_tasks_total",
+            "Tasks of a worker which failed.",
+            &labels,
+            worker.failed_tasks as f64,
+        );
+        metrics.counter(
+            "onceuponai_worker_busy_seconds_total",
+            "Time a worker spent running tasks.",
+            &labels,
+            worker.busy_seconds,
+        );
+        metrics.counter(
+            "onceuponai_worker_generated_tokens_total",
+            "Chunks streamed by a worker, one per generated token.",
+            &labels,
+            worker.generated_tokens as f64,
+        );
+        metrics.counter(
+            "onceuponai_worker_generation_seconds_total",
+            "Time a worker spent running streamed tasks.",
+            &labels,
+            worker.generation_seconds,
+        );
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render()))
+}
@@ -2,7 +2,10 @@ use actix_web::{http::header::ContentType, HttpResponse, Responder};
 pub mod actors;
 pub mod auth;
 pub mod chat;
+pub mod events;
+pub mod metrics;
 pub mod oai;
+pub mod tasks;
 pub mod users;
 
 // pub const ASSETS_CSS_HASH: &str = "577b6681";
@@ -1,15 +1,22 @@
-use super::actors::{base_invoke, Mappers};
+use super::actors::{base_invoke, InvokeContext, Mappers};
 use crate::models::InvokeRequest;
 use crate::serve::AppState;
-use actix_web::web;
 use actix_web::Responder;
+use actix_web::{web, HttpRequest, HttpResponse};
 use anyhow::Result;
 use onceuponai_abstractions::EntityValue;
 use onceuponai_actors::abstractions::openai::ChatCompletionRequest;
-use onceuponai_actors::abstractions::ActorInvokeData;
+use onceuponai_actors::abstractions::{ActorInvokeData, ModelInfo};
+use onceuponai_actors::actors::main_actor::{MainActorSpec, CONNECTED_ACTORS};
+use onceuponai_actors::actors::pipeline::PIPELINE_KIND;
+use onceuponai_actors::actors::routing::RouteTarget;
+use onceuponai_actors::actors::ActorInfo;
+use onceuponai_core::common::ResultExt;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
+use uuid::Uuid;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ChatCompletionsRequest {
@@ -25,7 +32,169 @@ pub struct EmbeddingsRequest {
     pub encoding_format: Option<bool>,
 }
 
+/// Model of the OpenAI models API: an actor, a route or a pipeline, served by the connected
+/// replicas it resolves to.
+#[derive(Serialize, Debug, Clone)]
+pub struct ModelObject {
+    /// `kind/name`, the model of chat completion and embeddings requests.
+    pub id: String,
+    pub object: String,
+    pub owned_by: String,
+    pub features: Option<Vec<String>>,
+    /// Models a route or the steps of a pipeline run, not set for actors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub targets: Option<Vec<String>>,
+    /// Info of the first replica, the first target of a route or the output step of a
+    /// pipeline.
+    pub info: ModelInfo,
+    pub replicas: Vec<ModelReplica>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ModelReplica {
+    pub uuid: Uuid,
+    pub draining: bool,
+    pub info: ModelInfo,
+}
+
+fn model_objects(spec: &MainActorSpec) -> Result<Vec<ModelObject>, Box<dyn Error>> {
+    let connected_actors = CONNECTED_ACTORS
+        .get()
+        .expect("CONNECTED_ACTORS")
+        .lock()
+        .map_box_err()?;
+
+    let mut actors: BTreeMap<String, Vec<&ActorInfo>> = BTreeMap::new();
+    for actor in connected_actors.values() {
+        actors
+            .entry(format!("{}/{}", actor.kind, actor.metadata.name))
+            .or_default()
+            .push(actor);
+    }
+
+    // A route takes over the id of an actor it shares it with.
+    let mut models: Vec<ModelObject> = actors
+        .iter()
+        .filter(|(id, _)| spec.route(id).is_none())
+        .map(|(id, replicas)| model_object(id.clone(), replicas, None))
+        .collect();
+
+    for route in spec.routes.iter().flatten() {
+        let targets = route.targets.iter().map(RouteTarget::id).collect();
+        let replicas = serving_replicas(spec, &actors, &route.model);
+        models.push(model_object(route.model.clone(), &replicas, Some(targets)));
+    }
+
+    for pipeline in spec.pipelines.iter().flatten() {
+        let id = format!("{PIPELINE_KIND}/{}", pipeline.name);
+        let targets = pipeline
+            .steps
+            .iter()
+            .map(|step| format!("{}/{}", step.kind, step.name))
+            .collect();
+        let replicas = serving_replicas(spec, &actors, &id);
+        let mut model = model_object(id, &replicas, Some(targets));
+        model.features = Some(vec![PIPELINE_KIND.to_string()]);
+        models.push(model);
+    }
+
+    Ok(models)
+}
+
+fn model_object(id: String, replicas: &[&ActorInfo], targets: Option<Vec<String>>) -> ModelObject {
+    let mut features: Option<Vec<String>> = None;
+    for feature in replicas
+        .iter()
+        .flat_map(|actor| actor.metadata.features.iter().flatten())
+    {
+        let features = features.get_or_insert_with(Vec::new);
+        if !features.contains(feature) {
+            features.push(feature.clone());
+        }
+    }
+
+    ModelObject {
+        id,
+        object: "model".to_string(),
+        owned_by: "onceuponai".to_string(),
+        features,
+        targets,
+        info: replicas
+            .first()
+            .map(|actor| actor.model_info.clone())
+            .unwrap_or_default(),
+        replicas: replicas
+            .iter()
+            .map(|actor| ModelReplica {
+                uuid: actor.uuid,
+                draining: actor.draining,
+                info: actor.model_info.clone(),
+            })
+            .collect(),
+    }
+}
+
+/// Connected replicas answering a model: the actor, the targets of a route or the output step
+/// of a pipeline, resolved recursively.
+fn serving_replicas<'a>(
+    spec: &MainActorSpec,
+    actors: &BTreeMap<String, Vec<&'a ActorInfo>>,
+    model: &str,
+) -> Vec<&'a ActorInfo> {
+    let mut replicas = vec![];
+    let mut seen: Vec<String> = vec![];
+    let mut pending = vec![model.to_string()];
+    while let Some(model) = pending.pop() {
+        if seen.contains(&model) {
+            continue;
+        }
+        seen.push(model.clone());
+
+        let pipeline = model
+            .strip_prefix(PIPELINE_KIND)
+            .and_then(|name| name.strip_prefix('/'))
+            .and_then(|name| spec.pipeline(name));
+        if let Some(route) = spec.route(&model) {
+            pending.extend(route.targets.iter().rev().map(RouteTarget::id));
+        } else if let Some(pipeline) = pipeline {
+            let output = match &pipeline.output {
+                Some(output) => pipeline.steps.iter().find(|step| &step.id == output),
+                None => pipeline.steps.last(),
+            };
+            pending.extend(output.map(|step| format!("{}/{}", step.kind, step.name)));
+        } else if let Some(found) = actors.get(&model) {
+            replicas.extend(found.iter().copied());
+        }
+    }
+    replicas
+}
+
+pub async fn v1_models(
+    _req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let models = model_objects(&app_state.spec)?;
+    Ok(HttpResponse::Ok().json(json!({ "object": "list", "data": models })))
+}
+
+pub async fn v1_model(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let kind = req.match_info().get("kind").expect("KIND");
+    let name = req.match_info().get("name").expect("NAME");
+    let id = format!("{kind}/{name}");
+
+    match model_objects(&app_state.spec)?
+        .into_iter()
+        .find(|model| model.id == id)
+    {
+        Some(model) => Ok(HttpResponse::Ok().json(model)),
+        None => Ok(HttpResponse::NotFound().body(format!("MODEL {id} NOT FOUND"))),
+    }
+}
 pub async fn v1_chat_completions(
+    req: HttpRequest,
     chat_completions_request: web::Json<ChatCompletionRequest>,
     app_state: web::Data<AppState>,
 ) -> Result<impl Responder, Box<dyn Error>> {
@@ -33,6 +202,11 @@ pub async fn v1_chat_completions(
     let kind: String = model[0].to_string();
     let name: String = model[1].to_string();
 
+    let mut context = InvokeContext::from_request(&req);
+    if context.session_key.is_none() {
+        context.session_key = chat_completions_request.user.clone();
+    }
+
     let invoke_request = InvokeRequest {
         config: HashMap::new(),
         stream: chat_completions_request.stream,
@@ -42,6 +216,7 @@ pub async fn v1_chat_completions(
         kind,
         name,
         app_state,
+        context,
         invoke_request,
         Mappers::OaiChatCompletions,
     )
@@ -49,6 +224,7 @@ pub async fn v1_chat_completions(
 }
 
 pub async fn v1_embeddings(
+    req: HttpRequest,
     embeddings_request: web::Json<EmbeddingsRequest>,
     app_state: web::Data<AppState>,
 ) -> Result<impl Responder, Box<dyn Error>> {
@@ -68,6 +244,7 @@ pub async fn v1_embeddings(
         kind,
         name,
         app_state,
+        InvokeContext::from_request(&req),
         invoke_request,
         Mappers::OaiEmbeddings,
     )
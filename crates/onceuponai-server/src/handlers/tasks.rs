@@ -0,0 +1,87 @@
+use crate::audit::request_subject;
+use crate::roles::request_roles;
+use crate::scopes::Scopes;
+use crate::serve::AppState;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use anyhow::Result;
+use onceuponai_actors::actors::main_actor::{CancelTask, ListTasks};
+use onceuponai_core::common::ResultExt;
+use serde_json::json;
+use std::error::Error;
+use uuid::Uuid;
+
+/// Tasks visible to the caller, admins see every task.
+pub(crate) enum TaskScope {
+    All,
+    Subject(String),
+}
+
+impl TaskScope {
+    /// Resolves the scope of the caller, `None` when it is not authenticated. Tokens of an
+    /// admin need the `admin` scope to see every task.
+    pub(crate) fn of(req: &HttpRequest, app_state: &AppState) -> Option<Self> {
+        let secret = app_state.spec.personal_access_token_secret.as_deref();
+        let (_, subject) = request_subject(req, secret)?;
+        // OIDC users with roles are admins by role, the others by the admins list.
+        let is_admin = match request_roles(req, secret) {
+            Some(roles) => roles.is_admin(),
+            None => app_state.spec.is_admin(&subject),
+        };
+        if is_admin && Scopes::of_request(req, secret).is_admin() {
+            Some(TaskScope::All)
+        } else {
+            Some(TaskScope::Subject(subject))
+        }
+    }
+
+    /// Subject the tasks are filtered by.
+    pub(crate) fn subject(self) -> Option<String> {
+        match self {
+            TaskScope::All => None,
+            TaskScope::Subject(subject) => Some(subject),
+        }
+    }
+}
+
+pub async fn tasks(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let Some(scope) = TaskScope::of(&req, &app_state) else {
+        return Ok(HttpResponse::Unauthorized().finish());
+    };
+
+    let tasks = app_state
+        .addr
+        .send(ListTasks {
+            subject: scope.subject(),
+        })
+        .await
+        .map_box_err()?;
+    Ok(HttpResponse::Ok().json(tasks))
+}
+
+pub async fn cancel_task(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> Result<impl Responder, Box<dyn Error>> {
+    let task_id = req.match_info().get("task_id").expect("TASK_ID");
+    let task_id = Uuid::parse_str(task_id).map_box_err()?;
+    let Some(scope) = TaskScope::of(&req, &app_state) else {
+        return Ok(HttpResponse::Unauthorized().finish());
+    };
+
+    let task = app_state
+        .addr
+        .send(CancelTask {
+            task_id,
+            subject: scope.subject(),
+        })
+        .await
+        .map_box_err()?;
+
+    match task {
+        Some(task) => Ok(HttpResponse::Accepted().json(json!({ "task": task, "cancelled": true }))),
+        None => Ok(HttpResponse::NotFound().body(format!("TASK {task_id} NOT FOUND"))),
+    }
+}
@@ -6,8 +6,15 @@
 //pub mod bot;
 // pub mod cli;
 //pub mod config;
+pub mod audit;
+pub mod cache;
 pub mod guards;
 pub mod handlers;
 pub mod models;
+pub mod oidc;
+pub mod roles;
+pub mod scopes;
 pub mod serve;
 pub mod session;
+pub mod spawn;
+pub mod tokens;
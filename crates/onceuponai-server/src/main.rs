@@ -1,13 +1,21 @@
 use clap::Parser;
 use onceuponai_actors::abstractions::ActorMetadata;
 use onceuponai_actors::actors::main_actor::{
-    MainActorAuthConfig, MainActorOidcConfig, MainActorSpec,
+    MainActorAuditConfig, MainActorAuthConfig, MainActorCacheConfig, MainActorCacheModelConfig,
+    MainActorOidcConfig, MainActorPersonalTokensConfig, MainActorRedactionRule,
+    MainActorRolesConfig, MainActorSemanticCacheConfig, MainActorSpawnConfig, MainActorSpec,
 };
+use onceuponai_actors::actors::pipeline::{validate_pipelines, PipelineSpec};
+use onceuponai_actors::actors::routing::load_routes;
+use onceuponai_actors::cluster::security::CLUSTER_SECRET_ENV;
 use onceuponai_actors::cluster::start_main_cluster;
+use onceuponai_actors::telemetry::{self, TracingConfig, TracingExporter};
 use onceuponai_core::common::{
     env_or_some, env_or_some_or_fn, generate_token, random_base64, ResultExt,
 };
-use onceuponai_server::handlers::auth::generate_pat_token;
+use onceuponai_server::cache::{parse_model_config, parse_semantic_config};
+use onceuponai_server::tokens::issue_root_token;
+use std::collections::HashMap;
 
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about)]
@@ -28,6 +36,87 @@ pub struct MainArgs {
     session_key: Option<String>,
     #[clap(long)]
     personal_access_token_secret: Option<String>,
+    /// SQLite database of the issued personal tokens.
+    #[clap(long)]
+    personal_tokens_path: Option<String>,
+    /// Seconds a personal token lookup is cached, revocations on other servers take this long.
+    #[clap(long)]
+    personal_tokens_cache_ttl: Option<u64>,
+    /// Subject with the admin role, can be repeated. The root token and the token login user
+    /// when not set.
+    #[clap(long)]
+    admin: Vec<String>,
+    #[clap(long)]
+    overload_threshold: Option<usize>,
+    #[clap(long, default_value_t = false)]
+    cache: bool,
+    #[clap(long)]
+    cache_path: Option<String>,
+    #[clap(long)]
+    cache_ttl: Option<u64>,
+    #[clap(long)]
+    cache_max_bytes: Option<usize>,
+    /// Cache settings of a model, as `kind/name=ttl:60,max_bytes:1048576,enabled:false`, can
+    /// be repeated.
+    #[clap(long)]
+    cache_model: Vec<String>,
+    /// Semantic cache of a chat model, as `kind/name=embed_kind/embed_name`.
+    #[clap(long)]
+    cache_semantic: Vec<String>,
+    #[clap(long)]
+    cache_semantic_threshold: Option<f32>,
+    /// Writes an audit log of the invoke requests.
+    #[clap(long, default_value_t = false)]
+    audit: bool,
+    #[clap(long)]
+    audit_path: Option<String>,
+    #[clap(long)]
+    audit_max_bytes: Option<u64>,
+    #[clap(long)]
+    audit_max_files: Option<usize>,
+    /// Also records the prompts and responses, after redaction.
+    #[clap(long, default_value_t = false)]
+    audit_capture_payloads: bool,
+    /// Regular expression redacted from captured payloads, replaces the default rules, can be
+    /// repeated.
+    #[clap(long)]
+    audit_redact: Vec<String>,
+    /// YAML file with a pipeline spec, can be repeated.
+    #[clap(long)]
+    pipeline: Vec<String>,
+    /// YAML file with the route rules, weighted targets and shadow target of public models.
+    #[clap(long)]
+    routes: Option<String>,
+    /// Secret workers must prove to join the cluster, `ONCEUPONAI_CLUSTER_SECRET` when not set.
+    #[clap(long)]
+    cluster_secret: Option<String>,
+    /// Encrypts invoke payloads exchanged with the workers.
+    #[clap(long, default_value_t = false)]
+    cluster_encryption: bool,
+    /// Exports traces with `otlp`, `stdout` or `file`, `OTEL_TRACES_EXPORTER` when not set.
+    #[clap(long)]
+    tracing_exporter: Option<TracingExporter>,
+    /// OTLP traces endpoint, e.g. `http://localhost:4318/v1/traces`.
+    #[clap(long)]
+    tracing_endpoint: Option<String>,
+    /// File of the `file` traces exporter.
+    #[clap(long)]
+    tracing_path: Option<String>,
+    #[clap(long)]
+    tracing_sample_ratio: Option<f64>,
+    /// Allows starting worker binaries from the actors gallery through the API.
+    #[clap(long, default_value_t = false)]
+    spawn: bool,
+    /// Directory with the worker binaries, `PATH` when not set.
+    #[clap(long)]
+    spawn_bin_dir: Option<String>,
+    #[clap(long)]
+    spawn_base_port: Option<u16>,
+    #[clap(long)]
+    spawn_max_workers: Option<usize>,
+    /// Restarts of a crashed spawned worker allowed within ten minutes.
+    #[clap(long)]
+    spawn_max_restarts: Option<u32>,
     #[clap(long, default_value_t = false)]
     oidc: bool,
     #[clap(long)]
@@ -38,11 +127,79 @@ pub struct MainArgs {
     oidc_client_secret: Option<String>,
     #[clap(long)]
     oidc_redirect_url: Option<String>,
+    #[clap(long)]
+    oidc_post_logout_redirect_url: Option<String>,
+    /// Seconds the provider metadata and signing keys are reused.
+    #[clap(long)]
+    oidc_metadata_ttl: Option<u64>,
+    /// Keeps refresh tokens so sessions can be extended at `/auth/refresh`.
+    #[clap(long, default_value_t = false)]
+    oidc_refresh_tokens: bool,
+    /// ID token claim with the groups of the user, `groups` when not set.
+    #[clap(long)]
+    oidc_roles_claim: Option<String>,
+    /// Group with the admin role, can be repeated.
+    #[clap(long)]
+    oidc_admin_group: Vec<String>,
+    /// Group with the user role, can be repeated. Every user who logs in when not set.
+    #[clap(long)]
+    oidc_user_group: Vec<String>,
+    /// Group allowed to invoke a model, as `kind/name=group` or `kind/*=group`, can be
+    /// repeated.
+    #[clap(long)]
+    oidc_model_group: Vec<String>,
+}
+
+/// Roles from the OIDC group arguments, `None` when no group is set.
+fn roles_config(main_args: &MainArgs) -> anyhow::Result<Option<MainActorRolesConfig>> {
+    if main_args.oidc_admin_group.is_empty()
+        && main_args.oidc_user_group.is_empty()
+        && main_args.oidc_model_group.is_empty()
+    {
+        return Ok(None);
+    }
+
+    let mut models: HashMap<String, Vec<String>> = HashMap::new();
+    for model_group in &main_args.oidc_model_group {
+        let (model, group) = model_group.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!("MODEL GROUP {model_group:?} IS NOT IN kind/name=group FORMAT")
+        })?;
+        models
+            .entry(model.to_string())
+            .or_default()
+            .push(group.to_string());
+    }
+
+    Ok(Some(MainActorRolesConfig {
+        claim: main_args.oidc_roles_claim.clone(),
+        admin_groups: Some(main_args.oidc_admin_group.clone()),
+        user_groups: (!main_args.oidc_user_group.is_empty())
+            .then(|| main_args.oidc_user_group.clone()),
+        models: Some(models),
+    }))
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let main_args = MainArgs::parse();
+    if let Some(cluster_secret) = &main_args.cluster_secret {
+        // Spawned workers inherit the secret.
+        std::env::set_var(CLUSTER_SECRET_ENV, cluster_secret);
+    }
+
+    let spawn = main_args.spawn.then(|| MainActorSpawnConfig {
+        bin_dir: main_args.spawn_bin_dir.clone(),
+        actor_host: main_args
+            .actor_host
+            .rsplit_once(':')
+            .map(|(host, _)| host.to_string()),
+        actor_seed: Some(main_args.actor_host.clone()),
+        base_port: main_args.spawn_base_port,
+        max_workers: main_args.spawn_max_workers,
+        max_restarts: main_args.spawn_max_restarts,
+    });
+    let roles = roles_config(&main_args).map_io_err()?;
+
     let metadata = ActorMetadata {
         actor_host: main_args.actor_host,
         name: "main_actor".to_string(),
@@ -50,6 +207,17 @@ async fn main() -> std::io::Result<()> {
         actor_id: None,
         actor_seed: None,
         sidecar_id: None,
+        max_concurrency: None,
+        queue_limit: None,
+        shutdown_grace_period: None,
+        cluster_secret: main_args.cluster_secret,
+        cluster_encryption: Some(main_args.cluster_encryption),
+        tracing: main_args.tracing_exporter.map(|exporter| TracingConfig {
+            exporter,
+            endpoint: main_args.tracing_endpoint,
+            path: main_args.tracing_path,
+            sample_ratio: main_args.tracing_sample_ratio,
+        }),
     };
 
     let auth = if main_args.oidc {
@@ -59,13 +227,74 @@ async fn main() -> std::io::Result<()> {
                 issuer_url: env_or_some("OIDC_ISSUER_URL", main_args.oidc_issuer_url),
                 client_secret: env_or_some("OIDC_CLIENT_SECRET", main_args.oidc_client_secret),
                 redirect_url: env_or_some("OIDC_REDIRECT_URL", main_args.oidc_redirect_url),
+                post_logout_redirect_url: main_args.oidc_post_logout_redirect_url,
+                metadata_ttl: main_args.oidc_metadata_ttl,
+                refresh_tokens: Some(main_args.oidc_refresh_tokens),
             }),
             _auth_token: None,
+            roles,
         })
     } else {
         None
     };
 
+    let cache = if main_args.cache {
+        let mut models = main_args
+            .cache_model
+            .iter()
+            .map(String::as_str)
+            .map(parse_model_config)
+            .collect::<anyhow::Result<HashMap<String, MainActorCacheModelConfig>>>()
+            .map_io_err()?;
+        for value in &main_args.cache_semantic {
+            let (model, embed_model) = parse_semantic_config(value).map_io_err()?;
+            models.entry(model).or_default().semantic = Some(MainActorSemanticCacheConfig {
+                embed_model,
+                threshold: main_args.cache_semantic_threshold,
+                max_entries: None,
+            });
+        }
+
+        Some(MainActorCacheConfig {
+            path: main_args.cache_path,
+            ttl: main_args.cache_ttl,
+            max_bytes: main_args.cache_max_bytes,
+            models: Some(models),
+        })
+    } else {
+        None
+    };
+
+    let audit = main_args.audit.then(|| MainActorAuditConfig {
+        path: main_args.audit_path,
+        max_bytes: main_args.audit_max_bytes,
+        max_files: main_args.audit_max_files,
+        capture_payloads: Some(main_args.audit_capture_payloads),
+        redact: (!main_args.audit_redact.is_empty()).then(|| {
+            main_args
+                .audit_redact
+                .iter()
+                .map(|pattern| MainActorRedactionRule {
+                    pattern: pattern.clone(),
+                    replacement: None,
+                })
+                .collect()
+        }),
+    });
+
+    let mut pipelines = vec![];
+    for path in &main_args.pipeline {
+        let pipeline: PipelineSpec =
+            serde_yaml::from_str(&std::fs::read_to_string(path)?).map_io_err()?;
+        pipelines.push(pipeline);
+    }
+    validate_pipelines(&pipelines).map_io_err()?;
+
+    let routes = match &main_args.routes {
+        Some(path) => Some(load_routes(path).map_io_err()?),
+        None => None,
+    };
+
     let spec = MainActorSpec {
         server_host: main_args.host,
         server_port: main_args.port,
@@ -83,15 +312,21 @@ async fn main() -> std::io::Result<()> {
             || random_base64(64),
         )),
         auth,
+        admins: (!main_args.admin.is_empty()).then_some(main_args.admin),
+        overload_threshold: main_args.overload_threshold,
+        routes,
+        cache,
+        pipelines: Some(pipelines),
+        spawn,
+        audit,
+        personal_tokens: Some(MainActorPersonalTokensConfig {
+            path: main_args.personal_tokens_path,
+            cache_ttl: main_args.personal_tokens_cache_ttl,
+        }),
     };
 
-    let secret = spec
-        .personal_access_token_secret
-        .clone()
-        .expect("PERSONAL_ACCESS_TOKEN_SECRET");
-
     let auth_token = generate_token(50);
-    let personal_token = generate_pat_token(&secret, "root", 30);
+    let personal_token = issue_root_token(&spec).map_io_err()?;
     println!("PERSONAL TOKEN: {personal_token}");
 
     let res = start_main_cluster(metadata, spec)
@@ -99,5 +334,7 @@ async fn main() -> std::io::Result<()> {
         .map_io_err()?
         .expect("MAIN ACTOR SPEC");
 
-    onceuponai_server::serve::serve(res.0, res.1, auth_token).await
+    let served = onceuponai_server::serve::serve(res.0, res.1, auth_token).await;
+    telemetry::shutdown();
+    served
 }
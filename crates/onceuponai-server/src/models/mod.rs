@@ -1,6 +1,9 @@
+use crate::roles::UserRoles;
+use crate::tokens::PersonalToken;
 use onceuponai_abstractions::EntityValue;
 use onceuponai_actors::abstractions::ActorInvokeData;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -18,6 +21,7 @@ pub struct EmbeddingsRequest {
 #[serde(tag = "type", rename_all = "camelCase")]
 pub struct AuthCallback {
     pub code: String,
+    pub state: String,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -29,17 +33,36 @@ pub struct TokenLogin {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PATRequest {
     pub expiration_days: i64,
+    /// Label shown when the tokens are listed.
+    pub name: Option<String>,
+    /// Scopes the token is restricted to, e.g. `invoke:chat` or `models:gemma/*`, the scopes
+    /// of the caller when not set.
+    pub scopes: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 pub struct PATResponse {
     pub personal_access_token: String,
+    pub token: PersonalToken,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PATClaims {
     pub sub: String,
     pub exp: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<usize>,
+    /// Id of the token record, tokens without it are rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Not restricted when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+    /// Roles of the OIDC user who minted the token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roles: Option<UserRoles>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -48,3 +71,35 @@ pub struct InvokeRequest {
     pub data: ActorInvokeData,
     pub stream: Option<bool>,
 }
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DrainRequest {
+    /// Seconds given to in-flight tasks before they are cancelled.
+    pub grace_period: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelAction {
+    Unload,
+    Load,
+    Reload,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ActorModelRequest {
+    pub command: ModelAction,
+    /// Worker configuration (`kind`, `metadata`, `spec`), required to load.
+    pub config: Option<Value>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SpawnActorRequest {
+    /// Gallery item or template id.
+    pub id: String,
+    pub name: Option<String>,
+    pub device: Option<String>,
+    /// Spec values overriding the gallery ones.
+    #[serde(default)]
+    pub spec: HashMap<String, Value>,
+}
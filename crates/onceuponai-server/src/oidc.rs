@@ -0,0 +1,76 @@
+use anyhow::{anyhow, Result};
+use onceuponai_actors::actors::main_actor::MainActorOidcConfig;
+use openidconnect::core::CoreClient;
+use openidconnect::reqwest::async_http_client;
+use openidconnect::{
+    ClientId, ClientSecret, EndSessionUrl, IssuerUrl, ProviderMetadataWithLogout, RedirectUrl,
+};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+const DEFAULT_METADATA_TTL: u64 = 3600;
+
+/// OIDC provider of the server. Its metadata, with the keys the ID tokens are signed with, is
+/// discovered on the first login and reused until it expires.
+pub struct OidcProvider {
+    config: MainActorOidcConfig,
+    ttl: Duration,
+    metadata: Mutex<Option<(Instant, ProviderMetadataWithLogout)>>,
+}
+
+impl OidcProvider {
+    pub fn new(config: MainActorOidcConfig) -> Self {
+        let ttl = Duration::from_secs(config.metadata_ttl.unwrap_or(DEFAULT_METADATA_TTL));
+        OidcProvider {
+            config,
+            ttl,
+            metadata: Mutex::new(None),
+        }
+    }
+
+    pub fn config(&self) -> &MainActorOidcConfig {
+        &self.config
+    }
+
+    pub fn refresh_tokens(&self) -> bool {
+        self.config.refresh_tokens.unwrap_or_default()
+    }
+
+    /// Client of the provider and its end session endpoint, `None` when the provider does not
+    /// support RP-initiated logout.
+    pub async fn client(&self) -> Result<(CoreClient, Option<EndSessionUrl>)> {
+        let metadata = self.metadata().await?;
+        let end_session_url = metadata.additional_metadata().end_session_endpoint.clone();
+        let client = CoreClient::from_provider_metadata(
+            metadata,
+            ClientId::new(self.config.client_id.clone()),
+            Some(ClientSecret::new(self.config.client_secret.clone())),
+        )
+        .set_redirect_uri(RedirectUrl::new(self.config.redirect_url.clone())?);
+        Ok((client, end_session_url))
+    }
+
+    /// Drops the cached metadata, e.g. when a token is signed with a key it does not have yet.
+    pub fn invalidate(&self) {
+        *self.cache() = None;
+    }
+
+    async fn metadata(&self) -> Result<ProviderMetadataWithLogout> {
+        if let Some((read, metadata)) = self.cache().as_ref() {
+            if read.elapsed() < self.ttl {
+                return Ok(metadata.clone());
+            }
+        }
+
+        let issuer_url = IssuerUrl::new(self.config.issuer_url.clone())?;
+        let metadata = ProviderMetadataWithLogout::discover_async(issuer_url, async_http_client)
+            .await
+            .map_err(|e| anyhow!("OIDC DISCOVERY FAILED: {e}"))?;
+        *self.cache() = Some((Instant::now(), metadata.clone()));
+        Ok(metadata)
+    }
+
+    fn cache(&self) -> std::sync::MutexGuard<'_, Option<(Instant, ProviderMetadataWithLogout)>> {
+        self.metadata.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
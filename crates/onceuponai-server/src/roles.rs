@@ -0,0 +1,170 @@
+use crate::handlers::auth::verify_pat_token;
+use actix_session::SessionExt;
+use actix_web::HttpRequest;
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine};
+use onceuponai_actors::actors::main_actor::MainActorRolesConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const DEFAULT_CLAIM: &str = "groups";
+pub const ROLES_SESSION_KEY: &str = "ROLES";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    User,
+}
+
+/// Role of an OIDC user, kept in the session and in the tokens the user mints.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserRoles {
+    pub role: Role,
+    /// Groups of the user named in the roles config, the others are dropped to keep the
+    /// session cookie small.
+    pub groups: Vec<String>,
+}
+
+impl UserRoles {
+    /// Resolves the role of a user from the claims of the ID token, `None` when the user has
+    /// no role. `admins` are admins whatever their groups.
+    pub fn resolve(
+        config: &MainActorRolesConfig,
+        admins: Option<&[String]>,
+        email: &str,
+        claims: &Value,
+    ) -> Option<Self> {
+        let claim = config.claim.as_deref().unwrap_or(DEFAULT_CLAIM);
+        let groups: Vec<String> = claim_values(claims, claim)
+            .into_iter()
+            .filter(|group| is_configured(config, group))
+            .collect();
+        let in_any = |allowed: &Option<Vec<String>>| {
+            allowed
+                .iter()
+                .flatten()
+                .any(|allowed| groups.contains(allowed))
+        };
+
+        let role = if in_any(&config.admin_groups)
+            || admins.is_some_and(|admins| admins.iter().any(|admin| admin == email))
+        {
+            Role::Admin
+        } else if config.user_groups.is_none() || in_any(&config.user_groups) {
+            Role::User
+        } else {
+            return None;
+        };
+        Some(UserRoles { role, groups })
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// True when the groups of the user give access to `model` (`kind/name`). Models without
+    /// an access list are open to every user.
+    pub fn allows_model(&self, config: &MainActorRolesConfig, model: &str) -> bool {
+        if self.is_admin() {
+            return true;
+        }
+
+        let Some(models) = &config.models else {
+            return true;
+        };
+        let kind = model.split_once('/').map(|(kind, _)| format!("{kind}/*"));
+        let allowed = models
+            .get(model)
+            .or_else(|| kind.as_ref().and_then(|kind| models.get(kind)));
+        match allowed {
+            Some(allowed) => allowed.iter().any(|group| self.groups.contains(group)),
+            None => true,
+        }
+    }
+}
+
+/// Roles of the caller, from the session or the claims of its personal token. `None` for
+/// callers without roles, e.g. when the roles are not configured or for the root token.
+pub fn request_roles(req: &HttpRequest, pat_secret: Option<&str>) -> Option<UserRoles> {
+    let session = req.get_session();
+    if let Ok(Some(_)) = session.get::<String>("EMAIL") {
+        return session.get::<UserRoles>(ROLES_SESSION_KEY).ok().flatten();
+    }
+
+    let token = req
+        .headers()
+        .get("authorization")?
+        .to_str()
+        .ok()?
+        .replace("Bearer ", "");
+    verify_pat_token(&token, pat_secret?).ok()?.claims.roles
+}
+
+/// Claims of a compact JWT, the ID token must be verified before.
+pub fn jwt_claims(token: &str) -> Result<Value> {
+    let payload = token.split('.').nth(1).ok_or(anyhow!("INVALID JWT"))?;
+    let payload = general_purpose::URL_SAFE_NO_PAD.decode(payload.trim_end_matches('='))?;
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+/// Strings of a claim at a dotted path, a single string or an array of strings.
+fn claim_values(claims: &Value, path: &str) -> Vec<String> {
+    let value = path
+        .split('.')
+        .try_fold(claims, |value, key| value.get(key));
+    match value {
+        Some(Value::String(value)) => vec![value.clone()],
+        Some(Value::Array(values)) => values
+            .iter()
+            .filter_map(|value| value.as_str().map(String::from))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn is_configured(config: &MainActorRolesConfig, group: &str) -> bool {
+    config.admin_groups.iter().flatten().any(|g| g == group)
+        || config.user_groups.iter().flatten().any(|g| g == group)
+        || config
+            .models
+            .iter()
+            .flat_map(|models| models.values())
+            .flatten()
+            .any(|g| g == group)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_roles_from_group_claims() {
+        let config = MainActorRolesConfig {
+            claim: Some("realm_access.roles".to_string()),
+            admin_groups: Some(vec!["ops".to_string()]),
+            user_groups: Some(vec!["staff".to_string()]),
+            models: Some(HashMap::from([(
+                "mistralrs/*".to_string(),
+                vec!["ml".to_string()],
+            )])),
+        };
+        let claims = |groups: &[&str]| json!({"realm_access": {"roles": groups}});
+
+        let admin = UserRoles::resolve(&config, None, "a@example.com", &claims(&["ops"]));
+        assert!(admin.unwrap().is_admin());
+        assert!(UserRoles::resolve(&config, None, "g@example.com", &claims(&["guest"])).is_none());
+
+        let user = UserRoles::resolve(&config, None, "u@example.com", &claims(&["staff", "other"]))
+            .unwrap();
+        assert_eq!(user.role, Role::User);
+        assert_eq!(user.groups, vec!["staff".to_string()]);
+        assert!(user.allows_model(&config, "gemma/gemma2b"));
+        assert!(!user.allows_model(&config, "mistralrs/mistral7b"));
+
+        let ml = UserRoles::resolve(&config, None, "m@example.com", &claims(&["staff", "ml"]));
+        assert!(ml.unwrap().allows_model(&config, "mistralrs/mistral7b"));
+    }
+}
@@ -0,0 +1,288 @@
+use crate::handlers::auth::verify_pat_token;
+use crate::roles::{UserRoles, ROLES_SESSION_KEY};
+use actix_session::SessionExt;
+use actix_web::http::Method;
+use actix_web::HttpRequest;
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Operation or resource a personal token is restricted to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scope {
+    /// `invoke:<feature>`, e.g. `invoke:chat`, `invoke:embed`, `invoke:pipeline` or `invoke:*`.
+    Invoke(String),
+    /// `models:<kind>/<name>`, `<name>` may be `*`.
+    Model(String),
+    /// `admin`, every route and model.
+    Admin,
+    /// `tokens:create`, minting, listing and revoking tokens no wider than the caller's.
+    TokensCreate,
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(scope: &str) -> Result<Self> {
+        match scope.split_once(':') {
+            None if scope == "admin" => Ok(Scope::Admin),
+            Some(("tokens", "create")) => Ok(Scope::TokensCreate),
+            Some(("invoke", feature)) if !feature.is_empty() => {
+                Ok(Scope::Invoke(feature.to_string()))
+            }
+            Some(("models", model)) if model.split_once('/').is_some() => {
+                Ok(Scope::Model(model.to_string()))
+            }
+            _ => Err(anyhow!("UNKNOWN SCOPE {scope}")),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Invoke(feature) => write!(f, "invoke:{feature}"),
+            Scope::Model(model) => write!(f, "models:{model}"),
+            Scope::Admin => write!(f, "admin"),
+            Scope::TokensCreate => write!(f, "tokens:create"),
+        }
+    }
+}
+
+/// What a route requires from a scoped token.
+enum RouteAccess {
+    /// Routes limited to the caller's own data.
+    Any,
+    /// Invoke routes, the model is checked when the request is handled.
+    Invoke,
+    Scope(Scope),
+}
+
+/// Scopes of a caller. Sessions and tokens minted without scopes are not restricted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scopes(Option<Vec<Scope>>);
+
+impl Scopes {
+    pub fn parse(scopes: Option<&[String]>) -> Result<Self> {
+        let scopes = scopes
+            .map(|scopes| scopes.iter().map(|scope| scope.parse()).collect())
+            .transpose()?;
+        Ok(Scopes(scopes))
+    }
+
+    /// Scopes of an OIDC user, users invoke models and mint tokens, admins are not restricted.
+    pub fn for_roles(roles: &UserRoles) -> Self {
+        if roles.is_admin() {
+            Scopes::default()
+        } else {
+            Scopes(Some(vec![
+                Scope::Invoke("*".to_string()),
+                Scope::TokensCreate,
+            ]))
+        }
+    }
+
+    /// Scopes of the bearer token of a request, sessions get the scopes of their roles and
+    /// are not restricted without. Tokens with invalid scopes get none.
+    pub fn of_request(req: &HttpRequest, pat_secret: Option<&str>) -> Self {
+        let session = req.get_session();
+        if let Ok(Some(_)) = session.get::<String>("EMAIL") {
+            return session
+                .get::<UserRoles>(ROLES_SESSION_KEY)
+                .ok()
+                .flatten()
+                .map(|roles| Scopes::for_roles(&roles))
+                .unwrap_or_default();
+        }
+
+        let Some(token) = req
+            .headers()
+            .get("authorization")
+            .and_then(|header| header.to_str().ok())
+            .map(|header| header.replace("Bearer ", ""))
+        else {
+            return Scopes::default();
+        };
+
+        pat_secret
+            .and_then(|secret| verify_pat_token(&token, secret).ok())
+            .and_then(|token| Scopes::parse(token.claims.scopes.as_deref()).ok())
+            .unwrap_or(Scopes(Some(vec![])))
+    }
+
+    pub fn has(&self, scope: &Scope) -> bool {
+        match &self.0 {
+            Some(scopes) => scopes.contains(&Scope::Admin) || scopes.contains(scope),
+            None => true,
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.has(&Scope::Admin)
+    }
+
+    /// True when a token with `scopes` grants nothing these scopes do not.
+    pub fn covers(&self, scopes: &Scopes) -> bool {
+        if self.is_admin() {
+            return true;
+        }
+        let (Some(own), Some(requested)) = (&self.0, &scopes.0) else {
+            return false;
+        };
+
+        // A token without model scopes may invoke every model, so it is wider than one with.
+        let restricted_models = own.iter().any(|scope| matches!(scope, Scope::Model(_)));
+        if restricted_models
+            && !requested
+                .iter()
+                .any(|scope| matches!(scope, Scope::Model(_)))
+        {
+            return false;
+        }
+
+        requested.iter().all(|scope| match scope {
+            Scope::Invoke(feature) => own.iter().any(|own| match own {
+                Scope::Invoke(own) => own == "*" || own == feature,
+                _ => false,
+            }),
+            Scope::Model(pattern) => {
+                !restricted_models
+                    || own.iter().any(|own| match own {
+                        Scope::Model(own) => {
+                            own == pattern
+                                || own.strip_suffix("/*").is_some_and(|kind| {
+                                    pattern.split_once('/').is_some_and(|(k, _)| k == kind)
+                                })
+                        }
+                        _ => false,
+                    })
+            }
+            scope => own.contains(scope),
+        })
+    }
+
+    /// Checked by the auth guard, before the request is handled.
+    pub fn allows_route(&self, method: &Method, path: &str) -> bool {
+        match route_access(method, path) {
+            RouteAccess::Any => true,
+            RouteAccess::Invoke => {
+                self.is_admin()
+                    || self.0.as_ref().is_some_and(|scopes| {
+                        scopes.iter().any(|scope| matches!(scope, Scope::Invoke(_)))
+                    })
+            }
+            RouteAccess::Scope(scope) => self.has(&scope),
+        }
+    }
+
+    /// True when `model` (`kind/name`) with one of `features` may be invoked.
+    pub fn allows_invoke(&self, model: &str, features: &[String]) -> bool {
+        let Some(scopes) = &self.0 else {
+            return true;
+        };
+        if scopes.contains(&Scope::Admin) {
+            return true;
+        }
+
+        // Tokens without model scopes may invoke every model.
+        let models: Vec<&String> = scopes
+            .iter()
+            .filter_map(|scope| match scope {
+                Scope::Model(pattern) => Some(pattern),
+                _ => None,
+            })
+            .collect();
+        let model_allowed =
+            models.is_empty() || models.iter().any(|pattern| model_matches(pattern, model));
+
+        let feature_allowed = scopes.iter().any(|scope| match scope {
+            Scope::Invoke(feature) => feature == "*" || features.contains(feature),
+            _ => false,
+        });
+
+        model_allowed && feature_allowed
+    }
+
+    /// Scopes as stored in the token claims.
+    pub fn to_strings(&self) -> Option<Vec<String>> {
+        self.0
+            .as_ref()
+            .map(|scopes| scopes.iter().map(|scope| scope.to_string()).collect())
+    }
+}
+
+fn model_matches(pattern: &str, model: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(kind) => model.split_once('/').is_some_and(|(k, _)| k == kind),
+        None => pattern == model,
+    }
+}
+
+fn route_access(method: &Method, path: &str) -> RouteAccess {
+    let path = path.trim_end_matches('/');
+    if path.starts_with("/api/user/personal-token") {
+        return RouteAccess::Scope(Scope::TokensCreate);
+    }
+
+    match (method, path) {
+        (&Method::POST, "/v1/chat/completions" | "/v1/embeddings") => RouteAccess::Invoke,
+        (&Method::POST, path) if path.starts_with("/api/invoke/") => RouteAccess::Invoke,
+        (&Method::GET, path) if path == "/v1/models" || path.starts_with("/v1/models/") => {
+            RouteAccess::Any
+        }
+        (&Method::GET, "/api/user" | "/api/tasks" | "/api/events") => RouteAccess::Any,
+        (&Method::DELETE, path) if path.starts_with("/api/tasks/") => RouteAccess::Any,
+        _ => RouteAccess::Scope(Scope::Admin),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::Role;
+
+    fn scopes(scopes: &[&str]) -> Scopes {
+        let scopes: Vec<String> = scopes.iter().map(|s| s.to_string()).collect();
+        Scopes::parse(Some(&scopes)).unwrap()
+    }
+
+    #[test]
+    fn test_scopes_restrict_routes_and_models() {
+        assert!(Scopes::parse(Some(&["invoke".to_string()])).is_err());
+        assert!(Scopes::parse(Some(&["models:gemma".to_string()])).is_err());
+
+        let ci = scopes(&["invoke:chat", "models:gemma/*"]);
+        assert!(ci.allows_route(&Method::POST, "/v1/chat/completions"));
+        assert!(ci.allows_route(&Method::GET, "/v1/models"));
+        assert!(!ci.allows_route(&Method::POST, "/api/user/personal-token"));
+        assert!(!ci.allows_route(&Method::POST, "/api/actors/spawn"));
+        assert!(ci.allows_invoke("gemma/gemma2b", &["chat".to_string()]));
+        assert!(!ci.allows_invoke("e5/e5small", &["embed".to_string()]));
+        assert!(!ci.allows_invoke("gemma/gemma2b", &["embed".to_string()]));
+
+        let embed = scopes(&["invoke:embed"]);
+        assert!(embed.allows_invoke("e5/e5small", &["embed".to_string()]));
+        assert!(!embed.allows_route(&Method::GET, "/metrics"));
+
+        let unrestricted = Scopes::default();
+        assert!(unrestricted.allows_route(&Method::POST, "/api/actors/spawn"));
+        assert!(unrestricted.covers(&ci));
+        assert!(!ci.covers(&unrestricted));
+        assert!(scopes(&["tokens:create", "invoke:chat", "models:gemma/*"]).covers(&ci));
+        assert!(!scopes(&["tokens:create", "invoke:chat"]).covers(&embed));
+        assert!(!ci.covers(&scopes(&["invoke:chat"])));
+        assert!(!ci.covers(&scopes(&["invoke:chat", "models:e5/*"])));
+        assert!(scopes(&["invoke:*", "models:gemma/*"])
+            .covers(&scopes(&["invoke:chat", "models:gemma/gemma2b"])));
+        assert!(scopes(&["admin"]).covers(&unrestricted));
+
+        let user = Scopes::for_roles(&UserRoles {
+            role: Role::User,
+            groups: vec![],
+        });
+        assert!(user.allows_route(&Method::POST, "/v1/chat/completions"));
+        assert!(!user.allows_route(&Method::POST, "/api/actors/spawn"));
+        assert!(user.covers(&ci));
+        assert!(!user.covers(&unrestricted));
+    }
+}
@@ -1,18 +1,33 @@
+use crate::audit::AuditLog;
+use crate::cache::ResponseCache;
 use crate::guards::AuthGuard;
-use crate::handlers::actors::{actors_gallery, connected_actors, invoke};
-use crate::handlers::oai::{v1_chat_completions, v1_embeddings};
+use crate::handlers::actors::{
+    actor_model, actors_gallery, connected_actors, drain_actor, invalidate_cache, invoke,
+    kill_actor, routes, spawn_actor, spawned_actors,
+};
+use crate::handlers::events::events;
+use crate::handlers::metrics::{metrics, record_http_request};
+use crate::handlers::oai::{v1_chat_completions, v1_embeddings, v1_model, v1_models};
+use crate::handlers::tasks::{cancel_task, tasks};
 use crate::handlers::{self, assets_css, assets_js, favicon, health, index_html, logo};
+use crate::oidc::OidcProvider;
+use crate::spawn::WorkerSpawner;
+use crate::tokens::PersonalTokenStore;
 use actix::Addr;
+use actix_web::dev::Service;
 // use actix_files as fs;
 use actix_session::{storage::CookieSessionStore, SessionMiddleware};
-use actix_web::middleware::Logger;
+use actix_web::middleware::{from_fn, Logger};
 use actix_web::HttpResponse;
 use actix_web::{cookie::Key, web, App, HttpServer};
 use anyhow::Result;
 use base64::{engine::general_purpose, Engine as _};
+use futures::FutureExt;
 use num_traits::Zero;
 use onceuponai_actors::actors::main_actor::{MainActor, MainActorSpec};
 use onceuponai_core::common::ResultExt;
+use std::sync::Arc;
+use std::time::Instant;
 
 fn get_secret_key(spec: &MainActorSpec) -> Result<Key> {
     let key = spec.session_key.clone().expect("SESSION_KEY");
@@ -23,6 +38,11 @@ fn get_secret_key(spec: &MainActorSpec) -> Result<Key> {
 pub struct AppState {
     pub addr: Addr<MainActor>,
     pub spec: MainActorSpec,
+    pub cache: Option<Arc<ResponseCache>>,
+    pub spawner: Option<Arc<WorkerSpawner>>,
+    pub audit: Option<Arc<AuditLog>>,
+    pub tokens: Arc<PersonalTokenStore>,
+    pub oidc: Option<Arc<OidcProvider>>,
 }
 
 pub async fn serve(
@@ -50,16 +70,59 @@ pub async fn serve(
         );
     }
 
+    let cache = spec.cache.clone().map(|c| Arc::new(ResponseCache::new(c)));
+    let spawner = match spec.spawn.clone() {
+        Some(config) => Some(Arc::new(WorkerSpawner::new(config).map_io_err()?)),
+        None => None,
+    };
+
+    let audit = match spec.audit.clone() {
+        Some(config) => Some(Arc::new(AuditLog::new(config).map_io_err()?)),
+        None => None,
+    };
+
+    let tokens = Arc::new(
+        PersonalTokenStore::new(spec.personal_tokens.clone().unwrap_or_default()).map_io_err()?,
+    );
+
+    // Shared by the workers, so the provider metadata is discovered once.
+    let oidc = sp.is_oidc().then(|| Arc::new(OidcProvider::new(sp.oidc())));
+
     let mut server = HttpServer::new(move || {
         let mut app = App::new()
+            // Registered before the session middleware, so it runs inside it.
+            .wrap(from_fn(handlers::auth::refresh_expiring_sessions))
             .wrap(SessionMiddleware::new(
                 CookieSessionStore::default(),
                 secret_key.clone(),
             ))
             .wrap(Logger::default())
+            .wrap_fn(|req, srv| {
+                let started = Instant::now();
+                let route = req.match_pattern();
+                let method = req.method().to_string();
+                srv.call(req).map(move |res| {
+                    let status = match &res {
+                        Ok(res) => res.status(),
+                        Err(e) => e.as_response_error().status_code(),
+                    };
+                    record_http_request(
+                        route.as_deref(),
+                        &method,
+                        status.as_u16(),
+                        started.elapsed(),
+                    );
+                    res
+                })
+            })
             .app_data(web::Data::new(AppState {
                 addr: addr.clone(),
                 spec: sp.clone(),
+                cache: cache.clone(),
+                spawner: spawner.clone(),
+                audit: audit.clone(),
+                tokens: tokens.clone(),
+                oidc: oidc.clone(),
             }))
             .route("/", web::get().to(index_html))
             .route("/index.js", web::get().to(assets_js))
@@ -73,7 +136,9 @@ pub async fn serve(
             app = app.service(
                 web::scope("/auth")
                     .route("", web::get().to(handlers::auth::auth))
-                    .route("/callback", web::get().to(handlers::auth::auth_callback)),
+                    .route("/callback", web::get().to(handlers::auth::auth_callback))
+                    .route("/logout", web::get().to(handlers::auth::logout))
+                    .route("/refresh", web::post().to(handlers::auth::refresh)),
             );
         } else {
             app = app.route("/login", web::get().to(handlers::auth::token_login));
@@ -85,6 +150,8 @@ pub async fn serve(
                 .personal_access_token_secret
                 .expect("PERSONAL_ACCESS_TOKEN_SECRET")
                 .to_string(),
+            tokens: tokens.clone(),
+            roles: sp.roles().cloned(),
         };
 
         app = app.default_service(web::route().to(HttpResponse::Unauthorized));
@@ -94,19 +161,45 @@ pub async fn serve(
                 .guard(auth_guard.clone())
                 .route("/actors", web::get().to(connected_actors))
                 .route("/actors/gallery", web::get().to(actors_gallery))
+                .route("/actors/spawn", web::post().to(spawn_actor))
+                .route("/actors/spawned", web::get().to(spawned_actors))
+                .route("/actors/{sidecar_id}", web::delete().to(kill_actor))
+                .route("/actors/{uuid}/drain", web::post().to(drain_actor))
+                .route("/actors/{uuid}/model", web::post().to(actor_model))
+                .route("/routes", web::get().to(routes))
+                .route("/events", web::get().to(events))
+                .route("/tasks", web::get().to(tasks))
+                .route("/tasks/{task_id}", web::delete().to(cancel_task))
+                .route("/cache/{kind}/{name}", web::delete().to(invalidate_cache))
                 .route("/invoke/{kind}/{name}", web::post().to(invoke))
                 .route("/user", web::get().to(handlers::users::user))
                 .route(
                     "/user/personal-token",
                     web::post().to(handlers::auth::personal_token),
+                )
+                .route(
+                    "/user/personal-tokens",
+                    web::get().to(handlers::auth::personal_tokens),
+                )
+                .route(
+                    "/user/personal-tokens/{id}",
+                    web::delete().to(handlers::auth::revoke_personal_token),
                 ),
         );
 
+        app = app.service(
+            web::resource("/metrics")
+                .guard(auth_guard.clone())
+                .route(web::get().to(metrics)),
+        );
+
         app = app.service(
             web::scope("v1")
                 .guard(auth_guard)
                 .route("/chat/completions", web::post().to(v1_chat_completions))
-                .route("/embeddings", web::post().to(v1_embeddings)),
+                .route("/embeddings", web::post().to(v1_embeddings))
+                .route("/models", web::get().to(v1_models))
+                .route("/models/{kind}/{name}", web::get().to(v1_model)),
         );
 
         //app.service(fs::Files::new("/", "../onceuponai-ui/dist/").show_files_listing())
@@ -9,7 +9,10 @@ use serde::{de::DeserializeOwned, Serialize};
 pub enum SessionItems {
     PKCE,
     NONCE,
+    STATE,
     EMAIL,
+    RefreshToken,
+    ExpiresAt,
 }
 
 impl SessionItems {
@@ -17,7 +20,10 @@ impl SessionItems {
         match self {
             SessionItems::PKCE => "PKCE",
             SessionItems::NONCE => "NONCE",
+            SessionItems::STATE => "STATE",
             SessionItems::EMAIL => "EMAIL",
+            SessionItems::RefreshToken => "REFRESH_TOKEN",
+            SessionItems::ExpiresAt => "EXPIRES_AT",
         }
     }
 }
@@ -33,9 +39,21 @@ pub trait SessionExt {
     fn nonce(&self) -> Result<String>;
     fn set_nonce(&self, pkce: &str) -> Result<()>;
     fn rm_nonce(&self) -> Result<String>;
+    fn state(&self) -> Result<String>;
+    fn set_state(&self, state: &str) -> Result<()>;
+    fn rm_state(&self) -> Result<String>;
     fn email(&self) -> Result<String>;
     fn set_email(&self, email: &str) -> Result<()>;
     fn rm_email(&self) -> Result<String>;
+    fn refresh_token(&self) -> Result<String>;
+    fn set_refresh_token(&self, refresh_token: &str) -> Result<()>;
+    fn rm_refresh_token(&self) -> Result<String>;
+
+    /// Unix time the session expires at, sessions without refresh tokens do not expire.
+    fn set_expires_at(&self, expires_at: i64) -> Result<()>;
+    fn is_expired(&self) -> bool;
+    /// True when the session expires in less than `seconds`.
+    fn expires_within(&self, seconds: i64) -> bool;
 }
 
 macro_rules! session_method {
@@ -57,7 +75,33 @@ macro_rules! session_method {
 impl SessionExt for Session {
     session_method!(pkce, set_pkce, rm_pkce, SessionItems::PKCE);
     session_method!(nonce, set_nonce, rm_nonce, SessionItems::NONCE);
+    session_method!(state, set_state, rm_state, SessionItems::STATE);
     session_method!(email, set_email, rm_email, SessionItems::EMAIL);
+    session_method!(
+        refresh_token,
+        set_refresh_token,
+        rm_refresh_token,
+        SessionItems::RefreshToken
+    );
+
+    fn set_expires_at(&self, expires_at: i64) -> Result<()> {
+        self.try_set(SessionItems::ExpiresAt.as_str(), expires_at)
+    }
+
+    fn is_expired(&self) -> bool {
+        match self.get::<i64>(SessionItems::ExpiresAt.as_str()) {
+            Ok(Some(expires_at)) => expires_at <= chrono::Utc::now().timestamp(),
+            Ok(None) => false,
+            Err(_) => true,
+        }
+    }
+
+    fn expires_within(&self, seconds: i64) -> bool {
+        match self.get::<i64>(SessionItems::ExpiresAt.as_str()) {
+            Ok(Some(expires_at)) => expires_at - chrono::Utc::now().timestamp() < seconds,
+            _ => false,
+        }
+    }
 
     fn try_get<T: DeserializeOwned>(&self, key: &str) -> Result<T> {
         self.get(key)?.ok_or_err(key)
@@ -0,0 +1,198 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+pub const ACTORS_GALLERY: &str = include_str!("../../actors_gallery.yaml");
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ActorsGallery {
+    pub templates: Vec<GalleryTemplate>,
+    #[serde(rename = "galery")]
+    pub gallery: Vec<GalleryItem>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GalleryTemplate {
+    pub id: String,
+    pub kind: String,
+    pub device: String,
+    pub sidecar: String,
+    pub metadata: GalleryMetadata,
+    pub spec: Vec<GallerySpecItem>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GalleryItem {
+    pub id: String,
+    pub template: Option<String>,
+    pub device: Option<String>,
+    pub metadata: GalleryMetadata,
+    #[serde(default)]
+    pub spec: Vec<GallerySpecItem>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GalleryMetadata {
+    pub name: String,
+    pub description: Option<String>,
+    pub url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GallerySpecItem {
+    pub key: String,
+    pub value: Value,
+    #[serde(rename = "type")]
+    pub value_type: Option<String>,
+}
+
+/// Gallery entry filled with user values, ready to be passed to a worker binary.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ActorInstance {
+    pub kind: String,
+    pub name: String,
+    pub sidecar: String,
+    pub device: String,
+    pub spec: Map<String, Value>,
+}
+
+impl ActorsGallery {
+    pub fn load() -> Result<Self> {
+        Ok(serde_yaml::from_str(ACTORS_GALLERY)?)
+    }
+
+    /// Instantiates a gallery item or a template. User values override the item values,
+    /// which override the template values. Keys without a value are left out.
+    pub fn instantiate(
+        &self,
+        id: &str,
+        name: Option<String>,
+        device: Option<String>,
+        values: &HashMap<String, Value>,
+    ) -> Result<ActorInstance> {
+        let (template, item) = match self.gallery.iter().find(|i| i.id == id) {
+            Some(item) => {
+                let template_id = item
+                    .template
+                    .as_ref()
+                    .ok_or(anyhow!("GALLERY ITEM {id:?} HAS NO TEMPLATE"))?;
+                let template = self
+                    .templates
+                    .iter()
+                    .find(|t| &t.id == template_id)
+                    .ok_or(anyhow!("TEMPLATE {template_id:?} NOT FOUND"))?;
+                (template, Some(item))
+            }
+            None => (
+                self.templates
+                    .iter()
+                    .find(|t| t.id == id)
+                    .ok_or(anyhow!("GALLERY ITEM {id:?} NOT FOUND"))?,
+                None,
+            ),
+        };
+
+        let mut spec = Map::new();
+        let item_spec = item.map(|i| i.spec.iter()).into_iter().flatten();
+        for spec_item in template.spec.iter().chain(item_spec) {
+            spec.insert(spec_item.key.clone(), spec_item.value.clone());
+        }
+        for (key, value) in values {
+            spec.insert(key.clone(), value.clone());
+        }
+        spec.retain(|_, value| !value.is_null());
+
+        let device = device
+            .or_else(|| item.and_then(|i| i.device.clone()))
+            .unwrap_or_else(|| template.device.clone());
+        if device.is_empty() || !device.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(anyhow!("INVALID DEVICE {device:?}"));
+        }
+        spec.insert("device".to_string(), Value::String(device.clone()));
+
+        let name = name.unwrap_or_else(|| {
+            item.map(|i| i.metadata.name.clone())
+                .unwrap_or_else(|| template.metadata.name.clone())
+        });
+
+        Ok(ActorInstance {
+            kind: template.kind.clone(),
+            name,
+            sidecar: template.sidecar.clone(),
+            device,
+            spec,
+        })
+    }
+}
+
+impl ActorInstance {
+    /// Worker configuration accepted by `spawn -j`, the metadata is passed separately.
+    pub fn config(&self) -> Value {
+        json!({
+            "kind": self.kind,
+            "metadata": {
+                "name": self.name,
+                "actor_host": "",
+            },
+            "spec": self.spec,
+        })
+    }
+
+    /// Binary name of the worker, as used for the Tauri sidecars.
+    pub fn binary(&self) -> String {
+        format!("{}-{}", self.sidecar, self.device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instantiate_merges_template_item_and_values() {
+        let gallery = ActorsGallery::load().unwrap();
+        let values = HashMap::from([
+            ("hf_token".to_string(), json!("token")),
+            ("seed".to_string(), json!(1)),
+        ]);
+        let instance = gallery
+            .instantiate(
+                "mistralrs/gemma-2b-it",
+                None,
+                Some("cpu".to_string()),
+                &values,
+            )
+            .unwrap();
+
+        assert_eq!(instance.name, "mistralrs-gemma-2b-it");
+        assert_eq!(instance.binary(), "onceuponai-actors-mistralrs-cpu");
+        assert_eq!(instance.spec["model_id"], json!("google/gemma-2b-it"));
+        assert_eq!(instance.device, "cpu");
+        assert_eq!(instance.spec["device"], json!("cpu"));
+        assert_eq!(instance.spec["hf_token"], json!("token"));
+        assert_eq!(instance.spec["seed"], json!(1));
+        assert!(instance.spec.values().all(|v| !v.is_null()));
+        assert_eq!(instance.config()["metadata"]["name"], json!(instance.name));
+    }
+
+    #[test]
+    fn test_instantiate_rejects_unknown_id_and_device() {
+        let gallery = ActorsGallery::load().unwrap();
+        let values = HashMap::new();
+        assert!(gallery.instantiate("unknown", None, None, &values).is_err());
+        assert!(gallery
+            .instantiate("e5", None, Some("../cpu".to_string()), &values)
+            .is_err());
+
+        let instance = gallery
+            .instantiate("e5", Some("embed".to_string()), None, &values)
+            .unwrap();
+        assert_eq!(instance.name, "embed");
+        assert_eq!(instance.kind, "e5");
+        assert_eq!(
+            instance.spec["model_repo"],
+            json!("intfloat/multilingual-e5-small")
+        );
+    }
+}
@@ -0,0 +1,281 @@
+pub mod gallery;
+
+use self::gallery::{ActorInstance, ActorsGallery};
+use crate::models::SpawnActorRequest;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use onceuponai_actors::abstractions::ActorMetadata;
+use onceuponai_actors::actors::main_actor::MainActorSpawnConfig;
+use onceuponai_actors::cluster::security::ClusterSecurity;
+use onceuponai_actors::initialize::{library_path_str, LD_LIBRARY_PATH_ENV};
+use onceuponai_actors::supervisor::{RestartPolicy, Supervisor, SupervisorEvent, SupervisorHandle};
+use onceuponai_actors::telemetry;
+use onceuponai_core::common::{serialize_and_encode, SerializationType};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use uuid::Uuid;
+
+const DEFAULT_ACTOR_HOST: &str = "127.0.0.1";
+const DEFAULT_BASE_PORT: u16 = 1993;
+const PORT_RANGE: u16 = 1000;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum WorkerStatus {
+    Running,
+    Restarting { code: Option<i32> },
+    Exited { code: Option<i32> },
+}
+
+/// Worker process started by the server.
+#[derive(Serialize, Debug, Clone)]
+pub struct SpawnedWorker {
+    pub sidecar_id: Uuid,
+    pub gallery_id: String,
+    pub name: String,
+    pub kind: String,
+    pub binary: String,
+    pub actor_host: String,
+    pub pid: Option<u32>,
+    /// Unix time in seconds.
+    pub started_at: u64,
+    pub status: WorkerStatus,
+    pub restarts: u32,
+}
+
+struct WorkerEntry {
+    worker: SpawnedWorker,
+    port: u16,
+    supervisor: SupervisorHandle,
+}
+
+/// Starts worker binaries for gallery items and keeps track of the child processes.
+pub struct WorkerSpawner {
+    config: MainActorSpawnConfig,
+    gallery: ActorsGallery,
+    workers: Mutex<HashMap<Uuid, WorkerEntry>>,
+}
+
+impl WorkerSpawner {
+    pub fn new(config: MainActorSpawnConfig) -> Result<Self> {
+        Ok(WorkerSpawner {
+            config,
+            gallery: ActorsGallery::load()?,
+            workers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn list(&self) -> Vec<SpawnedWorker> {
+        let workers = self.workers.lock().unwrap();
+        let mut list: Vec<SpawnedWorker> = workers.values().map(|e| e.worker.clone()).collect();
+        list.sort_by_key(|w| w.started_at);
+        list
+    }
+
+    pub fn spawn(self: &Arc<Self>, request: SpawnActorRequest) -> Result<SpawnedWorker> {
+        let instance =
+            self.gallery
+                .instantiate(&request.id, request.name, request.device, &request.spec)?;
+
+        let mut workers = self.workers.lock().unwrap();
+        if let Some(max_workers) = self.config.max_workers {
+            let running = workers
+                .values()
+                .filter(|e| !matches!(e.worker.status, WorkerStatus::Exited { .. }))
+                .count();
+            if running >= max_workers {
+                return Err(anyhow!(
+                    "MAXIMAL NUMBER OF SPAWNED WORKERS ({max_workers}) REACHED"
+                ));
+            }
+        }
+
+        let host = self
+            .config
+            .actor_host
+            .clone()
+            .unwrap_or(DEFAULT_ACTOR_HOST.to_string());
+        let used: Vec<u16> = workers.values().map(|e| e.port).collect();
+        let port = allocate_port(
+            &host,
+            self.config.base_port.unwrap_or(DEFAULT_BASE_PORT),
+            &used,
+        )?;
+        let actor_host = format!("{host}:{port}");
+        let sidecar_id = Uuid::new_v4();
+
+        let mut command = self.command(&instance, &actor_host, sidecar_id)?;
+        let child = launch(&mut command, &instance.name)?;
+        info!(
+            "SPAWNED WORKER {} ({}) ON {actor_host}",
+            instance.name,
+            instance.binary()
+        );
+
+        let worker = SpawnedWorker {
+            sidecar_id,
+            gallery_id: request.id,
+            name: instance.name.clone(),
+            kind: instance.kind.clone(),
+            binary: instance.binary(),
+            actor_host,
+            pid: child.id(),
+            started_at: unix_now(),
+            status: WorkerStatus::Running,
+            restarts: 0,
+        };
+
+        // The restarted worker keeps the command, so its name, sidecar id and host.
+        let policy = RestartPolicy {
+            max_restarts: self.config.max_restarts.unwrap_or(5),
+            ..Default::default()
+        };
+        let spawner = Arc::clone(self);
+        let supervisor = Supervisor::new(&instance.name, sidecar_id, policy).run(
+            child,
+            move || launch(&mut command, &instance.name),
+            move |event| spawner.on_event(&sidecar_id, event),
+        );
+        workers.insert(
+            sidecar_id,
+            WorkerEntry {
+                worker: worker.clone(),
+                port,
+                supervisor,
+            },
+        );
+
+        Ok(worker)
+    }
+
+    /// Kills a spawned worker and forgets it, `None` when the sidecar id is unknown.
+    pub fn kill(&self, sidecar_id: &Uuid) -> Option<SpawnedWorker> {
+        let entry = self.workers.lock().unwrap().remove(sidecar_id)?;
+        entry.supervisor.stop();
+        Some(entry.worker)
+    }
+
+    fn on_event(&self, sidecar_id: &Uuid, event: &SupervisorEvent) {
+        let mut workers = self.workers.lock().unwrap();
+        let Some(entry) = workers.get_mut(sidecar_id) else {
+            return;
+        };
+
+        match event {
+            SupervisorEvent::Exited { code, restart_in } => {
+                entry.worker.pid = None;
+                entry.worker.status = match restart_in {
+                    Some(_) => WorkerStatus::Restarting { code: *code },
+                    None => WorkerStatus::Exited { code: *code },
+                };
+            }
+            SupervisorEvent::Restarted { pid, restarts } => {
+                entry.worker.pid = *pid;
+                entry.worker.restarts = *restarts;
+                entry.worker.status = WorkerStatus::Running;
+            }
+            SupervisorEvent::Stopped => {}
+        }
+    }
+
+    fn command(
+        &self,
+        instance: &ActorInstance,
+        actor_host: &str,
+        sidecar_id: Uuid,
+    ) -> Result<Command> {
+        let metadata = ActorMetadata {
+            name: instance.name.clone(),
+            features: None,
+            actor_id: None,
+            actor_host: actor_host.to_string(),
+            actor_seed: self.config.actor_seed.clone(),
+            sidecar_id: Some(sidecar_id),
+            max_concurrency: None,
+            queue_limit: None,
+            shutdown_grace_period: None,
+            // The secret is inherited through the environment, not the command line.
+            cluster_secret: None,
+            cluster_encryption: ClusterSecurity::get().map(|s| s.is_encrypted()),
+            tracing: telemetry::config(),
+        };
+        let metadata = serialize_and_encode(metadata, SerializationType::YAML)?;
+        let config = serialize_and_encode(instance.config(), SerializationType::JSON)?;
+
+        let binary = match &self.config.bin_dir {
+            Some(bin_dir) => PathBuf::from(bin_dir).join(instance.binary()),
+            None => PathBuf::from(instance.binary()),
+        };
+
+        let mut command = Command::new(binary);
+        command
+            .env(LD_LIBRARY_PATH_ENV, library_path_str())
+            .args(["spawn", "-j", &config, "-m", &metadata])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        Ok(command)
+    }
+}
+
+/// First port from `base_port` which is not used by a spawned worker and can be bound.
+fn allocate_port(host: &str, base_port: u16, used: &[u16]) -> Result<u16> {
+    (base_port..base_port.saturating_add(PORT_RANGE))
+        .filter(|port| !used.contains(port))
+        .find(|port| TcpListener::bind((host, *port)).is_ok())
+        .ok_or(anyhow!("NO FREE PORT FROM {base_port} ON {host}"))
+}
+
+fn launch(command: &mut Command, name: &str) -> Result<Child> {
+    let mut child = command.spawn()?;
+    forward_output(name, child.stdout.take());
+    forward_output(name, child.stderr.take());
+    Ok(child)
+}
+
+fn forward_output<R>(name: &str, output: Option<R>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let Some(output) = output else {
+        return;
+    };
+
+    let name = name.to_string();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(output).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            info!("[{name}] {line}");
+        }
+    });
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_port_skips_used_and_bound_ports() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let bound = listener.local_addr().unwrap().port();
+        let base_port = bound.saturating_sub(1);
+
+        let port = allocate_port("127.0.0.1", base_port, &[base_port]).unwrap();
+        assert_ne!(port, base_port);
+        assert_ne!(port, bound);
+    }
+}
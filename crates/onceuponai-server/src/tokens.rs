@@ -0,0 +1,351 @@
+use crate::handlers::auth::generate_pat_token;
+use crate::models::PATClaims;
+use crate::roles::UserRoles;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use log::error;
+use onceuponai_actors::actors::main_actor::{
+    MainActorPersonalTokensConfig, MainActorSpec, ROOT_SUBJECT,
+};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+use uuid::Uuid;
+
+const DEFAULT_PATH: &str = "personal_tokens.db";
+const DEFAULT_CACHE_TTL: u64 = 30;
+/// Lookups cached before the expired ones are dropped.
+const CACHE_PRUNE_SIZE: usize = 4096;
+const ROOT_TOKEN_EXPIRATION_DAYS: i64 = 30;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS personal_tokens (
+    id TEXT PRIMARY KEY,
+    subject TEXT NOT NULL,
+    name TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    revoked_at TEXT,
+    client_ip TEXT,
+    scopes TEXT
+);
+CREATE INDEX IF NOT EXISTS personal_tokens_subject ON personal_tokens (subject);";
+
+const COLUMNS: &str = "id, subject, name, created_at, expires_at, revoked_at, client_ip, scopes";
+
+/// Record of an issued personal access token, the token itself is never stored.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PersonalToken {
+    /// `jti` claim of the token.
+    pub id: String,
+    pub subject: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// Address the token was requested from.
+    pub client_ip: Option<String>,
+    /// Not restricted when not set.
+    pub scopes: Option<Vec<String>>,
+}
+
+impl PersonalToken {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(PersonalToken {
+            id: row.get(0)?,
+            subject: row.get(1)?,
+            name: row.get(2)?,
+            created_at: row.get(3)?,
+            expires_at: row.get(4)?,
+            revoked_at: row.get(5)?,
+            client_ip: row.get(6)?,
+            scopes: row
+                .get::<_, Option<String>>(7)?
+                .map(|scopes| scopes.split_whitespace().map(String::from).collect()),
+        })
+    }
+}
+
+/// What a new token may do, everything its subject may when not restricted.
+#[derive(Debug, Clone, Default)]
+pub struct TokenGrant {
+    pub scopes: Option<Vec<String>>,
+    /// Roles of the OIDC user minting the token.
+    pub roles: Option<UserRoles>,
+    /// Expiry of the personal token minting this one, the new token does not outlive it.
+    pub expires_before: Option<DateTime<Utc>>,
+}
+
+/// Issued personal access tokens, kept in SQLite so they survive restarts and can be
+/// revoked before they expire.
+pub struct PersonalTokenStore {
+    connection: Mutex<Connection>,
+    cache_ttl: std::time::Duration,
+    /// Whether a token id is active, looked up by the auth guard on every request.
+    active: Mutex<HashMap<String, (Instant, bool)>>,
+}
+
+impl PersonalTokenStore {
+    pub fn new(config: MainActorPersonalTokensConfig) -> Result<Self> {
+        let path = config.path.unwrap_or(DEFAULT_PATH.to_string());
+        let connection = Connection::open(&path)
+            .map_err(|e| anyhow!("PERSONAL TOKENS STORE {path} CAN NOT BE OPENED: {e}"))?;
+        connection.execute_batch(SCHEMA)?;
+        // Stores created before tokens had scopes.
+        if connection
+            .prepare("SELECT scopes FROM personal_tokens LIMIT 0")
+            .is_err()
+        {
+            connection.execute("ALTER TABLE personal_tokens ADD COLUMN scopes TEXT", [])?;
+        }
+
+        Ok(PersonalTokenStore {
+            connection: Mutex::new(connection),
+            cache_ttl: std::time::Duration::from_secs(
+                config.cache_ttl.unwrap_or(DEFAULT_CACHE_TTL),
+            ),
+            active: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Mints a token of `subject` and records it.
+    pub fn issue(
+        &self,
+        secret: &str,
+        subject: &str,
+        name: &str,
+        expiration_days: i64,
+        client_ip: Option<String>,
+        grant: TokenGrant,
+    ) -> Result<(String, PersonalToken)> {
+        let created_at = Utc::now();
+        let expires_at = created_at + Duration::days(expiration_days);
+        let record = PersonalToken {
+            id: Uuid::new_v4().to_string(),
+            subject: subject.to_string(),
+            name: name.to_string(),
+            created_at,
+            expires_at: grant
+                .expires_before
+                .map_or(expires_at, |limit| expires_at.min(limit)),
+            revoked_at: None,
+            client_ip,
+            scopes: grant.scopes,
+        };
+
+        self.connection().execute(
+            &format!(
+                "INSERT INTO personal_tokens ({COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+            ),
+            params![
+                record.id,
+                record.subject,
+                record.name,
+                record.created_at,
+                record.expires_at,
+                record.revoked_at,
+                record.client_ip,
+                record.scopes.as_ref().map(|scopes| scopes.join(" ")),
+            ],
+        )?;
+
+        let token = generate_pat_token(
+            secret,
+            &PATClaims {
+                sub: record.subject.clone(),
+                exp: record.expires_at.timestamp() as usize,
+                iat: Some(record.created_at.timestamp() as usize),
+                jti: Some(record.id.clone()),
+                name: Some(record.name.clone()),
+                scopes: record.scopes.clone(),
+                roles: grant.roles,
+            },
+        );
+        Ok((token, record))
+    }
+
+    /// Tokens of a subject, or of everyone when not set, the newest first.
+    pub fn list(&self, subject: Option<&str>) -> Result<Vec<PersonalToken>> {
+        let connection = self.connection();
+        let tokens = match subject {
+            Some(subject) => connection
+                .prepare(&format!(
+                    "SELECT {COLUMNS} FROM personal_tokens WHERE subject = ?1 ORDER BY created_at DESC"
+                ))?
+                .query_map([subject], PersonalToken::from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?,
+            None => connection
+                .prepare(&format!(
+                    "SELECT {COLUMNS} FROM personal_tokens ORDER BY created_at DESC"
+                ))?
+                .query_map([], PersonalToken::from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?,
+        };
+        Ok(tokens)
+    }
+
+    /// Revokes a token of `subject`, or of anyone when not set. `None` when there is no such
+    /// token, a token revoked before keeps its revocation time.
+    pub fn revoke(&self, id: &str, subject: Option<&str>) -> Result<Option<PersonalToken>> {
+        let connection = self.connection();
+        let Some(token) = connection
+            .query_row(
+                &format!("SELECT {COLUMNS} FROM personal_tokens WHERE id = ?1"),
+                [id],
+                PersonalToken::from_row,
+            )
+            .optional()?
+            .filter(|token| subject.is_none_or(|subject| token.subject == subject))
+        else {
+            return Ok(None);
+        };
+
+        if token.revoked_at.is_some() {
+            return Ok(Some(token));
+        }
+
+        let revoked_at = Utc::now();
+        connection.execute(
+            "UPDATE personal_tokens SET revoked_at = ?1 WHERE id = ?2",
+            params![revoked_at, id],
+        )?;
+        self.cache().insert(id.to_string(), (Instant::now(), false));
+
+        Ok(Some(PersonalToken {
+            revoked_at: Some(revoked_at),
+            ..token
+        }))
+    }
+
+    /// True when the token was issued by this store and is not revoked. Lookups are cached,
+    /// so a token revoked by another server is rejected once the cached lookup expires.
+    pub fn is_active(&self, id: &str) -> bool {
+        if let Some((read, active)) = self.cache().get(id) {
+            if read.elapsed() < self.cache_ttl {
+                return *active;
+            }
+        }
+
+        let active = self
+            .connection()
+            .query_row(
+                "SELECT revoked_at IS NULL FROM personal_tokens WHERE id = ?1",
+                [id],
+                |row| row.get::<_, bool>(0),
+            )
+            .optional()
+            .map(|active| active.unwrap_or(false))
+            .unwrap_or_else(|e| {
+                error!("PERSONAL TOKEN {id} LOOKUP FAILED: {e}");
+                false
+            });
+
+        let mut cache = self.cache();
+        if cache.len() >= CACHE_PRUNE_SIZE {
+            let ttl = self.cache_ttl;
+            cache.retain(|_, (read, _)| read.elapsed() < ttl);
+        }
+        cache.insert(id.to_string(), (Instant::now(), active));
+        active
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn cache(&self) -> std::sync::MutexGuard<'_, HashMap<String, (Instant, bool)>> {
+        self.active.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Mints the root token printed at startup and records it, so it can be revoked like the
+/// other tokens.
+pub fn issue_root_token(spec: &MainActorSpec) -> Result<String> {
+    let secret = spec
+        .personal_access_token_secret
+        .as_deref()
+        .ok_or_else(|| anyhow!("PERSONAL_ACCESS_TOKEN_SECRET"))?;
+    let store = PersonalTokenStore::new(spec.personal_tokens.clone().unwrap_or_default())?;
+    let (token, _) = store.issue(
+        secret,
+        ROOT_SUBJECT,
+        ROOT_SUBJECT,
+        ROOT_TOKEN_EXPIRATION_DAYS,
+        None,
+        TokenGrant::default(),
+    )?;
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::auth::verify_pat_token;
+
+    #[test]
+    fn test_issue_list_and_revoke() -> Result<()> {
+        let store = PersonalTokenStore::new(MainActorPersonalTokensConfig {
+            path: Some(":memory:".to_string()),
+            cache_ttl: Some(60),
+        })?;
+
+        let scopes = Some(vec![
+            "invoke:chat".to_string(),
+            "models:gemma/*".to_string(),
+        ]);
+        let grant = TokenGrant {
+            scopes: scopes.clone(),
+            ..Default::default()
+        };
+        let (token, record) = store.issue("secret", "user@example.com", "ci", 30, None, grant)?;
+        store.issue(
+            "secret",
+            "other@example.com",
+            "laptop",
+            30,
+            None,
+            TokenGrant::default(),
+        )?;
+
+        let claims = verify_pat_token(&token, "secret")?.claims;
+        assert_eq!(claims.jti.as_deref(), Some(record.id.as_str()));
+        assert_eq!(claims.name.as_deref(), Some("ci"));
+        assert_eq!(claims.scopes, scopes);
+        assert!(store.is_active(&record.id));
+        assert!(!store.is_active("unknown"));
+
+        assert_eq!(store.list(Some("user@example.com"))?, vec![record.clone()]);
+        assert_eq!(store.list(None)?.len(), 2);
+
+        assert!(store
+            .revoke(&record.id, Some("other@example.com"))?
+            .is_none());
+        let revoked = store.revoke(&record.id, Some("user@example.com"))?.unwrap();
+        assert!(revoked.revoked_at.is_some());
+        assert!(!store.is_active(&record.id));
+        assert_eq!(store.list(Some("user@example.com"))?, vec![revoked]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_issue_does_not_outlive_the_parent_token() -> Result<()> {
+        let store = PersonalTokenStore::new(MainActorPersonalTokensConfig {
+            path: Some(":memory:".to_string()),
+            cache_ttl: Some(60),
+        })?;
+
+        let expires_before = Utc::now() + Duration::days(1);
+        let grant = TokenGrant {
+            expires_before: Some(expires_before),
+            ..Default::default()
+        };
+        let (token, record) = store.issue("secret", "user@example.com", "ci", 365, None, grant)?;
+        assert_eq!(record.expires_at, expires_before);
+        let claims = verify_pat_token(&token, "secret")?.claims;
+        assert_eq!(claims.exp, expires_before.timestamp() as usize);
+        Ok(())
+    }
+}
@@ -2,20 +2,26 @@ use crate::{
     server::{TauriAppConfig, TauriAppState},
     SpawnedActor, SPAWNED_ACTORS,
 };
+use async_trait::async_trait;
 use futures::StreamExt;
 use onceuponai_actors::{
     abstractions::ActorMetadata,
     initialize::{library_path_str, LD_LIBRARY_PATH_ENV},
+    supervisor::{RestartPolicy, Supervisor, WorkerProcess},
 };
 use onceuponai_core::{
     common::{serialize_and_encode, ResultExt, SerializationType},
-    notifications::{Notification, NotificationLevel},
+    notifications::Notification,
 };
 use onceuponai_server::handlers::oai::ChatCompletionsRequest;
 use reqwest::Client;
 use serde_json::{json, Value};
-use tauri::{path::BaseDirectory, AppHandle, Emitter, Manager, State};
-use tauri_plugin_shell::{process::CommandEvent, ShellExt};
+use std::sync::{Arc, Mutex};
+use tauri::{async_runtime::Receiver, path::BaseDirectory, AppHandle, Emitter, Manager, State};
+use tauri_plugin_shell::{
+    process::{CommandChild, CommandEvent},
+    ShellExt,
+};
 use uuid::Uuid;
 
 #[tauri::command]
@@ -35,7 +41,6 @@ pub fn kill_actor(sidecar_id: Uuid) -> Result<(), String> {
         actors
             .remove(&sidecar_id)
             .expect("SPAWNED_ACTOR")
-            .child
             .kill()
             .map_str_err()?;
     }
@@ -79,26 +84,91 @@ pub async fn spawn_actor(
     config.actor_next_port += 1;
     let sidecar_id = Uuid::new_v4();
     let metadata = ActorMetadata {
-        name,
+        name: name.clone(),
         features: None,
         actor_id: None,
         actor_host: format!("{}:{}", config.actor_base_host, config.actor_next_port),
         actor_seed: Some(config.actor_seed.clone()),
         sidecar_id: Some(sidecar_id),
+        max_concurrency: None,
+        queue_limit: None,
+        shutdown_grace_period: None,
+        cluster_secret: None,
+        cluster_encryption: None,
+        tracing: None,
     };
     let metadata = serialize_and_encode(metadata, SerializationType::YAML).map_str_err()?;
 
-    let ld_library_path = library_path_str();
-    let sidecar_command = app
-        .shell()
-        .sidecar(format!("{}-{}", sidecar, device))
+    let binary = format!("{}-{}", sidecar, device);
+    let child = Arc::new(Mutex::new(None));
+    let process = SidecarProcess::launch(&app, &binary, &spec_json_base64, &metadata, &child)
+        .map_str_err()?;
+
+    // Restarts reuse the metadata, so the worker keeps its name, sidecar id and host.
+    let events = app.clone();
+    let restarted = child.clone();
+    let supervisor = Supervisor::new(&name, sidecar_id, RestartPolicy::default()).run(
+        process,
+        move || SidecarProcess::launch(&app, &binary, &spec_json_base64, &metadata, &restarted),
+        move |event| {
+            let (message, level) = event.notification(&name, &sidecar_id);
+            if let Ok(message) = Notification::build(&message, level) {
+                events.emit("message", message).unwrap();
+            }
+        },
+    );
+
+    SPAWNED_ACTORS
+        .get()
+        .expect("SPAWNED_ACTORS")
+        .lock()
         .map_str_err()?
-        .env(LD_LIBRARY_PATH_ENV, ld_library_path)
-        .args(["spawn", "-j", &spec_json_base64, "-m", &metadata]);
-    let (mut rx, child) = sidecar_command.spawn().map_str_err()?;
+        .insert(sidecar_id, SpawnedActor { child, supervisor });
+
+    Ok(json!({"sidecar_id": sidecar_id}))
+}
+
+/// Sidecar worker, the child is shared with `SpawnedActor` to kill it on exit.
+struct SidecarProcess {
+    app: AppHandle,
+    rx: Receiver<CommandEvent>,
+    child: Arc<Mutex<Option<CommandChild>>>,
+}
 
-    tauri::async_runtime::spawn(async move {
-        while let Some(message) = rx.recv().await {
+impl SidecarProcess {
+    fn launch(
+        app: &AppHandle,
+        binary: &str,
+        spec_json_base64: &str,
+        metadata: &str,
+        child: &Arc<Mutex<Option<CommandChild>>>,
+    ) -> anyhow::Result<Self> {
+        let ld_library_path = library_path_str();
+        let sidecar_command = app
+            .shell()
+            .sidecar(binary)?
+            .env(LD_LIBRARY_PATH_ENV, ld_library_path)
+            .args(["spawn", "-j", spec_json_base64, "-m", metadata]);
+        let (rx, sidecar_child) = sidecar_command.spawn()?;
+        *child.lock().map_anyhow_err()? = Some(sidecar_child);
+
+        Ok(SidecarProcess {
+            app: app.clone(),
+            rx,
+            child: child.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl WorkerProcess for SidecarProcess {
+    fn pid(&self) -> Option<u32> {
+        self.child.lock().ok()?.as_ref().map(|c| c.pid())
+    }
+
+    async fn wait(&mut self) -> Option<i32> {
+        while let Some(message) = self.rx.recv().await {
+            let app = &self.app;
             match message {
                 CommandEvent::Stderr(buf) => {
                     let text = std::str::from_utf8(&buf).unwrap();
@@ -120,25 +190,23 @@ pub async fn spawn_actor(
                     println!("ERROR {}", &error);
                     app.emit("message", error).unwrap();
                 }
-                CommandEvent::Terminated(_) => {
+                CommandEvent::Terminated(payload) => {
                     println!("TERMINATED");
-                    let message =
-                        Notification::build("ACTOR TERMINATED", NotificationLevel::Info).unwrap();
-                    app.emit("message", message).unwrap();
+                    return payload.code;
                 }
                 _ => println!("OTHER"),
             }
         }
-    });
 
-    SPAWNED_ACTORS
-        .get()
-        .expect("SPAWNED_ACTORS")
-        .lock()
-        .map_str_err()?
-        .insert(sidecar_id, SpawnedActor { child });
+        None
+    }
 
-    Ok(json!({"sidecar_id": sidecar_id}))
+    async fn kill(&mut self) -> anyhow::Result<()> {
+        if let Some(child) = self.child.lock().map_anyhow_err()?.take() {
+            child.kill()?;
+        }
+        Ok(())
+    }
 }
 
 #[tauri::command]
@@ -8,6 +8,7 @@ use clap::{Parser, Subcommand, ValueEnum};
 use commands::{actors_gallery, config, init_actor, kill_actor, spawn_actor, v1_chat_completions};
 use once_cell::sync::OnceCell;
 use onceuponai_actors::initialize::{library_path_str, LD_LIBRARY_PATH_ENV};
+use onceuponai_actors::supervisor::SupervisorHandle;
 use onceuponai_core::common::ResultExt;
 use serde::{Deserialize, Serialize};
 use server::{TauriAppConfig, TauriAppState};
@@ -22,7 +23,19 @@ use uuid::Uuid;
 
 #[derive(Debug)]
 struct SpawnedActor {
-    child: CommandChild,
+    child: Arc<Mutex<Option<CommandChild>>>,
+    supervisor: SupervisorHandle,
+}
+
+impl SpawnedActor {
+    /// Stops the supervisor first, so the worker is not restarted.
+    fn kill(&self) -> Result<()> {
+        self.supervisor.stop();
+        if let Some(child) = self.child.lock().map_anyhow_err()?.take() {
+            child.kill()?;
+        }
+        Ok(())
+    }
 }
 
 static SPAWNED_ACTORS: OnceCell<Arc<Mutex<HashMap<Uuid, SpawnedActor>>>> = OnceCell::new();
@@ -52,6 +65,11 @@ pub struct MainArgs {
     session_key: Option<String>,
     #[clap(long)]
     personal_access_token_secret: Option<String>,
+    #[clap(long)]
+    overload_threshold: Option<usize>,
+    /// YAML file with the route rules, weighted targets and shadow target of public models.
+    #[clap(long)]
+    routes: Option<String>,
     #[clap(long, default_value_t = false)]
     headless: bool,
     #[clap(long, default_value_t = false)]
@@ -186,7 +204,7 @@ fn clean_up_resources() -> Result<()> {
         let mut actors = actors_mutex.lock().map_anyhow_err()?;
 
         for (_uuid, actor) in actors.drain() {
-            actor.child.kill()?;
+            actor.kill()?;
         }
     }
     println!("Cleaning up resources...");
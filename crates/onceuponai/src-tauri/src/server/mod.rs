@@ -4,11 +4,12 @@ use onceuponai_actors::abstractions::ActorMetadata;
 use onceuponai_actors::actors::main_actor::{
     MainActor, MainActorAuthConfig, MainActorOidcConfig, MainActorSpec,
 };
+use onceuponai_actors::actors::routing::load_routes;
 use onceuponai_actors::cluster::start_main_cluster;
 use onceuponai_core::common::{
     env_or_some, env_or_some_or_fn, generate_token, random_base64, ResultExt,
 };
-use onceuponai_server::handlers::auth::generate_pat_token;
+use onceuponai_server::tokens::issue_root_token;
 use serde::{Deserialize, Serialize};
 use std::{
     io,
@@ -43,6 +44,17 @@ pub fn init(config: Option<Arc<Mutex<TauriAppConfig>>>, main_args: MainArgs) ->
         actor_id: None,
         actor_seed: None,
         sidecar_id: None,
+        max_concurrency: None,
+        queue_limit: None,
+        shutdown_grace_period: None,
+        cluster_secret: None,
+        cluster_encryption: None,
+        tracing: None,
+    };
+
+    let routes = match &main_args.routes {
+        Some(path) => Some(load_routes(path).map_io_err()?),
+        None => None,
     };
 
     let auth = if main_args.oidc {
@@ -52,8 +64,12 @@ pub fn init(config: Option<Arc<Mutex<TauriAppConfig>>>, main_args: MainArgs) ->
                 issuer_url: env_or_some("OIDC_ISSUER_URL", main_args.oidc_issuer_url),
                 client_secret: env_or_some("OIDC_CLIENT_SECRET", main_args.oidc_client_secret),
                 redirect_url: env_or_some("OIDC_REDIRECT_URL", main_args.oidc_redirect_url),
+                post_logout_redirect_url: None,
+                metadata_ttl: None,
+                refresh_tokens: None,
             }),
             _auth_token: None,
+            roles: None,
         })
     } else {
         None
@@ -76,6 +92,14 @@ pub fn init(config: Option<Arc<Mutex<TauriAppConfig>>>, main_args: MainArgs) ->
             || random_base64(64),
         )),
         auth,
+        admins: None,
+        overload_threshold: main_args.overload_threshold,
+        routes,
+        cache: None,
+        pipelines: None,
+        spawn: None,
+        audit: None,
+        personal_tokens: None,
     };
 
     if let Some(conf) = config {
@@ -87,14 +111,8 @@ pub fn init(config: Option<Arc<Mutex<TauriAppConfig>>>, main_args: MainArgs) ->
                 .unwrap()
                 .expect("MAIN ACTOR SPEC");
 
-            let secret = res
-                .0
-                .personal_access_token_secret
-                .clone()
-                .expect("PERSONAL_ACCESS_TOKEN_SECRET");
-
             let auth_token = generate_token(50);
-            let personal_token = generate_pat_token(&secret, "root", 30);
+            let personal_token = issue_root_token(&res.0).map_io_err()?;
             shared_config.base_url = format!("http://localhost:{}", res.0.server_port);
             shared_config.auth_token = auth_token.clone();
             shared_config.personal_token = personal_token;
@@ -107,13 +125,8 @@ pub fn init(config: Option<Arc<Mutex<TauriAppConfig>>>, main_args: MainArgs) ->
             onceuponai_server::serve::serve(res.0, res.1, auth_token).await
         })
     } else {
-        let secret = spec
-            .personal_access_token_secret
-            .clone()
-            .expect("PERSONAL_ACCESS_TOKEN_SECRET");
-
         let auth_token = generate_token(50);
-        let personal_token = generate_pat_token(&secret, "root", 30);
+        let personal_token = issue_root_token(&spec).map_io_err()?;
         println!("PERSONAL TOKEN: {personal_token}");
 
         actix_rt::System::new().block_on(async {